@@ -0,0 +1,106 @@
+// --- CSI v1 MESSAGES ---
+// Hand-written prost equivalents of the parts of csi.proto (spec v1) this
+// driver uses. Tags match the upstream definitions; fields the driver never
+// reads or sets are left out, and prost skips them on the wire.
+
+use std::collections::HashMap;
+
+// --- Identity ---
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPluginInfoRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPluginInfoResponse {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub vendor_version: String,
+    #[prost(map = "string, string", tag = "3")]
+    pub manifest: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPluginCapabilitiesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPluginCapabilitiesResponse {
+    /// Node-only driver: no controller service, so this stays empty.
+    #[prost(message, repeated, tag = "1")]
+    pub capabilities: Vec<PluginCapability>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PluginCapability {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProbeRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ProbeResponse {
+    /// google.protobuf.BoolValue
+    #[prost(message, optional, tag = "1")]
+    pub ready: Option<bool>,
+}
+
+// --- Node ---
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodePublishVolumeRequest {
+    #[prost(string, tag = "1")]
+    pub volume_id: String,
+    #[prost(string, tag = "4")]
+    pub target_path: String,
+    #[prost(bool, tag = "6")]
+    pub readonly: bool,
+    #[prost(map = "string, string", tag = "8")]
+    pub volume_context: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodePublishVolumeResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeUnpublishVolumeRequest {
+    #[prost(string, tag = "1")]
+    pub volume_id: String,
+    #[prost(string, tag = "2")]
+    pub target_path: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeUnpublishVolumeResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeGetCapabilitiesRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeGetCapabilitiesResponse {
+    /// No STAGE_UNSTAGE_VOLUME: ephemeral volumes are published directly.
+    #[prost(message, repeated, tag = "1")]
+    pub capabilities: Vec<NodeServiceCapability>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeServiceCapability {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeGetInfoRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeGetInfoResponse {
+    #[prost(string, tag = "1")]
+    pub node_id: String,
+    #[prost(int64, tag = "2")]
+    pub max_volumes_per_node: i64,
+}
+
+// --- Generated services (see build.rs) ---
+
+pub mod identity {
+    include!(concat!(env!("OUT_DIR"), "/csi.v1.Identity.rs"));
+}
+
+pub mod node {
+    include!(concat!(env!("OUT_DIR"), "/csi.v1.Node.rs"));
+}
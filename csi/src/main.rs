@@ -0,0 +1,401 @@
+// --- KUBE-CACHE CSI NODE PLUGIN ---
+// Exposes cached datasets to pods as read-only volumes without hostPath, so
+// the cache works under the `restricted` Pod Security level. Pods use a CSI
+// ephemeral inline volume whose attributes name the dataset:
+//
+//   volumes:
+//     - name: weights
+//       csi:
+//         driver: cache.kube-cache.openai.com
+//         readOnly: true
+//         volumeAttributes:
+//           dataset: s3://models/gpt-4-weights
+//
+// NodePublishVolume waits for the operator to finish the cache entry, then
+// bind-mounts it read-only into the volume directory as `<last key segment>`.
+//
+// Any pod can write such a volume, so before publishing one the plugin asks
+// the operator (`OPERATOR_URL`, `GET /authorize`) whether the pod's namespace
+// may read the dataset. The pod comes from the kubelet's pod info
+// (`podInfoOnMount`). No operator URL, or no answer, means no volume. The
+// operator answers with the dataset's canonical URI (e.g. an Azure `https://`
+// blob URL becomes `az://`), and the cache entry is looked up under that, as
+// the operator stores it.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::{info, warn};
+
+mod csi;
+use csi::identity::identity_server::{Identity, IdentityServer};
+use csi::node::node_server::{Node, NodeServer};
+use csi::*;
+
+pub const DRIVER_NAME: &str = "cache.kube-cache.openai.com";
+
+/// Volume attribute naming the dataset URI.
+const DATASET_ATTRIBUTE: &str = "dataset";
+/// Volume attribute set to `"true"` for pods reading through the operator's
+/// lazy mount instead of the finished entry.
+const LAZY_ATTRIBUTE: &str = "lazy";
+/// Pod info the kubelet adds to the volume context (`podInfoOnMount`).
+const POD_NAMESPACE_ATTRIBUTE: &str = "csi.storage.k8s.io/pod.namespace";
+const POD_NAME_ATTRIBUTE: &str = "csi.storage.k8s.io/pod.name";
+const POD_UID_ATTRIBUTE: &str = "csi.storage.k8s.io/pod.uid";
+
+struct Settings {
+    endpoint: String,
+    node_id: String,
+    cache_root: PathBuf,
+    publish_timeout: Duration,
+    operator_url: Option<String>,
+}
+
+impl Settings {
+    fn from_env() -> Self {
+        Self {
+            endpoint: std::env::var("CSI_ENDPOINT").unwrap_or_else(|_| "unix:///csi/csi.sock".to_string()),
+            node_id: std::env::var("NODE_NAME").unwrap_or_default(),
+            cache_root: std::env::var("CACHE_ROOT").unwrap_or_else(|_| "/var/lib/kube-cache".to_string()).into(),
+            publish_timeout: Duration::from_secs(
+                std::env::var("PUBLISH_TIMEOUT_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(90),
+            ),
+            operator_url: std::env::var("OPERATOR_URL").ok().filter(|v| !v.is_empty()),
+        }
+    }
+}
+
+// --- IDENTITY SERVICE ---
+struct IdentityService;
+
+#[tonic::async_trait]
+impl Identity for IdentityService {
+    async fn get_plugin_info(&self, _: Request<GetPluginInfoRequest>) -> Result<Response<GetPluginInfoResponse>, Status> {
+        Ok(Response::new(GetPluginInfoResponse {
+            name: DRIVER_NAME.to_string(),
+            vendor_version: env!("CARGO_PKG_VERSION").to_string(),
+            manifest: Default::default(),
+        }))
+    }
+
+    async fn get_plugin_capabilities(
+        &self,
+        _: Request<GetPluginCapabilitiesRequest>,
+    ) -> Result<Response<GetPluginCapabilitiesResponse>, Status> {
+        Ok(Response::new(GetPluginCapabilitiesResponse::default()))
+    }
+
+    async fn probe(&self, _: Request<ProbeRequest>) -> Result<Response<ProbeResponse>, Status> {
+        Ok(Response::new(ProbeResponse { ready: Some(true) }))
+    }
+}
+
+// --- NODE SERVICE ---
+struct NodeService {
+    node_id: String,
+    cache_root: PathBuf,
+    publish_timeout: Duration,
+    operator_url: Option<String>,
+    http: reqwest::Client,
+}
+
+impl NodeService {
+    // Asks the operator whether the pod behind a volume may read its dataset,
+    // and returns the dataset's canonical URI.
+    async fn authorize(&self, context: &HashMap<String, String>, dataset: &str) -> Result<String, Status> {
+        let Some(url) = self.operator_url.as_deref() else {
+            return Err(Status::failed_precondition("OPERATOR_URL is not set, cannot authorize the pod"));
+        };
+        let mut query = vec![("dataset", dataset)];
+        let pod_info = [("namespace", POD_NAMESPACE_ATTRIBUTE), ("pod", POD_NAME_ATTRIBUTE), ("uid", POD_UID_ATTRIBUTE)];
+        for (name, key) in pod_info {
+            match context.get(key).filter(|v| !v.is_empty()) {
+                Some(value) => query.push((name, value.as_str())),
+                None => return Err(Status::failed_precondition(format!("volume context lacks '{}' (podInfoOnMount)", key))),
+            }
+        }
+
+        let resp = self.http.get(format!("{}/authorize", url.trim_end_matches('/')))
+            .query(&query)
+            .send()
+            .await
+            .map_err(|e| Status::unavailable(format!("cannot reach the operator: {}", e)))?;
+        let status = resp.status();
+        let reason = resp.text().await.unwrap_or_default();
+        if status.is_success() {
+            return match reason.contains("://") {
+                true => Ok(reason),
+                false => Err(Status::unavailable(format!("operator did not return the canonical URI of '{}'", dataset))),
+            };
+        }
+        match status {
+            reqwest::StatusCode::FORBIDDEN | reqwest::StatusCode::BAD_REQUEST => Err(Status::permission_denied(reason)),
+            _ => Err(Status::unavailable(format!("operator answered {}: {}", status, reason))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Node for NodeService {
+    async fn node_publish_volume(
+        &self,
+        request: Request<NodePublishVolumeRequest>,
+    ) -> Result<Response<NodePublishVolumeResponse>, Status> {
+        let req = request.into_inner();
+        if req.target_path.is_empty() {
+            return Err(Status::invalid_argument("target_path is required"));
+        }
+        let dataset = req.volume_context.get(DATASET_ATTRIBUTE)
+            .ok_or_else(|| Status::invalid_argument(format!("volume attribute '{}' is required", DATASET_ATTRIBUTE)))?;
+
+        let dataset = match self.authorize(&req.volume_context, dataset).await {
+            Ok(canonical) => canonical,
+            Err(e) => {
+                warn!(event = "publish_denied", volume_id = %req.volume_id, dataset = %dataset, error = %e.message(), "Not publishing dataset");
+                return Err(e);
+            }
+        };
+        let dataset = dataset.as_str();
+
+        let lazy = req.volume_context.get(LAZY_ATTRIBUTE).map(String::as_str) == Some("true");
+        let source = match lazy {
+            true => lazy_path(&self.cache_root, dataset),
+            false => cache_path(&self.cache_root, dataset),
+        };
+        let target_dir = PathBuf::from(&req.target_path);
+        let target = target_dir.join(entry_name(dataset));
+
+        // Already published (kubelet retries are idempotent)
+        if is_mounted(&target) {
+            return Ok(Response::new(NodePublishVolumeResponse {}));
+        }
+
+        info!(event = "publish_wait", volume_id = %req.volume_id, dataset = %dataset, "Waiting for cache entry");
+        if !wait_for_entry(&source, lazy, self.publish_timeout).await {
+            // kubelet retries with backoff; the pod stays ContainerCreating meanwhile
+            return Err(Status::unavailable(format!("dataset '{}' is not cached on this node yet", dataset)));
+        }
+
+        std::fs::create_dir_all(&target_dir).map_err(|e| Status::internal(e.to_string()))?;
+        if source.is_dir() {
+            std::fs::create_dir_all(&target).map_err(|e| Status::internal(e.to_string()))?;
+        } else {
+            std::fs::File::create(&target).map_err(|e| Status::internal(e.to_string()))?;
+        }
+        bind_mount_readonly(&source, &target).map_err(|e| Status::internal(format!("bind mount failed: {}", e)))?;
+
+        info!(event = "publish", volume_id = %req.volume_id, dataset = %dataset, target = %target.display(), "Published dataset");
+        Ok(Response::new(NodePublishVolumeResponse {}))
+    }
+
+    async fn node_unpublish_volume(
+        &self,
+        request: Request<NodeUnpublishVolumeRequest>,
+    ) -> Result<Response<NodeUnpublishVolumeResponse>, Status> {
+        let req = request.into_inner();
+        let target_dir = PathBuf::from(&req.target_path);
+
+        // The volume directory holds exactly one bind-mounted entry
+        if let Ok(entries) = std::fs::read_dir(&target_dir) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if is_mounted(&path) {
+                    unmount(&path).map_err(|e| Status::internal(format!("unmount failed: {}", e)))?;
+                }
+                let _ = if path.is_dir() { std::fs::remove_dir(&path) } else { std::fs::remove_file(&path) };
+            }
+        }
+        if let Err(e) = std::fs::remove_dir(&target_dir) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!(event = "unpublish_cleanup", target = %target_dir.display(), error = %e, "Could not remove volume directory");
+            }
+        }
+
+        info!(event = "unpublish", volume_id = %req.volume_id, "Unpublished dataset");
+        Ok(Response::new(NodeUnpublishVolumeResponse {}))
+    }
+
+    async fn node_get_capabilities(
+        &self,
+        _: Request<NodeGetCapabilitiesRequest>,
+    ) -> Result<Response<NodeGetCapabilitiesResponse>, Status> {
+        Ok(Response::new(NodeGetCapabilitiesResponse::default()))
+    }
+
+    async fn node_get_info(&self, _: Request<NodeGetInfoRequest>) -> Result<Response<NodeGetInfoResponse>, Status> {
+        Ok(Response::new(NodeGetInfoResponse {
+            node_id: self.node_id.clone(),
+            max_volumes_per_node: 0,
+        }))
+    }
+}
+
+// --- CACHE LAYOUT ---
+// Must match the operator's DatasetRef::cache_path: S3 entries keep their
+// scheme-less name, other schemes are prefixed with theirs, and a digest of
+// the URI keeps distinct datasets apart. The readable part is cut to
+// `MAX_READABLE_NAME` bytes so names stay under NAME_MAX.
+const MAX_READABLE_NAME: usize = 200;
+
+fn cache_path(cache_root: &Path, dataset: &str) -> PathBuf {
+    let mut readable = match dataset.split_once("://") {
+        Some(("s3", rest)) => rest.replace('/', "-"),
+        Some((scheme, rest)) => format!("{}-{}", scheme, rest.replace('/', "-")),
+        None => dataset.replace('/', "-"),
+    };
+    if readable.len() > MAX_READABLE_NAME {
+        let end = (0..=MAX_READABLE_NAME).rev().find(|i| readable.is_char_boundary(*i)).unwrap_or(0);
+        readable.truncate(end);
+    }
+    let digest = Sha256::digest(dataset.as_bytes());
+    let suffix: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    cache_root.join(format!("{}-{}", readable, suffix))
+}
+
+// Must match the operator's DatasetRef::lazy_path.
+fn lazy_path(cache_root: &Path, dataset: &str) -> PathBuf {
+    let mut path = cache_path(cache_root, dataset).into_os_string();
+    path.push(".lazy");
+    PathBuf::from(path)
+}
+
+// File name the dataset appears under inside the volume (last path segment,
+// without any query string, or OCI tag/digest).
+fn entry_name(dataset: &str) -> String {
+    let path = dataset.split('?').next().unwrap_or(dataset);
+    let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("dataset");
+    if dataset.starts_with("oci://") {
+        name.split(['@', ':']).next().unwrap_or(name).to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// Must match the operator's storage::is_complete: an entry counts once its
+// `<entry>.complete` marker is written, after the rename into place.
+fn is_complete(path: &Path) -> bool {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".complete");
+    Path::new(&marker).exists() && path.exists()
+}
+
+// The operator downloads into `<entry>.part`, renames on completion and then
+// writes the marker. A lazy mount point exists before it is mounted, so for
+// those wait for the mount.
+async fn wait_for_entry(path: &Path, lazy: bool, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let ready = if lazy { is_mounted(path) } else { is_complete(path) };
+        if ready {
+            return true;
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+// --- MOUNT HELPERS ---
+fn c_path(path: &Path) -> std::io::Result<CString> {
+    CString::new(path.as_os_str().as_encoded_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+}
+
+fn bind_mount_readonly(source: &Path, target: &Path) -> std::io::Result<()> {
+    let src = c_path(source)?;
+    let dst = c_path(target)?;
+
+    // A bind mount ignores MS_RDONLY on the first call; remount to apply it.
+    let flags = [libc::MS_BIND, libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY];
+    for flags in flags {
+        let ret = unsafe { libc::mount(src.as_ptr(), dst.as_ptr(), std::ptr::null(), flags, std::ptr::null()) };
+        if ret != 0 {
+            let err = std::io::Error::last_os_error();
+            if flags & libc::MS_REMOUNT != 0 {
+                let _ = unmount(target);
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+fn unmount(target: &Path) -> std::io::Result<()> {
+    let dst = c_path(target)?;
+    let ret = unsafe { libc::umount2(dst.as_ptr(), libc::MNT_DETACH) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn is_mounted(target: &Path) -> bool {
+    let Ok(mounts) = std::fs::read_to_string("/proc/self/mountinfo") else { return false };
+    let target = target.to_string_lossy();
+    // Field 5 of mountinfo is the mount point
+    mounts.lines().any(|line| line.split(' ').nth(4) == Some(target.as_ref()))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
+        .init();
+
+    let settings = Settings::from_env();
+
+    // 1. Bind the kubelet-facing socket, replacing a stale one from a previous run
+    let socket = settings.endpoint.strip_prefix("unix://").unwrap_or(&settings.endpoint).to_string();
+    if Path::new(&socket).exists() {
+        std::fs::remove_file(&socket)?;
+    }
+    if let Some(parent) = Path::new(&socket).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let listener = UnixListener::bind(&socket)?;
+
+    info!(event = "startup", driver = DRIVER_NAME, node = %settings.node_id, socket = %socket, "Kube-Cache CSI Node Plugin Online");
+
+    // 2. Serve Identity + Node
+    let node = NodeService {
+        node_id: settings.node_id,
+        cache_root: settings.cache_root,
+        publish_timeout: settings.publish_timeout,
+        operator_url: settings.operator_url,
+        http: reqwest::Client::builder().timeout(Duration::from_secs(10)).build()?,
+    };
+
+    Server::builder()
+        .add_service(IdentityServer::new(IdentityService))
+        .add_service(NodeServer::new(node))
+        .serve_with_incoming(UnixListenerStream::new(listener))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Shared with the operator's tests, so both sides agree on the layout
+    const CACHE_PATHS: &str = include_str!("../../testdata/cache_paths.txt");
+
+    #[test]
+    fn cache_paths_match_the_operator() {
+        for line in CACHE_PATHS.lines().filter(|l| !l.starts_with('#')) {
+            let (uri, path) = line.split_once(' ').unwrap();
+            assert_eq!(cache_path(Path::new("/cache"), uri), PathBuf::from(path), "{}", uri);
+            assert_eq!(lazy_path(Path::new("/cache"), uri), PathBuf::from(format!("{}.lazy", path)), "{}", uri);
+        }
+    }
+}
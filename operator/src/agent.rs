@@ -0,0 +1,454 @@
+// --- NODE AGENT ---
+// hostPath and CSI cache entries only exist on the node that downloaded them,
+// so a gated pod's data is fetched before it is released, on the node it will
+// run on:
+//
+// 1. The leader decides whether the pod may have its data (source policy,
+//    tenant access) and pins it, still gated, to a schedulable node matching
+//    its `nodeSelector` that runs a kube-cache pod: idle nodes first, then the
+//    one with the fewest pods already pinned. The pin is a
+//    `kubernetes.io/hostname` nodeSelector plus the `NODE_LABEL` label.
+// 2. Every replica runs on its own node (DaemonSet) and watches the pods
+//    pinned there. It fetches each one's dataset and sets `DATA_ANNOTATION`
+//    to `ready` once the entry is complete, or `failed` while a failed
+//    download waits for its retry.
+// 3. The leader releases the pod (with the rest of its gang) once it is
+//    `ready`. On `failed` it is released to read from the source itself if
+//    `fail_open` allows and its volume does not need the entry (CSI); hostPath
+//    pods always wait for the retry.
+//
+// Pods bound to the node without ever being gated (the webhook fails open)
+// are served too, and sit in ContainerCreating until their entry is in place:
+// hostPath volumes are typed, the CSI plugin waits.
+//
+// The agent authorizes again before fetching anything: nobody may be handed
+// an entry fetched for someone else unless they could read its source
+// themselves. Refusals and failed downloads are retried every `RETRY_DELAY`.
+//
+// The CSI plugin hands out entries too, to whatever pod names a dataset in
+// its volume attributes, so it asks `GET /authorize` on the metrics port
+// before publishing a volume: the pod must exist with the given UID, and its
+// namespace must pass the same checks as a download. The answer carries the
+// dataset's canonical URI, which names the cache entry the plugin mounts.
+
+use crate::config::Config;
+use crate::dataset::{self, DatasetError, DatasetRef};
+use crate::metrics::MetricsState;
+use crate::namespaces::{self, NamespaceFilter, NamespaceScope};
+use crate::prewarm::{self, PrewarmSettings, HOSTNAME_LABEL};
+use crate::scheduler::{self, Completed, DownloadQueue, Retries, Waiter, RETRY_DELAY};
+use crate::storage::{self, Backends};
+use crate::throttle::Throttle;
+use crate::webhook;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::{routing::get, Router};
+use futures::stream::{self, BoxStream, StreamExt};
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{ListParams, Patch, PatchParams};
+use kube::runtime::watcher::{self, Event};
+use kube::{Api, Client, ResourceExt};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+/// Label on a gated pod naming the node its data is fetched onto.
+pub const NODE_LABEL: &str = "kube-cache.openai.com/node";
+/// Set by the agent on a pinned pod: `ready` or `failed`.
+pub const DATA_ANNOTATION: &str = "kube-cache.openai.com/data";
+
+/// What the agent last reported for a pinned pod.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataState {
+    Ready,
+    Failed,
+}
+
+impl DataState {
+    fn as_str(self) -> &'static str {
+        match self {
+            DataState::Ready => "ready",
+            DataState::Failed => "failed",
+        }
+    }
+
+    /// The pod's reported state, if its agent has reported one.
+    pub fn of(pod: &Pod) -> Option<Self> {
+        match pod.annotations().get(DATA_ANNOTATION).map(String::as_str) {
+            Some("ready") => Some(DataState::Ready),
+            Some("failed") => Some(DataState::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// The node a gated pod is pinned to, if any.
+pub fn pinned_node(pod: &Pod) -> Option<&str> {
+    pod.labels().get(NODE_LABEL).map(String::as_str)
+}
+
+/// Pins a gated pod to the node its data will be fetched onto (see the
+/// header). None if no node with a kube-cache pod matches its `nodeSelector`.
+pub async fn pin(client: &Client, settings: &PrewarmSettings, pod: &Pod) -> Result<Option<String>, kube::Error> {
+    let selector = pod.spec.as_ref().and_then(|s| s.node_selector.clone()).unwrap_or_default();
+    let mut targets = prewarm::targets(client, settings, &selector, true).await?;
+    if targets.is_empty() {
+        targets = prewarm::targets(client, settings, &selector, false).await?;
+    }
+
+    let pods: Api<Pod> = Api::all(client.clone());
+    let pinned = pods.list(&ListParams::default().labels(NODE_LABEL).fields("status.phase=Pending")).await?;
+    let load = |node: &str| pinned.items.iter().filter(|p| pinned_node(p) == Some(node)).count();
+    let Some(target) = targets.iter().min_by_key(|t| load(&t.node)) else {
+        return Ok(None);
+    };
+
+    // Gated pods may still gain a nodeSelector
+    let patch = json!({
+        "metadata": { "labels": { NODE_LABEL: target.node } },
+        "spec": { "nodeSelector": { HOSTNAME_LABEL: target.hostname } },
+    });
+    let (namespace, name) = pod_id(pod);
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+    pods.patch(&name, &PatchParams::default(), &Patch::Merge(patch)).await?;
+    Ok(Some(target.node.clone()))
+}
+
+/// Fetches datasets for the pods pinned or scheduled to one node.
+pub struct NodeAgent {
+    client: Client,
+    node: String,
+    dataset_annotation: String,
+    cache_root: String,
+    #[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+    lazy_mounts: bool,
+    queue: Arc<DownloadQueue>,
+    backends: Arc<Backends>,
+    #[cfg_attr(not(feature = "fuse"), allow(dead_code))]
+    throttle: Arc<Throttle>,
+    metrics: MetricsState,
+    #[cfg(feature = "fuse")]
+    mounts: Arc<storage::lazy::LazyMounts>,
+}
+
+// What became of a pod the agent looked at
+enum Outcome {
+    /// Nothing to fetch for it
+    Skip,
+    /// Its entry is in place, queued or being mounted
+    Served,
+    /// Refused; look again later
+    Retry,
+}
+
+impl NodeAgent {
+    pub fn new(
+        client: Client,
+        config: &Config,
+        node: String,
+        queue: Arc<DownloadQueue>,
+        backends: Arc<Backends>,
+        throttle: Arc<Throttle>,
+        metrics: MetricsState,
+    ) -> Self {
+        Self {
+            client,
+            node,
+            dataset_annotation: config.dataset_annotation.clone(),
+            cache_root: config.cache_root.clone(),
+            lazy_mounts: config.lazy_mounts,
+            queue,
+            backends,
+            throttle,
+            metrics,
+            #[cfg(feature = "fuse")]
+            mounts: Arc::new(storage::lazy::LazyMounts::new(&config.cache_root)),
+        }
+    }
+
+    /// Watches this node's pods until the process exits. `done` reports the
+    /// download workers' results.
+    pub async fn run(self, scope: NamespaceScope, mut done: mpsc::UnboundedReceiver<Completed>) {
+        let (mut pods, filter) = loop {
+            match self.watch(&scope).await {
+                Ok(watch) => break watch,
+                Err(e) => {
+                    warn!(event = "agent_watch_error", node = %self.node, error = ?e, "Cannot watch this node's pods, retrying");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        };
+        info!(event = "agent_start", node = %self.node, "Fetching datasets for pods scheduled to this node");
+
+        // Lazy mounts report failures back here, to be retried like downloads
+        let (failed_tx, mut failed_rx) = mpsc::unbounded_channel::<Pod>();
+        let retries = Retries::default();
+        let mut served: HashSet<(String, String)> = HashSet::new();
+        let mut published: HashMap<(String, String), usize> = HashMap::new();
+        let mut positions_tick = tokio::time::interval(Duration::from_secs(5));
+
+        loop {
+            tokio::select! {
+                Some(event) = pods.next() => match event {
+                    Event::Apply(pod) | Event::InitApply(pod) => {
+                        if !filter.allows(pod.metadata.namespace.as_deref().unwrap_or_default()) {
+                            continue;
+                        }
+                        let id = pod_id(&pod);
+                        if served.contains(&id) || retries.pending(&id) {
+                            continue;
+                        }
+                        match self.serve(&pod, &failed_tx).await {
+                            Outcome::Skip => {}
+                            Outcome::Served => { served.insert(id); }
+                            Outcome::Retry => retries.schedule(&self.client, &pod),
+                        }
+                    }
+                    Event::Delete(pod) => {
+                        let (namespace, name) = pod_id(&pod);
+                        self.queue.forget(&namespace, &name);
+                        self.metrics.set_queue_depth(self.queue.depth());
+                        if published.remove(&(namespace.clone(), name.clone())).is_some() {
+                            self.metrics.clear_queue_position(&namespace, &name);
+                        }
+                        served.remove(&(namespace, name));
+                    }
+                    Event::Init | Event::InitDone => {}
+                },
+                Some(pod) = failed_rx.recv() => {
+                    served.remove(&pod_id(&pod));
+                    retries.schedule(&self.client, &pod);
+                },
+                Some(done) = done.recv() => {
+                    info!(event = "data_ready", path = %done.path, ok = done.ok, pods = done.waiters.len(), "Download finished");
+                    for waiter in done.waiters {
+                        let (namespace, name) = waiter.id();
+                        if published.remove(&(namespace.clone(), name.clone())).is_some() {
+                            self.metrics.clear_queue_position(&namespace, &name);
+                        }
+                        if done.ok {
+                            report(&self.client, &waiter.pod, DataState::Ready).await;
+                        } else {
+                            info!(event = "download_retry", namespace = %namespace, pod_name = %name, "Download failed, retrying for the waiting pod");
+                            report(&self.client, &waiter.pod, DataState::Failed).await;
+                            served.remove(&(namespace, name));
+                            retries.schedule(&self.client, &waiter.pod);
+                        }
+                    }
+                    self.metrics.set_queue_depth(self.queue.depth());
+                },
+                _ = positions_tick.tick() => {
+                    publish_queue_positions(&self.client, &self.queue, &mut published, &self.metrics).await;
+                },
+            }
+        }
+    }
+
+    // Pods pinned to this node while gated, and pods bound to it.
+    async fn watch(&self, scope: &NamespaceScope) -> Result<(BoxStream<'static, Event<Pod>>, NamespaceFilter), kube::Error> {
+        let pinned = watcher::Config::default().labels(&format!("{}={}", NODE_LABEL, self.node));
+        let bound = watcher::Config::default().fields(&format!("spec.nodeName={}", self.node));
+        let (pinned, filter) = namespaces::watch_pods(&self.client, scope, pinned).await?;
+        let (bound, _) = namespaces::watch_pods(&self.client, scope, bound).await?;
+        Ok((stream::select(pinned, bound).boxed(), filter))
+    }
+
+    // Makes sure a pod pinned or bound to this node gets its data: counts a hit, queues
+    // the download or starts the lazy mount.
+    async fn serve(&self, pod: &Pod, failed: &mpsc::UnboundedSender<Pod>) -> Outcome {
+        // Running pods already have their volume; finished ones need nothing
+        let pending = pod.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Pending");
+        if !pending || pod.metadata.deletion_timestamp.is_some() {
+            return Outcome::Skip;
+        }
+        let (namespace, name) = pod_id(pod);
+
+        let dataset = match dataset::lookup(&self.client, pod, &self.dataset_annotation).await {
+            Some(Ok(dataset)) => dataset,
+            Some(Err(e @ DatasetError::Lookup(..))) => {
+                warn!(event = "dataset_lookup_error", pod_name = %name, error = %e, "Could not read the pod's Dataset, retrying");
+                return Outcome::Retry;
+            }
+            Some(Err(e)) => {
+                error!(event = "dataset_invalid", pod_name = %name, error = %e, "Cannot resolve dataset reference");
+                return Outcome::Skip;
+            }
+            None => return Outcome::Skip,
+        };
+
+        #[cfg(feature = "fuse")]
+        let lazy = self.lazy_mounts && webhook::wants_lazy(pod, &dataset);
+        #[cfg(not(feature = "fuse"))]
+        let lazy = false;
+
+        let file_path = dataset.cache_path(&self.cache_root);
+        if !lazy && storage::is_complete(&file_path) {
+            info!(event = "cache_hit", pod_name = %name, path = %file_path, "Dataset found locally");
+            self.metrics.count_hit();
+            report(&self.client, pod, DataState::Ready).await;
+            return Outcome::Served;
+        }
+
+        if let Err(e) = webhook::check_source(&self.client, &namespace, &dataset).await {
+            error!(event = "dataset_source_denied", pod_name = %name, dataset = %dataset.uri, error = %e, "Pod's namespace may not use its dataset source, not fetching it");
+            return Outcome::Retry;
+        }
+        if let Err(e) = self.backends.tenants.check_access(&dataset).await {
+            error!(event = "dataset_access_denied", pod_name = %name, dataset = %dataset.uri, error = %e, "Pod cannot read its dataset, not fetching it");
+            return Outcome::Retry;
+        }
+
+        // Mounted off the watch loop: listing a large prefix takes a while
+        #[cfg(feature = "fuse")]
+        if lazy {
+            let (mounts, backends, throttle) = (self.mounts.clone(), self.backends.clone(), self.throttle.clone());
+            let (client, cache_root, pod, failed) = (self.client.clone(), self.cache_root.clone(), pod.clone(), failed.clone());
+            tokio::spawn(async move {
+                match mounts.mount(backends, throttle, &dataset, &cache_root).await {
+                    Ok(()) => report(&client, &pod, DataState::Ready).await,
+                    Err(e) => {
+                        error!(event = "lazy_mount_error", pod_name = %name, dataset = %dataset.uri, error = %e, "Cannot mount dataset lazily, retrying");
+                        report(&client, &pod, DataState::Failed).await;
+                        let _ = failed.send(pod);
+                    }
+                }
+            });
+            return Outcome::Served;
+        }
+        #[cfg(not(feature = "fuse"))]
+        let _ = (lazy, failed);
+
+        let priority = scheduler::pod_priority(&self.client, pod).await;
+        let waiter = Waiter { pod: pod.clone(), priority };
+        if self.queue.enqueue(dataset.clone(), file_path, waiter) {
+            info!(event = "download_queued", pod_name = %name, dataset = %dataset.uri, priority, depth = self.queue.depth(), "Queued dataset download");
+        }
+        self.metrics.set_queue_depth(self.queue.depth());
+        Outcome::Served
+    }
+}
+
+/// Answers the CSI plugin's `GET /authorize` (see the header).
+#[derive(Clone)]
+pub struct PublishAuthorizer {
+    client: Client,
+    backends: Arc<Backends>,
+    dataset_annotation: String,
+}
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    namespace: String,
+    pod: String,
+    uid: String,
+    dataset: String,
+}
+
+impl PublishAuthorizer {
+    pub fn new(client: Client, backends: Arc<Backends>, config: &Config) -> Self {
+        Self { client, backends, dataset_annotation: config.dataset_annotation.clone() }
+    }
+
+    pub fn routes(self) -> Router {
+        Router::new().route("/authorize", get(authorize_handler)).with_state(self)
+    }
+
+    async fn authorize(&self, query: &AuthorizeQuery) -> Result<String, (StatusCode, String)> {
+        let denied = |message: String| (StatusCode::FORBIDDEN, message);
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), &query.namespace);
+        let pod = pods.get_opt(&query.pod).await
+            .map_err(|e| (StatusCode::SERVICE_UNAVAILABLE, e.to_string()))?
+            .filter(|pod| pod.metadata.uid.as_deref() == Some(query.uid.as_str()))
+            .ok_or_else(|| denied(format!("no pod {}/{} with uid {}", query.namespace, query.pod, query.uid)))?;
+
+        // The pod's own reference carries its credentials; a hand-written
+        // volume is read as the operator. Both are compared in canonical form
+        let requested = DatasetRef::parse(&query.dataset).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        let dataset = match dataset::lookup(&self.client, &pod, &self.dataset_annotation).await {
+            Some(Ok(dataset)) if dataset.uri == requested.uri => dataset,
+            _ => requested,
+        };
+        webhook::check_source(&self.client, &query.namespace, &dataset).await.map_err(|e| denied(e.to_string()))?;
+        self.backends.tenants.check_access(&dataset).await.map_err(|e| denied(e.to_string()))?;
+        Ok(dataset.uri)
+    }
+}
+
+async fn authorize_handler(State(authorizer): State<PublishAuthorizer>, Query(query): Query<AuthorizeQuery>) -> (StatusCode, String) {
+    match authorizer.authorize(&query).await {
+        Ok(uri) => (StatusCode::OK, uri),
+        Err((status, message)) => {
+            warn!(event = "publish_denied", namespace = %query.namespace, pod_name = %query.pod, dataset = %query.dataset, error = %message, "Refused a CSI volume");
+            (status, message)
+        }
+    }
+}
+
+// Tells the leader how a pinned pod's data is doing. Pods that were never
+// gated have nobody waiting on it. A failed patch is logged; the pod's next
+// retry reports again.
+async fn report(client: &Client, pod: &Pod, state: DataState) {
+    if pinned_node(pod).is_none() || DataState::of(pod) == Some(state) {
+        return;
+    }
+    let (namespace, name) = pod_id(pod);
+    let patch = json!({ "metadata": { "annotations": { DATA_ANNOTATION: state.as_str() } } });
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+    if let Err(e) = pods.patch(&name, &PatchParams::default(), &Patch::Merge(patch)).await {
+        error!(event = "data_report_error", pod_name = %name, state = state.as_str(), error = ?e, "Failed to report the pod's data to the leader");
+    }
+}
+
+fn pod_id(pod: &Pod) -> (String, String) {
+    (pod.metadata.namespace.clone().unwrap_or_default(), pod.metadata.name.clone().unwrap_or_default())
+}
+
+// Writes each waiting pod's queue position onto the pod and into metrics.
+// Only changed positions are patched, to keep API traffic down.
+async fn publish_queue_positions(
+    client: &Client,
+    queue: &DownloadQueue,
+    published: &mut HashMap<(String, String), usize>,
+    metrics_state: &MetricsState,
+) {
+    for ((namespace, name), position) in queue.positions() {
+        if published.get(&(namespace.clone(), name.clone())) == Some(&position) {
+            continue;
+        }
+
+        let patch = json!({
+            "metadata": { "annotations": { scheduler::QUEUE_POSITION_ANNOTATION: position.to_string() } }
+        });
+        let pods: Api<Pod> = Api::namespaced(client.clone(), &namespace);
+        if let Err(e) = pods.patch(&name, &PatchParams::default(), &Patch::Merge(patch)).await {
+            error!(event = "queue_position_error", pod_name = %name, error = ?e, "Failed to annotate queue position");
+            continue;
+        }
+
+        metrics_state.set_queue_position(&namespace, &name, position);
+        published.insert((namespace, name), position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_pinned_pods_have_a_data_state() {
+        let pod = |metadata: serde_json::Value| -> Pod { serde_json::from_value(json!({"metadata": metadata})).unwrap() };
+        let pinned = pod(json!({"labels": {NODE_LABEL: "node-a"}, "annotations": {DATA_ANNOTATION: "ready"}}));
+        assert_eq!(pinned_node(&pinned), Some("node-a"));
+        assert_eq!(DataState::of(&pinned), Some(DataState::Ready));
+
+        let failed = pod(json!({"annotations": {DATA_ANNOTATION: "failed"}}));
+        assert_eq!(DataState::of(&failed), Some(DataState::Failed));
+        assert_eq!(pinned_node(&failed), None);
+        assert_eq!(DataState::of(&pod(json!({"annotations": {DATA_ANNOTATION: "yes"}}))), None);
+        assert_eq!(DataState::of(&pod(json!({}))), None);
+    }
+}
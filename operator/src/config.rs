@@ -0,0 +1,524 @@
+// --- CONFIGURATION ---
+// Every setting can come from a flag, an environment variable or a config
+// file (YAML, or TOML when the file ends in `.toml`). Precedence, highest first:
+//
+//   --flag  >  ENV_VAR  >  config file  >  built-in default
+//
+// File keys are the field names below, e.g.
+//
+//   cache_root: /var/lib/kube-cache
+//   s3_endpoint: http://minio:9000
+//   watch_namespaces: [team-a, team-b]
+//
+// The merged result is validated before anything starts, and
+// `--print-config` dumps it (as YAML) and exits. With `config_map` set, the
+// operator also follows that ConfigMap at runtime (see `reload`).
+
+use crate::webhook::MountMode;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Each node downloads into its own cache root
+    Node,
+    /// One shared ReadWriteMany PVC per dataset
+    Pvc,
+}
+
+#[derive(Clone, Debug, Parser, Serialize, Deserialize)]
+#[command(name = "kube-cache", version, about = "Dataset cache gatekeeper for GPU pods")]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// YAML or TOML config file
+    #[arg(long, env = "CONFIG_FILE")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+
+    /// Print the merged configuration and exit
+    #[arg(long)]
+    #[serde(skip)]
+    pub print_config: bool,
+
+    // --- Pod contract ---
+    /// Scheduling gate the webhook adds and the operator removes
+    #[arg(long, env = "GATE_NAME", default_value = "kube-cache.openai.com/gate")]
+    pub gate_name: String,
+
+    /// Pod annotation carrying the (templated) dataset URI
+    #[arg(long, env = "DATASET_ANNOTATION", default_value = "x-openai/required-dataset")]
+    pub dataset_annotation: String,
+
+    // --- Cache ---
+    /// Node directory holding cache entries (mounted at the same path on the host)
+    #[arg(long, env = "CACHE_ROOT", default_value = "/tmp")]
+    pub cache_root: String,
+
+    #[arg(long, env = "CACHE_MODE", value_enum, default_value = "node")]
+    pub cache_mode: CacheMode,
+
+    #[arg(long, env = "MOUNT_MODE", value_enum, default_value = "hostpath")]
+    pub mount_mode: MountMode,
+
+    /// Release pods annotated `kube-cache.openai.com/lazy` once their
+    /// directory dataset is listed and serve it over FUSE, fetching on read.
+    /// Needs a build with the `fuse` feature.
+    #[arg(long, env = "LAZY_MOUNTS", default_value_t = false, action = ArgAction::Set)]
+    pub lazy_mounts: bool,
+
+    // --- Endpoints ---
+    #[arg(long, env = "METRICS_PORT", default_value_t = 8080)]
+    pub metrics_port: u16,
+
+    #[arg(long, env = "WEBHOOK_PORT", default_value_t = 8443)]
+    pub webhook_port: u16,
+
+    /// Serves this node's cache entries to other kube-cache pods; 0 turns
+    /// peer-to-peer transfers off
+    #[arg(long, env = "PEER_PORT", default_value_t = 8081)]
+    pub peer_port: u16,
+
+    /// Objects larger than this are fetched from many peers at once, chunk by
+    /// chunk; 0 fetches each object from a single peer
+    #[arg(long, env = "SWARM_CHUNK_BYTES", default_value_t = 16 * 1024 * 1024)]
+    pub swarm_chunk_bytes: u64,
+
+    /// Serves this node's cache to workloads over a subset of the S3 API
+    /// (point `AWS_ENDPOINT_URL` at it); 0 turns the proxy off
+    #[arg(long, env = "PROXY_PORT", default_value_t = 9000)]
+    pub proxy_port: u16,
+
+    /// Service fronting the webhook, used for its certificate and registration
+    #[arg(long, env = "WEBHOOK_SERVICE", default_value = "kube-cache-webhook")]
+    pub webhook_service: String,
+
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT", default_value = "http://tempo:4317")]
+    pub otlp_endpoint: String,
+
+    #[arg(long, env = "S3_ENDPOINT", default_value = "http://localhost:9000")]
+    pub s3_endpoint: String,
+
+    /// More S3 endpoints holding the same buckets, tried in order when
+    /// `s3_endpoint` fails (comma separated)
+    #[arg(long, env = "S3_MIRROR_ENDPOINTS", value_delimiter = ',')]
+    #[serde(default)]
+    pub s3_mirror_endpoints: Vec<String>,
+
+    /// GCS JSON API endpoint; point at fake-gcs-server for local testing
+    #[arg(long, env = "GCS_ENDPOINT", default_value = "https://storage.googleapis.com")]
+    pub gcs_endpoint: String,
+
+    /// Azure Blob endpoint for path-style access (e.g. Azurite); unset means
+    /// `https://<account>.blob.core.windows.net`
+    #[arg(long, env = "AZURE_STORAGE_ENDPOINT")]
+    pub azure_endpoint: Option<String>,
+
+    /// Registries reached over plain HTTP, e.g. a local `registry:2` (comma separated)
+    #[arg(long, env = "INSECURE_REGISTRIES", value_delimiter = ',')]
+    #[serde(default)]
+    pub insecure_registries: Vec<String>,
+
+    /// tracing filter directive, e.g. `info,kube=warn`
+    #[arg(long, env = "RUST_LOG", default_value = "info")]
+    pub log_level: String,
+
+    /// ConfigMap (in pod_namespace) to follow for live changes
+    #[arg(long, env = "CONFIG_MAP")]
+    pub config_map: Option<String>,
+
+    /// Key of the config file inside the ConfigMap
+    #[arg(long, env = "CONFIG_MAP_KEY", default_value = "config.yaml")]
+    pub config_map_key: String,
+
+    // --- Identity (normally from the Downward API) ---
+    #[arg(long, env = "POD_NAMESPACE", default_value = "default")]
+    pub pod_namespace: String,
+
+    /// Leader election identity; falls back to the hostname
+    #[arg(long, env = "POD_NAME")]
+    pub pod_name: Option<String>,
+
+    /// Node this replica runs on; in node mode it fetches the datasets of
+    /// the pods scheduled there
+    #[arg(long, env = "NODE_NAME")]
+    pub node_name: Option<String>,
+
+    #[arg(long, env = "LEASE_NAME", default_value = "kube-cache-leader")]
+    pub lease_name: String,
+
+    // --- Namespaces ---
+    /// Only watch these namespaces (comma separated)
+    #[arg(long, env = "WATCH_NAMESPACES", value_delimiter = ',')]
+    #[serde(default)]
+    pub watch_namespaces: Vec<String>,
+
+    /// Only act on pods in namespaces matching this label selector
+    #[arg(long, env = "NAMESPACE_SELECTOR")]
+    pub namespace_selector: Option<String>,
+
+    // --- Downloads ---
+    #[arg(long, env = "DOWNLOAD_CONCURRENCY", default_value_t = 4)]
+    pub download_concurrency: usize,
+
+    /// A pending download climbs one priority rank per this many seconds
+    #[arg(long, env = "QUEUE_AGING_SECONDS", default_value_t = 60)]
+    pub queue_aging_seconds: u64,
+
+    /// 0 = unlimited
+    #[arg(long, env = "NODE_BANDWIDTH_BYTES_PER_SEC", default_value_t = 0)]
+    pub node_bandwidth_bytes_per_sec: u64,
+
+    /// Shared by all replicas; 0 = unlimited
+    #[arg(long, env = "CLUSTER_BANDWIDTH_BYTES_PER_SEC", default_value_t = 0)]
+    pub cluster_bandwidth_bytes_per_sec: u64,
+
+    /// 0 = unlimited
+    #[arg(long, env = "MAX_CONCURRENT_S3_REQUESTS", default_value_t = 8)]
+    pub max_concurrent_s3_requests: usize,
+
+    /// Read datasets that name no credentials Secret with the operator's own
+    /// identity. If false they are refused (plain HTTP(S) excepted).
+    #[arg(long, env = "ALLOW_OPERATOR_CREDENTIALS", default_value_t = true, action = ArgAction::Set)]
+    pub allow_operator_credentials: bool,
+
+    /// Directory file:// keys resolve under (`file:///a.bin` reads
+    /// `<root>/a.bin`), in the operator container and, in pvc mode, on the
+    /// node running the fill Job. Unset refuses file:// sources.
+    #[arg(long, env = "FILE_SEED_ROOT")]
+    pub file_seed_root: Option<String>,
+
+    /// Release pods when their download or fill fails (they read from the
+    /// source themselves). If false they stay gated and it is retried.
+    /// hostPath pods always wait: their volume cannot exist without the entry.
+    #[arg(long, env = "FAIL_OPEN", default_value_t = true, action = ArgAction::Set)]
+    pub fail_open: bool,
+
+    // --- Pre-warming ---
+    /// Prometheus server that PrewarmPolicy queries run against; unset turns
+    /// metric-driven pre-warming off
+    #[arg(long, env = "PROMETHEUS_URL")]
+    pub prometheus_url: Option<String>,
+
+    /// How often PrewarmPolicy queries are evaluated and schedules checked
+    #[arg(long, env = "PREWARM_INTERVAL_SECONDS", default_value_t = 30)]
+    pub prewarm_interval_seconds: u64,
+
+    /// Warm nodes for Deployments, StatefulSets and Jobs whose pod template
+    /// wants a dataset as soon as they scale up or start, before their pods
+    /// exist. Needs cache_mode 'node'.
+    #[arg(long, env = "WORKLOAD_PREFETCH", default_value_t = false, action = ArgAction::Set)]
+    pub workload_prefetch: bool,
+
+    // --- PVC mode ---
+    #[arg(long, env = "PVC_STORAGE_CLASS")]
+    pub pvc_storage_class: Option<String>,
+
+    #[arg(long, env = "PVC_SIZE", default_value = "100Gi")]
+    pub pvc_size: String,
+
+    #[arg(long, env = "PVC_DOWNLOADER_IMAGE", default_value = "amazon/aws-cli:2.15.0")]
+    pub pvc_downloader_image: String,
+
+    /// Secret (in the pod's namespace) with AWS_* keys for the downloader
+    #[arg(long, env = "PVC_CREDENTIALS_SECRET")]
+    pub pvc_credentials_secret: Option<String>,
+
+    #[arg(long, env = "PVC_IDLE_TTL_SECONDS", default_value_t = 3600)]
+    pub pvc_idle_ttl_seconds: u64,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file could not be read
+    Read(PathBuf, std::io::Error),
+    /// The config file is not valid YAML/TOML for this struct
+    Parse(PathBuf, String),
+    /// A setting has an unusable value
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {}: {}", path.display(), e),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Flags and env vars as given on startup, kept so a config file (or a
+/// ConfigMap, see `reload`) can be layered under them again later.
+#[derive(Clone)]
+pub struct ConfigSource {
+    cli: Config,
+    /// Settings given as a flag or env var, which a file cannot override
+    explicit: HashSet<String>,
+}
+
+impl ConfigSource {
+    /// Parses flags and env. Exits on `--help`/`--version` and malformed flags.
+    pub fn from_args() -> Self {
+        Self::from_matches(&Config::command().get_matches())
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Self {
+        let cli = Config::from_arg_matches(matches).unwrap_or_else(|e| e.exit());
+        let explicit = Config::command().get_arguments()
+            .map(|arg| arg.get_id().to_string())
+            .filter(|id| matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+            ))
+            .collect();
+        Self { cli, explicit }
+    }
+
+    /// Layers flags and env over the `--config` file, if any, and validates the result.
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        match &self.cli.config {
+            Some(path) => {
+                let text = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                self.layer(path, &text)
+            }
+            None => finish(self.cli.clone()),
+        }
+    }
+
+    /// Like [`load`](Self::load), with `text` standing in for the config
+    /// file. `origin` names it in errors and picks the format by extension.
+    pub fn layer(&self, origin: &Path, text: &str) -> Result<Config, ConfigError> {
+        let file = parse_file(origin, text)?;
+        let mut merged = serde_json::to_value(&self.cli).expect("config serializes");
+        let fields = merged.as_object_mut().expect("config is a struct");
+        for (key, value) in file {
+            // Unknown keys are left for deserialization to reject
+            if !self.explicit.contains(&key) {
+                fields.insert(key, value);
+            }
+        }
+        let mut config: Config = serde_json::from_value(merged)
+            .map_err(|e| ConfigError::Parse(origin.to_path_buf(), e.to_string()))?;
+        config.config = self.cli.config.clone();
+        config.print_config = self.cli.print_config;
+        finish(config)
+    }
+}
+
+// Normalizes and validates a merged configuration.
+fn finish(mut config: Config) -> Result<Config, ConfigError> {
+    // An env var set to "" means unset, as it did before flags existed
+    config.watch_namespaces.retain(|ns| !ns.trim().is_empty());
+    config.insecure_registries.retain(|r| !r.trim().is_empty());
+    config.s3_mirror_endpoints.retain(|e| !e.trim().is_empty());
+    for field in [
+        &mut config.pod_name,
+        &mut config.node_name,
+        &mut config.namespace_selector,
+        &mut config.pvc_storage_class,
+        &mut config.pvc_credentials_secret,
+        &mut config.config_map,
+        &mut config.azure_endpoint,
+        &mut config.file_seed_root,
+    ] {
+        if field.as_deref().map(str::is_empty).unwrap_or(false) {
+            *field = None;
+        }
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        for (name, value) in [("gate_name", &self.gate_name), ("dataset_annotation", &self.dataset_annotation)] {
+            if !is_qualified_name(value) {
+                return invalid(format!("{} '{}' is not a valid Kubernetes qualified name", name, value));
+            }
+        }
+        if !Path::new(&self.cache_root).is_absolute() {
+            return invalid(format!("cache_root '{}' must be an absolute path", self.cache_root));
+        }
+        if let Some(root) = self.file_seed_root.as_deref().filter(|r| !Path::new(r).is_absolute()) {
+            return invalid(format!("file_seed_root '{}' must be an absolute path", root));
+        }
+        if self.metrics_port == 0 || self.webhook_port == 0 {
+            return invalid("ports must be non-zero".to_string());
+        }
+        let ports = [
+            ("metrics_port", self.metrics_port),
+            ("webhook_port", self.webhook_port),
+            ("peer_port", self.peer_port),
+            ("proxy_port", self.proxy_port),
+        ];
+        for (i, (name, port)) in ports.iter().enumerate() {
+            if let Some((other, _)) = ports[i + 1..].iter().find(|(_, p)| p == port && *port != 0) {
+                return invalid(format!("{} and {} are both {}", name, other, port));
+            }
+        }
+        let endpoints = [
+            ("otlp_endpoint", Some(&self.otlp_endpoint)),
+            ("s3_endpoint", Some(&self.s3_endpoint)),
+            ("gcs_endpoint", Some(&self.gcs_endpoint)),
+            ("azure_endpoint", self.azure_endpoint.as_ref()),
+            ("prometheus_url", self.prometheus_url.as_ref()),
+        ];
+        let mirrors = self.s3_mirror_endpoints.iter().map(|e| ("s3_mirror_endpoints", Some(e)));
+        for (name, value) in endpoints.into_iter().chain(mirrors).filter_map(|(name, value)| value.map(|v| (name, v))) {
+            if !value.starts_with("http://") && !value.starts_with("https://") {
+                return invalid(format!("{} '{}' must be an http:// or https:// URL", name, value));
+            }
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_level) {
+            return invalid(format!("log_level '{}': {}", self.log_level, e));
+        }
+        if self.download_concurrency == 0 {
+            return invalid("download_concurrency must be at least 1".to_string());
+        }
+        if self.prewarm_interval_seconds == 0 {
+            return invalid("prewarm_interval_seconds must be at least 1".to_string());
+        }
+        if !self.watch_namespaces.is_empty() && self.namespace_selector.is_some() {
+            return invalid("watch_namespaces and namespace_selector are mutually exclusive".to_string());
+        }
+        if self.mount_mode == MountMode::Pvc {
+            return invalid("mount_mode 'pvc' is implied by cache_mode 'pvc'; use hostpath or csi".to_string());
+        }
+        if self.lazy_mounts && !cfg!(feature = "fuse") {
+            return invalid("lazy_mounts needs a build with the `fuse` feature".to_string());
+        }
+        if self.lazy_mounts && self.cache_mode != CacheMode::Node {
+            return invalid("lazy_mounts needs cache_mode 'node'".to_string());
+        }
+        if self.workload_prefetch && self.cache_mode != CacheMode::Node {
+            return invalid("workload_prefetch needs cache_mode 'node'".to_string());
+        }
+        Ok(())
+    }
+
+    /// The effective configuration as YAML, for `--print-config`.
+    pub fn dump(&self) -> String {
+        serde_yaml::to_string(self).expect("config serializes")
+    }
+}
+
+// Splits a config file into its top-level keys. `.toml` is TOML, anything else YAML.
+fn parse_file(path: &Path, text: &str) -> Result<serde_json::Map<String, serde_json::Value>, ConfigError> {
+    let parse_error = |e: String| ConfigError::Parse(path.to_path_buf(), e);
+
+    let value: serde_json::Value = if path.extension().and_then(|e| e.to_str()) == Some("toml") {
+        toml::from_str(text).map_err(|e| parse_error(e.to_string()))?
+    } else {
+        serde_yaml::from_str(text).map_err(|e| parse_error(e.to_string()))?
+    };
+
+    match value {
+        serde_json::Value::Object(map) => Ok(map),
+        // An empty YAML file
+        serde_json::Value::Null => Ok(Default::default()),
+        _ => Err(parse_error("expected a mapping at the top level".to_string())),
+    }
+}
+
+// `[prefix/]name`, as accepted for annotation keys and scheduling gates.
+fn is_qualified_name(value: &str) -> bool {
+    let (prefix, name) = match value.split_once('/') {
+        Some((prefix, name)) => (Some(prefix), name),
+        None => (None, value),
+    };
+    let name_ok = !name.is_empty()
+        && name.len() <= 63
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        && name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+    let prefix_ok = prefix
+        .map(|p| !p.is_empty() && p.len() <= 253 && p.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '.')))
+        .unwrap_or(true);
+    name_ok && prefix_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As if started with `args`. Env vars still apply, so only settings the
+    // test environment leaves unset are used.
+    fn source(args: &[&str]) -> ConfigSource {
+        let matches = Config::command().try_get_matches_from(["kube-cache"].iter().chain(args)).unwrap();
+        ConfigSource::from_matches(&matches)
+    }
+
+    fn defaults() -> Config {
+        source(&[]).load().unwrap()
+    }
+
+    #[test]
+    fn flags_win_over_the_file_and_the_file_over_defaults() {
+        let file = "cache_root: /from-file\nwebhook_port: 9443\n";
+        let config = source(&["--cache-root", "/from-flag"]).layer(Path::new("config.yaml"), file).unwrap();
+        assert_eq!(config.cache_root, "/from-flag");
+        assert_eq!(config.webhook_port, 9443);
+        assert_eq!(config.metrics_port, 8080);
+
+        let toml = "cache_root = \"/from-toml\"\n";
+        assert_eq!(source(&[]).layer(Path::new("config.toml"), toml).unwrap().cache_root, "/from-toml");
+        assert_eq!(source(&[]).layer(Path::new("empty.yaml"), "").unwrap().cache_root, "/tmp");
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        let layer = |origin: &str, text: &str| source(&[]).layer(Path::new(origin), text);
+        assert!(matches!(layer("config.yaml", "cache_rot: /x\n"), Err(ConfigError::Parse(..))));
+        assert!(matches!(layer("config.yaml", "- a\n- b\n"), Err(ConfigError::Parse(..))));
+        assert!(matches!(layer("config.toml", "cache_root: /x\n"), Err(ConfigError::Parse(..))));
+        // Merged values are validated too
+        assert!(matches!(layer("config.yaml", "cache_root: relative\n"), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn empty_values_mean_unset() {
+        let config = source(&[]).layer(Path::new("config.yaml"), "watch_namespaces: ['', ' ']\nnode_name: ''\n").unwrap();
+        assert!(config.watch_namespaces.is_empty());
+        assert_eq!(config.node_name, None);
+    }
+
+    #[test]
+    fn validation_catches_bad_settings() {
+        assert!(defaults().validate().is_ok());
+
+        let invalid: [fn(&mut Config); 8] = [
+            |c| c.gate_name = "not a name".to_string(),
+            |c| c.dataset_annotation = "x-openai/".to_string(),
+            |c| c.cache_root = "cache".to_string(),
+            |c| c.peer_port = c.metrics_port,
+            |c| c.s3_endpoint = "minio:9000".to_string(),
+            |c| c.download_concurrency = 0,
+            |c| {
+                c.watch_namespaces = vec!["team-a".to_string()];
+                c.namespace_selector = Some("kube-cache=enabled".to_string());
+            },
+            |c| c.mount_mode = MountMode::Pvc,
+        ];
+        for (i, break_it) in invalid.iter().enumerate() {
+            let mut config = defaults();
+            break_it(&mut config);
+            assert!(matches!(config.validate(), Err(ConfigError::Invalid(_))), "case {}", i);
+        }
+
+        // Two features off are not a clash
+        let mut config = defaults();
+        config.peer_port = 0;
+        config.proxy_port = 0;
+        assert!(config.validate().is_ok());
+    }
+}
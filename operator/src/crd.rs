@@ -0,0 +1,68 @@
+// --- CUSTOM RESOURCES ---
+// A Dataset names a source once so pods can refer to it by name
+// (`kube-cache.openai.com/dataset: llama-70b`) instead of repeating the URI.
+//
+// A PrewarmPolicy warms datasets on nodes ahead of demand, when a Prometheus
+// query crosses a threshold or on a cron schedule (see `prewarm`).
+
+use kube::CustomResource;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "kube-cache.openai.com",
+    version = "v1alpha1",
+    kind = "Dataset",
+    namespaced,
+    printcolumn = r#"{"name":"Source", "type":"string", "jsonPath":".spec.source"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct DatasetSpec {
+    /// Source URI, e.g. `s3://models/gpt-4-weights`. Supports the same shard
+    /// placeholders as the `x-openai/required-dataset` annotation.
+    pub source: String,
+    /// Secret in this namespace with the credentials to read `source` with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_secret: Option<String>,
+    /// Sources holding the same content, tried in order when `source` fails
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+}
+
+#[derive(CustomResource, Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[kube(
+    group = "kube-cache.openai.com",
+    version = "v1alpha1",
+    kind = "PrewarmPolicy",
+    namespaced,
+    printcolumn = r#"{"name":"Query", "type":"string", "jsonPath":".spec.query"}"#,
+    printcolumn = r#"{"name":"Threshold", "type":"number", "jsonPath":".spec.threshold"}"#,
+    printcolumn = r#"{"name":"Schedule", "type":"string", "jsonPath":".spec.schedule"}"#
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PrewarmPolicySpec {
+    /// PromQL expression, evaluated as an instant query
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query: Option<String>,
+    /// Warm idle nodes when any sample of the query result is above this
+    #[serde(default)]
+    pub threshold: f64,
+    /// Cron schedule (`minute hour day-of-month month day-of-week`, UTC) to
+    /// warm every matching node on, whatever the query says
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    /// Drop the datasets again from the nodes a scheduled run warmed this
+    /// long after it, unless pods there use them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evict_after_seconds: Option<u64>,
+    /// Source URIs, or names of Datasets in this namespace
+    pub datasets: Vec<String>,
+    /// Labels of the nodes to warm; every node if empty
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_selector: BTreeMap<String, String>,
+    /// Warm at most this many nodes per run; all of them if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_nodes: Option<u32>,
+}
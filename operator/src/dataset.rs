@@ -0,0 +1,522 @@
+// --- DATASET REFERENCES ---
+// Parses the dataset annotation (`x-openai/required-dataset` unless
+// configured otherwise). References may be
+// templated so each worker of an Indexed Job pulls only its own shard:
+//
+//   s3://data/shard-{index:05}.tar       -> s3://data/shard-00003.tar
+//   s3://data/{label:team}/weights.bin  -> s3://data/vision/weights.bin
+//
+// `{index}` is the pod's completion index. `{label:<key>}` is any pod label.
+// Either can take a zero-padded width, e.g. `{index:05}` or `{label:rank:03}`.
+//
+// Instead of a URI, a pod may name a Dataset object in its own namespace with
+// `kube-cache.openai.com/dataset`; its `spec.source` is rendered the same way.
+//
+// Supported sources (see `storage` for how each is fetched):
+//
+//   s3://bucket/key              S3 or MinIO
+//   gs://bucket/object           Google Cloud Storage
+//   az://account/container/blob  Azure Blob Storage
+//   oci://registry/repo:tag      OCI artifact (or `repo@sha256:<digest>`)
+//   https://host/path            plain HTTP(S) download
+//   file:///weights.bin          a path under the operator's file_seed_root
+//
+// `https://<account>.blob.core.windows.net/<container>/<blob>` is accepted too
+// and rewritten to the `az://` form, so both spellings share one cache entry.
+//
+// Either form may name credentials for the source: a Secret in the pod's
+// namespace, via `kube-cache.openai.com/credentials` on the pod (which wins)
+// or `spec.credentialsSecret` on the Dataset. See `storage::credentials`.
+//
+// Either form may also list mirrors holding the same content elsewhere (other
+// endpoints, regions or buckets), comma separated in
+// `kube-cache.openai.com/mirrors` on the pod (which wins) or as
+// `spec.mirrors` on the Dataset. They take the same placeholders and
+// credentials, must have the same shape (object, prefix or OCI artifact), and
+// share the primary's cache entry. See `storage::mirrors`.
+//
+// A key ending in `/` is a prefix: every object under it is cached as one
+// directory entry. OCI artifacts are always directory entries holding their
+// (unpacked) layers.
+
+use crate::crd::Dataset;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{Api, Client};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub const DATASET_REF_ANNOTATION: &str = "kube-cache.openai.com/dataset";
+pub const CREDENTIALS_ANNOTATION: &str = "kube-cache.openai.com/credentials";
+pub const MIRRORS_ANNOTATION: &str = "kube-cache.openai.com/mirrors";
+
+// Set by the Job controller on every pod of an Indexed Job
+pub const COMPLETION_INDEX: &str = "batch.kubernetes.io/job-completion-index";
+
+// Widest zero padding a placeholder may ask for; anything larger is a typo
+// (or an attempt to make the operator allocate a huge string per pod)
+const MAX_PAD_WIDTH: usize = 20;
+
+// Longest readable part of a cache entry name, in bytes. With the digest and
+// the `.lazy` or `.complete` suffix the name stays well under NAME_MAX (255).
+// Must match the CSI plugin.
+const MAX_READABLE_NAME: usize = 200;
+
+// Host suffix of Azure Blob Storage account endpoints
+const AZURE_BLOB_HOST: &str = ".blob.core.windows.net";
+
+#[derive(Debug)]
+pub enum DatasetError {
+    /// A `{...}` placeholder was not closed
+    Unterminated(String),
+    /// The template asked for something the pod does not carry
+    Missing(String),
+    /// Unknown placeholder or bad width
+    BadPlaceholder(String),
+    /// Not a `scheme://location/key` URI
+    BadUri(String),
+    /// A scheme no storage backend handles
+    UnknownScheme(String),
+    /// The referenced Dataset object does not exist
+    NotFound(String),
+    /// The referenced Dataset object could not be read
+    Lookup(String, kube::Error),
+    /// A mirror is not the same kind of source as the primary
+    MirrorMismatch(String),
+}
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatasetError::Unterminated(t) => write!(f, "unterminated placeholder in '{}'", t),
+            DatasetError::Missing(what) => write!(f, "pod has no {}", what),
+            DatasetError::BadPlaceholder(p) => write!(f, "unsupported placeholder '{{{}}}'", p),
+            DatasetError::BadUri(u) => write!(f, "'{}' is not a valid dataset URI", u),
+            DatasetError::UnknownScheme(s) => write!(
+                f, "unsupported scheme '{}://' (expected one of {})", s,
+                Scheme::ALL.iter().map(|s| format!("{}://", s.as_str())).collect::<Vec<_>>().join(", "),
+            ),
+            DatasetError::NotFound(name) => write!(f, "Dataset '{}' not found", name),
+            DatasetError::Lookup(name, e) => write!(f, "cannot read Dataset '{}': {}", name, e),
+            DatasetError::MirrorMismatch(uri) => write!(f, "mirror '{}' is not the same kind of source as the dataset", uri),
+        }
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+/// Where a dataset is fetched from; picks the storage backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    S3,
+    Gs,
+    Az,
+    Oci,
+    Http,
+    Https,
+    File,
+}
+
+impl Scheme {
+    pub const ALL: &'static [Scheme] = &[Scheme::S3, Scheme::Gs, Scheme::Az, Scheme::Oci, Scheme::Http, Scheme::Https, Scheme::File];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::S3 => "s3",
+            Scheme::Gs => "gs",
+            Scheme::Az => "az",
+            Scheme::Oci => "oci",
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+            Scheme::File => "file",
+        }
+    }
+}
+
+/// A Secret holding credentials for a dataset's source.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SecretRef {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl fmt::Display for SecretRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+/// A resolved dataset location.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct DatasetRef {
+    pub uri: String,
+    pub scheme: Scheme,
+    /// Bucket for S3/GCS, storage account for Azure, `host[:port]` for
+    /// HTTP(S) and registries, empty for files
+    pub bucket: String,
+    /// Object key (`container/blob` for Azure, `repo:tag` or `repo@digest`
+    /// for OCI), URL path (with query) or file path, without the leading `/`
+    pub key: String,
+    /// Read the source with these instead of the operator's own identity
+    pub credentials: Option<SecretRef>,
+    /// Other copies of the same content, in the order to fall back to them
+    pub mirrors: Vec<DatasetRef>,
+}
+
+impl DatasetRef {
+    pub fn parse(uri: &str) -> Result<Self, DatasetError> {
+        let bad = || DatasetError::BadUri(uri.to_string());
+        let (scheme, rest) = uri.split_once("://").ok_or_else(bad)?;
+        let scheme = *Scheme::ALL.iter()
+            .find(|s| s.as_str() == scheme)
+            .ok_or_else(|| DatasetError::UnknownScheme(scheme.to_string()))?;
+
+        let (bucket, key) = rest.split_once('/').ok_or_else(bad)?;
+        // file:///path has an empty authority; everything else needs one
+        if key.is_empty() || (bucket.is_empty() != (scheme == Scheme::File)) {
+            return Err(bad());
+        }
+
+        let account = match scheme {
+            Scheme::Az => Some(bucket),
+            Scheme::Https => bucket.strip_suffix(AZURE_BLOB_HOST),
+            _ => None,
+        };
+        if let Some(account) = account {
+            // Azure needs a container and a blob name (or prefix) inside it
+            match key.split_once('/') {
+                Some((container, blob)) if !container.is_empty() && !blob.is_empty() => {}
+                _ => return Err(bad()),
+            }
+            return Ok(Self {
+                uri: format!("az://{}/{}", account, key),
+                scheme: Scheme::Az,
+                bucket: account.to_string(),
+                key: key.to_string(),
+                credentials: None,
+                mirrors: Vec::new(),
+            });
+        }
+
+        Ok(Self {
+            uri: uri.to_string(),
+            scheme,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            credentials: None,
+            mirrors: Vec::new(),
+        })
+    }
+
+    /// Another object in the same bucket/host (e.g. one listed under a prefix).
+    /// Mirrors are not carried over.
+    pub fn with_key(&self, key: &str) -> Self {
+        Self {
+            uri: format!("{}://{}/{}", self.scheme.as_str(), self.bucket, key),
+            scheme: self.scheme,
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            credentials: self.credentials.clone(),
+            mirrors: Vec::new(),
+        }
+    }
+
+    /// True if the reference names every object under a prefix.
+    pub fn is_prefix(&self) -> bool {
+        self.key.ends_with('/')
+    }
+
+    /// True if the cache entry is a directory rather than a single file.
+    pub fn is_directory(&self) -> bool {
+        self.is_prefix() || self.scheme == Scheme::Oci
+    }
+
+    /// Name the dataset appears under inside a mounted volume (last key
+    /// segment, without query, tag or digest).
+    pub fn entry_name(&self) -> &str {
+        let path = self.key.split('?').next().unwrap_or(&self.key);
+        let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or(path);
+        match self.scheme {
+            Scheme::Oci => name.split(['@', ':']).next().unwrap_or(name),
+            _ => name,
+        }
+    }
+
+    /// Where this dataset lives inside the local cache: the URI flattened into
+    /// a readable name (S3 without a scheme prefix, other schemes with theirs)
+    /// plus a digest of the whole URI. Flattening alone is not one-to-one
+    /// (`s3://a/b-c` and `s3://a-b/c` both become `a-b-c`), and two datasets
+    /// must never share an entry. Long names are cut short, which the digest
+    /// also makes safe. Must match `cache_path` in the CSI plugin.
+    pub fn cache_path(&self, cache_root: &str) -> String {
+        let rest = self.uri.split_once("://").map(|(_, rest)| rest).unwrap_or(&self.uri);
+        let mut readable = match self.scheme {
+            Scheme::S3 => rest.replace('/', "-"),
+            scheme => format!("{}-{}", scheme.as_str(), rest.replace('/', "-")),
+        };
+        if readable.len() > MAX_READABLE_NAME {
+            let end = (0..=MAX_READABLE_NAME).rev().find(|i| readable.is_char_boundary(*i)).unwrap_or(0);
+            readable.truncate(end);
+        }
+        let digest = Sha256::digest(self.uri.as_bytes());
+        let suffix: String = digest[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("{}/{}-{}", cache_root, readable, suffix)
+    }
+
+    /// Where a lazy mount of this dataset appears (see `storage::lazy`).
+    /// Must match the lazy source in the CSI plugin.
+    pub fn lazy_path(&self, cache_root: &str) -> String {
+        format!("{}.lazy", self.cache_path(cache_root))
+    }
+}
+
+/// Expands shard placeholders in a dataset reference using the pod's
+/// completion index and labels.
+pub fn render(template: &str, pod: &Pod) -> Result<String, DatasetError> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}')
+            .ok_or_else(|| DatasetError::Unterminated(template.to_string()))?;
+        let placeholder = &rest[start + 1..start + end];
+        out.push_str(&expand(placeholder, pod)?);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+
+    Ok(out)
+}
+
+/// Renders and parses the pod's dataset annotation, if it has one.
+pub fn resolve(pod: &Pod, annotation: &str) -> Option<Result<DatasetRef, DatasetError>> {
+    let template = pod.metadata.annotations.as_ref()?.get(annotation)?;
+    Some(parse_for(pod, template, None, &[]))
+}
+
+// Renders a source and its mirrors for the pod and attaches the credentials it names
+fn parse_for(pod: &Pod, template: &str, secret: Option<&String>, mirrors: &[String]) -> Result<DatasetRef, DatasetError> {
+    let annotations = pod.metadata.annotations.as_ref();
+    let mut dataset = render(template, pod).and_then(|uri| DatasetRef::parse(&uri))?;
+    let secret = annotations
+        .and_then(|a| a.get(CREDENTIALS_ANNOTATION))
+        .or(secret)
+        .filter(|name| !name.is_empty());
+    dataset.credentials = secret.map(|name| SecretRef {
+        namespace: pod.metadata.namespace.clone().unwrap_or_else(|| "default".to_string()),
+        name: name.clone(),
+    });
+
+    let mirrors: Vec<&str> = match annotations.and_then(|a| a.get(MIRRORS_ANNOTATION)) {
+        Some(list) => list.split(',').map(str::trim).filter(|m| !m.is_empty()).collect(),
+        None => mirrors.iter().map(String::as_str).collect(),
+    };
+    for template in mirrors {
+        let mut mirror = render(template, pod).and_then(|uri| DatasetRef::parse(&uri))?;
+        if mirror.is_prefix() != dataset.is_prefix() || (mirror.scheme == Scheme::Oci) != (dataset.scheme == Scheme::Oci) {
+            return Err(DatasetError::MirrorMismatch(mirror.uri));
+        }
+        mirror.credentials = dataset.credentials.clone();
+        dataset.mirrors.push(mirror);
+    }
+    Ok(dataset)
+}
+
+/// True if the pod asks for a dataset, either inline or by Dataset name.
+pub fn wants_dataset(pod: &Pod, annotation: &str) -> bool {
+    pod.metadata.annotations.as_ref()
+        .map(|a| a.contains_key(annotation) || a.contains_key(DATASET_REF_ANNOTATION))
+        .unwrap_or(false)
+}
+
+/// Like [`resolve`], but also follows `kube-cache.openai.com/dataset`
+/// references. An inline URI wins if a pod carries both.
+pub async fn lookup(client: &Client, pod: &Pod, annotation: &str) -> Option<Result<DatasetRef, DatasetError>> {
+    if let Some(resolved) = resolve(pod, annotation) {
+        return Some(resolved);
+    }
+
+    let name = pod.metadata.annotations.as_ref()?.get(DATASET_REF_ANNOTATION)?;
+    let namespace = pod.metadata.namespace.as_deref().unwrap_or("default");
+    let datasets: Api<Dataset> = Api::namespaced(client.clone(), namespace);
+
+    let spec = match datasets.get_opt(name).await {
+        Ok(Some(ds)) => ds.spec,
+        Ok(None) => return Some(Err(DatasetError::NotFound(name.clone()))),
+        Err(e) => return Some(Err(DatasetError::Lookup(name.clone(), e))),
+    };
+    Some(parse_for(pod, &spec.source, spec.credentials_secret.as_ref(), &spec.mirrors))
+}
+
+/// Resolves a dataset named outside any pod (a source URI, or the name of a
+/// Dataset in `namespace`) the way a pod in `namespace` naming it would.
+pub async fn lookup_in(client: &Client, namespace: &str, reference: &str) -> Result<DatasetRef, DatasetError> {
+    const INLINE: &str = "source";
+    let key = if reference.contains("://") { INLINE } else { DATASET_REF_ANNOTATION };
+    let mut pod = Pod::default();
+    pod.metadata.namespace = Some(namespace.to_string());
+    pod.metadata.annotations = Some(BTreeMap::from([(key.to_string(), reference.to_string())]));
+    match lookup(client, &pod, INLINE).await {
+        Some(resolved) => resolved,
+        None => Err(DatasetError::NotFound(reference.to_string())),
+    }
+}
+
+fn expand(placeholder: &str, pod: &Pod) -> Result<String, DatasetError> {
+    let bad = || DatasetError::BadPlaceholder(placeholder.to_string());
+
+    let (value, width) = if let Some(spec) = placeholder.strip_prefix("index") {
+        let width = match spec.strip_prefix(':') {
+            Some(w) => Some(w),
+            None if spec.is_empty() => None,
+            None => return Err(bad()),
+        };
+        (completion_index(pod).ok_or_else(|| DatasetError::Missing("completion index".to_string()))?, width)
+    } else if let Some(spec) = placeholder.strip_prefix("label:") {
+        // Label keys never contain ':', so a second ':' starts the width
+        let (key, width) = match spec.split_once(':') {
+            Some((key, width)) => (key, Some(width)),
+            None => (spec, None),
+        };
+        let value = pod.metadata.labels.as_ref()
+            .and_then(|l| l.get(key))
+            .cloned()
+            .ok_or_else(|| DatasetError::Missing(format!("label '{}'", key)))?;
+        (value, width)
+    } else {
+        return Err(bad());
+    };
+
+    match width {
+        None => Ok(value),
+        Some(w) => {
+            let width: usize = w.strip_prefix('0').unwrap_or(w).parse().map_err(|_| bad())?;
+            if width > MAX_PAD_WIDTH {
+                return Err(bad());
+            }
+            Ok(format!("{:0>width$}", value, width = width))
+        }
+    }
+}
+
+fn completion_index(pod: &Pod) -> Option<String> {
+    // Annotation is always present on Indexed Job pods; the label only since 1.28
+    pod.metadata.annotations.as_ref()
+        .and_then(|a| a.get(COMPLETION_INDEX))
+        .or_else(|| pod.metadata.labels.as_ref().and_then(|l| l.get(COMPLETION_INDEX)))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // Shared with the CSI plugin's tests, so both sides agree on the layout
+    const CACHE_PATHS: &str = include_str!("../../testdata/cache_paths.txt");
+
+    fn shard_pod() -> Pod {
+        serde_json::from_value(json!({"metadata": {
+            "namespace": "ml",
+            "annotations": {COMPLETION_INDEX: "3"},
+            "labels": {"team": "vision", "rank": "7"},
+        }})).unwrap()
+    }
+
+    #[test]
+    fn renders_index_and_labels() {
+        let pod = shard_pod();
+        assert_eq!(render("s3://data/shard-{index:05}.tar", &pod).unwrap(), "s3://data/shard-00003.tar");
+        assert_eq!(render("s3://data/{label:team}/w-{label:rank:03}", &pod).unwrap(), "s3://data/vision/w-007");
+        assert_eq!(render("s3://data/{index}", &pod).unwrap(), "s3://data/3");
+        assert_eq!(render("s3://data/plain", &pod).unwrap(), "s3://data/plain");
+    }
+
+    #[test]
+    fn rejects_bad_placeholders() {
+        let pod = shard_pod();
+        assert!(matches!(render("s3://data/{index", &pod), Err(DatasetError::Unterminated(_))));
+        assert!(matches!(render("s3://data/{node}", &pod), Err(DatasetError::BadPlaceholder(_))));
+        assert!(matches!(render("s3://data/{indexes}", &pod), Err(DatasetError::BadPlaceholder(_))));
+        assert!(matches!(render("s3://data/{index:x}", &pod), Err(DatasetError::BadPlaceholder(_))));
+        assert!(matches!(render("s3://data/{label:missing}", &pod), Err(DatasetError::Missing(_))));
+        assert!(matches!(render("s3://data/{index}", &Pod::default()), Err(DatasetError::Missing(_))));
+    }
+
+    #[test]
+    fn caps_pad_width() {
+        let pod = shard_pod();
+        assert_eq!(render("{index:020}", &pod).unwrap().len(), 20);
+        assert!(matches!(render("{index:021}", &pod), Err(DatasetError::BadPlaceholder(_))));
+        assert!(matches!(render("{index:0999999999}", &pod), Err(DatasetError::BadPlaceholder(_))));
+        assert!(matches!(render("{label:rank:0999999999}", &pod), Err(DatasetError::BadPlaceholder(_))));
+    }
+
+    #[test]
+    fn parses_sources() {
+        let s3 = DatasetRef::parse("s3://bucket/path/to/weights.bin").unwrap();
+        assert_eq!((s3.scheme, s3.bucket.as_str(), s3.key.as_str()), (Scheme::S3, "bucket", "path/to/weights.bin"));
+        assert!(!s3.is_directory());
+        assert_eq!(s3.entry_name(), "weights.bin");
+
+        let prefix = DatasetRef::parse("gs://bucket/shards/").unwrap();
+        assert!(prefix.is_prefix() && prefix.is_directory());
+        assert_eq!(prefix.entry_name(), "shards");
+
+        let oci = DatasetRef::parse("oci://registry.io/models/llama:v1").unwrap();
+        assert!(oci.is_directory());
+        assert_eq!(oci.entry_name(), "llama");
+    }
+
+    #[test]
+    fn rewrites_azure_https_urls() {
+        let https = DatasetRef::parse("https://acct.blob.core.windows.net/models/w.bin").unwrap();
+        let az = DatasetRef::parse("az://acct/models/w.bin").unwrap();
+        assert_eq!(https, az);
+        assert_eq!(https.uri, "az://acct/models/w.bin");
+        assert!(DatasetRef::parse("az://acct/container-only").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_uris() {
+        assert!(matches!(DatasetRef::parse("bucket/key"), Err(DatasetError::BadUri(_))));
+        assert!(matches!(DatasetRef::parse("ftp://host/key"), Err(DatasetError::UnknownScheme(_))));
+        assert!(matches!(DatasetRef::parse("s3://bucket"), Err(DatasetError::BadUri(_))));
+        assert!(matches!(DatasetRef::parse("s3://bucket/"), Err(DatasetError::BadUri(_))));
+        assert!(matches!(DatasetRef::parse("s3:///key"), Err(DatasetError::BadUri(_))));
+    }
+
+    #[test]
+    fn cache_paths_are_flat() {
+        let s3 = DatasetRef::parse("s3://bucket/a/b.bin").unwrap();
+        let path = s3.cache_path("/cache");
+        assert!(path.starts_with("/cache/bucket-a-b.bin-"), "{}", path);
+        assert!(!path["/cache/".len()..].contains('/'));
+        let gs = DatasetRef::parse("gs://bucket/a/b.bin").unwrap();
+        assert!(gs.cache_path("/cache").starts_with("/cache/gs-bucket-a-b.bin-"));
+        assert_eq!(gs.lazy_path("/cache"), format!("{}.lazy", gs.cache_path("/cache")));
+    }
+
+    #[test]
+    fn distinct_uris_get_distinct_cache_paths() {
+        let uris = ["s3://a/b-c", "s3://a-b/c", "s3://gs/bucket/x", "gs://bucket/x", "s3://a/b/", "s3://a/b"];
+        let paths: std::collections::HashSet<String> = uris.iter()
+            .map(|uri| DatasetRef::parse(uri).unwrap().cache_path("/cache"))
+            .collect();
+        assert_eq!(paths.len(), uris.len());
+        // Stable for the same URI
+        let a = DatasetRef::parse("s3://a/b-c").unwrap();
+        assert_eq!(a.cache_path("/cache"), DatasetRef::parse("s3://a/b-c").unwrap().cache_path("/cache"));
+    }
+
+    #[test]
+    fn cache_paths_match_the_shared_layout() {
+        for line in CACHE_PATHS.lines().filter(|l| !l.starts_with('#')) {
+            let (uri, path) = line.split_once(' ').unwrap();
+            let dataset = DatasetRef::parse(uri).unwrap();
+            assert_eq!(dataset.cache_path("/cache"), path, "{}", uri);
+            assert_eq!(dataset.lazy_path("/cache"), format!("{}.lazy", path), "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,242 @@
+// --- GANG-AWARE RELEASE ---
+// Distributed training pods (Indexed Jobs, JobSets, PyTorchJob workers) are
+// only useful once *every* worker can start. Releasing them one at a time lets
+// the scheduler bind half a gang to GPUs while the rest are still downloading,
+// so gang members are held until all of them have their data, then released together.
+// In node mode "has its data" means the agent on the node a member is pinned
+// to reported its entry complete (see `agent`).
+
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::ListParams, Api, Client};
+use std::collections::HashMap;
+
+/// Explicit gang membership. Pods with the same value are released together.
+pub const GANG_LABEL: &str = "kube-cache.openai.com/gang";
+/// Optional override for how many pods make up a gang.
+pub const GANG_SIZE_ANNOTATION: &str = "kube-cache.openai.com/gang-size";
+
+// Labels that well-known batch controllers stamp on every pod of a group.
+// Checked in order; our own label always wins.
+const GANG_LABELS: [&str; 3] = [
+    GANG_LABEL,
+    "jobset.sigs.k8s.io/jobset-name",
+    "training.kubeflow.org/job-name",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GangKey {
+    pub namespace: String,
+    /// Either `<label>=<value>` or `job/<uid>` for pods grouped by their owning Job.
+    pub id: String,
+}
+
+#[derive(Default)]
+struct Gang {
+    expected: Option<usize>,
+    // pod name -> dataset ready
+    members: HashMap<String, bool>,
+}
+
+#[derive(Default)]
+pub struct GangTracker {
+    gangs: HashMap<GangKey, Gang>,
+}
+
+/// Works out which gang (if any) a pod belongs to.
+pub fn gang_key(pod: &Pod) -> Option<GangKey> {
+    let namespace = pod.metadata.namespace.clone().unwrap_or_else(|| "default".to_string());
+
+    if let Some(labels) = pod.metadata.labels.as_ref() {
+        for label in GANG_LABELS {
+            if let Some(value) = labels.get(label) {
+                return Some(GangKey { namespace, id: format!("{}={}", label, value) });
+            }
+        }
+    }
+
+    // Fall back to the controlling Job (covers plain Indexed Jobs)
+    pod.metadata.owner_references.as_ref()?
+        .iter()
+        .find(|o| o.kind == "Job" && o.controller.unwrap_or(false))
+        .map(|o| GangKey { namespace, id: format!("job/{}", o.uid) })
+}
+
+/// How many pods the gang is waiting for. Resolution order:
+/// 1. the `gang-size` annotation
+/// 2. the owning Job's parallelism (capped by completions)
+/// 3. the number of pods carrying the same gang label that wait on a dataset
+///    (see [`waits_on_dataset`]); a PyTorchJob master without a dataset, or a
+///    finished pod, would otherwise hold the gang forever
+pub async fn expected_size(client: &Client, pod: &Pod, key: &GangKey, gate_name: &str, annotation: &str) -> Option<usize> {
+    if let Some(size) = pod.metadata.annotations.as_ref()
+        .and_then(|a| a.get(GANG_SIZE_ANNOTATION))
+        .and_then(|s| s.parse::<usize>().ok())
+    {
+        return Some(size);
+    }
+
+    let jobs: Api<Job> = Api::namespaced(client.clone(), &key.namespace);
+    if key.id.starts_with("job/") {
+        let owner = pod.metadata.owner_references.as_ref()?
+            .iter()
+            .find(|o| o.kind == "Job")?;
+        let spec = jobs.get(&owner.name).await.ok()?.spec?;
+        let parallelism = spec.parallelism.unwrap_or(1);
+        let size = match spec.completions {
+            Some(completions) => parallelism.min(completions),
+            None => parallelism,
+        };
+        return usize::try_from(size).ok();
+    }
+
+    let pods: Api<Pod> = Api::namespaced(client.clone(), &key.namespace);
+    let lp = ListParams::default().labels(&key.id);
+    pods.list(&lp).await.ok().map(|list| list.items.iter().filter(|p| waits_on_dataset(p, gate_name, annotation)).count())
+}
+
+/// True for a pod that is still gated by us, asks for a dataset and has not
+/// finished: the only pods that will ever be marked ready.
+pub fn waits_on_dataset(pod: &Pod, gate_name: &str, annotation: &str) -> bool {
+    let finished = pod.status.as_ref()
+        .and_then(|s| s.phase.as_deref())
+        .is_some_and(|phase| phase == "Succeeded" || phase == "Failed");
+    let gated = pod.spec.as_ref()
+        .and_then(|s| s.scheduling_gates.as_ref())
+        .is_some_and(|gates| gates.iter().any(|g| g.name == gate_name));
+    !finished && gated && crate::dataset::wants_dataset(pod, annotation)
+}
+
+impl GangTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a gated member that is still waiting for its data, so the gang
+    /// is not released early if its size could not be resolved.
+    pub fn mark_waiting(&mut self, key: &GangKey, pod_name: &str) {
+        self.gangs.entry(key.clone()).or_default()
+            .members.entry(pod_name.to_string()).or_insert(false);
+    }
+
+    /// Marks a member's data as ready. Returns every member of the gang once
+    /// all of them are ready; the gang is forgotten at that point.
+    pub fn mark_ready(&mut self, key: &GangKey, pod_name: &str, expected: Option<usize>) -> Option<Vec<String>> {
+        let gang = self.gangs.entry(key.clone()).or_default();
+        gang.members.insert(pod_name.to_string(), true);
+        if expected.is_some() {
+            gang.expected = expected;
+        }
+
+        let ready = gang.members.values().filter(|r| **r).count();
+        let needed = gang.expected.unwrap_or(gang.members.len()).max(gang.members.len());
+        if ready < needed {
+            return None;
+        }
+
+        self.gangs.remove(key).map(|g| g.members.into_keys().collect())
+    }
+
+    /// (ready, needed) for logging while a gang is held.
+    pub fn progress(&self, key: &GangKey) -> (usize, usize) {
+        self.gangs.get(key).map(|g| {
+            let ready = g.members.values().filter(|r| **r).count();
+            (ready, g.expected.unwrap_or(g.members.len()).max(g.members.len()))
+        }).unwrap_or((0, 0))
+    }
+
+    /// Drops a deleted pod from its gang.
+    pub fn forget(&mut self, key: &GangKey, pod_name: &str) {
+        if let Some(gang) = self.gangs.get_mut(key) {
+            gang.members.remove(pod_name);
+            if gang.members.is_empty() {
+                self.gangs.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key() -> GangKey {
+        GangKey { namespace: "ml".to_string(), id: format!("{}=run-1", GANG_LABEL) }
+    }
+
+    fn pod(value: serde_json::Value) -> Pod {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn releases_once_every_member_is_ready() {
+        let mut gangs = GangTracker::new();
+        gangs.mark_waiting(&key(), "w0");
+        gangs.mark_waiting(&key(), "w1");
+        assert_eq!(gangs.mark_ready(&key(), "w0", Some(2)), None);
+        assert_eq!(gangs.progress(&key()), (1, 2));
+
+        let mut members = gangs.mark_ready(&key(), "w1", None).unwrap();
+        members.sort();
+        assert_eq!(members, vec!["w0", "w1"]);
+        assert_eq!(gangs.progress(&key()), (0, 0));
+    }
+
+    #[test]
+    fn waits_for_members_not_seen_yet() {
+        let mut gangs = GangTracker::new();
+        assert_eq!(gangs.mark_ready(&key(), "w0", Some(3)), None);
+        assert_eq!(gangs.mark_ready(&key(), "w1", None), None);
+        assert!(gangs.mark_ready(&key(), "w2", None).is_some());
+    }
+
+    #[test]
+    fn unknown_size_waits_for_known_members() {
+        let mut gangs = GangTracker::new();
+        gangs.mark_waiting(&key(), "w1");
+        assert_eq!(gangs.mark_ready(&key(), "w0", None), None);
+        assert!(gangs.mark_ready(&key(), "w1", None).is_some());
+    }
+
+    #[test]
+    fn forgotten_member_no_longer_blocks() {
+        let mut gangs = GangTracker::new();
+        gangs.mark_waiting(&key(), "w1");
+        assert_eq!(gangs.mark_ready(&key(), "w0", None), None);
+        gangs.forget(&key(), "w1");
+        assert!(gangs.mark_ready(&key(), "w0", None).is_some());
+    }
+
+    #[test]
+    fn gang_key_prefers_our_label_then_the_job() {
+        let labelled = pod(json!({"metadata": {"namespace": "ml", "labels": {
+            GANG_LABEL: "run-1", "jobset.sigs.k8s.io/jobset-name": "js"}}}));
+        assert_eq!(gang_key(&labelled), Some(key()));
+
+        let job = pod(json!({"metadata": {"ownerReferences": [
+            {"apiVersion": "batch/v1", "kind": "Job", "name": "j", "uid": "u1", "controller": true}]}}));
+        assert_eq!(gang_key(&job), Some(GangKey { namespace: "default".to_string(), id: "job/u1".to_string() }));
+        assert_eq!(gang_key(&pod(json!({"metadata": {}}))), None);
+    }
+
+    #[test]
+    fn only_gated_dataset_pods_count_towards_the_gang() {
+        let worker = json!({
+            "metadata": {"annotations": {"ds": "s3://b/k"}},
+            "spec": {"containers": [], "schedulingGates": [{"name": "gate"}]},
+        });
+        assert!(waits_on_dataset(&pod(worker.clone()), "gate", "ds"));
+
+        let master = json!({"spec": {"containers": [], "schedulingGates": [{"name": "gate"}]}});
+        assert!(!waits_on_dataset(&pod(master), "gate", "ds"));
+
+        let mut finished = worker.clone();
+        finished["status"] = json!({"phase": "Succeeded"});
+        assert!(!waits_on_dataset(&pod(finished), "gate", "ds"));
+
+        let mut released = worker;
+        released["spec"]["schedulingGates"] = json!([]);
+        assert!(!waits_on_dataset(&pod(released), "gate", "ds"));
+    }
+}
@@ -0,0 +1,128 @@
+// --- LEADER ELECTION ---
+// Several operator replicas can run at once; a coordination.k8s.io Lease
+// decides which one reconciles. Standbys keep serving metrics, health and
+// the admission webhook, and take over once the leader stops renewing.
+//
+// Updates go through `replace` with the Lease's resourceVersion, so two
+// replicas racing for an expired Lease cannot both win.
+
+use crate::config::Config;
+use crate::metrics::MetricsState;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{Duration as ChronoDuration, Utc};
+use kube::api::{ObjectMeta, PostParams};
+use kube::{Api, Client};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+pub struct LeaderSettings {
+    pub lease_name: String,
+    pub namespace: String,
+    pub identity: String,
+    pub lease_duration: Duration,
+    pub renew_interval: Duration,
+}
+
+impl LeaderSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            lease_name: config.lease_name.clone(),
+            namespace: config.pod_namespace.clone(),
+            // Downward API pod name; falls back to the container hostname
+            identity: config.pod_name.clone()
+                .or_else(|| std::env::var("HOSTNAME").ok())
+                .unwrap_or_else(|| "kube-cache".to_string()),
+            lease_duration: Duration::from_secs(15),
+            renew_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Keeps trying to acquire or renew the Lease and publishes whether this
+/// replica is the leader.
+pub async fn run(client: Client, settings: LeaderSettings, leading: watch::Sender<bool>, metrics_state: MetricsState) {
+    let leases: Api<Lease> = Api::namespaced(client, &settings.namespace);
+    let mut tick = tokio::time::interval(settings.renew_interval);
+    let mut last_renewed: Option<Instant> = None;
+
+    loop {
+        tick.tick().await;
+        let is_leader = match try_acquire(&leases, &settings).await {
+            Ok(true) => {
+                last_renewed = Some(Instant::now());
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!(event = "lease_error", error = ?e, "Failed to renew leader lease");
+                // Step down before another replica could consider the lease expired
+                let deadline = settings.lease_duration.saturating_sub(settings.renew_interval);
+                *leading.borrow() && last_renewed.map(|t| t.elapsed() < deadline).unwrap_or(false)
+            }
+        };
+
+        if is_leader != *leading.borrow() {
+            if is_leader {
+                info!(event = "leader_acquired", identity = %settings.identity, lease = %settings.lease_name, "Became leader");
+                metrics_state.count_leader_transition();
+            } else {
+                warn!(event = "leader_lost", identity = %settings.identity, lease = %settings.lease_name, "Lost leadership");
+            }
+            metrics_state.set_leader(is_leader);
+            let _ = leading.send(is_leader);
+        }
+    }
+}
+
+async fn try_acquire(leases: &Api<Lease>, settings: &LeaderSettings) -> Result<bool, kube::Error> {
+    let now = Utc::now();
+    let duration_secs = settings.lease_duration.as_secs() as i32;
+
+    let Some(mut lease) = leases.get_opt(&settings.lease_name).await? else {
+        // 1. Nobody has ever held it
+        let lease = Lease {
+            metadata: ObjectMeta { name: Some(settings.lease_name.clone()), ..Default::default() },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(settings.identity.clone()),
+                lease_duration_seconds: Some(duration_secs),
+                acquire_time: Some(MicroTime(now)),
+                renew_time: Some(MicroTime(now)),
+                lease_transitions: Some(0),
+            }),
+        };
+        return match leases.create(&PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e),
+        };
+    };
+
+    let spec = lease.spec.get_or_insert_with(Default::default);
+    let holder = spec.holder_identity.clone().unwrap_or_default();
+    let expires = spec.renew_time.as_ref().map(|t| t.0)
+        .map(|t| t + ChronoDuration::seconds(i64::from(spec.lease_duration_seconds.unwrap_or(duration_secs))));
+    let expired = expires.map(|e| e < now).unwrap_or(true);
+
+    if holder != settings.identity {
+        if !expired && !holder.is_empty() {
+            return Ok(false);
+        }
+        // 2. Take over an abandoned lease
+        spec.holder_identity = Some(settings.identity.clone());
+        spec.acquire_time = Some(MicroTime(now));
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+    }
+
+    // 3. Renew (or finish taking over)
+    spec.renew_time = Some(MicroTime(now));
+    spec.lease_duration_seconds = Some(duration_secs);
+
+    match leases.replace(&settings.lease_name, &PostParams::default(), &lease).await {
+        Ok(_) => Ok(true),
+        // Someone else updated it first
+        Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
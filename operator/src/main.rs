@@ -1,5 +1,6 @@
 // --- IMPORTS ---
-use kube::{Api, Client, api::{WatchEvent, WatchParams, Patch, PatchParams}};
+use kube::{Api, Client, api::{Patch, PatchParams}};
+use kube::runtime::watcher::{self, Event};
 use k8s_openapi::api::core::v1::Pod;
 use futures::StreamExt;
 use serde_json::json;
@@ -12,14 +13,8 @@ use opentelemetry_sdk::{trace as sdktrace, Resource};
 use opentelemetry_otlp::WithExportConfig; 
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Registry};
 
-// --- NEW IMPORTS FOR S3 ---
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::{Client as S3Client, config::Region};
-use std::fs::File;
-use std::io::Write;
-
 // NEW: Logging Imports
-use tracing::{info, error}; // Removed unused 'Level'
+use tracing::{info, error, warn}; // Removed unused 'Level'
 
 // NEW: Metrics Imports
 mod metrics;
@@ -28,6 +23,57 @@ use axum::{routing::get, Router, extract::State};
 use std::net::SocketAddr;
 use prometheus::{Encoder, TextEncoder};
 
+mod config;
+use config::{CacheMode, Config, ConfigSource};
+
+mod gang;
+use gang::GangTracker;
+
+mod crd;
+mod webhook;
+use webhook::MountMode;
+mod dataset;
+use dataset::{DatasetError, DatasetRef};
+
+mod scheduler;
+use scheduler::{Completed, DownloadQueue, Retries};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+mod throttle;
+use throttle::Throttle;
+
+mod storage;
+use storage::Backends;
+
+mod peers;
+use peers::Peers;
+
+mod proxy;
+
+mod prewarm;
+use prewarm::{PrewarmSettings, Warmer};
+
+mod prefetch;
+
+mod pvc;
+use pvc::{FillState, PvcSettings};
+
+mod leader;
+use leader::LeaderSettings;
+
+mod reload;
+
+mod namespaces;
+use namespaces::NamespaceScope;
+
+mod agent;
+use agent::{DataState, NodeAgent, PublishAuthorizer};
+use tokio::sync::watch;
+
 // --- METRICS SERVER ---
 async fn metrics_handler(State(state): State<MetricsState>) -> String {
     let encoder = TextEncoder::new();
@@ -37,23 +83,25 @@ async fn metrics_handler(State(state): State<MetricsState>) -> String {
     String::from_utf8(result).unwrap()
 }
 
-async fn start_metrics_server(state: MetricsState) {
+async fn start_metrics_server(state: MetricsState, port: u16, extra: Router) {
     let app = Router::new()
         .route("/metrics", get(metrics_handler))
-        .with_state(state);
+        .route("/healthz", get(|| async { "ok" }))
+        .with_state(state)
+        .merge(extra);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    info!(event = "server_start", port = 8080, "Metrics Server listening");
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!(event = "server_start", port, "Metrics Server listening");
     
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
 
-fn init_telemetry() {
+fn init_telemetry(config: &Config) -> reload::LogHandle {
     // 1. Create the OTLP (OpenTelemetry) Exporter
     let exporter = opentelemetry_otlp::new_exporter()
         .tonic()
-        .with_endpoint("http://tempo:4317"); 
+        .with_endpoint(&config.otlp_endpoint);
 
     // 2. Define the Tracer
     let tracer = opentelemetry_otlp::new_pipeline()
@@ -70,8 +118,10 @@ fn init_telemetry() {
     // 3. Connect Tracing to Logs (Stdout) AND Traces (Tempo)
     let telemetry = tracing_opentelemetry::layer().with_tracer(tracer);
     let logger = tracing_subscriber::fmt::layer().json(); 
-    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| "info".into());
+    // Behind a reload layer so the ConfigMap can change the level live
+    let (env_filter, log_handle) = tracing_subscriber::reload::Layer::new(
+        tracing_subscriber::EnvFilter::new(&config.log_level),
+    );
 
     // 4. Register everything
     Registry::default()
@@ -79,13 +129,29 @@ fn init_telemetry() {
         .with(logger)
         .with(telemetry)
         .init();
+
+    log_handle
 }
 
 // --- MAIN OPERATOR LOOP ---
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // 0. Load and validate the configuration (flags > env > file > defaults)
+    let config_source = ConfigSource::from_args();
+    let config = match config_source.load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("kube-cache: {}", e);
+            std::process::exit(2);
+        }
+    };
+    if config.print_config {
+        print!("{}", config.dump());
+        return Ok(());
+    }
+
     // 1. Initialize Telemetry (Logs + Traces)
-    init_telemetry();
+    let log_handle = init_telemetry(&config);
 
     // 2. Install Crypto Provider
     ring::default_provider()
@@ -95,124 +161,423 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // 3. Initialize the Observability Layer
     let metrics_state = MetricsState::new();
     
-    // 4. Spawn the Web Server
-    let server_state = metrics_state.clone();
-    tokio::spawn(async move {
-        start_metrics_server(server_state).await;
+    let client = Client::try_default().await?;
+
+    // 5. Serve the Admission Webhook (injects the gate so users don't have to)
+    tokio::spawn(webhook::run(client.clone(), webhook::WebhookSettings::from_config(&config)));
+
+    // Follow the ConfigMap for live tuning, on standbys too (log level)
+    let (config_tx, config_rx) = watch::channel(config.clone());
+    tokio::spawn(reload::watch_config_map(client.clone(), config_source, config_tx, log_handle, metrics_state.clone()));
+
+    // Serve this node's cache to peers and workloads, on standbys too (node mode only)
+    let peers = Arc::new(Peers::from_config(&config));
+    let backends = Arc::new(Backends::from_config(&config, client.clone(), metrics_state.clone(), peers.clone()).await);
+
+    // 4. Spawn the Web Server (metrics, health, and the CSI plugin's publish checks)
+    let authorizer = PublishAuthorizer::new(client.clone(), backends.clone(), &config);
+    tokio::spawn(start_metrics_server(metrics_state.clone(), config.metrics_port, authorizer.routes()));
+
+    // Download workers run on standbys too, so every replica can pre-warm its own node
+    let queue = Arc::new(DownloadQueue::new(Duration::from_secs(config.queue_aging_seconds)));
+    let throttle = Arc::new(Throttle::from_config(&config));
+    tokio::spawn(throttle::track_cluster_share(client.clone(), config.pod_namespace.clone(), throttle.clone()));
+
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel::<Completed>();
+    let workers = Arc::new(Workers {
+        queue: queue.clone(),
+        throttle: throttle.clone(),
+        backends: backends.clone(),
+        done: done_tx,
+        metrics_state: metrics_state.clone(),
+        target: watch::channel(0).0,
+        running: AtomicUsize::new(0),
     });
+    workers.resize(config.download_concurrency);
+    let warmer = Warmer::new(queue.clone(), config.cache_root.clone(), peers.clone(), backends.clone(), metrics_state.clone());
 
-    let client = Client::try_default().await?;
-    let pods: Api<Pod> = Api::namespaced(client.clone(), "default");
-    
-    let gate_name = "kube-cache.openai.com/gate";
-    let wp = WatchParams::default();
-
-    info!(event = "startup", version = env!("CARGO_PKG_VERSION"), "Kube-Cache Gatekeeper Online");
-
-    let mut stream = pods.watch(&wp, "0").await?.boxed();
-
-    while let Some(status) = stream.next().await {
-        match status {
-            Ok(WatchEvent::Added(pod)) | Ok(WatchEvent::Modified(pod)) => {
-                let name = pod.metadata.name.clone().unwrap_or_default();
-                
-                let has_gate = pod.spec.as_ref()
-                    .and_then(|s| s.scheduling_gates.as_ref())
-                    .map(|gates| gates.iter().any(|g| g.name == gate_name))
-                    .unwrap_or(false);
-
-                if has_gate {
-                    info!(event = "pod_locked", pod_name = %name, "Locked Pod Detected");
-                    
-                    if let Some(annotations) = pod.metadata.annotations {
-                        if let Some(data_url) = annotations.get("x-openai/required-dataset") {
-                            
-                            info!(event = "delegation_start", pod_name = %name, dataset = %data_url, "Delegating download to job");
-                            
-                            let filename = data_url.replace("s3://", "").replace("/", "-");
-                            let file_path = format!("/tmp/{}", filename);
-
-                            if std::path::Path::new(&file_path).exists() {
-                                info!(event = "cache_hit", pod_name = %name, path = %file_path, "Dataset found locally");
-                                metrics_state.count_hit();
-                            } else {
-                                info!(event = "cache_miss", pod_name = %name, path = %file_path, "Downloading dataset");
-                                metrics_state.count_miss();
-
-                                let start = std::time::Instant::now();
-
-                                info!(event = "download_start", path = %file_path, "Starting real S3 download...");
-                                
-                                if let Err(e) = download_file_from_s3(&file_path).await {
-                                    error!(event = "download_error", error = ?e, "Failed to download from S3");
-                                }
+    if config.cache_mode == CacheMode::Node && peers.enabled() {
+        tokio::spawn(peers::serve(peers.clone(), config.cache_root.clone(), warmer.clone().routes()));
+        tokio::spawn(peers::discover(client.clone(), config.pod_namespace.clone(), peers.clone()));
+    }
+    if config.cache_mode == CacheMode::Node && config.proxy_port != 0 {
+        tokio::spawn(proxy::serve(config.proxy_port, client.clone(), config.cache_root.clone(), backends.clone(), throttle.clone(), metrics_state.clone()));
+    }
+
+    // Live tuning applies on every replica: each one downloads for its own node
+    tokio::spawn(follow_live_config(config_rx.clone(), throttle.clone(), queue.clone(), workers.clone()));
+
+    // 6. Fetch datasets for the pods scheduled to this node, on standbys too
+    match config.node_name.clone().filter(|_| config.cache_mode == CacheMode::Node) {
+        Some(node) => {
+            let agent = NodeAgent::new(client.clone(), &config, node, queue.clone(), backends.clone(), throttle.clone(), metrics_state.clone());
+            tokio::spawn(agent.run(NamespaceScope::from_config(&config), done_rx));
+        }
+        None => {
+            if config.cache_mode == CacheMode::Node {
+                warn!(event = "node_name_missing", "NODE_NAME is unset, nothing is fetched for the pods on this node");
+            }
+            // Only pre-warming downloads finish here, and nobody waits on them
+            tokio::spawn(async move { while done_rx.recv().await.is_some() {} });
+        }
+    }
+
+    // 7. Leader Election: standbys stop here and only serve metrics, health, the webhook, peers and their node
+    let (leading_tx, mut leading_rx) = watch::channel(false);
+    tokio::spawn(leader::run(client.clone(), LeaderSettings::from_config(&config), leading_tx, metrics_state.clone()));
+    loop {
+        info!(event = "standby", "Waiting for leadership");
+        leading_rx.wait_for(|leading| *leading).await?;
+        lead(&client, &config, config_rx.clone(), &mut leading_rx, &metrics_state, &backends, &warmer).await?;
+    }
+}
+
+// --- LEADER LOOP ---
+// Releases gated pods once they may have their data, until leadership is
+// lost. The leader-only background tasks are aborted with it, so a replica
+// that loses its lease goes back to standby without restarting (its node's
+// downloads and mounts carry on).
+async fn lead(
+    client: &Client,
+    config: &Config,
+    mut config_rx: watch::Receiver<Config>,
+    leading_rx: &mut watch::Receiver<bool>,
+    metrics_state: &MetricsState,
+    backends: &Arc<Backends>,
+    warmer: &Warmer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let gate_name = config.gate_name.as_str();
+    let mut gangs = GangTracker::new();
+    let mut tasks = tokio::task::JoinSet::new();
+
+    // Pick up any live changes made while on standby
+    let mut fail_open = config_rx.borrow_and_update().fail_open;
+
+    // Warm nodes ahead of demand, when a PrewarmPolicy's query crosses its threshold or on its schedule
+    if config.cache_mode == CacheMode::Node {
+        tasks.spawn(prewarm::run(client.clone(), PrewarmSettings::from_config(config), warmer.clone(), metrics_state.clone()));
+    }
+    // ...and as soon as a Deployment, StatefulSet or Job wanting a dataset asks for more pods
+    if config.workload_prefetch {
+        let settings = PrewarmSettings::from_config(config);
+        tasks.spawn(prefetch::run(client.clone(), NamespaceScope::from_config(config), settings, warmer.clone(), metrics_state.clone()));
+    }
 
-                                let duration = start.elapsed().as_secs_f64();
-                                metrics_state.observe_warmup(duration);
+    // PVC mode: datasets are filled once into a shared claim instead of per node
+    let pvc_settings = (config.cache_mode == CacheMode::Pvc).then(|| Arc::new(PvcSettings::from_config(config)));
+    if let Some(settings) = pvc_settings.clone() {
+        let gc_client = client.clone();
+        tasks.spawn(async move { pvc::collect_garbage(gc_client, &settings).await });
+    }
+    let prewarm_settings = PrewarmSettings::from_config(config);
+    let mut pvc_waiting: HashMap<(String, String), (Pod, DatasetRef)> = HashMap::new();
+    let mut pvc_tick = tokio::time::interval(Duration::from_secs(10));
+
+    info!(event = "startup", version = env!("CARGO_PKG_VERSION"), pvc_mode = pvc_settings.is_some(), "Kube-Cache Gatekeeper Online");
+
+    let scope = NamespaceScope::from_config(config);
+    let (mut stream, namespace_filter) = namespaces::watch_pods(client, &scope, watcher::Config::default()).await?;
+    let retries = Retries::default();
+
+    loop {
+        tokio::select! {
+            event = stream.next() => {
+                let Some(event) = event else { break };
+                match event {
+                    Event::Apply(pod) | Event::InitApply(pod) => {
+                        let name = pod.metadata.name.clone().unwrap_or_default();
+                        if !namespace_filter.allows(pod.metadata.namespace.as_deref().unwrap_or_default()) {
+                            continue;
+                        }
+
+                        let has_gate = pod.spec.as_ref()
+                            .and_then(|s| s.scheduling_gates.as_ref())
+                            .map(|gates| gates.iter().any(|g| g.name == gate_name))
+                            .unwrap_or(false);
+
+                        if !has_gate || retries.pending(&(pod.metadata.namespace.clone().unwrap_or_default(), name.clone())) {
+                            continue;
+                        }
+
+                        // Templated references ({index}, {label:...}) are rendered per pod
+                        let dataset = match dataset::lookup(client, &pod, &config.dataset_annotation).await {
+                            Some(Ok(dataset)) => dataset,
+                            Some(Err(e @ DatasetError::Lookup(..))) => {
+                                warn!(event = "dataset_lookup_error", pod_name = %name, error = %e, "Could not read the pod's Dataset, retrying");
+                                retries.schedule(client, &pod);
+                                continue;
+                            }
+                            Some(Err(e)) => {
+                                error!(event = "dataset_invalid", pod_name = %name, error = %e, "Cannot resolve dataset reference");
+                                continue;
                             }
+                            None => continue,
+                        };
 
-                            info!(event = "data_ready", pod_name = %name, "Data ready on disk");
+                        info!(event = "pod_locked", pod_name = %name, "Locked Pod Detected");
 
-                            let patch = json!({
-                                "spec": { "schedulingGates": [] }
-                            });
-                            
-                            let pp = PatchParams::default();
-                            pods.patch(&name, &pp, &Patch::Merge(patch)).await?;
-                            
-                            info!(event = "pod_release", pod_name = %name, "Pod released to scheduler");
+                        if let Some(key) = gang::gang_key(&pod) {
+                            gangs.mark_waiting(&key, &name);
                         }
+
+                        // Admission may have been skipped (the webhook fails open), so the
+                        // namespace's source policy is enforced here, before any download
+                        let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+                        if let Err(e) = webhook::check_source(client, &namespace, &dataset).await {
+                            error!(event = "dataset_source_denied", pod_name = %name, dataset = %dataset.uri, error = %e, "Pod's namespace may not use its dataset source, keeping it gated");
+                            retries.schedule(client, &pod);
+                            continue;
+                        }
+
+                        // Whatever the failure policy, a pod without access to its source
+                        // must not be handed a cache entry someone else downloaded
+                        if let Err(e) = backends.tenants.check_access(&dataset).await {
+                            error!(event = "dataset_access_denied", pod_name = %name, dataset = %dataset.uri, error = %e, "Pod cannot read its dataset, keeping it gated");
+                            retries.schedule(client, &pod);
+                            continue;
+                        }
+
+                        let Some(settings) = pvc_settings.as_deref() else {
+                            // Node mode: pinned to a node whose agent fetches the data there and reports back
+                            match (agent::pinned_node(&pod), DataState::of(&pod)) {
+                                (None, _) => match agent::pin(client, &prewarm_settings, &pod).await {
+                                    Ok(Some(node)) => info!(event = "pod_pinned", namespace = %namespace, pod_name = %name, node = %node, "Fetching dataset on the pod's node"),
+                                    Ok(None) => {
+                                        warn!(event = "pod_unplaceable", namespace = %namespace, pod_name = %name, "No schedulable node with a kube-cache pod matches the pod, keeping it gated");
+                                        retries.schedule(client, &pod);
+                                    }
+                                    Err(e) => {
+                                        error!(event = "pod_pin_error", pod_name = %name, error = ?e, "Failed to pin pod to a node");
+                                        retries.schedule(client, &pod);
+                                    }
+                                },
+                                (Some(_), Some(DataState::Ready)) => pod_ready(client, &mut gangs, config, metrics_state, &pod).await,
+                                // Failed downloads release the pod to read from the source itself, unless
+                                // fail_open is off or its hostPath volume cannot exist without the entry
+                                (Some(_), Some(DataState::Failed)) if fail_open && config.mount_mode != MountMode::HostPath => {
+                                    pod_ready(client, &mut gangs, config, metrics_state, &pod).await
+                                }
+                                // The agent is still fetching, or retrying
+                                (Some(_), _) => {}
+                            }
+                            continue;
+                        };
+                        let id = (namespace.clone(), name.clone());
+                        if pvc_waiting.contains_key(&id) {
+                            continue;
+                        }
+                        match pvc::ensure_populated(client, settings, &namespace, &dataset).await {
+                            Ok(FillState::Ready) => pod_ready(client, &mut gangs, config, metrics_state, &pod).await,
+                            Ok(FillState::Filling) => { pvc_waiting.insert(id, (pod.clone(), dataset)); }
+                            // Failed fills release the pod to read from the source itself, unless fail_open is off
+                            Ok(FillState::Failed) if fail_open => pod_ready(client, &mut gangs, config, metrics_state, &pod).await,
+                            Ok(FillState::Failed) => {
+                                info!(event = "pod_held", namespace = %namespace, pod_name = %name, "PVC fill failed, keeping pod gated for retry");
+                                retries.schedule(client, &pod);
+                            }
+                            Err(e) => {
+                                error!(event = "pvc_error", pod_name = %name, error = ?e, "Failed to provision dataset PVC");
+                                retries.schedule(client, &pod);
+                            }
+                        }
+                    },
+                    Event::Delete(pod) => {
+                        let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+                        let name = pod.metadata.name.clone().unwrap_or_default();
+                        pvc_waiting.remove(&(namespace.clone(), name.clone()));
+                        if let Some(key) = gang::gang_key(&pod) {
+                            gangs.forget(&key, &name);
+                        }
+                    },
+                    Event::Init | Event::InitDone => {}
+                }
+            },
+            Ok(()) = config_rx.changed() => {
+                fail_open = config_rx.borrow_and_update().fail_open;
+            },
+            _ = leading_rx.changed() => {
+                if !*leading_rx.borrow() {
+                    // Another replica may already be reconciling
+                    return Ok(());
+                }
+            },
+            _ = pvc_tick.tick(), if !pvc_waiting.is_empty() => {
+                let Some(settings) = pvc_settings.as_deref() else { continue };
+                // One status check per claim, however many pods wait on it:
+                // Some(true) filled, Some(false) failed, None still filling
+                let mut states: HashMap<(String, String), Option<bool>> = HashMap::new();
+                let mut finished = Vec::new();
+                for (id, (pod, dataset)) in pvc_waiting.iter() {
+                    let claim = (id.0.clone(), pvc::claim_name(dataset));
+                    let state = match states.get(&claim) {
+                        Some(state) => *state,
+                        None => {
+                            let state = match pvc::ensure_populated(client, settings, &id.0, dataset).await {
+                                Ok(FillState::Ready) => Some(true),
+                                Ok(FillState::Failed) => Some(false),
+                                Ok(FillState::Filling) => None,
+                                Err(e) => {
+                                    error!(event = "pvc_error", claim = %claim.1, error = ?e, "Failed to check dataset PVC");
+                                    None
+                                }
+                            };
+                            states.insert(claim, state);
+                            state
+                        }
+                    };
+                    if let Some(filled) = state {
+                        finished.push((id.clone(), pod.clone(), filled));
+                    }
+                }
+                for (id, pod, filled) in finished {
+                    pvc_waiting.remove(&id);
+                    if filled || fail_open {
+                        pod_ready(client, &mut gangs, config, metrics_state, &pod).await;
+                    } else {
+                        info!(event = "pod_held", namespace = %id.0, pod_name = %id.1, "PVC fill failed, keeping pod gated for retry");
+                        retries.schedule(client, &pod);
                     }
                 }
             },
-            Ok(WatchEvent::Error(e)) => error!(error = ?e, "Watch stream error"),
-            _ => {}
         }
     }
 
     Ok(())
 }
 
-// NEW: Real S3 Download Function
-#[tracing::instrument(fields(bucket="models", key="gpt-4-weights"))]
-async fn download_file_from_s3(target_path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let region_provider = RegionProviderChain::default_provider().or_else(Region::new("us-east-1"));
+// Applies the live settings the download machinery follows (see `reload`).
+async fn follow_live_config(mut config_rx: watch::Receiver<Config>, throttle: Arc<Throttle>, queue: Arc<DownloadQueue>, workers: Arc<Workers>) {
+    loop {
+        let live = config_rx.borrow_and_update().clone();
+        throttle.set_limits(
+            live.node_bandwidth_bytes_per_sec,
+            live.cluster_bandwidth_bytes_per_sec,
+            live.max_concurrent_s3_requests,
+        );
+        queue.set_aging(Duration::from_secs(live.queue_aging_seconds));
+        workers.resize(live.download_concurrency);
+        if config_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
 
-    let s3_endpoint = std::env::var("S3_ENDPOINT")
-        .unwrap_or_else(|_| "http://localhost:9000".to_string());
+// --- DOWNLOAD WORKERS ---
+// Shared by every worker. The pool can be resized at runtime.
+struct Workers {
+    queue: Arc<DownloadQueue>,
+    throttle: Arc<Throttle>,
+    backends: Arc<Backends>,
+    done: mpsc::UnboundedSender<Completed>,
+    metrics_state: MetricsState,
+    target: watch::Sender<usize>,
+    running: AtomicUsize,
+}
 
-    info!(event = "config_check", endpoint = %s3_endpoint, "Connecting to S3 Storage");
+impl Workers {
+    /// Grows the pool right away. Surplus workers retire once idle, so
+    /// shrinking never cuts a download short.
+    fn resize(self: &Arc<Self>, target: usize) {
+        self.target.send_replace(target);
+        while self.running.load(Ordering::SeqCst) < target {
+            self.running.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(download_worker(self.clone()));
+        }
+    }
 
-    let config = aws_config::defaults(aws_config::BehaviorVersion::latest())
-        .region(region_provider)
-        .endpoint_url(&s3_endpoint)
-        .load()
-        .await;
+    // Claims one surplus slot; the caller must then exit.
+    fn retire(&self) -> bool {
+        let target = *self.target.borrow();
+        self.running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| (running > target).then(|| running - 1))
+            .is_ok()
+    }
+}
 
-    let s3_config = aws_sdk_s3::config::Builder::from(&config)
-        .force_path_style(true)
-        .build();
+// Pulls the highest-priority dataset off the queue, fetches it and reports back
+// to the node agent, which retries failures.
+async fn download_worker(workers: Arc<Workers>) {
+    let Workers { queue, throttle, backends, done, metrics_state, .. } = workers.as_ref();
+    let mut target = workers.target.subscribe();
 
-    let client = S3Client::from_conf(s3_config);
+    loop {
+        if workers.retire() {
+            return;
+        }
+        let job = tokio::select! {
+            job = queue.pop() => job,
+            _ = target.changed() => continue,
+        };
+        // Pre-warm downloads wait behind every pod and were never asked for
+        if !job.prewarm {
+            metrics_state.observe_queue_wait(job.waited.as_secs_f64());
+            metrics_state.count_miss();
+        }
 
-    let bucket = "models";
-    let key = "gpt-4-weights";
+        info!(event = "download_start", path = %job.path, dataset = %job.dataset.uri, "Starting download...");
+        let start = std::time::Instant::now();
+
+        let metered = throttle.metered();
+        let ok = match storage::fetch(backends, &job.dataset, &job.path, &metered).await {
+            Ok(()) => true,
+            Err(e) => {
+                error!(event = "download_error", dataset = %job.dataset.uri, error = %e, "Download failed");
+                false
+            }
+        };
+        // Failed downloads too: throttling is often what made them time out
+        metrics_state.observe_throttled(metered.held().as_secs_f64());
+        match (job.prewarm, ok) {
+            (true, true) => metrics_state.count_success(),
+            (true, false) => metrics_state.count_prewarm_failure(),
+            (false, _) => {}
+        }
 
-    info!(event = "s3_start", bucket = %bucket, key = %key, "Starting S3 download stream");
+        metrics_state.observe_warmup(start.elapsed().as_secs_f64());
 
-    let mut resp = client
-        .get_object()
-        .bucket(bucket)
-        .key(key)
-        .send()
-        .await?;
+        if done.send(queue.finish(&job.path, ok)).is_err() {
+            return;
+        }
+    }
+}
 
-    let mut file = File::create(target_path)?;
-    
-    while let Some(bytes) = resp.body.try_next().await? {
-        file.write_all(&bytes)?;
+// The pod's data is ready: in its PVC, or (node mode) on the node it is
+// pinned to. Release it now, or hold it until the rest of its gang is ready.
+async fn pod_ready(client: &Client, gangs: &mut GangTracker, config: &Config, metrics_state: &MetricsState, pod: &Pod) {
+    let name = pod.metadata.name.clone().unwrap_or_default();
+
+    let Some(key) = gang::gang_key(pod) else {
+        let namespace = pod.metadata.namespace.clone().unwrap_or_default();
+        release_pod(client, &namespace, &name).await;
+        return;
+    };
+
+    let expected = gang::expected_size(client, pod, &key, &config.gate_name, &config.dataset_annotation).await;
+    match gangs.mark_ready(&key, &name, expected) {
+        Some(members) => {
+            info!(event = "gang_release", gang = %key.id, size = members.len(), "All gang members ready");
+            for member in members {
+                release_pod(client, &key.namespace, &member).await;
+            }
+            metrics_state.count_gang_release();
+        }
+        None => {
+            let (ready, needed) = gangs.progress(&key);
+            info!(event = "gang_wait", pod_name = %name, gang = %key.id, ready, needed, "Holding pod until gang is ready");
+        }
     }
+}
 
-    info!(event = "s3_complete", path = %target_path, "Download finished successfully");
-    Ok(())
-}
\ No newline at end of file
+// A failed patch is logged, not fatal: usually the pod was deleted meanwhile.
+async fn release_pod(client: &Client, namespace: &str, name: &str) {
+    let patch = json!({
+        "spec": { "schedulingGates": [] }
+    });
+
+    let pp = PatchParams::default();
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    match pods.patch(name, &pp, &Patch::Merge(patch)).await {
+        Ok(_) => info!(event = "pod_release", namespace = %namespace, pod_name = %name, "Pod released to scheduler"),
+        Err(e) => error!(event = "pod_release_error", namespace = %namespace, pod_name = %name, error = ?e, "Failed to release pod"),
+    }
+}
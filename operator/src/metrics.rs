@@ -1,7 +1,9 @@
 use prometheus::{
-    IntCounter, Histogram, HistogramOpts, Registry, 
-    IntGauge, opts, register_int_counter_with_registry, 
-    register_histogram_with_registry, register_int_gauge_with_registry
+    IntCounter, IntCounterVec, Histogram, HistogramOpts, HistogramVec, Registry, 
+    IntGauge, IntGaugeVec, opts, register_int_counter_with_registry, 
+    register_int_counter_vec_with_registry,
+    register_histogram_with_registry, register_histogram_vec_with_registry,
+    register_int_gauge_with_registry, register_int_gauge_vec_with_registry
 };
 
 use std::sync::Arc;
@@ -14,16 +16,36 @@ pub struct MetricsState {
     
     // 1. The Scoreboard (Counters)
     pub ops_prewarm_success: IntCounter,
+    pub ops_prewarm_failure: IntCounter,
     pub ops_cache_hit: IntCounter,
     pub ops_cache_miss: IntCounter,
+    pub ops_gang_release: IntCounter,
+    pub leader_transitions: IntCounter,
+    pub config_reloads: IntCounterVec,
+    pub mirror_requests: IntCounterVec,
+    pub swarm_bytes: IntCounterVec,
+    pub proxy_requests: IntCounterVec,
+    pub prewarm_evaluations: IntCounterVec,
+    pub workload_prefetches: IntCounterVec,
 
     // 2. The Stopwatch (Histograms)
     pub latency_warmup: Histogram,
+    #[allow(dead_code)] // exported, nothing records it yet
     pub latency_queue: Histogram,
+    pub latency_download_queue: Histogram,
+    pub latency_throttled: Histogram,
+    pub latency_mirror: HistogramVec,
 
     // 3. The Speedometer (Gauges)
+    #[allow(dead_code)] // exported, nothing records it yet
     pub throughput_nvme: IntGauge,
+    #[allow(dead_code)] // exported, nothing records it yet
     pub gpu_idle_seconds: IntGauge,
+    pub download_queue_depth: IntGauge,
+    pub download_queue_position: IntGaugeVec,
+    pub is_leader: IntGauge,
+    pub config_restart_required: IntGaugeVec,
+    pub mirror_up: IntGaugeVec,
 }
 
 impl MetricsState {
@@ -36,6 +58,11 @@ impl MetricsState {
             registry
         ).unwrap();
 
+        let ops_prewarm_failure = register_int_counter_with_registry!(
+            opts!("gpu_prewarm_failure_total", "Total failed GPU pre-warm operations"),
+            registry
+        ).unwrap();
+
         let ops_cache_hit = register_int_counter_with_registry!(
             opts!("cache_hit_total", "Total times data was found locally"),
             registry
@@ -46,6 +73,52 @@ impl MetricsState {
             registry
         ).unwrap();
 
+        let ops_gang_release = register_int_counter_with_registry!(
+            opts!("gang_release_total", "Total pod gangs released together"),
+            registry
+        ).unwrap();
+
+        let leader_transitions = register_int_counter_with_registry!(
+            opts!("leader_transitions_total", "Times this replica became leader"),
+            registry
+        ).unwrap();
+
+        let config_reloads = register_int_counter_vec_with_registry!(
+            opts!("config_reloads_total", "ConfigMap changes seen, by outcome (applied, rejected)"),
+            &["result"],
+            registry
+        ).unwrap();
+
+        let mirror_requests = register_int_counter_vec_with_registry!(
+            opts!("mirror_requests_total", "Reads from each dataset mirror, by outcome (success, failure, not_found)"),
+            &["mirror", "result"],
+            registry
+        ).unwrap();
+
+        let swarm_bytes = register_int_counter_vec_with_registry!(
+            opts!("swarm_bytes_total", "Bytes of chunked swarm downloads, by where they came from (peer, origin)"),
+            &["source"],
+            registry
+        ).unwrap();
+
+        let proxy_requests = register_int_counter_vec_with_registry!(
+            opts!("proxy_requests_total", "S3 proxy requests, by operation (get, head, list) and outcome (hit, origin, error)"),
+            &["op", "result"],
+            registry
+        ).unwrap();
+
+        let prewarm_evaluations = register_int_counter_vec_with_registry!(
+            opts!("prewarm_evaluations_total", "PrewarmPolicy query evaluations, by policy and outcome (triggered, below, error)"),
+            &["policy", "result"],
+            registry
+        ).unwrap();
+
+        let workload_prefetches = register_int_counter_vec_with_registry!(
+            opts!("workload_prefetch_total", "Prefetches started from workload templates, by kind (Deployment, StatefulSet, Job)"),
+            &["kind"],
+            registry
+        ).unwrap();
+
         // --- 2. Histograms ---
         let bucket_opts = HistogramOpts::new("warmup_latency_seconds", "Time taken to download data")
             .buckets(vec![1.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]);
@@ -61,6 +134,28 @@ impl MetricsState {
             registry
         ).unwrap();
 
+        let download_queue_opts = HistogramOpts::new("download_queue_wait_seconds", "Time a download waits for a worker")
+            .buckets(vec![0.1, 1.0, 5.0, 30.0, 60.0, 300.0, 900.0]);
+        let latency_download_queue = register_histogram_with_registry!(
+            download_queue_opts,
+            registry
+        ).unwrap();
+
+        let throttle_opts = HistogramOpts::new("download_throttled_seconds", "Time a transfer spent held back by bandwidth or request limits")
+            .buckets(vec![0.0, 0.5, 1.0, 5.0, 30.0, 120.0, 600.0]);
+        let latency_throttled = register_histogram_with_registry!(
+            throttle_opts,
+            registry
+        ).unwrap();
+
+        let mirror_opts = HistogramOpts::new("mirror_request_duration_seconds", "Time each dataset mirror took to serve an object")
+            .buckets(vec![0.1, 1.0, 10.0, 60.0, 300.0, 900.0]);
+        let latency_mirror = register_histogram_vec_with_registry!(
+            mirror_opts,
+            &["mirror"],
+            registry
+        ).unwrap();
+
         // --- 3. Gauges ---
         let throughput_nvme = register_int_gauge_with_registry!(
             opts!("nvme_read_throughput_bytes", "Current read speed of NVMe cache"),
@@ -72,16 +167,61 @@ impl MetricsState {
             registry
         ).unwrap();
 
+        let download_queue_depth = register_int_gauge_with_registry!(
+            opts!("download_queue_depth", "Datasets waiting for a download worker"),
+            registry
+        ).unwrap();
+
+        let download_queue_position = register_int_gauge_vec_with_registry!(
+            opts!("download_queue_position", "Queue position of each gated pod"),
+            &["namespace", "pod"],
+            registry
+        ).unwrap();
+
+        let is_leader = register_int_gauge_with_registry!(
+            opts!("leader_is_leader", "1 if this replica currently holds the leader lease"),
+            registry
+        ).unwrap();
+
+        let config_restart_required = register_int_gauge_vec_with_registry!(
+            opts!("config_restart_required", "1 for each changed setting that only takes effect after a restart"),
+            &["setting"],
+            registry
+        ).unwrap();
+
+        let mirror_up = register_int_gauge_vec_with_registry!(
+            opts!("mirror_up", "0 while a dataset mirror is skipped after repeated failures"),
+            &["mirror"],
+            registry
+        ).unwrap();
+
         Self {
             // FIX 2: We wrap the registry in Arc::new() so it can be shared!
             registry: Arc::new(registry), 
             ops_prewarm_success,
+            ops_prewarm_failure,
             ops_cache_hit,
             ops_cache_miss,
+            ops_gang_release,
+            leader_transitions,
+            config_reloads,
+            mirror_requests,
+            swarm_bytes,
+            proxy_requests,
+            prewarm_evaluations,
+            workload_prefetches,
             latency_warmup,
             latency_queue,
+            latency_download_queue,
+            latency_throttled,
+            latency_mirror,
             throughput_nvme,
             gpu_idle_seconds,
+            download_queue_depth,
+            download_queue_position,
+            is_leader,
+            config_restart_required,
+            mirror_up,
         }
     }
 
@@ -91,6 +231,10 @@ impl MetricsState {
         self.ops_prewarm_success.inc();
     }
 
+    pub fn count_prewarm_failure(&self) {
+        self.ops_prewarm_failure.inc();
+    }
+
     pub fn count_hit(&self) {
         self.ops_cache_hit.inc();
     }
@@ -99,7 +243,78 @@ impl MetricsState {
         self.ops_cache_miss.inc();
     }
 
+    pub fn count_gang_release(&self) {
+        self.ops_gang_release.inc();
+    }
+
     pub fn observe_warmup(&self, seconds: f64) {
         self.latency_warmup.observe(seconds);
     }
-}
\ No newline at end of file
+
+    pub fn observe_queue_wait(&self, seconds: f64) {
+        self.latency_download_queue.observe(seconds);
+    }
+
+    pub fn observe_throttled(&self, seconds: f64) {
+        self.latency_throttled.observe(seconds);
+    }
+
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.download_queue_depth.set(depth as i64);
+    }
+
+    pub fn set_queue_position(&self, namespace: &str, pod: &str, position: usize) {
+        self.download_queue_position.with_label_values(&[namespace, pod]).set(position as i64);
+    }
+
+    pub fn clear_queue_position(&self, namespace: &str, pod: &str) {
+        let _ = self.download_queue_position.remove_label_values(&[namespace, pod]);
+    }
+
+    pub fn count_leader_transition(&self) {
+        self.leader_transitions.inc();
+    }
+
+    pub fn set_leader(&self, leading: bool) {
+        self.is_leader.set(i64::from(leading));
+    }
+
+    pub fn count_config_reload(&self, result: &str) {
+        self.config_reloads.with_label_values(&[result]).inc();
+    }
+
+    pub fn count_mirror(&self, mirror: &str, result: &str) {
+        self.mirror_requests.with_label_values(&[mirror, result]).inc();
+    }
+
+    pub fn observe_mirror(&self, mirror: &str, seconds: f64) {
+        self.latency_mirror.with_label_values(&[mirror]).observe(seconds);
+    }
+
+    pub fn count_swarm_bytes(&self, source: &str, bytes: u64) {
+        self.swarm_bytes.with_label_values(&[source]).inc_by(bytes);
+    }
+
+    pub fn count_proxy(&self, op: &str, result: &str) {
+        self.proxy_requests.with_label_values(&[op, result]).inc();
+    }
+
+    pub fn count_prewarm_evaluation(&self, policy: &str, result: &str) {
+        self.prewarm_evaluations.with_label_values(&[policy, result]).inc();
+    }
+
+    pub fn count_workload_prefetch(&self, kind: &str) {
+        self.workload_prefetches.with_label_values(&[kind]).inc();
+    }
+
+    pub fn set_mirror_up(&self, mirror: &str, up: bool) {
+        self.mirror_up.with_label_values(&[mirror]).set(i64::from(up));
+    }
+
+    pub fn set_restart_required(&self, settings: &[String]) {
+        self.config_restart_required.reset();
+        for setting in settings {
+            self.config_restart_required.with_label_values(&[setting.as_str()]).set(1);
+        }
+    }
+}
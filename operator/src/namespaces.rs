@@ -0,0 +1,136 @@
+// --- WATCHED NAMESPACES ---
+// By default gated pods are picked up in every namespace. Two settings narrow that:
+//
+// - watch_namespaces:   allow-list. One watch per namespace, so the
+//                       operator only needs namespaced Roles for pods.
+// - namespace_selector: label selector on Namespaces (e.g. `kube-cache=enabled`).
+//                       Watches cluster-wide and drops pods from other namespaces.
+//
+// Workload prefetch (see `prefetch`) follows the same scope.
+
+use crate::config::Config;
+use futures::stream::{self, BoxStream, StreamExt};
+use k8s_openapi::api::core::v1::{Namespace, Pod};
+use k8s_openapi::NamespaceResourceScope;
+use kube::api::ListParams;
+use kube::runtime::watcher::{self, watcher, Event};
+use kube::runtime::WatchStreamExt;
+use kube::{Api, Client, Resource};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub enum NamespaceScope {
+    All,
+    AllowList(Vec<String>),
+    Selector(String),
+}
+
+impl NamespaceScope {
+    pub fn from_config(config: &Config) -> Self {
+        let allow: Vec<String> = config.watch_namespaces.iter()
+            .map(|ns| ns.trim().to_string())
+            .filter(|ns| !ns.is_empty())
+            .collect();
+        if !allow.is_empty() {
+            return NamespaceScope::AllowList(allow);
+        }
+
+        match &config.namespace_selector {
+            Some(selector) if !selector.is_empty() => NamespaceScope::Selector(selector.clone()),
+            _ => NamespaceScope::All,
+        }
+    }
+}
+
+/// Decides whether an event's namespace is in scope. Only the selector mode
+/// filters; the other modes are already scoped by what they watch.
+#[derive(Clone)]
+pub struct NamespaceFilter {
+    matching: Option<Arc<RwLock<HashSet<String>>>>,
+}
+
+impl NamespaceFilter {
+    pub fn allows(&self, namespace: &str) -> bool {
+        match &self.matching {
+            Some(set) => set.read().unwrap().contains(namespace),
+            None => true,
+        }
+    }
+}
+
+/// Opens the pod watch(es) for the configured scope. Each namespace's watch
+/// re-lists and resumes on its own, with backoff, so one failing namespace
+/// never ends the stream; errors are logged and skipped. `config` narrows
+/// what is watched (e.g. a field selector for one node's pods).
+pub async fn watch_pods(
+    client: &Client,
+    scope: &NamespaceScope,
+    config: watcher::Config,
+) -> Result<(BoxStream<'static, Event<Pod>>, NamespaceFilter), kube::Error> {
+    let filter = namespace_filter(client, scope).await?;
+    let streams = scoped_apis::<Pod>(client, scope).into_iter().map(|pods| {
+        watcher(pods, config.clone())
+            .default_backoff()
+            .filter_map(|event| async move {
+                match event {
+                    Ok(event) => Some(event),
+                    Err(e) => {
+                        warn!(event = "pod_watch_error", error = %e, "Pod watch error, retrying");
+                        None
+                    }
+                }
+            })
+            .boxed()
+    });
+    Ok((stream::select_all(streams).boxed(), filter))
+}
+
+/// One Api per namespace for an allow-list, else a single cluster-wide one,
+/// so other namespaced kinds can be watched the way pods are.
+pub fn scoped_apis<K>(client: &Client, scope: &NamespaceScope) -> Vec<Api<K>>
+where
+    K: Resource<Scope = NamespaceResourceScope, DynamicType = ()>,
+{
+    match scope {
+        NamespaceScope::AllowList(namespaces) => namespaces.iter().map(|ns| Api::namespaced(client.clone(), ns)).collect(),
+        NamespaceScope::All | NamespaceScope::Selector(_) => vec![Api::all(client.clone())],
+    }
+}
+
+/// The filter for events from the scope's watches, kept up to date in the
+/// background for the selector mode.
+pub async fn namespace_filter(client: &Client, scope: &NamespaceScope) -> Result<NamespaceFilter, kube::Error> {
+    let NamespaceScope::Selector(selector) = scope else {
+        return Ok(NamespaceFilter { matching: None });
+    };
+    let matching = Arc::new(RwLock::new(HashSet::new()));
+    refresh(client, selector, &matching).await?;
+    tokio::spawn(keep_refreshed(client.clone(), selector.clone(), matching.clone()));
+    Ok(NamespaceFilter { matching: Some(matching) })
+}
+
+async fn refresh(client: &Client, selector: &str, matching: &RwLock<HashSet<String>>) -> Result<(), kube::Error> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    let list = namespaces.list(&ListParams::default().labels(selector)).await?;
+    let names: HashSet<String> = list.items.into_iter().filter_map(|ns| ns.metadata.name).collect();
+
+    let mut current = matching.write().unwrap();
+    if *current != names {
+        info!(event = "namespaces_changed", selector = %selector, count = names.len(), "Watched namespace set changed");
+        *current = names;
+    }
+    Ok(())
+}
+
+// Namespaces gain and lose labels at runtime; re-list periodically.
+async fn keep_refreshed(client: Client, selector: String, matching: Arc<RwLock<HashSet<String>>>) {
+    let mut tick = tokio::time::interval(Duration::from_secs(30));
+    loop {
+        tick.tick().await;
+        if let Err(e) = refresh(&client, &selector, &matching).await {
+            warn!(event = "namespace_refresh_error", error = ?e, "Failed to refresh namespace selector");
+        }
+    }
+}
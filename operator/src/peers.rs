@@ -0,0 +1,696 @@
+// --- PEER-TO-PEER DISTRIBUTION ---
+// Every kube-cache pod serves its node's cache entries to the others over
+// HTTP on `peer_port`, so a dataset only has to leave the object store once
+// per cluster rather than once per node:
+//
+//   GET/HEAD /peer/v1/entries/<entry>[/<path in a directory entry>]
+//
+// `<entry>` is the cache entry's file name (see `DatasetRef::cache_path`).
+// Single `Range: bytes=a-b` requests are answered with 206. Entries still being
+// downloaded are served from their `.part` path: HEAD reports the final size
+// with `x-kube-cache-partial: true`, and reads follow the file as it grows.
+// Swarm downloads fill their `.part` out of order (it is preallocated, so
+// holes read as zeros); those are only probed here and read through their
+// verified chunks.
+//
+// Objects are also split into `swarm_chunk_bytes` chunks, each hashed with
+// SHA-256 as it is written, so a downloader can pull different chunks from
+// different peers at once (see `storage::swarm`):
+//
+//   GET /peer/v1/chunks/<entry>[/<path>]            which chunks this pod holds
+//                                                   and which it is fetching
+//   GET /peer/v1/chunks/<entry>[/<path>]?index=N    chunk N, with its hash in
+//                                                   `x-kube-cache-sha256`
+//
+// Chunks of complete entries are hashed on first request, in the background
+// (503 until then), unless this pod downloaded them itself.
+//
+// Peers are the other Running `app=kube-cache` pods in `pod_namespace`, found
+// through the Kubernetes API. Only they are served: the cache holds entries
+// fetched with tenants' credentials, so requests from any other address
+// (including pods not yet discovered) get 403. Downloads try peers holding the entry before
+// the dataset's own mirrors (see `storage::fetch`); bytes from peers must
+// match the checksum the source publishes, or the object is fetched again
+// from the source.
+
+use crate::config::Config;
+use crate::storage;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path as UrlPath, Query, Request, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::middleware::{self, Next};
+use axum::{routing::get, Json, Router};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use k8s_openapi::api::core::v1::Pod;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use kube::{api::ListParams, Api, Client};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tracing::{debug, info, warn};
+
+pub const PEER_PATH: &str = "/peer/v1/entries";
+pub const CHUNK_PATH: &str = "/peer/v1/chunks";
+pub const CHUNK_HASH_HEADER: &str = "x-kube-cache-sha256";
+const PARTIAL_HEADER: &str = "x-kube-cache-partial";
+
+// Entry names and paths inside an entry keep their '/' separators
+const URL_PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/').remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a read of an in-progress entry waits for the file to grow
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const CHUNK_SIZE: u64 = 256 * 1024;
+
+/// Another kube-cache pod.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Peer {
+    pub name: String,
+    /// `ip:port` of its peer server
+    pub addr: String,
+}
+
+impl Peer {
+    /// `http://<addr><base>/<entry>[/<path>]`, escaped.
+    pub fn url(&self, base: &str, entry: &str, path: Option<&str>) -> String {
+        let mut url = format!("http://{}{}/{}", self.addr, base, escape(entry));
+        if let Some(path) = path {
+            url.push('/');
+            url.push_str(&escape(path));
+        }
+        url
+    }
+}
+
+/// Percent-escapes an entry name or a path inside an entry for a peer URL.
+pub fn escape(path: &str) -> String {
+    utf8_percent_encode(path, URL_PATH).to_string()
+}
+
+/// Which chunks of one object a peer holds and which it is fetching.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Advert {
+    pub size: u64,
+    pub chunk_size: u64,
+    /// Bitfields, base64, one bit per chunk (MSB first)
+    pub have: String,
+    pub fetching: String,
+}
+
+#[derive(Deserialize)]
+struct ChunkQuery {
+    index: Option<usize>,
+}
+
+// An object being written: final size and the chunks done (with their hash)
+// or in flight so far
+struct Progress {
+    size: u64,
+    /// Written front to back, so readers can follow the file as it grows
+    sequential: bool,
+    hashes: Vec<Option<[u8; 32]>>,
+    fetching: Vec<bool>,
+}
+
+// Chunk hashes of a complete file, for the size it had when hashed
+struct Hashed {
+    size: u64,
+    hashes: Vec<[u8; 32]>,
+}
+
+/// Known peers, plus the objects this pod is writing right now (so they can
+/// be served before they are complete) and chunk hashes of complete ones.
+pub struct Peers {
+    port: u16,
+    chunk_size: u64,
+    own_name: Option<String>,
+    known: RwLock<Vec<Peer>>,
+    /// Objects being written, by their `.part` path
+    writing: Mutex<HashMap<PathBuf, Progress>>,
+    /// Complete files by path; `None` while they are being hashed
+    hashed: Mutex<HashMap<PathBuf, Option<Arc<Hashed>>>>,
+    client: reqwest::Client,
+}
+
+impl Peers {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            port: config.peer_port,
+            chunk_size: config.swarm_chunk_bytes,
+            own_name: config.pod_name.clone(),
+            known: RwLock::new(Vec::new()),
+            writing: Mutex::new(HashMap::new()),
+            hashed: Mutex::new(HashMap::new()),
+            client: reqwest::Client::builder().timeout(PROBE_TIMEOUT).build().unwrap_or_default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.port != 0
+    }
+
+    /// Chunk size for swarm transfers; 0 when they are off.
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub fn chunk_count(&self, size: u64) -> usize {
+        match self.chunk_size {
+            0 => 0,
+            chunk_size => size.div_ceil(chunk_size) as usize,
+        }
+    }
+
+    pub fn own_name(&self) -> Option<&str> {
+        self.own_name.as_deref()
+    }
+
+    /// Number of other kube-cache pods last seen.
+    pub fn known(&self) -> usize {
+        self.known.read().unwrap().len()
+    }
+
+    /// True if `ip` is another kube-cache pod's.
+    pub fn is_peer(&self, ip: IpAddr) -> bool {
+        self.known.read().unwrap().iter().any(|p| p.addr.parse::<SocketAddr>().is_ok_and(|a| a.ip() == ip))
+    }
+
+    /// Announces that `path` is being written front to back and will end up
+    /// `size` bytes. The announcement lasts as long as the returned guard.
+    pub fn writing(&self, path: &Path, size: u64) -> Writing<'_> {
+        self.announce(path, size, true)
+    }
+
+    /// Like `writing`, for a file filled chunk by chunk in any order: peers
+    /// can fetch its finished chunks but not stream it.
+    pub fn writing_chunks(&self, path: &Path, size: u64) -> Writing<'_> {
+        self.announce(path, size, false)
+    }
+
+    fn announce(&self, path: &Path, size: u64, sequential: bool) -> Writing<'_> {
+        let chunks = self.chunk_count(size);
+        let progress = Progress { size, sequential, hashes: vec![None; chunks], fetching: vec![false; chunks] };
+        self.writing.lock().unwrap().insert(path.to_path_buf(), progress);
+        Writing { peers: self, path: path.to_path_buf() }
+    }
+
+    fn expected_size(&self, path: &Path) -> Option<u64> {
+        self.writing.lock().unwrap().get(path).map(|p| p.size)
+    }
+
+    fn is_sequential(&self, path: &Path) -> bool {
+        self.writing.lock().unwrap().get(path).is_some_and(|p| p.sequential)
+    }
+
+    /// Up to `limit` peers that have the entry (complete or in progress), in
+    /// random order so downloads spread over them.
+    pub async fn holders(&self, entry: &str, limit: usize) -> Vec<Peer> {
+        if !self.enabled() {
+            return Vec::new();
+        }
+        let mut known = self.known.read().unwrap().clone();
+        let random = RandomState::new();
+        known.sort_by_key(|peer| random.hash_one(&peer.name));
+
+        let probes = known.into_iter().map(|peer| async move {
+            let url = peer.url(PEER_PATH, entry, None);
+            match self.client.head(&url).send().await {
+                Ok(resp) if resp.status().is_success() => Some(peer),
+                Ok(_) => None,
+                Err(e) => {
+                    debug!(event = "peer_probe_error", peer = %peer.name, error = %e, "Peer did not answer");
+                    None
+                }
+            }
+        });
+        futures::future::join_all(probes).await
+            .into_iter()
+            .flatten()
+            .take(limit)
+            .collect()
+    }
+
+    // Where a complete file's chunk hashes stand: known, being computed, or
+    // just scheduled for computing (the latter two both `None`).
+    fn complete_hashes(self: &Arc<Self>, path: &Path, size: u64) -> Option<Arc<Hashed>> {
+        let mut hashed = self.hashed.lock().unwrap();
+        match hashed.get(path) {
+            Some(Some(known)) if known.size == size => return Some(known.clone()),
+            Some(None) => return None,
+            _ => {}
+        }
+        hashed.insert(path.to_path_buf(), None);
+
+        let (peers, path) = (self.clone(), path.to_path_buf());
+        tokio::task::spawn_blocking(move || {
+            let result = hash_file(&path, peers.chunk_size);
+            let mut hashed = peers.hashed.lock().unwrap();
+            match result {
+                Ok(hashes) => { hashed.insert(path, Some(Arc::new(Hashed { size, hashes }))); }
+                Err(e) => {
+                    warn!(event = "peer_hash_error", path = %path.display(), error = %e, "Cannot hash cache entry");
+                    hashed.remove(&path);
+                }
+            }
+        });
+        None
+    }
+}
+
+/// Drops the in-progress announcement of one object.
+pub struct Writing<'a> {
+    peers: &'a Peers,
+    path: PathBuf,
+}
+
+// Marks chunks of an object being written, for the adverts
+impl Writing<'_> {
+    pub fn fetching(&self, index: usize, fetching: bool) {
+        if let Some(progress) = self.peers.writing.lock().unwrap().get_mut(&self.path) {
+            if let Some(flag) = progress.fetching.get_mut(index) {
+                *flag = fetching;
+            }
+        }
+    }
+
+    pub fn done(&self, index: usize, hash: [u8; 32]) {
+        if let Some(progress) = self.peers.writing.lock().unwrap().get_mut(&self.path) {
+            if let Some(slot) = progress.hashes.get_mut(index) {
+                *slot = Some(hash);
+                progress.fetching[index] = false;
+            }
+        }
+    }
+}
+
+// Once written in full, the hashes stay known under the path the object will
+// have after the entry is renamed into place.
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        let Some(progress) = self.peers.writing.lock().unwrap().remove(&self.path) else { return };
+        let hashes: Option<Vec<[u8; 32]>> = progress.hashes.into_iter().collect();
+        if let (Some(hashes), Some(path)) = (hashes.filter(|h| !h.is_empty()), complete_path(&self.path)) {
+            let hashed = Hashed { size: progress.size, hashes };
+            self.peers.hashed.lock().unwrap().insert(path, Some(Arc::new(hashed)));
+        }
+    }
+}
+
+// `<root>/<entry>.part[/<path>]` -> `<root>/<entry>[/<path>]`
+fn complete_path(part: &Path) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    let mut renamed = false;
+    for component in part.components() {
+        match component.as_os_str().to_str().and_then(|c| c.strip_suffix(".part")) {
+            Some(entry) if !renamed => {
+                path.push(entry);
+                renamed = true;
+            }
+            _ => path.push(component),
+        }
+    }
+    renamed.then_some(path)
+}
+
+fn hash_file(path: &Path, chunk_size: u64) -> std::io::Result<Vec<[u8; 32]>> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hashes = Vec::new();
+    let mut buf = vec![0u8; chunk_size as usize];
+    loop {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read(&mut buf[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            return Ok(hashes);
+        }
+        hashes.push(Sha256::digest(&buf[..filled]).into());
+        if filled < buf.len() {
+            return Ok(hashes);
+        }
+    }
+}
+
+/// One bit per flag, MSB first, base64.
+pub fn encode_bits(flags: impl Iterator<Item = bool>) -> String {
+    let mut bytes = Vec::new();
+    for (i, flag) in flags.enumerate() {
+        if i % 8 == 0 {
+            bytes.push(0u8);
+        }
+        if flag {
+            *bytes.last_mut().unwrap() |= 0x80 >> (i % 8);
+        }
+    }
+    BASE64.encode(bytes)
+}
+
+pub fn decode_bits(bits: &str, count: usize) -> Vec<bool> {
+    let bytes = BASE64.decode(bits).unwrap_or_default();
+    (0..count).map(|i| bytes.get(i / 8).map(|b| b & (0x80 >> (i % 8)) != 0).unwrap_or(false)).collect()
+}
+
+/// Periodically lists the other kube-cache pods.
+pub async fn discover(client: Client, namespace: String, peers: Arc<Peers>) {
+    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+    let lp = ListParams::default().labels("app=kube-cache");
+    let mut tick = tokio::time::interval(DISCOVERY_INTERVAL);
+
+    loop {
+        tick.tick().await;
+        let list = match pods.list(&lp).await {
+            Ok(list) => list,
+            Err(e) => {
+                warn!(event = "peer_discovery_error", error = ?e, "Failed to list kube-cache peers");
+                continue;
+            }
+        };
+        let mut found: Vec<Peer> = list.items.iter()
+            .filter(|p| p.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Running"))
+            .filter(|p| p.metadata.name.is_some() && p.metadata.name != peers.own_name)
+            .filter_map(|p| {
+                let ip = p.status.as_ref()?.pod_ip.as_ref()?;
+                Some(Peer {
+                    name: p.metadata.name.clone()?,
+                    addr: SocketAddr::new(ip.parse().ok()?, peers.port).to_string(),
+                })
+            })
+            .collect();
+        found.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut known = peers.known.write().unwrap();
+        if *known != found {
+            info!(event = "peers_changed", peers = found.len(), "Updated kube-cache peer list");
+            *known = found;
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ServerState {
+    peers: Arc<Peers>,
+    cache_root: PathBuf,
+}
+
+/// Serves this node's cache entries to peers, plus any `extra` routes.
+pub async fn serve(peers: Arc<Peers>, cache_root: String, extra: Router) {
+    let port = peers.port;
+    let state = ServerState { peers, cache_root: cache_root.into() };
+    let app = Router::new()
+        .route(&format!("{}/:entry", PEER_PATH), get(entry_handler))
+        .route(&format!("{}/:entry/*path", PEER_PATH), get(file_handler))
+        .route(&format!("{}/:entry", CHUNK_PATH), get(entry_chunks_handler))
+        .route(&format!("{}/:entry/*path", CHUNK_PATH), get(file_chunks_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), peers_only))
+        .with_state(state)
+        .merge(extra);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!(event = "server_start", port, "Peer Server listening");
+
+    match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => {
+            if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+                warn!(event = "peer_server_error", error = %e, "Peer server stopped");
+            }
+        }
+        Err(e) => warn!(event = "peer_server_error", port, error = %e, "Cannot listen for peers"),
+    }
+}
+
+async fn peers_only(
+    State(state): State<ServerState>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !state.peers.is_peer(remote.ip()) {
+        debug!(event = "peer_refused", remote = %remote, path = %request.uri().path(), "Refused a request from outside the peer set");
+        return (StatusCode::FORBIDDEN, "not a kube-cache peer").into_response();
+    }
+    next.run(request).await
+}
+
+async fn entry_handler(
+    State(state): State<ServerState>,
+    method: Method,
+    UrlPath(entry): UrlPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    respond(&state, &method, &entry, None, &headers).await
+}
+
+async fn file_handler(
+    State(state): State<ServerState>,
+    method: Method,
+    UrlPath((entry, path)): UrlPath<(String, String)>,
+    headers: HeaderMap,
+) -> Response {
+    respond(&state, &method, &entry, Some(&path), &headers).await
+}
+
+async fn entry_chunks_handler(
+    State(state): State<ServerState>,
+    UrlPath(entry): UrlPath<String>,
+    Query(query): Query<ChunkQuery>,
+) -> Response {
+    chunks(&state, &entry, None, query.index).await
+}
+
+async fn file_chunks_handler(
+    State(state): State<ServerState>,
+    UrlPath((entry, path)): UrlPath<(String, String)>,
+    Query(query): Query<ChunkQuery>,
+) -> Response {
+    chunks(&state, &entry, Some(&path), query.index).await
+}
+
+// The advert for one object, or one chunk of it with its hash
+async fn chunks(state: &ServerState, entry: &str, path: Option<&str>, index: Option<usize>) -> Response {
+    let chunk_size = state.peers.chunk_size;
+    let Some((file_path, partial)) = locate(state, entry, path).filter(|(p, _)| p.is_file() && chunk_size > 0) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let (size, hashes, fetching) = if partial {
+        let writing = state.peers.writing.lock().unwrap();
+        match writing.get(&file_path) {
+            Some(progress) => (progress.size, progress.hashes.clone(), progress.fetching.clone()),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        }
+    } else {
+        let size = match tokio::fs::metadata(&file_path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => return StatusCode::NOT_FOUND.into_response(),
+        };
+        match state.peers.complete_hashes(&file_path, size) {
+            Some(hashed) => {
+                let hashes: Vec<Option<[u8; 32]>> = hashed.hashes.iter().copied().map(Some).collect();
+                let count = hashes.len();
+                (size, hashes, vec![false; count])
+            }
+            None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+        }
+    };
+
+    let Some(index) = index else {
+        return Json(Advert {
+            size,
+            chunk_size,
+            have: encode_bits(hashes.iter().map(Option::is_some)),
+            fetching: encode_bits(fetching.into_iter()),
+        }).into_response();
+    };
+
+    let Some(Some(hash)) = hashes.get(index) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let start = index as u64 * chunk_size;
+    let len = chunk_size.min(size - start) as usize;
+    let read = async {
+        let mut file = tokio::fs::File::open(&file_path).await?;
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let mut buf = vec![0u8; len];
+        file.read_exact(&mut buf).await?;
+        Ok::<_, std::io::Error>(buf)
+    };
+    match read.await {
+        Ok(buf) => {
+            let mut resp = Bytes::from(buf).into_response();
+            if let Ok(value) = HeaderValue::from_str(&hex::encode(hash)) {
+                resp.headers_mut().insert(CHUNK_HASH_HEADER, value);
+            }
+            resp
+        }
+        Err(e) => {
+            warn!(event = "peer_serve_error", path = %file_path.display(), error = %e, "Cannot read chunk");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+// Where a request points: the complete entry, else its `.part` while this pod
+// writes it. Anything that could leave the cache root is refused.
+fn locate(state: &ServerState, entry: &str, path: Option<&str>) -> Option<(PathBuf, bool)> {
+    let plain = |p: &str| !p.is_empty() && Path::new(p).components().all(|c| matches!(c, Component::Normal(_)));
+    if !plain(entry) || entry.contains('/') || entry.ends_with(".part") || !path.map(plain).unwrap_or(true) {
+        return None;
+    }
+
+    let complete = state.cache_root.join(entry);
+    if storage::is_complete(&complete) {
+        let complete = path.map(|p| complete.join(p)).unwrap_or(complete);
+        return complete.exists().then_some((complete, false));
+    }
+    let part = state.cache_root.join(format!("{}.part", entry));
+    let part = path.map(|p| part.join(p)).unwrap_or(part);
+    part.exists().then_some((part, true))
+}
+
+async fn respond(state: &ServerState, method: &Method, entry: &str, path: Option<&str>, headers: &HeaderMap) -> Response {
+    let Some((file_path, partial)) = locate(state, entry, path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Directory entries answer probes only
+    if file_path.is_dir() {
+        return match path {
+            None => with_partial(StatusCode::OK.into_response(), partial),
+            Some(_) => StatusCode::NOT_FOUND.into_response(),
+        };
+    }
+
+    let size = if partial {
+        // An orphaned `.part` (e.g. from a crash) is not served
+        match state.peers.expected_size(&file_path) {
+            // Swarm downloads are probed only; their holes would read as zeros
+            Some(_) if method != Method::HEAD && !state.peers.is_sequential(&file_path) => {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            Some(size) => size,
+            None => return StatusCode::NOT_FOUND.into_response(),
+        }
+    } else {
+        match tokio::fs::metadata(&file_path).await {
+            Ok(meta) => meta.len(),
+            Err(_) => return StatusCode::NOT_FOUND.into_response(),
+        }
+    };
+
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).and_then(|v| parse_range(v, size));
+    let (status, start, end) = match range {
+        Some(Ok((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Some(Err(())) => {
+            let mut resp = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+            if let Ok(value) = HeaderValue::from_str(&format!("bytes */{}", size)) {
+                resp.headers_mut().insert(header::CONTENT_RANGE, value);
+            }
+            return resp;
+        }
+        None => (StatusCode::OK, 0, size),
+    };
+
+    let mut file = match tokio::fs::File::open(&file_path).await {
+        Ok(file) => file,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    if let Err(e) = file.seek(std::io::SeekFrom::Start(start)).await {
+        warn!(event = "peer_serve_error", path = %file_path.display(), error = %e, "Cannot seek cache entry");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    let follow = partial.then(|| (state.peers.clone(), file_path.clone()));
+    let body = Body::from_stream(read_stream(file, end - start, follow));
+    let mut resp = (status, body).into_response();
+    let headers = resp.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end - 1, size)) {
+            headers.insert(header::CONTENT_RANGE, value);
+        }
+    }
+    with_partial(resp, partial)
+}
+
+fn with_partial(mut resp: Response, partial: bool) -> Response {
+    if partial {
+        resp.headers_mut().insert(PARTIAL_HEADER, HeaderValue::from_static("true"));
+    }
+    resp
+}
+
+// A single `bytes=a-b` / `bytes=a-` / `bytes=-n` range as `start..end`. `None`
+// means the header is ignored (whole body), `Err` that it cannot be satisfied.
+pub fn parse_range(value: &str, size: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.split_once('-')?;
+    let (start, end) = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let n: u64 = suffix.parse().ok()?;
+            (size.saturating_sub(n), size)
+        }
+        (first, "") => (first.parse().ok()?, size),
+        (first, last) => (first.parse().ok()?, last.parse::<u64>().ok()?.saturating_add(1).min(size)),
+    };
+    Some(if start < end { Ok((start, end)) } else { Err(()) })
+}
+
+// Streams `len` bytes from the file's position. For an in-progress entry it
+// waits for the writer at the end of the file, giving up once the writer is
+// gone or has not written anything for `STALL_TIMEOUT`.
+pub fn read_stream(
+    file: tokio::fs::File,
+    len: u64,
+    follow: Option<(Arc<Peers>, PathBuf)>,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> {
+    futures::stream::unfold((file, len), move |(mut file, remaining)| {
+        let follow = follow.clone();
+        async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0u8; CHUNK_SIZE.min(remaining) as usize];
+            let mut idle = Duration::ZERO;
+            loop {
+                match file.read(&mut buf).await {
+                    Ok(0) => {
+                        let writing = follow.as_ref().map(|(peers, path)| peers.expected_size(path).is_some()).unwrap_or(false);
+                        if !writing || idle >= STALL_TIMEOUT {
+                            let e = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "cache entry stopped growing");
+                            return Some((Err(e), (file, 0)));
+                        }
+                        tokio::time::sleep(POLL_INTERVAL).await;
+                        idle += POLL_INTERVAL;
+                    }
+                    Ok(n) => {
+                        buf.truncate(n);
+                        return Some((Ok(Bytes::from(buf)), (file, remaining - n as u64)));
+                    }
+                    Err(e) => return Some((Err(e), (file, 0))),
+                }
+            }
+        }
+    })
+}
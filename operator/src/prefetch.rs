@@ -0,0 +1,253 @@
+// --- WORKLOAD PREFETCH ---
+// By the time a gated pod exists its download is already on the critical
+// path. With `workload_prefetch` the leader also watches Deployments,
+// StatefulSets and Jobs whose pod template wants a dataset (the dataset
+// annotation or a `kube-cache.openai.com/dataset` reference) and warms nodes
+// as soon as the controller is asked for more pods:
+//
+// - Deployment / StatefulSet: `replicas` rises, or a new one is created
+// - Job: it is created or unsuspended, for `parallelism` pods (capped by
+//   `completions`), or its `parallelism` rises
+//
+// Each new replica gets one idle node matching the template's `nodeSelector`,
+// warmed the way a PrewarmPolicy warms them (see `prewarm`). The template is
+// rendered as if it were the replica's pod, so for an Indexed Job replica i
+// gets completion index i and each node fetches one shard. Workloads that
+// already exist when the watch (re)starts are only recorded: their pods are
+// most likely there already.
+
+use crate::dataset::{self, COMPLETION_INDEX};
+use crate::metrics::MetricsState;
+use crate::namespaces::{self, NamespaceScope};
+use crate::prewarm::{self, PrewarmSettings, Warmer};
+use futures::stream::{self, BoxStream, StreamExt};
+use k8s_openapi::api::apps::v1::{Deployment, StatefulSet};
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{Pod, PodTemplateSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::NamespaceResourceScope;
+use kube::runtime::watcher::{self, watcher, Event};
+use kube::runtime::WatchStreamExt;
+use kube::{Api, Client, Resource, ResourceExt};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A Deployment, StatefulSet or Job, reduced to what prefetching needs
+struct Workload {
+    kind: &'static str,
+    namespace: String,
+    name: String,
+    uid: String,
+    /// Pods the controller is asked to run
+    wanted: i32,
+    /// Indexed Job: replica i is completion index i
+    indexed: bool,
+    template: PodTemplateSpec,
+}
+
+impl Workload {
+    fn new(kind: &'static str, metadata: ObjectMeta, wanted: i32, indexed: bool, template: PodTemplateSpec) -> Self {
+        Self {
+            kind,
+            namespace: metadata.namespace.unwrap_or_default(),
+            name: metadata.name.unwrap_or_default(),
+            uid: metadata.uid.unwrap_or_default(),
+            wanted,
+            indexed,
+            template,
+        }
+    }
+
+    fn deployment(deployment: Deployment) -> Option<Self> {
+        let spec = deployment.spec?;
+        Some(Self::new("Deployment", deployment.metadata, spec.replicas.unwrap_or(1), false, spec.template))
+    }
+
+    fn stateful_set(set: StatefulSet) -> Option<Self> {
+        let spec = set.spec?;
+        Some(Self::new("StatefulSet", set.metadata, spec.replicas.unwrap_or(1), false, spec.template))
+    }
+
+    fn job(job: Job) -> Option<Self> {
+        let spec = job.spec?;
+        let parallelism = spec.parallelism.unwrap_or(1);
+        let wanted = if spec.suspend.unwrap_or(false) { 0 } else { spec.completions.map_or(parallelism, |c| c.min(parallelism)) };
+        let indexed = spec.completion_mode.as_deref() == Some("Indexed");
+        Some(Self::new("Job", job.metadata, wanted, indexed, spec.template))
+    }
+
+    // What the replica's pod will look like, as far as dataset resolution goes
+    fn pod(&self, replica: i32) -> Pod {
+        let mut metadata = self.template.metadata.clone().unwrap_or_default();
+        metadata.namespace = Some(self.namespace.clone());
+        if self.indexed {
+            metadata.annotations.get_or_insert_with(Default::default).insert(COMPLETION_INDEX.to_string(), replica.to_string());
+        }
+        Pod { metadata, spec: self.template.spec.clone(), status: None }
+    }
+}
+
+enum Change {
+    /// Seen while the watch (re)lists
+    Listed(Workload),
+    Applied(Workload),
+    /// By uid
+    Deleted(String),
+}
+
+// Workload changes from every scoped Api of one kind.
+fn changes<K>(client: &Client, scope: &NamespaceScope, convert: fn(K) -> Option<Workload>) -> BoxStream<'static, Change>
+where
+    K: Resource<Scope = NamespaceResourceScope, DynamicType = ()> + Clone + serde::de::DeserializeOwned + std::fmt::Debug + Send + 'static,
+{
+    let streams = namespaces::scoped_apis::<K>(client, scope).into_iter().map(move |api: Api<K>| {
+        watcher(api, watcher::Config::default())
+            .default_backoff()
+            .filter_map(move |event| async move {
+                match event {
+                    Ok(Event::InitApply(object)) => convert(object).map(Change::Listed),
+                    Ok(Event::Apply(object)) => convert(object).map(Change::Applied),
+                    Ok(Event::Delete(object)) => object.uid().map(Change::Deleted),
+                    Ok(_) => None,
+                    Err(e) => {
+                        warn!(event = "prefetch_watch_error", error = %e, "Workload watch error");
+                        None
+                    }
+                }
+            })
+            .boxed()
+    });
+    stream::select_all(streams).boxed()
+}
+
+/// Watches workload controllers and warms nodes for the replicas they are
+/// about to create. Runs on the leader.
+pub async fn run(client: Client, scope: NamespaceScope, settings: PrewarmSettings, warmer: Warmer, metrics: MetricsState) {
+    let filter = match namespaces::namespace_filter(&client, &scope).await {
+        Ok(filter) => filter,
+        Err(e) => {
+            warn!(event = "prefetch_start_error", error = ?e, "Cannot resolve watched namespaces, workload prefetch is off");
+            return;
+        }
+    };
+    let mut changes = stream::select_all([
+        changes(&client, &scope, Workload::deployment),
+        changes(&client, &scope, Workload::stateful_set),
+        changes(&client, &scope, Workload::job),
+    ]);
+    let settings = Arc::new(settings);
+    let http = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default();
+    // Pods each workload wanted when last seen, by uid
+    let mut seen: HashMap<String, i32> = HashMap::new();
+    info!(event = "prefetch_start", "Watching workloads for dataset prefetch");
+
+    while let Some(change) = changes.next().await {
+        let (workload, listed) = match change {
+            Change::Listed(workload) => (workload, true),
+            Change::Applied(workload) => (workload, false),
+            Change::Deleted(uid) => {
+                seen.remove(&uid);
+                continue;
+            }
+        };
+        if !filter.allows(&workload.namespace) || !dataset::wants_dataset(&workload.pod(0), &settings.dataset_annotation) {
+            continue;
+        }
+
+        let added = added_replicas(&mut seen, &workload, listed);
+        if added <= 0 {
+            continue;
+        }
+        metrics.count_workload_prefetch(workload.kind);
+        let (client, settings, warmer, http) = (client.clone(), settings.clone(), warmer.clone(), http.clone());
+        tokio::spawn(async move { prefetch(&client, &http, &settings, &warmer, &workload, added).await });
+    }
+}
+
+// Pods the workload wants beyond what it wanted when last seen, recording
+// the new count. Workloads found by a (re)list count as already running.
+fn added_replicas(seen: &mut HashMap<String, i32>, workload: &Workload, listed: bool) -> i32 {
+    match seen.insert(workload.uid.clone(), workload.wanted) {
+        Some(before) => workload.wanted - before,
+        None if listed => 0,
+        None => workload.wanted,
+    }
+}
+
+// Warms one idle node per added replica with the dataset that replica will want.
+async fn prefetch(client: &Client, http: &reqwest::Client, settings: &PrewarmSettings, warmer: &Warmer, workload: &Workload, added: i32) {
+    let id = format!("{}/{}", workload.namespace, workload.name);
+    let selector = workload.template.spec.as_ref().and_then(|s| s.node_selector.clone()).unwrap_or_default();
+    let targets = match prewarm::targets(client, settings, &selector, true).await {
+        Ok(targets) => targets,
+        Err(e) => {
+            warn!(event = "prefetch_nodes_error", kind = workload.kind, workload = %id, error = ?e, "Failed to find idle nodes");
+            return;
+        }
+    };
+
+    let mut nodes = Vec::new();
+    let mut failed = 0;
+    for (target, replica) in targets.iter().zip(workload.wanted - added..workload.wanted) {
+        let dataset = match dataset::lookup(client, &workload.pod(replica), &settings.dataset_annotation).await {
+            Some(Ok(dataset)) => dataset,
+            Some(Err(e)) => {
+                warn!(event = "prefetch_dataset_invalid", kind = workload.kind, workload = %id, replica, error = %e, "Cannot resolve dataset from pod template");
+                break;
+            }
+            None => break,
+        };
+        if let Err(e) = warmer.authorize(client, &workload.namespace, &dataset).await {
+            warn!(event = "prefetch_source_denied", kind = workload.kind, workload = %id, dataset = %dataset.uri, error = %e, "Pod template's dataset not allowed");
+            break;
+        }
+        failed += prewarm::warm_target(http, warmer, target, &[dataset]).await.failed;
+        nodes.push(target.node.as_str());
+    }
+    info!(event = "workload_prefetch", kind = workload.kind, workload = %id, replicas = added, nodes = ?nodes, failed, "Warming nodes ahead of new replicas");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn job(mut spec: serde_json::Value) -> Workload {
+        spec["template"] = json!({"metadata": {"annotations": {"x-openai/required-dataset": "s3://b/shard.bin"}}});
+        let job: Job = serde_json::from_value(json!({"metadata": {"namespace": "ml", "name": "train", "uid": "u1"}, "spec": spec})).unwrap();
+        Workload::job(job).unwrap()
+    }
+
+    #[test]
+    fn only_replicas_beyond_the_last_count_are_added() {
+        let mut seen = HashMap::new();
+        let mut workload = job(json!({"parallelism": 2}));
+        assert_eq!(added_replicas(&mut seen, &workload, true), 0);
+        workload.wanted = 5;
+        assert_eq!(added_replicas(&mut seen, &workload, false), 3);
+        assert_eq!(added_replicas(&mut seen, &workload, false), 0);
+        workload.wanted = 1;
+        assert_eq!(added_replicas(&mut seen, &workload, false), -4);
+
+        // Created while watching: every replica is new
+        let mut created = job(json!({"parallelism": 4}));
+        created.uid = "u2".to_string();
+        assert_eq!(added_replicas(&mut seen, &created, false), 4);
+    }
+
+    #[test]
+    fn jobs_want_their_running_pods_by_completion_index() {
+        assert_eq!(job(json!({"parallelism": 8, "completions": 3})).wanted, 3);
+        assert_eq!(job(json!({"parallelism": 8, "suspend": true})).wanted, 0);
+        assert_eq!(job(json!({})).wanted, 1);
+
+        let indexed = job(json!({"parallelism": 4, "completionMode": "Indexed"}));
+        let annotations = indexed.pod(2).metadata.annotations.unwrap();
+        assert_eq!(annotations.get(COMPLETION_INDEX).map(String::as_str), Some("2"));
+        assert!(!job(json!({"parallelism": 4})).pod(2).metadata.annotations.unwrap().contains_key(COMPLETION_INDEX));
+    }
+}
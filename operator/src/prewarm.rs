@@ -0,0 +1,631 @@
+// --- PRE-WARMING ---
+// A PrewarmPolicy names datasets to warm ahead of demand and the nodes to
+// warm them on (`nodeSelector`, e.g. a node pool label). The leader checks
+// every policy each `prewarm_interval_seconds`:
+//
+// - Predictive: `query` is evaluated as an instant query against the
+//   Prometheus HTTP API (`prometheus_url`). While any sample of the result is
+//   above `threshold`, idle matching nodes are warmed.
+// - Scheduled: at each `schedule` time (5-field cron, UTC) every matching
+//   node is warmed, busy or not. With `evictAfterSeconds`, what the run
+//   queued is dropped from those nodes again that long after it, except where
+//   a pod on the node (or pinned to it and pending) uses it. Entries that were
+//   already cached or queued belong to someone else and are left alone. Pending evictions live in the leader's memory
+//   and are lost if leadership changes.
+//
+// Warming asks the kube-cache pod on each node to fetch the datasets, on that
+// pod's peer server (`peer_port`):
+//
+//   POST   /peer/v1/warm   {"uri": ..., "credentials": "ns/name", "mirrors": [...]}
+//   DELETE /peer/v1/warm   same body, to evict
+//
+// The pod queues the download behind every waiting pod (see `scheduler`)
+// unless the entry is cached or already queued, so repeating a request while
+// the query stays high is harmless. Pods landing there later get cache hits.
+// Only requests from known peers are accepted; the leader handles its own
+// node directly. Each pre-warm download counts towards
+// `gpu_prewarm_success_total` or `gpu_prewarm_failure_total` on the node that
+// ran it; a request that cannot be delivered counts as a failure on the leader.
+//
+// A node is idle when it is Ready, schedulable and runs no pod requesting
+// GPUs. Only nodes running a kube-cache pod can be warmed. `maxNodes` caps
+// how many are warmed per run, always picking the first ones by name.
+
+use crate::config::Config;
+use crate::crd::PrewarmPolicy;
+use crate::dataset::{self, DatasetError, DatasetRef, SecretRef};
+use crate::metrics::MetricsState;
+use crate::peers::Peers;
+use crate::scheduler::DownloadQueue;
+use crate::storage::{self, Backends};
+use crate::webhook;
+use axum::extract::{ConnectInfo, State};
+use axum::http::StatusCode;
+use axum::routing::post;
+use chrono::{DateTime, Utc};
+use croner::Cron;
+use axum::{Json, Router};
+use k8s_openapi::api::core::v1::{Node, Pod};
+use kube::api::ListParams;
+use kube::{Api, Client, ResourceExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub const WARM_PATH: &str = "/peer/v1/warm";
+const GPU_RESOURCE: &str = "nvidia.com/gpu";
+/// Node label pods are pinned to a node by.
+pub const HOSTNAME_LABEL: &str = "kubernetes.io/hostname";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct PrewarmSettings {
+    /// Predictive policies are skipped without one
+    pub prometheus_url: Option<String>,
+    pub interval: Duration,
+    /// Where the kube-cache pods run
+    pub namespace: String,
+    pub peer_port: u16,
+    pub dataset_annotation: String,
+}
+
+impl PrewarmSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            prometheus_url: config.prometheus_url.clone(),
+            interval: Duration::from_secs(config.prewarm_interval_seconds),
+            namespace: config.pod_namespace.clone(),
+            peer_port: config.peer_port,
+            dataset_annotation: config.dataset_annotation.clone(),
+        }
+    }
+}
+
+/// A dataset to warm, as sent between kube-cache pods.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WarmRequest {
+    pub uri: String,
+    /// `namespace/name` of the Secret to read the source with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+}
+
+impl WarmRequest {
+    pub fn new(dataset: &DatasetRef) -> Self {
+        Self {
+            uri: dataset.uri.clone(),
+            credentials: dataset.credentials.as_ref().map(SecretRef::to_string),
+            mirrors: dataset.mirrors.iter().map(|m| m.uri.clone()).collect(),
+        }
+    }
+
+    fn dataset(&self) -> Result<DatasetRef, DatasetError> {
+        let credentials = self.credentials.as_deref()
+            .and_then(|c| c.split_once('/'))
+            .map(|(namespace, name)| SecretRef { namespace: namespace.to_string(), name: name.to_string() });
+        let mut dataset = DatasetRef::parse(&self.uri)?;
+        dataset.credentials = credentials.clone();
+        for uri in &self.mirrors {
+            let mut mirror = DatasetRef::parse(uri)?;
+            mirror.credentials = credentials.clone();
+            dataset.mirrors.push(mirror);
+        }
+        Ok(dataset)
+    }
+}
+
+/// Queues pre-warm downloads on this node and evicts them, for the leader
+/// directly and for other kube-cache pods through the peer server.
+#[derive(Clone)]
+pub struct Warmer {
+    queue: Arc<DownloadQueue>,
+    cache_root: String,
+    peers: Arc<Peers>,
+    backends: Arc<Backends>,
+    metrics: MetricsState,
+}
+
+impl Warmer {
+    pub fn new(
+        queue: Arc<DownloadQueue>,
+        cache_root: String,
+        peers: Arc<Peers>,
+        backends: Arc<Backends>,
+        metrics: MetricsState,
+    ) -> Self {
+        Self { queue, cache_root, peers, backends, metrics }
+    }
+
+    /// Checks pods in `namespace` may have the dataset, as the watch loop
+    /// does before a pod's download: the namespace's source policy, then
+    /// the dataset's credentials (see `TenantClients::check_access`).
+    pub async fn authorize(&self, client: &Client, namespace: &str, dataset: &DatasetRef) -> Result<(), String> {
+        webhook::check_source(client, namespace, dataset).await.map_err(|e| e.to_string())?;
+        self.backends.tenants.check_access(dataset).await.map_err(|e| e.to_string())
+    }
+
+    /// Queues a dataset unless it is cached or queued already. Returns
+    /// which of the three it was.
+    pub fn warm(&self, dataset: DatasetRef) -> &'static str {
+        let path = dataset.cache_path(&self.cache_root);
+        if storage::is_complete(&path) {
+            return "cached";
+        }
+        let uri = dataset.uri.clone();
+        if !self.queue.enqueue_prewarm(dataset, path) {
+            return "already queued";
+        }
+        info!(event = "prewarm_queued", dataset = %uri, depth = self.queue.depth(), "Queued pre-warm download");
+        "queued"
+    }
+
+    /// Drops a dataset from the cache unless it is queued or downloading.
+    /// Returns "evicted", "absent", "busy" or "failed".
+    pub fn evict(&self, dataset: &DatasetRef) -> &'static str {
+        let path = dataset.cache_path(&self.cache_root);
+        if self.queue.contains(&path) {
+            return "busy";
+        }
+        // Unmarked and renamed first so the entry disappears at once
+        let doomed = format!("{}.evicting", path);
+        match storage::unmark(&path).and_then(|()| std::fs::rename(&path, &doomed)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return "absent",
+            Err(e) => {
+                warn!(event = "prewarm_evict_error", dataset = %dataset.uri, error = %e, "Cannot evict pre-warmed dataset");
+                return "failed";
+            }
+        }
+        tokio::task::spawn_blocking(move || {
+            let doomed = Path::new(&doomed);
+            let _ = if doomed.is_dir() { std::fs::remove_dir_all(doomed) } else { std::fs::remove_file(doomed) };
+        });
+        info!(event = "prewarm_evicted", dataset = %dataset.uri, "Evicted pre-warmed dataset");
+        "evicted"
+    }
+
+    /// Peer server routes accepting warm and evict requests from other
+    /// kube-cache pods.
+    pub fn routes(self) -> Router {
+        Router::new().route(WARM_PATH, post(warm_handler).delete(evict_handler)).with_state(self)
+    }
+}
+
+async fn warm_handler(
+    State(warmer): State<Warmer>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    Json(request): Json<WarmRequest>,
+) -> (StatusCode, String) {
+    if !warmer.peers.is_peer(remote.ip()) {
+        return (StatusCode::FORBIDDEN, "not a kube-cache peer".to_string());
+    }
+    match request.dataset() {
+        Ok(dataset) => (StatusCode::ACCEPTED, warmer.warm(dataset).to_string()),
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()),
+    }
+}
+
+async fn evict_handler(
+    State(warmer): State<Warmer>,
+    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    Json(request): Json<WarmRequest>,
+) -> (StatusCode, String) {
+    if !warmer.peers.is_peer(remote.ip()) {
+        return (StatusCode::FORBIDDEN, "not a kube-cache peer".to_string());
+    }
+    let dataset = match request.dataset() {
+        Ok(dataset) => dataset,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let result = warmer.evict(&dataset);
+    let status = match result {
+        "busy" => StatusCode::CONFLICT,
+        "failed" => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::OK,
+    };
+    (status, result.to_string())
+}
+
+// What a scheduled run queued on each node, to drop once `due`
+struct Eviction {
+    due: tokio::time::Instant,
+    policy: String,
+    targets: Vec<(Target, Vec<DatasetRef>)>,
+}
+
+/// Checks every PrewarmPolicy on a timer: warms idle nodes for those whose
+/// query is above its threshold and every matching node for those whose
+/// schedule came up, and evicts what scheduled runs warmed once due. Runs
+/// on the leader.
+pub async fn run(client: Client, settings: PrewarmSettings, warmer: Warmer, metrics: MetricsState) {
+    let policies: Api<PrewarmPolicy> = Api::all(client.clone());
+    let http = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build().unwrap_or_default();
+    let mut tick = tokio::time::interval(settings.interval);
+    let mut last_check = Utc::now();
+    let mut evictions: Vec<Eviction> = Vec::new();
+    info!(event = "prewarm_start", prometheus = ?settings.prometheus_url, "Checking pre-warm policies");
+
+    loop {
+        tick.tick().await;
+        let now = Utc::now();
+        let list = match policies.list(&ListParams::default()).await {
+            Ok(list) => list,
+            Err(e) => {
+                warn!(event = "prewarm_list_error", error = ?e, "Failed to list pre-warm policies");
+                continue;
+            }
+        };
+
+        for policy in &list.items {
+            let id = format!("{}/{}", policy.namespace().unwrap_or_default(), policy.name_any());
+            if let Some(schedule) = &policy.spec.schedule {
+                match schedule_due(schedule, &last_check, &now) {
+                    Ok(true) => {
+                        if let Some(eviction) = scheduled_run(&client, &http, &settings, &warmer, policy, &id).await {
+                            evictions.push(eviction);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!(event = "prewarm_schedule_invalid", policy = %id, schedule = %schedule, error = %e, "Invalid pre-warm schedule"),
+                }
+            }
+
+            let (Some(expr), Some(prometheus_url)) = (&policy.spec.query, &settings.prometheus_url) else { continue };
+            let value = match query(&http, prometheus_url, expr).await {
+                Ok(value) => value,
+                Err(e) => {
+                    warn!(event = "prewarm_query_error", policy = %id, error = %e, "Pre-warm query failed");
+                    metrics.count_prewarm_evaluation(&id, "error");
+                    continue;
+                }
+            };
+            match value {
+                Some(value) if value > policy.spec.threshold => {
+                    metrics.count_prewarm_evaluation(&id, "triggered");
+                    info!(event = "prewarm_triggered", policy = %id, value, threshold = policy.spec.threshold, "Pre-warm query above threshold");
+                    warm_nodes(&client, &http, &settings, &warmer, policy, &id).await;
+                }
+                _ => metrics.count_prewarm_evaluation(&id, "below"),
+            }
+        }
+        last_check = now;
+
+        let (due, waiting): (Vec<_>, Vec<_>) = evictions.into_iter().partition(|e| e.due <= tokio::time::Instant::now());
+        evictions = waiting;
+        for eviction in due {
+            evict(&client, &http, &settings, &warmer, &eviction).await;
+        }
+    }
+}
+
+// True if the cron schedule has a time in `(after, until]`.
+fn schedule_due(schedule: &str, after: &DateTime<Utc>, until: &DateTime<Utc>) -> Result<bool, croner::errors::CronError> {
+    let next = Cron::new(schedule).parse()?.find_next_occurrence(after, false)?;
+    Ok(next <= *until)
+}
+
+#[derive(Deserialize)]
+struct QueryResponse {
+    status: String,
+    #[serde(default)]
+    data: Option<QueryData>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QueryData {
+    result_type: String,
+    result: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct Sample {
+    /// `[timestamp, "value"]`
+    value: (f64, String),
+}
+
+// Highest sample of an instant query; `None` if it returned none.
+async fn query(http: &reqwest::Client, base: &str, expr: &str) -> Result<Option<f64>, String> {
+    let url = format!("{}/api/v1/query", base.trim_end_matches('/'));
+    let response = http.get(&url).query(&[("query", expr)]).send().await.map_err(|e| e.to_string())?;
+    let body: QueryResponse = response.json().await.map_err(|e| e.to_string())?;
+    if body.status != "success" {
+        return Err(body.error.unwrap_or(body.status));
+    }
+    let data = body.data.ok_or("response without data")?;
+    let values = match data.result_type.as_str() {
+        "vector" => serde_json::from_value::<Vec<Sample>>(data.result).map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|s| s.value.1)
+            .collect(),
+        "scalar" => vec![serde_json::from_value::<(f64, String)>(data.result).map_err(|e| e.to_string())?.1],
+        other => return Err(format!("expected an instant vector or a scalar, got a {}", other)),
+    };
+    Ok(values.iter().filter_map(|v| v.parse::<f64>().ok()).filter(|v| !v.is_nan()).reduce(f64::max))
+}
+
+// Asks the kube-cache pod on each idle node the policy targets to fetch its datasets.
+async fn warm_nodes(client: &Client, http: &reqwest::Client, settings: &PrewarmSettings, warmer: &Warmer, policy: &PrewarmPolicy, id: &str) {
+    let Some((targets, datasets)) = plan(client, settings, warmer, policy, id, true).await else { return };
+    let nodes: Vec<&str> = targets.iter().map(|t| t.node.as_str()).collect();
+    let mut failed = 0;
+    for target in &targets {
+        failed += warm_target(http, warmer, target, &datasets).await.failed;
+    }
+    info!(event = "prewarm_sent", policy = %id, nodes = ?nodes, datasets = datasets.len(), failed, "Asked idle nodes to pre-warm");
+}
+
+// Warms every node the policy targets, and returns what to evict later if it asks for that.
+async fn scheduled_run(
+    client: &Client,
+    http: &reqwest::Client,
+    settings: &PrewarmSettings,
+    warmer: &Warmer,
+    policy: &PrewarmPolicy,
+    id: &str,
+) -> Option<Eviction> {
+    let (targets, datasets) = plan(client, settings, warmer, policy, id, false).await?;
+    let nodes: Vec<String> = targets.iter().map(|t| t.node.clone()).collect();
+    let mut failed = 0;
+    let mut queued = Vec::new();
+    for target in targets {
+        let warmed = warm_target(http, warmer, &target, &datasets).await;
+        failed += warmed.failed;
+        if !warmed.queued.is_empty() {
+            queued.push((target, warmed.queued));
+        }
+    }
+    info!(
+        event = "prewarm_scheduled_run", policy = %id, nodes = ?nodes, datasets = datasets.len(), failed,
+        evict_after_seconds = ?policy.spec.evict_after_seconds, "Ran scheduled pre-warm"
+    );
+
+    let after = Duration::from_secs(policy.spec.evict_after_seconds?);
+    (!queued.is_empty()).then(|| Eviction { due: tokio::time::Instant::now() + after, policy: id.to_string(), targets: queued })
+}
+
+// The policy's datasets and the nodes to warm them on, or `None` if there is nothing to do.
+async fn plan(
+    client: &Client,
+    settings: &PrewarmSettings,
+    warmer: &Warmer,
+    policy: &PrewarmPolicy,
+    id: &str,
+    idle_only: bool,
+) -> Option<(Vec<Target>, Vec<DatasetRef>)> {
+    let namespace = policy.namespace().unwrap_or_default();
+    let mut datasets = Vec::new();
+    for reference in &policy.spec.datasets {
+        let dataset = match dataset::lookup_in(client, &namespace, reference).await {
+            Ok(dataset) => dataset,
+            Err(e) => {
+                warn!(event = "prewarm_dataset_invalid", policy = %id, dataset = %reference, error = %e, "Cannot resolve pre-warm dataset");
+                continue;
+            }
+        };
+        match warmer.authorize(client, &namespace, &dataset).await {
+            Ok(()) => datasets.push(dataset),
+            Err(e) => warn!(event = "prewarm_source_denied", policy = %id, dataset = %dataset.uri, error = %e, "Pre-warm dataset not allowed"),
+        }
+    }
+    if datasets.is_empty() {
+        return None;
+    }
+
+    let mut targets = match targets(client, settings, &policy.spec.node_selector, idle_only).await {
+        Ok(targets) => targets,
+        Err(e) => {
+            warn!(event = "prewarm_nodes_error", policy = %id, error = ?e, "Failed to find nodes to pre-warm");
+            return None;
+        }
+    };
+    targets.truncate(policy.spec.max_nodes.map_or(usize::MAX, |n| n as usize));
+    Some((targets, datasets))
+}
+
+// Drops what a scheduled run queued from its nodes, except where pods use it.
+async fn evict(client: &Client, http: &reqwest::Client, settings: &PrewarmSettings, warmer: &Warmer, eviction: &Eviction) {
+    let targets: Vec<&Target> = eviction.targets.iter().map(|(target, _)| target).collect();
+    let in_use = match datasets_in_use(client, &settings.dataset_annotation, &targets).await {
+        Ok(in_use) => in_use,
+        Err(e) => {
+            warn!(event = "prewarm_evict_error", policy = %eviction.policy, error = ?e, "Cannot tell which datasets are in use, keeping them");
+            return;
+        }
+    };
+    let mut evicted = 0;
+    for (target, datasets) in &eviction.targets {
+        for dataset in datasets {
+            if in_use.get(&target.node).is_some_and(|used| used.contains(&dataset.cache_path(""))) {
+                continue;
+            }
+            let result = if Some(target.pod.as_str()) == warmer.peers.own_name() {
+                Ok(warmer.evict(dataset).to_string())
+            } else {
+                let url = format!("http://{}{}", target.addr, WARM_PATH);
+                match http.delete(&url).json(&WarmRequest::new(dataset)).send().await {
+                    Ok(response) => response.text().await,
+                    Err(e) => Err(e),
+                }
+            };
+            match result {
+                Ok(result) if result == "evicted" => evicted += 1,
+                Ok(_) => {}
+                Err(e) => warn!(event = "prewarm_evict_error", policy = %eviction.policy, node = %target.node, dataset = %dataset.uri, error = %e, "Evict request failed"),
+            }
+        }
+    }
+    info!(event = "prewarm_evicted", policy = %eviction.policy, nodes = eviction.targets.len(), evicted, "Evicted scheduled pre-warm");
+}
+
+// Cache entries (as `cache_path("")`) the unfinished pods on each target
+// node ask for: pods bound there, and pending ones pinned there by hostname.
+async fn datasets_in_use(client: &Client, annotation: &str, targets: &[&Target]) -> Result<HashMap<String, HashSet<String>>, kube::Error> {
+    let by_hostname: HashMap<&str, &str> = targets.iter().map(|t| (t.hostname.as_str(), t.node.as_str())).collect();
+    let pods: Api<Pod> = Api::all(client.clone());
+    let active = pods.list(&ListParams::default().fields("status.phase!=Succeeded,status.phase!=Failed")).await?;
+    let mut in_use: HashMap<String, HashSet<String>> = HashMap::new();
+    for pod in &active.items {
+        let Some(node) = pod_node(pod, &by_hostname) else { continue };
+        if !dataset::wants_dataset(pod, annotation) {
+            continue;
+        }
+        if let Some(Ok(dataset)) = dataset::lookup(client, pod, annotation).await {
+            in_use.entry(node.to_string()).or_default().insert(dataset.cache_path(""));
+        }
+    }
+    Ok(in_use)
+}
+
+// The node a pod runs on, or is pinned to with a hostname nodeSelector.
+fn pod_node<'a>(pod: &'a Pod, by_hostname: &HashMap<&str, &'a str>) -> Option<&'a str> {
+    let spec = pod.spec.as_ref()?;
+    if let Some(node) = spec.node_name.as_deref() {
+        return Some(node);
+    }
+    let hostname = spec.node_selector.as_ref()?.get(HOSTNAME_LABEL)?;
+    by_hostname.get(hostname.as_str()).copied()
+}
+
+/// A node to warm and the kube-cache pod on it.
+pub struct Target {
+    pub node: String,
+    /// The node's `kubernetes.io/hostname` label, what pods pin to
+    pub hostname: String,
+    pub pod: String,
+    /// `ip:port` of the pod's peer server
+    pub addr: String,
+}
+
+/// What `warm_target` did on a node.
+pub struct Warmed {
+    /// Requests that could not be delivered
+    pub failed: usize,
+    /// Datasets this call queued, rather than found cached or already queued
+    pub queued: Vec<DatasetRef>,
+}
+
+/// Queues datasets on a target: directly if it is this pod, else through
+/// its peer server.
+pub async fn warm_target(http: &reqwest::Client, warmer: &Warmer, target: &Target, datasets: &[DatasetRef]) -> Warmed {
+    let mut warmed = Warmed { failed: 0, queued: Vec::new() };
+    for dataset in datasets {
+        let result = if Some(target.pod.as_str()) == warmer.peers.own_name() {
+            Ok(warmer.warm(dataset.clone()).to_string())
+        } else {
+            let url = format!("http://{}{}", target.addr, WARM_PATH);
+            match http.post(&url).json(&WarmRequest::new(dataset)).send().await.and_then(|r| r.error_for_status()) {
+                Ok(response) => response.text().await,
+                Err(e) => Err(e),
+            }
+        };
+        match result {
+            Ok(result) if result == "queued" => warmed.queued.push(dataset.clone()),
+            Ok(_) => {}
+            Err(e) => {
+                warn!(event = "prewarm_request_error", node = %target.node, dataset = %dataset.uri, error = %e, "Pre-warm request failed");
+                warmer.metrics.count_prewarm_failure();
+                warmed.failed += 1;
+            }
+        }
+    }
+    warmed
+}
+
+/// Schedulable nodes matching `selector` that run a kube-cache pod, by name;
+/// only idle ones with `idle_only`.
+pub async fn targets(
+    client: &Client,
+    settings: &PrewarmSettings,
+    selector: &BTreeMap<String, String>,
+    idle_only: bool,
+) -> Result<Vec<Target>, kube::Error> {
+    let labels = selector.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join(",");
+    let nodes: Api<Node> = Api::all(client.clone());
+    let mut nodes = nodes.list(&ListParams::default().labels(&labels)).await?.items;
+    nodes.sort_by_key(|n| n.name_any());
+
+    // Pods bound to a node and not finished keep it busy
+    let pods: Api<Pod> = Api::all(client.clone());
+    let active = pods.list(&ListParams::default().fields("status.phase!=Succeeded,status.phase!=Failed")).await?;
+    let busy: HashSet<&str> = active.items.iter()
+        .filter(|p| requests_gpu(p))
+        .filter_map(|p| p.spec.as_ref()?.node_name.as_deref())
+        .collect();
+
+    let caches: Api<Pod> = Api::namespaced(client.clone(), &settings.namespace);
+    let caches = caches.list(&ListParams::default().labels("app=kube-cache")).await?;
+    let mut on_node: BTreeMap<String, (String, String)> = BTreeMap::new();
+    for pod in &caches.items {
+        let Some(status) = pod.status.as_ref() else { continue };
+        let (Some(node), Some(ip)) = (pod.spec.as_ref().and_then(|s| s.node_name.clone()), status.pod_ip.as_ref()) else { continue };
+        let Ok(ip) = ip.parse() else { continue };
+        if status.phase.as_deref() == Some("Running") {
+            on_node.insert(node, (pod.name_any(), SocketAddr::new(ip, settings.peer_port).to_string()));
+        }
+    }
+
+    Ok(nodes.iter()
+        .filter(|n| is_schedulable(n) && !(idle_only && busy.contains(n.name_any().as_str())))
+        .filter_map(|n| {
+            let (pod, addr) = on_node.get(&n.name_any())?.clone();
+            let hostname = n.labels().get(HOSTNAME_LABEL).cloned().unwrap_or_else(|| n.name_any());
+            Some(Target { node: n.name_any(), hostname, pod, addr })
+        })
+        .collect())
+}
+
+fn requests_gpu(pod: &Pod) -> bool {
+    let Some(spec) = pod.spec.as_ref() else { return false };
+    spec.containers.iter().filter_map(|c| c.resources.as_ref()).any(|r| {
+        r.requests.as_ref().is_some_and(|q| q.contains_key(GPU_RESOURCE))
+            || r.limits.as_ref().is_some_and(|q| q.contains_key(GPU_RESOURCE))
+    })
+}
+
+fn is_schedulable(node: &Node) -> bool {
+    let cordoned = node.spec.as_ref().and_then(|s| s.unschedulable).unwrap_or(false);
+    let ready = node.status.as_ref()
+        .and_then(|s| s.conditions.as_ref())
+        .is_some_and(|c| c.iter().any(|c| c.type_ == "Ready" && c.status == "True"));
+    ready && !cordoned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn pending_pods_count_for_the_node_they_are_pinned_to() {
+        let by_hostname = HashMap::from([("host-a", "node-a")]);
+        let bound: Pod = serde_json::from_value(json!({"spec": {"containers": [], "nodeName": "node-b"}})).unwrap();
+        let pinned: Pod = serde_json::from_value(json!({"spec": {
+            "containers": [], "nodeSelector": {HOSTNAME_LABEL: "host-a"},
+        }})).unwrap();
+        let unplaced: Pod = serde_json::from_value(json!({"spec": {"containers": []}})).unwrap();
+
+        assert_eq!(pod_node(&bound, &by_hostname), Some("node-b"));
+        assert_eq!(pod_node(&pinned, &by_hostname), Some("node-a"));
+        assert_eq!(pod_node(&unplaced, &by_hostname), None);
+    }
+
+    #[test]
+    fn schedules_are_due_once_per_occurrence() {
+        let at = |time: &str| time.parse::<DateTime<Utc>>().unwrap();
+        let nightly = "0 3 * * *";
+        assert!(schedule_due(nightly, &at("2026-01-01T02:59:30Z"), &at("2026-01-01T03:00:00Z")).unwrap());
+        assert!(schedule_due(nightly, &at("2026-01-01T01:00:00Z"), &at("2026-01-01T04:00:00Z")).unwrap());
+        // The check that saw 03:00 go by does not fire again
+        assert!(!schedule_due(nightly, &at("2026-01-01T03:00:00Z"), &at("2026-01-01T03:00:30Z")).unwrap());
+        assert!(!schedule_due(nightly, &at("2026-01-01T03:00:30Z"), &at("2026-01-02T02:59:59Z")).unwrap());
+        assert!(schedule_due("not a schedule", &at("2026-01-01T00:00:00Z"), &at("2026-01-02T00:00:00Z")).is_err());
+    }
+}
@@ -0,0 +1,565 @@
+// --- S3 PROXY ---
+// A node-local endpoint speaking enough of the S3 API for workloads that read
+// with an S3 SDK rather than from a path. They set `AWS_ENDPOINT_URL` to the
+// node-local `kube-cache-s3` Service and keep their code:
+//
+//   GET  /<bucket>/<key>              GetObject (one `Range`, `If-Match`)
+//   HEAD /<bucket>/<key>              HeadObject
+//   GET  /<bucket>?list-type=2&...    ListObjectsV2 (prefix, delimiter,
+//                                     max-keys, start-after, continuation)
+//
+// Only path-style addressing is understood; configure the SDK to force it.
+//
+// An object is served from a complete cache entry holding it: its own entry,
+// or a prefix entry it lies under. Anything else, and every listing, falls
+// through to the object store via the same mirrors a download would use.
+// A listing asks the origin for just the page requested, so paging through a
+// large bucket costs one origin page per page. Nothing is cached on the way; fall-through reads are the workload's own
+// traffic and are not bandwidth-throttled, but each takes a request slot
+// like any other call to the object store.
+//
+// Callers are pods. The proxy is reached through a node-local Service (no
+// hostPort), and each caller is identified by its source IP, mapped to the one
+// running pod holding it, and so to a namespace. Requests from anything else
+// are refused. Every object and listing is then held to the caller's
+// namespace source policy (`webhook::check_source`) and to what the
+// operator's own identity may read (`check_access`), before the cache or the
+// object store is consulted: callers cannot prove any tenant's credentials,
+// so entries fetched with a tenant's Secret are never handed out unless the
+// operator could read them itself, and nothing is served when operator
+// credentials are disabled (`allow_operator_credentials: false`).
+// `proxy_requests_total` counts requests by operation and outcome (hit,
+// origin, error).
+
+use crate::dataset::{DatasetRef, Scheme};
+use crate::metrics::MetricsState;
+use crate::peers::{parse_range, read_stream};
+use crate::storage::{is_complete, is_plain_relative, Backends, ObjectInfo, PageRequest, StorageError};
+use crate::throttle::Throttle;
+use crate::webhook;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path as UrlPath, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::{routing::get, Router};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use base64::Engine;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::ListParams;
+use kube::{Api, Client};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncSeekExt;
+use tracing::{debug, info, warn};
+
+const S3_XMLNS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+const MAX_KEYS: usize = 1000;
+/// How long a caller's namespace is remembered. Pod IPs are reused, but not
+/// within seconds of the pod going away.
+const CALLER_TTL: Duration = Duration::from_secs(30);
+
+// `encoding-type=url` keys keep their '/' separators
+const KEY_ENCODING: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/').remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+#[derive(Clone)]
+struct ProxyState {
+    client: Client,
+    callers: Arc<Mutex<HashMap<IpAddr, Caller>>>,
+    backends: Arc<Backends>,
+    throttle: Arc<Throttle>,
+    cache_root: String,
+    metrics: MetricsState,
+}
+
+// A caller IP's pod namespace (`None`: not a pod), and when it was looked up
+type Caller = (Instant, Option<String>);
+
+// An S3 error response
+struct S3Error {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "Error", rename_all = "PascalCase")]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: &'a str,
+    resource: &'a str,
+}
+
+impl S3Error {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into() }
+    }
+
+    fn into_response(self, resource: &str) -> Response {
+        let body = ErrorBody { code: self.code, message: &self.message, resource };
+        (self.status, xml(&body)).into_response()
+    }
+}
+
+// Unreachable or failing stores answer 503, which SDKs retry
+impl From<StorageError> for S3Error {
+    fn from(e: StorageError) -> Self {
+        match e {
+            StorageError::NotFound(_) => S3Error::new(StatusCode::NOT_FOUND, "NoSuchKey", e.to_string()),
+            StorageError::Changed(_) => S3Error::new(StatusCode::PRECONDITION_FAILED, "PreconditionFailed", e.to_string()),
+            StorageError::Auth(_) => S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", e.to_string()),
+            StorageError::Unsupported(_) => S3Error::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", e.to_string()),
+            _ => S3Error::new(StatusCode::SERVICE_UNAVAILABLE, "ServiceUnavailable", e.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ListQuery {
+    list_type: Option<String>,
+    #[serde(default)]
+    prefix: String,
+    delimiter: Option<String>,
+    max_keys: Option<usize>,
+    start_after: Option<String>,
+    continuation_token: Option<String>,
+    encoding_type: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename = "ListBucketResult", rename_all = "PascalCase")]
+struct ListBucketResult {
+    #[serde(rename = "@xmlns")]
+    xmlns: &'static str,
+    name: String,
+    prefix: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delimiter: Option<String>,
+    max_keys: usize,
+    key_count: usize,
+    is_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    continuation_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_continuation_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encoding_type: Option<String>,
+    contents: Vec<Contents>,
+    common_prefixes: Vec<CommonPrefix>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Contents {
+    key: String,
+    #[serde(rename = "ETag", skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    size: u64,
+    storage_class: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct CommonPrefix {
+    prefix: String,
+}
+
+/// Serves the S3 API on `port` until the process exits.
+pub async fn serve(
+    port: u16,
+    client: Client,
+    cache_root: String,
+    backends: Arc<Backends>,
+    throttle: Arc<Throttle>,
+    metrics: MetricsState,
+) {
+    let state = ProxyState { client, callers: Arc::default(), backends, throttle, cache_root, metrics };
+    let app = Router::new()
+        .route("/:bucket", get(bucket_handler))
+        .route("/:bucket/*key", get(object_handler))
+        .with_state(state);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    info!(event = "server_start", port, "S3 proxy listening");
+
+    match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => {
+            if let Err(e) = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await {
+                warn!(event = "proxy_server_error", error = %e, "S3 proxy stopped");
+            }
+        }
+        Err(e) => warn!(event = "proxy_server_error", port, error = %e, "Cannot listen for S3 requests"),
+    }
+}
+
+// GetObject and HeadObject (axum answers HEAD with the GET handler, minus the body)
+async fn object_handler(
+    State(state): State<ProxyState>,
+    ConnectInfo(caller): ConnectInfo<SocketAddr>,
+    UrlPath((bucket, key)): UrlPath<(String, String)>,
+    Query(query): Query<HashMap<String, String>>,
+    method: Method,
+    headers: HeaderMap,
+) -> Response {
+    let op = if method == Method::HEAD { "head" } else { "get" };
+    let resource = format!("/{}/{}", bucket, key);
+    let result = async {
+        if query.contains_key("versionId") || query.contains_key("partNumber") {
+            return Err(S3Error::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "versionId and partNumber are not supported"));
+        }
+        let object = s3_ref(&bucket, &key);
+        authorize(&state, caller.ip(), &object).await?;
+        match cached(&state.cache_root, &object) {
+            Some(path) => serve_cached(&path, &headers).await.map(|resp| ("hit", resp)),
+            None => serve_origin(&state.backends, &state.throttle, &object, &method, &headers).await.map(|resp| ("origin", resp)),
+        }
+    }
+    .await;
+
+    match result {
+        Ok((outcome, resp)) => {
+            state.metrics.count_proxy(op, outcome);
+            debug!(event = "proxy_request", op, resource = %resource, outcome, "Served S3 request");
+            resp
+        }
+        Err(e) => {
+            state.metrics.count_proxy(op, "error");
+            debug!(event = "proxy_error", op, resource = %resource, code = e.code, error = %e.message, "S3 request failed");
+            e.into_response(&resource)
+        }
+    }
+}
+
+// ListObjectsV2, always from the object store
+async fn bucket_handler(
+    State(state): State<ProxyState>,
+    ConnectInfo(caller): ConnectInfo<SocketAddr>,
+    UrlPath(bucket): UrlPath<String>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    let resource = format!("/{}", bucket);
+    let result = async {
+        authorize(&state, caller.ip(), &s3_ref(&bucket, &query.prefix)).await?;
+        list(&state.backends, &state.throttle, &bucket, query).await
+    }
+    .await;
+    match result {
+        Ok(resp) => {
+            state.metrics.count_proxy("list", "origin");
+            resp
+        }
+        Err(e) => {
+            state.metrics.count_proxy("list", "error");
+            debug!(event = "proxy_error", op = "list", resource = %resource, code = e.code, error = %e.message, "S3 request failed");
+            e.into_response(&resource)
+        }
+    }
+}
+
+// The caller's namespace must be allowed the object's source, and the
+// operator's own identity must be able to read it (see the header).
+async fn authorize(state: &ProxyState, caller: IpAddr, object: &DatasetRef) -> Result<(), S3Error> {
+    let denied = |message: String| S3Error::new(StatusCode::FORBIDDEN, "AccessDenied", message);
+    let namespace = caller_namespace(state, caller).await?.ok_or_else(|| denied(format!("{} is not a running pod", caller)))?;
+    state.backends.tenants.permit(object)?;
+    webhook::check_source(&state.client, &namespace, object).await.map_err(|e| denied(e.to_string()))?;
+    state.backends.tenants.check_access(object).await?;
+    Ok(())
+}
+
+// The namespace of the running pod with this IP. Host-network pods share
+// their node's IP and identify nobody, nor do IPs several pods claim.
+async fn caller_namespace(state: &ProxyState, caller: IpAddr) -> Result<Option<String>, S3Error> {
+    if let Some((at, namespace)) = state.callers.lock().unwrap().get(&caller) {
+        if at.elapsed() < CALLER_TTL {
+            return Ok(namespace.clone());
+        }
+    }
+
+    let pods: Api<Pod> = Api::all(state.client.clone());
+    let params = ListParams::default().fields(&format!("status.podIP={}", caller));
+    let list = pods.list(&params).await.map_err(|e| {
+        warn!(event = "proxy_caller_error", caller = %caller, error = ?e, "Cannot look up the calling pod");
+        S3Error::new(StatusCode::SERVICE_UNAVAILABLE, "ServiceUnavailable", "cannot identify the caller")
+    })?;
+    let namespaces: BTreeSet<String> = list.items.iter()
+        .filter(|pod| !pod.spec.as_ref().and_then(|s| s.host_network).unwrap_or(false))
+        .filter(|pod| matches!(pod.status.as_ref().and_then(|s| s.phase.as_deref()), Some("Pending" | "Running")))
+        .filter_map(|pod| pod.metadata.namespace.clone())
+        .collect();
+    let namespace = (namespaces.len() == 1).then(|| namespaces.into_iter().next()).flatten();
+
+    let mut callers = state.callers.lock().unwrap();
+    callers.retain(|_, (at, _)| at.elapsed() < CALLER_TTL);
+    callers.insert(caller, (Instant::now(), namespace.clone()));
+    Ok(namespace)
+}
+
+fn s3_ref(bucket: &str, key: &str) -> DatasetRef {
+    DatasetRef {
+        uri: format!("s3://{}/{}", bucket, key),
+        scheme: Scheme::S3,
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        credentials: None,
+        mirrors: Vec::new(),
+    }
+}
+
+// The complete cache file holding an object: its own entry, else the
+// innermost prefix entry that has it.
+fn cached(cache_root: &str, object: &DatasetRef) -> Option<PathBuf> {
+    let own = PathBuf::from(object.cache_path(cache_root));
+    if own.is_file() && is_complete(&own) {
+        return Some(own);
+    }
+    object.key.rmatch_indices('/')
+        .map(|(i, _)| object.key.split_at(i + 1))
+        .filter(|(_, rest)| is_plain_relative(rest))
+        .map(|(prefix, rest)| (PathBuf::from(object.with_key(prefix).cache_path(cache_root)), rest))
+        .filter(|(entry, _)| is_complete(entry))
+        .map(|(entry, rest)| entry.join(rest))
+        .find(|path| path.is_file())
+}
+
+async fn serve_cached(path: &Path, headers: &HeaderMap) -> Result<Response, S3Error> {
+    let meta = tokio::fs::metadata(path).await.map_err(StorageError::from)?;
+    let modified = meta.modified().ok();
+    // Entries never change in place, so size and mtime identify the version
+    let mtime = modified.and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0);
+    let etag = format!("\"{:x}-{:x}\"", meta.len(), mtime);
+    check_if_match(headers, Some(&etag))?;
+
+    let (status, start, end) = select_range(headers, meta.len())?;
+    let mut file = tokio::fs::File::open(path).await.map_err(StorageError::from)?;
+    file.seek(std::io::SeekFrom::Start(start)).await.map_err(StorageError::from)?;
+    let body = Body::from_stream(read_stream(file, end - start, None));
+    Ok(object_response(status, (start, end, meta.len()), Some(&etag), modified, body))
+}
+
+async fn serve_origin(
+    backends: &Backends,
+    throttle: &Throttle,
+    object: &DatasetRef,
+    method: &Method,
+    headers: &HeaderMap,
+) -> Result<Response, S3Error> {
+    let mirrors = backends.mirrors(object).await?;
+    let (mirror, info) = backends.health.first(&mirrors, |m| async {
+        let _slot = throttle.request_slot().await;
+        m.backend.stat(&m.dataset).await
+    }).await?;
+    check_if_match(headers, info.etag.as_deref())?;
+
+    let (status, start, end) = select_range(headers, info.size)?;
+    let body = if method == Method::HEAD {
+        Body::empty()
+    } else {
+        let range = (status == StatusCode::PARTIAL_CONTENT).then_some(start..end);
+        // The slot is held until the body has been streamed
+        let slot = throttle.owned_request_slot().await;
+        let stream = mirror.backend.read(&mirror.dataset, range, info.etag.as_deref()).await?;
+        Body::from_stream(stream.inspect(move |_| { let _ = &slot; }))
+    };
+    Ok(object_response(status, (start, end, info.size), info.etag.as_deref(), None, body))
+}
+
+fn check_if_match(headers: &HeaderMap, etag: Option<&str>) -> Result<(), S3Error> {
+    let (Some(wanted), Some(etag)) = (headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok()), etag) else {
+        return Ok(());
+    };
+    if wanted.split(',').map(str::trim).any(|w| w == "*" || w == etag) {
+        return Ok(());
+    }
+    Err(S3Error::new(StatusCode::PRECONDITION_FAILED, "PreconditionFailed", format!("ETag is {}", etag)))
+}
+
+// 200 for the whole object, 206 for a satisfiable `Range`
+fn select_range(headers: &HeaderMap, size: u64) -> Result<(StatusCode, u64, u64), S3Error> {
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).and_then(|v| parse_range(v, size));
+    match range {
+        Some(Ok((start, end))) => Ok((StatusCode::PARTIAL_CONTENT, start, end)),
+        Some(Err(())) => Err(S3Error::new(StatusCode::RANGE_NOT_SATISFIABLE, "InvalidRange", format!("object is {} bytes", size))),
+        None => Ok((StatusCode::OK, 0, size)),
+    }
+}
+
+fn object_response(
+    status: StatusCode,
+    (start, end, size): (u64, u64, u64),
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+    body: Body,
+) -> Response {
+    let mut resp = (status, body).into_response();
+    let headers = resp.headers_mut();
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(end - start));
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    if let Some(value) = etag.and_then(|e| HeaderValue::from_str(e).ok()) {
+        headers.insert(header::ETAG, value);
+    }
+    if let Some(value) = modified.and_then(|t| HeaderValue::from_str(&httpdate::fmt_http_date(t)).ok()) {
+        headers.insert(header::LAST_MODIFIED, value);
+    }
+    if status == StatusCode::PARTIAL_CONTENT {
+        if let Ok(value) = HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end - 1, size)) {
+            headers.insert(header::CONTENT_RANGE, value);
+        }
+    }
+    resp
+}
+
+async fn list(backends: &Backends, throttle: &Throttle, bucket: &str, query: ListQuery) -> Result<Response, S3Error> {
+    if query.list_type.as_deref() != Some("2") {
+        return Err(S3Error::new(StatusCode::NOT_IMPLEMENTED, "NotImplemented", "only ListObjectsV2 (list-type=2) is supported"));
+    }
+    let max_keys = query.max_keys.unwrap_or(MAX_KEYS).min(MAX_KEYS);
+    let delimiter = query.delimiter.clone().filter(|d| !d.is_empty());
+    // Continue after the last key or common prefix returned, else after `start-after`
+    let after = match &query.continuation_token {
+        Some(token) => Some(BASE64.decode(token).ok().and_then(|t| String::from_utf8(t).ok()).ok_or_else(|| {
+            S3Error::new(StatusCode::BAD_REQUEST, "InvalidArgument", "bad continuation token")
+        })?),
+        None => query.start_after.clone(),
+    };
+    let start_after = after.as_deref().map(|after| resume_after(after, &query.prefix, delimiter.as_deref()));
+
+    // Only this page is fetched from the origin, never the whole prefix
+    let prefix = s3_ref(bucket, &query.prefix);
+    let mirrors = backends.mirrors(&prefix).await?;
+    let request = PageRequest { delimiter: delimiter.as_deref(), start_after: start_after.as_deref(), max_keys };
+    let (_, page) = backends.health.first(&mirrors, |m| async {
+        let _slot = throttle.request_slot().await;
+        m.backend.list_page(&m.dataset, &request).await
+    }).await?;
+
+    // Objects and common prefixes interleave in key order
+    let mut listed: Vec<(String, Option<ObjectInfo>)> = page.objects.into_iter().map(|o| (o.key.clone(), Some(o)))
+        .chain(page.common_prefixes.into_iter().map(|p| (p, None)))
+        .collect();
+    listed.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut contents = Vec::new();
+    let mut common_prefixes: Vec<CommonPrefix> = Vec::new();
+    let mut last: Option<String> = None;
+    let mut is_truncated = page.truncated;
+    for (value, object) in listed {
+        if !value.starts_with(query.prefix.as_str()) || after.as_deref().is_some_and(|after| value.as_str() <= after) {
+            continue;
+        }
+        if contents.len() + common_prefixes.len() == max_keys {
+            is_truncated = true;
+            break;
+        }
+        match object {
+            Some(object) => contents.push(Contents { key: object.key, etag: object.etag, size: object.size, storage_class: "STANDARD" }),
+            None => common_prefixes.push(CommonPrefix { prefix: value.clone() }),
+        }
+        last = Some(value);
+    }
+
+    let url = query.encoding_type.as_deref() == Some("url");
+    let encode = |s: String| if url { utf8_percent_encode(&s, KEY_ENCODING).to_string() } else { s };
+    let result = ListBucketResult {
+        xmlns: S3_XMLNS,
+        name: bucket.to_string(),
+        prefix: encode(query.prefix),
+        delimiter: query.delimiter.map(encode),
+        max_keys,
+        key_count: contents.len() + common_prefixes.len(),
+        is_truncated,
+        continuation_token: query.continuation_token,
+        next_continuation_token: last.filter(|_| is_truncated).map(|l| BASE64.encode(l)),
+        start_after: query.start_after.map(encode),
+        encoding_type: url.then(|| "url".to_string()),
+        contents: contents.into_iter().map(|c| Contents { key: encode(c.key), ..c }).collect(),
+        common_prefixes: common_prefixes.into_iter().map(|p| CommonPrefix { prefix: encode(p.prefix) }).collect(),
+    };
+    Ok((StatusCode::OK, xml(&result)).into_response())
+}
+
+// Where the origin listing resumes. After a common prefix that is past every
+// key under it, not just the prefix itself, or the origin would fold the
+// next of them into the same common prefix again.
+fn resume_after(after: &str, prefix: &str, delimiter: Option<&str>) -> String {
+    let is_common = delimiter.is_some_and(|d| after.strip_prefix(prefix).is_some_and(|rest| rest.ends_with(d)));
+    match is_common {
+        true => format!("{}{}", after, char::MAX),
+        false => after.to_string(),
+    }
+}
+
+fn xml<T: Serialize>(value: &T) -> ([(header::HeaderName, &'static str); 1], String) {
+    let body = quick_xml::se::to_string(value).unwrap_or_default();
+    (
+        [(header::CONTENT_TYPE, "application/xml")],
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{mark_complete, unmark};
+
+    // A complete entry for `uri` holding `files`, or the object itself if none
+    fn seed(root: &str, uri: &str, files: &[&str]) -> PathBuf {
+        let entry = PathBuf::from(DatasetRef::parse(uri).unwrap().cache_path(root));
+        match files {
+            [] => std::fs::write(&entry, b"object").unwrap(),
+            files => for file in files {
+                let path = entry.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, b"object").unwrap();
+            },
+        }
+        mark_complete(&entry).unwrap();
+        entry
+    }
+
+    #[test]
+    fn objects_are_found_in_their_own_or_the_innermost_prefix_entry() {
+        let dir = std::env::temp_dir().join(format!("kube-cache-proxy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let root = dir.to_str().unwrap();
+
+        let outer = seed(root, "s3://b/models/", &["a/weights.bin", "a/config.json", "b/weights.bin"]);
+        let inner = seed(root, "s3://b/models/a/", &["weights.bin"]);
+        let own = seed(root, "s3://b/models/b/weights.bin", &[]);
+
+        assert_eq!(cached(root, &s3_ref("b", "models/a/weights.bin")), Some(inner.join("weights.bin")));
+        assert_eq!(cached(root, &s3_ref("b", "models/a/config.json")), Some(outer.join("a/config.json")));
+        assert_eq!(cached(root, &s3_ref("b", "models/b/weights.bin")), Some(own));
+        assert_eq!(cached(root, &s3_ref("b", "models/missing.bin")), None);
+        // Directories and paths leaving the entry are not objects
+        assert_eq!(cached(root, &s3_ref("b", "models/a")), None);
+        assert_eq!(cached(root, &s3_ref("b", "models/a/../b/weights.bin")), None);
+
+        // Nor is anything in an entry that was never marked complete
+        std::fs::remove_file(inner.join("weights.bin")).unwrap();
+        unmark(&outer).unwrap();
+        assert_eq!(cached(root, &s3_ref("b", "models/a/weights.bin")), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn listings_resume_past_a_whole_common_prefix() {
+        let skip_all = format!("data/a/{}", char::MAX);
+        assert_eq!(resume_after("data/a/", "data/", Some("/")), skip_all);
+        assert!(skip_all.as_str() > "data/a/zzz/weights.bin" && skip_all.as_str() < "data/b");
+        // Keys, and anything without a delimiter, resume right after themselves
+        assert_eq!(resume_after("data/a.bin", "data/", Some("/")), "data/a.bin");
+        assert_eq!(resume_after("data/a/", "data/", None), "data/a/");
+        assert_eq!(resume_after("data/", "data/", Some("/")), "data/");
+    }
+}
@@ -0,0 +1,379 @@
+// --- PVC-BACKED SHARED DATASETS ---
+// On clusters with fast RWX storage it is cheaper to populate each dataset
+// once into a PersistentVolumeClaim than onto every node. In this mode
+// (`cache_mode: pvc`):
+//
+// 1. The webhook mounts `kube-cache-<hash>` read-only into the pod
+//    (pod volumes are immutable, so this has to happen at admission)
+// 2. The operator creates that PVC plus a downloader Job that fills it
+// 3. When the Job succeeds the PVC is annotated ready and waiting pods are released
+//
+// PVCs are reference counted: a garbage collector keeps each one while any
+// live pod mounts it, and deletes it after `pvc_idle_ttl_seconds` unused.
+
+use crate::config::Config;
+use crate::dataset::{DatasetRef, Scheme};
+use crate::storage::seed_path;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::{PersistentVolumeClaim, Pod};
+use kube::api::{DeleteParams, ListParams, Patch, PatchParams, PostParams, PropagationPolicy};
+use kube::{Api, Client};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const MANAGED_BY: &str = "app.kubernetes.io/managed-by=kube-cache";
+const DATASET_ANNOTATION: &str = "kube-cache.openai.com/source";
+const READY_ANNOTATION: &str = "kube-cache.openai.com/ready";
+const REFS_ANNOTATION: &str = "kube-cache.openai.com/refs";
+const IDLE_SINCE_ANNOTATION: &str = "kube-cache.openai.com/idle-since";
+
+/// Directory the PVC is mounted at inside the downloader Job.
+const FILL_MOUNT: &str = "/data";
+/// Where a `file://` source is mounted inside the downloader Job.
+const SOURCE_MOUNT: &str = "/source";
+
+pub struct PvcSettings {
+    pub storage_class: Option<String>,
+    pub size: String,
+    pub downloader_image: String,
+    /// Secret (in the pod's namespace) with AWS_* keys for the downloader
+    pub credentials_secret: Option<String>,
+    pub s3_endpoint: String,
+    pub idle_ttl: Duration,
+    /// Node directory file:// keys resolve under
+    pub file_seed_root: Option<String>,
+}
+
+impl PvcSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            storage_class: config.pvc_storage_class.clone(),
+            size: config.pvc_size.clone(),
+            downloader_image: config.pvc_downloader_image.clone(),
+            credentials_secret: config.pvc_credentials_secret.clone(),
+            s3_endpoint: config.s3_endpoint.clone(),
+            idle_ttl: Duration::from_secs(config.pvc_idle_ttl_seconds),
+            file_seed_root: config.file_seed_root.clone(),
+        }
+    }
+}
+
+pub enum FillState {
+    Ready,
+    Filling,
+    Failed,
+}
+
+/// Deterministic claim name for a dataset, shared by the webhook and the
+/// operator. FNV-1a rather than DefaultHasher so it is stable across builds.
+pub fn claim_name(dataset: &DatasetRef) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in dataset.uri.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("kube-cache-{:016x}", hash)
+}
+
+fn fill_job_name(claim: &str) -> String {
+    format!("{}-fill", claim)
+}
+
+/// Makes sure the dataset's PVC exists and is (being) filled. Returns where
+/// population stands.
+pub async fn ensure_populated(
+    client: &Client,
+    settings: &PvcSettings,
+    namespace: &str,
+    dataset: &DatasetRef,
+) -> Result<FillState, kube::Error> {
+    let claims: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
+    let jobs: Api<Job> = Api::namespaced(client.clone(), namespace);
+    let claim = claim_name(dataset);
+
+    // file:// sources are read from the node, and only from under the seed root
+    let seed = match dataset.scheme {
+        Scheme::File => match settings.file_seed_root.as_deref().map(|root| seed_path(Path::new(root), &dataset.key)) {
+            Some(Ok(path)) => Some(path),
+            Some(Err(e)) => {
+                error!(event = "pvc_source_refused", namespace = %namespace, dataset = %dataset.uri, error = %e, "Refusing file:// source");
+                return Ok(FillState::Failed);
+            }
+            None => {
+                error!(event = "pvc_source_refused", namespace = %namespace, dataset = %dataset.uri, "file:// sources need file_seed_root");
+                return Ok(FillState::Failed);
+            }
+        },
+        _ => None,
+    };
+
+    // 1. The claim
+    let pvc = match claims.get_opt(&claim).await? {
+        Some(pvc) => pvc,
+        None => {
+            let pvc = create_claim(&claims, settings, &claim, dataset).await?;
+            info!(event = "pvc_created", namespace = %namespace, claim = %claim, dataset = %dataset.uri, "Created dataset PVC");
+            pvc
+        }
+    };
+
+    let ready = pvc.metadata.annotations.as_ref()
+        .and_then(|a| a.get(READY_ANNOTATION))
+        .map(|v| v == "true")
+        .unwrap_or(false);
+    if ready {
+        return Ok(FillState::Ready);
+    }
+
+    // 2. The downloader Job
+    let job_name = fill_job_name(&claim);
+    let job = match jobs.get_opt(&job_name).await? {
+        Some(job) => job,
+        None => {
+            let job = jobs.create(&PostParams::default(), &fill_job(settings, &pvc, &claim, dataset, seed)).await?;
+            info!(event = "pvc_fill_start", namespace = %namespace, claim = %claim, job = %job_name, "Started PVC downloader job");
+            job
+        }
+    };
+
+    let status = job.status.unwrap_or_default();
+    if status.succeeded.unwrap_or(0) > 0 {
+        let patch = json!({ "metadata": { "annotations": { READY_ANNOTATION: "true" } } });
+        claims.patch(&claim, &PatchParams::default(), &Patch::Merge(patch)).await?;
+        info!(event = "pvc_ready", namespace = %namespace, claim = %claim, "Dataset PVC populated");
+        return Ok(FillState::Ready);
+    }
+
+    let failed = status.conditions.unwrap_or_default().iter()
+        .any(|c| c.type_ == "Failed" && c.status == "True");
+    if failed {
+        error!(event = "pvc_fill_failed", namespace = %namespace, claim = %claim, job = %job_name, "PVC downloader job failed");
+        // Drop the Job so the next gated pod retries
+        let dp = DeleteParams { propagation_policy: Some(PropagationPolicy::Background), ..Default::default() };
+        jobs.delete(&job_name, &dp).await?;
+        return Ok(FillState::Failed);
+    }
+
+    Ok(FillState::Filling)
+}
+
+async fn create_claim(
+    claims: &Api<PersistentVolumeClaim>,
+    settings: &PvcSettings,
+    claim: &str,
+    dataset: &DatasetRef,
+) -> Result<PersistentVolumeClaim, kube::Error> {
+    let pvc: PersistentVolumeClaim = serde_json::from_value(json!({
+        "metadata": {
+            "name": claim,
+            "labels": { "app.kubernetes.io/managed-by": "kube-cache" },
+            "annotations": { DATASET_ANNOTATION: dataset.uri },
+        },
+        "spec": {
+            "accessModes": ["ReadWriteMany"],
+            "storageClassName": settings.storage_class,
+            "resources": { "requests": { "storage": settings.size } },
+        },
+    })).expect("static PVC spec is valid");
+
+    match claims.create(&PostParams::default(), &pvc).await {
+        Err(kube::Error::Api(e)) if e.code == 409 => claims.get(claim).await,
+        result => result,
+    }
+}
+
+// Shell command that copies the dataset to `$TARGET.part`, per source scheme.
+// The default image (aws-cli on Amazon Linux) has both `aws` and `curl`; GCS
+// and Azure datasets need a downloader image with `gcloud` or `azcopy`, OCI
+// artifacts one with `oras`.
+fn fill_command(dataset: &DatasetRef) -> String {
+    let recursive = if dataset.is_prefix() { " --recursive" } else { "" };
+    match dataset.scheme {
+        Scheme::S3 => format!("aws s3 cp{} \"$SOURCE\" \"$TARGET.part\" --endpoint-url \"$S3_ENDPOINT\"", recursive),
+        Scheme::Gs => format!("gcloud storage cp{} \"$SOURCE\" \"$TARGET.part\"", recursive),
+        Scheme::Az => format!("azcopy copy{} \"$SOURCE\" \"$TARGET.part\"", recursive),
+        Scheme::Oci => "oras pull \"$SOURCE\" -o \"$TARGET.part\"".to_string(),
+        Scheme::Http | Scheme::Https => "curl -fsSL -o \"$TARGET.part\" \"$SOURCE\"".to_string(),
+        Scheme::File => format!("cp -r {} \"$TARGET.part\"", SOURCE_MOUNT),
+    }
+}
+
+// azcopy only understands account URLs, oras plain references
+fn fill_source(dataset: &DatasetRef) -> String {
+    match dataset.scheme {
+        Scheme::Az => format!("https://{}.blob.core.windows.net/{}", dataset.bucket, dataset.key),
+        Scheme::Oci => format!("{}/{}", dataset.bucket, dataset.key),
+        _ => dataset.uri.clone(),
+    }
+}
+
+// The downloader writes `<entry>.part` and renames it, like the node cache.
+// Owned by the PVC so deleting the claim cleans up the Job.
+fn fill_job(
+    settings: &PvcSettings,
+    pvc: &PersistentVolumeClaim,
+    claim: &str,
+    dataset: &DatasetRef,
+    seed: Option<PathBuf>,
+) -> Job {
+    // The dataset's own credentials (same namespace as the Job) win
+    let secret = dataset.credentials.as_ref().map(|s| &s.name).or(settings.credentials_secret.as_ref());
+    let env_from = match secret {
+        Some(secret) => json!([{ "secretRef": { "name": secret } }]),
+        None => json!([]),
+    };
+
+    // file:// sources are read from the node the Job lands on
+    let mut mounts = vec![json!({ "name": "data", "mountPath": FILL_MOUNT })];
+    let mut volumes = vec![json!({ "name": "data", "persistentVolumeClaim": { "claimName": claim } })];
+    if let Some(seed) = seed {
+        mounts.push(json!({ "name": "source", "mountPath": SOURCE_MOUNT, "readOnly": true }));
+        volumes.push(json!({ "name": "source", "hostPath": { "path": seed } }));
+    }
+
+    serde_json::from_value(json!({
+        "metadata": {
+            "name": fill_job_name(claim),
+            "labels": { "app.kubernetes.io/managed-by": "kube-cache" },
+            "ownerReferences": [{
+                "apiVersion": "v1",
+                "kind": "PersistentVolumeClaim",
+                "name": claim,
+                "uid": pvc.metadata.uid,
+            }],
+        },
+        "spec": {
+            "backoffLimit": 3,
+            "template": {
+                "spec": {
+                    "restartPolicy": "Never",
+                    "containers": [{
+                        "name": "fill",
+                        "image": settings.downloader_image,
+                        "command": ["sh", "-c",
+                            format!("{} && mv \"$TARGET.part\" \"$TARGET\"", fill_command(dataset))],
+                        "env": [
+                            { "name": "SOURCE", "value": fill_source(dataset) },
+                            { "name": "TARGET", "value": format!("{}/{}", FILL_MOUNT, dataset.entry_name()) },
+                            { "name": "S3_ENDPOINT", "value": settings.s3_endpoint },
+                        ],
+                        "envFrom": env_from,
+                        "volumeMounts": mounts,
+                    }],
+                    "volumes": volumes,
+                },
+            },
+        },
+    })).expect("static Job spec is valid")
+}
+
+// --- REFERENCE COUNTING ---
+// Counts live pods mounting each managed PVC. Unreferenced claims are stamped
+// with the time they went idle and deleted once idle for longer than the TTL.
+pub async fn collect_garbage(client: Client, settings: &PvcSettings) {
+    let claims: Api<PersistentVolumeClaim> = Api::all(client.clone());
+    let mut tick = tokio::time::interval(Duration::from_secs(60));
+
+    loop {
+        tick.tick().await;
+        let list = match claims.list(&ListParams::default().labels(MANAGED_BY)).await {
+            Ok(list) => list,
+            Err(e) => {
+                warn!(event = "pvc_gc_error", error = ?e, "Failed to list dataset PVCs");
+                continue;
+            }
+        };
+
+        for pvc in list.items {
+            let namespace = pvc.metadata.namespace.clone().unwrap_or_default();
+            let name = pvc.metadata.name.clone().unwrap_or_default();
+            if let Err(e) = check_claim(&client, settings, &namespace, &name, &pvc).await {
+                warn!(event = "pvc_gc_error", namespace = %namespace, claim = %name, error = ?e, "Failed to reference-count PVC");
+            }
+        }
+    }
+}
+
+async fn check_claim(
+    client: &Client,
+    settings: &PvcSettings,
+    namespace: &str,
+    name: &str,
+    pvc: &PersistentVolumeClaim,
+) -> Result<(), kube::Error> {
+    let pods: Api<Pod> = Api::namespaced(client.clone(), namespace);
+    let claims: Api<PersistentVolumeClaim> = Api::namespaced(client.clone(), namespace);
+
+    let refs = pods.list(&ListParams::default()).await?.items.iter()
+        .filter(|p| !matches!(p.status.as_ref().and_then(|s| s.phase.as_deref()), Some("Succeeded") | Some("Failed")))
+        .filter(|p| {
+            p.spec.as_ref()
+                .and_then(|s| s.volumes.as_ref())
+                .map(|vols| vols.iter().any(|v| {
+                    v.persistent_volume_claim.as_ref().map(|c| c.claim_name == name).unwrap_or(false)
+                }))
+                .unwrap_or(false)
+        })
+        .count();
+
+    let annotations = pvc.metadata.annotations.clone().unwrap_or_default();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    if refs > 0 {
+        if annotations.get(REFS_ANNOTATION) != Some(&refs.to_string()) || annotations.contains_key(IDLE_SINCE_ANNOTATION) {
+            let patch = json!({ "metadata": { "annotations": { REFS_ANNOTATION: refs.to_string(), IDLE_SINCE_ANNOTATION: null } } });
+            claims.patch(name, &PatchParams::default(), &Patch::Merge(patch)).await?;
+        }
+        return Ok(());
+    }
+
+    match annotations.get(IDLE_SINCE_ANNOTATION).and_then(|v| v.parse::<u64>().ok()) {
+        Some(since) if now.saturating_sub(since) >= settings.idle_ttl.as_secs() => {
+            claims.delete(name, &DeleteParams::default()).await?;
+            info!(event = "pvc_deleted", namespace = %namespace, claim = %name, "Deleted unused dataset PVC");
+        }
+        Some(_) => {}
+        None => {
+            let patch = json!({ "metadata": { "annotations": { REFS_ANNOTATION: "0", IDLE_SINCE_ANNOTATION: now.to_string() } } });
+            claims.patch(name, &PatchParams::default(), &Patch::Merge(patch)).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(uri: &str) -> DatasetRef {
+        DatasetRef::parse(uri).unwrap()
+    }
+
+    #[test]
+    fn claim_names_are_stable_per_dataset() {
+        // Existing claims are found by this name; it must never change
+        assert_eq!(claim_name(&source("s3://b/models/weights.bin")), "kube-cache-4383f6cfabdf7065");
+        assert_ne!(claim_name(&source("s3://b/models/weights.bin")), claim_name(&source("s3://b/models/")));
+    }
+
+    #[test]
+    fn fill_commands_match_the_source() {
+        assert_eq!(
+            fill_command(&source("s3://b/models/weights.bin")),
+            "aws s3 cp \"$SOURCE\" \"$TARGET.part\" --endpoint-url \"$S3_ENDPOINT\"",
+        );
+        assert_eq!(fill_command(&source("gs://bucket/shards/")), "gcloud storage cp --recursive \"$SOURCE\" \"$TARGET.part\"");
+        assert_eq!(fill_command(&source("az://acct/models/w.bin")), "azcopy copy \"$SOURCE\" \"$TARGET.part\"");
+        assert_eq!(fill_command(&source("oci://registry.io/models/llama:v1")), "oras pull \"$SOURCE\" -o \"$TARGET.part\"");
+        assert_eq!(fill_command(&source("https://host/w.bin")), "curl -fsSL -o \"$TARGET.part\" \"$SOURCE\"");
+        assert_eq!(fill_command(&source("file:///seed/models/")), "cp -r /source \"$TARGET.part\"");
+
+        assert_eq!(fill_source(&source("az://acct/models/w.bin")), "https://acct.blob.core.windows.net/models/w.bin");
+        assert_eq!(fill_source(&source("oci://registry.io/models/llama:v1")), "registry.io/models/llama:v1");
+    }
+}
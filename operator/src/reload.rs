@@ -0,0 +1,119 @@
+// --- LIVE CONFIGURATION ---
+// Follows the operator's own ConfigMap (`config_map`) so tuning does not need
+// a restart, which would interrupt in-flight downloads. The ConfigMap's
+// `config_map_key` is layered under flags and env exactly like the startup
+// config file, then validated; an invalid edit is logged and ignored.
+//
+// Settings in LIVE_SETTINGS are applied on the fly: the log level here, the
+// rest by the watch loop from the published config. Any other setting that
+// differs from what the process started with is reported through the
+// `config_restart_required{setting}` gauge and a warning until the next restart.
+
+use crate::config::{Config, ConfigSource};
+use crate::metrics::MetricsState;
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::runtime::watcher::{self, watcher, Event};
+use kube::{Api, Client};
+use std::path::Path;
+use tokio::sync::watch;
+use tracing::{info, warn};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Handle for swapping the log filter at runtime.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Settings that take effect without a restart.
+pub const LIVE_SETTINGS: &[&str] = &[
+    "log_level",
+    "download_concurrency",
+    "queue_aging_seconds",
+    "node_bandwidth_bytes_per_sec",
+    "cluster_bandwidth_bytes_per_sec",
+    "max_concurrent_s3_requests",
+    "fail_open",
+];
+
+/// Watches the ConfigMap and publishes every valid change on `updates`.
+pub async fn watch_config_map(
+    client: Client,
+    source: ConfigSource,
+    updates: watch::Sender<Config>,
+    log: LogHandle,
+    metrics_state: MetricsState,
+) {
+    let startup = updates.borrow().clone();
+    let Some(name) = startup.config_map.clone() else { return };
+    let key = startup.config_map_key.clone();
+    let origin = format!("configmap/{}/{}", name, key);
+
+    let config_maps: Api<ConfigMap> = Api::namespaced(client, &startup.pod_namespace);
+    let selector = format!("metadata.name={}", name);
+    let mut events = watcher(config_maps, watcher::Config::default().fields(&selector)).boxed();
+    info!(event = "config_watch", config_map = %name, key = %key, "Following ConfigMap for live configuration");
+    let mut reported: Vec<String> = Vec::new();
+
+    while let Some(event) = events.next().await {
+        let config_map = match event {
+            Ok(Event::Apply(cm)) | Ok(Event::InitApply(cm)) => cm,
+            Ok(Event::Delete(_)) => {
+                warn!(event = "config_map_deleted", config_map = %name, "ConfigMap deleted, keeping current configuration");
+                continue;
+            }
+            Ok(_) => continue,
+            Err(e) => {
+                warn!(event = "config_watch_error", error = %e, "ConfigMap watch error");
+                continue;
+            }
+        };
+
+        let text = config_map.data.as_ref().and_then(|d| d.get(&key)).cloned().unwrap_or_default();
+        let next = match source.layer(Path::new(&origin), &text) {
+            Ok(next) => next,
+            Err(e) => {
+                warn!(event = "config_rejected", error = %e, "Ignoring invalid configuration change");
+                metrics_state.count_config_reload("rejected");
+                continue;
+            }
+        };
+
+        let current = updates.borrow().clone();
+        let live: Vec<String> = changed(&current, &next).into_iter()
+            .filter(|s| LIVE_SETTINGS.contains(&s.as_str()))
+            .collect();
+        let pending: Vec<String> = changed(&startup, &next).into_iter()
+            .filter(|s| !LIVE_SETTINGS.contains(&s.as_str()))
+            .collect();
+
+        if next.log_level != current.log_level {
+            if let Err(e) = log.modify(|filter| *filter = EnvFilter::new(&next.log_level)) {
+                warn!(event = "log_level_error", error = %e, "Failed to change log level");
+            }
+        }
+
+        if pending != reported {
+            metrics_state.set_restart_required(&pending);
+            if !pending.is_empty() {
+                warn!(event = "config_restart_required", settings = ?pending, "Changed settings take effect after a restart");
+            }
+            reported = pending;
+        }
+
+        if !live.is_empty() {
+            info!(event = "config_reloaded", settings = ?live, "Applied configuration change");
+            metrics_state.count_config_reload("applied");
+            updates.send_replace(next);
+        }
+    }
+}
+
+/// Names of the settings that differ between two configurations.
+fn changed(a: &Config, b: &Config) -> Vec<String> {
+    let a = serde_json::to_value(a).expect("config serializes");
+    let b = serde_json::to_value(b).expect("config serializes");
+    let (Some(a), Some(b)) = (a.as_object(), b.as_object()) else { return Vec::new() };
+    a.iter()
+        .filter(|(key, value)| b.get(key.as_str()) != Some(value))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
@@ -0,0 +1,449 @@
+// --- DOWNLOAD SCHEDULER ---
+// Pending downloads are ordered by the waiting pods' priority, so production
+// inference pods do not sit behind a low-priority batch job. Aging works on
+// ranks rather than raw values: every aging interval an entry waits lifts it
+// one rank among the distinct priorities pending, so it overtakes the next
+// class up however far apart their PriorityClass values are (they range up
+// to 1e9), and nothing starves forever.
+//
+// Pre-warming queues datasets nobody waits for yet. They go behind every
+// waiting pod, and turn into a normal entry if a pod asks for them meanwhile.
+//
+// The effective priority changes with time, so a BinaryHeap would go stale;
+// the queue is a plain Vec that is scanned on pop (it only ever holds a few
+// hundred entries).
+
+use crate::dataset::DatasetRef;
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::api::scheduling::v1::PriorityClass;
+use kube::api::{Patch, PatchParams};
+use kube::{Api, Client};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Shows each gated pod its place in line.
+pub const QUEUE_POSITION_ANNOTATION: &str = "kube-cache.openai.com/queue-position";
+
+/// Failed attempts so far for a pod whose data is retried.
+pub const DOWNLOAD_FAILURES_ANNOTATION: &str = "kube-cache.openai.com/download-failures";
+
+/// A gated pod waiting on a download.
+#[derive(Clone)]
+pub struct Waiter {
+    pub pod: Pod,
+    pub priority: i32,
+}
+
+impl Waiter {
+    pub fn id(&self) -> (String, String) {
+        (
+            self.pod.metadata.namespace.clone().unwrap_or_default(),
+            self.pod.metadata.name.clone().unwrap_or_default(),
+        )
+    }
+}
+
+/// A unit of work handed to a download worker.
+pub struct DownloadJob {
+    pub dataset: DatasetRef,
+    pub path: String,
+    pub waited: Duration,
+    /// Pre-warming only; no pod was waiting when it started
+    pub prewarm: bool,
+}
+
+/// Sent back to the watch loop when a download finishes.
+pub struct Completed {
+    pub path: String,
+    pub ok: bool,
+    pub waiters: Vec<Waiter>,
+}
+
+struct Entry {
+    dataset: DatasetRef,
+    path: String,
+    enqueued: Instant,
+    waiters: Vec<Waiter>,
+    /// Queued by pre-warming; kept even once no pod waits on it
+    prewarm: bool,
+}
+
+impl Entry {
+    fn priority(&self) -> Option<i32> {
+        self.waiters.iter().map(|w| w.priority).max()
+    }
+
+    fn intervals(&self, now: Instant, aging: Duration) -> u64 {
+        now.duration_since(self.enqueued).as_secs() / aging.as_secs().max(1)
+    }
+}
+
+// Indices into `pending` in the order workers will take them: by priority
+// rank plus aging intervals, then first come first served. Entries nobody
+// waits on (pre-warming) come last and do not age.
+fn pop_order(pending: &[Entry], now: Instant, aging: Duration) -> Vec<usize> {
+    let mut ranks: Vec<i32> = pending.iter().filter_map(Entry::priority).collect();
+    ranks.sort_unstable();
+    ranks.dedup();
+    let score = |entry: &Entry| entry.priority().map(|priority| {
+        let rank = ranks.binary_search(&priority).unwrap_or_default() as u64;
+        rank + entry.intervals(now, aging)
+    });
+
+    let mut order: Vec<usize> = (0..pending.len()).collect();
+    // Stable, so ties keep enqueue order
+    order.sort_by_key(|&i| std::cmp::Reverse(score(&pending[i])));
+    order
+}
+
+#[derive(Default)]
+struct Inner {
+    pending: Vec<Entry>,
+    // path -> pods that attached while the download was running
+    active: HashMap<String, Vec<Waiter>>,
+}
+
+pub struct DownloadQueue {
+    inner: Mutex<Inner>,
+    notify: Notify,
+    aging_secs: AtomicU64,
+}
+
+impl DownloadQueue {
+    pub fn new(aging: Duration) -> Self {
+        Self {
+            inner: Mutex::new(Inner::default()),
+            notify: Notify::new(),
+            aging_secs: AtomicU64::new(aging.as_secs()),
+        }
+    }
+
+    /// Changes the aging interval; applies to entries already waiting too.
+    pub fn set_aging(&self, aging: Duration) {
+        self.aging_secs.store(aging.as_secs(), Ordering::Relaxed);
+    }
+
+    fn aging(&self) -> Duration {
+        Duration::from_secs(self.aging_secs.load(Ordering::Relaxed))
+    }
+
+    /// Adds a pod to the queue. Pods asking for a dataset that is already
+    /// queued or downloading share that download. Returns false if the pod
+    /// was already waiting.
+    pub fn enqueue(&self, dataset: DatasetRef, path: String, waiter: Waiter) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let id = waiter.id();
+
+        if let Some(waiters) = inner.active.get_mut(&path) {
+            if waiters.iter().any(|w| w.id() == id) {
+                return false;
+            }
+            waiters.push(waiter);
+            return true;
+        }
+
+        if let Some(entry) = inner.pending.iter_mut().find(|e| e.path == path) {
+            if entry.waiters.iter().any(|w| w.id() == id) {
+                return false;
+            }
+            entry.waiters.push(waiter);
+            return true;
+        }
+
+        inner.pending.push(Entry {
+            dataset,
+            path,
+            enqueued: Instant::now(),
+            waiters: vec![waiter],
+            prewarm: false,
+        });
+        drop(inner);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Queues a download no pod waits for (pre-warming). Returns false if
+    /// the dataset is already queued or downloading.
+    pub fn enqueue_prewarm(&self, dataset: DatasetRef, path: String) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.active.contains_key(&path) || inner.pending.iter().any(|e| e.path == path) {
+            return false;
+        }
+
+        inner.pending.push(Entry {
+            dataset,
+            path,
+            enqueued: Instant::now(),
+            waiters: Vec::new(),
+            prewarm: true,
+        });
+        drop(inner);
+        self.notify.notify_one();
+        true
+    }
+
+    /// Waits for the highest (aged) priority entry and marks it active.
+    pub async fn pop(&self) -> DownloadJob {
+        loop {
+            // Register interest before checking, so a concurrent enqueue is not missed
+            let notified = self.notify.notified();
+            if let Some(job) = self.try_pop() {
+                return job;
+            }
+            notified.await;
+        }
+    }
+
+    fn try_pop(&self) -> Option<DownloadJob> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let best = *pop_order(&inner.pending, now, self.aging()).first()?;
+
+        let entry = inner.pending.remove(best);
+        let prewarm = entry.waiters.is_empty();
+        inner.active.insert(entry.path.clone(), entry.waiters);
+        Some(DownloadJob {
+            dataset: entry.dataset,
+            path: entry.path,
+            waited: now.duration_since(entry.enqueued),
+            prewarm,
+        })
+    }
+
+    /// Marks a download as done and hands back every pod that was waiting on it.
+    pub fn finish(&self, path: &str, ok: bool) -> Completed {
+        let waiters = self.inner.lock().unwrap().active.remove(path).unwrap_or_default();
+        Completed { path: path.to_string(), ok, waiters }
+    }
+
+    /// Drops a deleted pod from whatever it was waiting on.
+    pub fn forget(&self, namespace: &str, name: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let id = (namespace.to_string(), name.to_string());
+        for entry in inner.pending.iter_mut() {
+            entry.waiters.retain(|w| w.id() != id);
+        }
+        inner.pending.retain(|e| e.prewarm || !e.waiters.is_empty());
+        for waiters in inner.active.values_mut() {
+            waiters.retain(|w| w.id() != id);
+        }
+    }
+
+    /// 1-based queue position of every pod still waiting for a worker,
+    /// in the order workers will pick them up.
+    pub fn positions(&self) -> Vec<((String, String), usize)> {
+        let inner = self.inner.lock().unwrap();
+        pop_order(&inner.pending, Instant::now(), self.aging())
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, e)| inner.pending[e].waiters.iter().map(move |w| (w.id(), i + 1)))
+            .collect()
+    }
+
+    /// True if the dataset at `path` is queued or downloading.
+    pub fn contains(&self, path: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.active.contains_key(path) || inner.pending.iter().any(|e| e.path == path)
+    }
+
+    pub fn depth(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+}
+
+/// The pod's scheduling priority. Admission normally resolves
+/// `priorityClassName` into `spec.priority`; if it has not, look the class up.
+pub async fn pod_priority(client: &Client, pod: &Pod) -> i32 {
+    let Some(spec) = pod.spec.as_ref() else { return 0 };
+    if let Some(priority) = spec.priority {
+        return priority;
+    }
+
+    match spec.priority_class_name.as_deref() {
+        Some(class) => {
+            let classes: Api<PriorityClass> = Api::all(client.clone());
+            classes.get(class).await.map(|pc| pc.value).unwrap_or(0)
+        }
+        None => 0,
+    }
+}
+
+/// How long a failed download waits before it is retried.
+pub const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Pods with a retry pending, so the watch events arriving meanwhile
+/// (status updates, queue positions, other controllers) do not each start
+/// another one.
+#[derive(Clone, Default)]
+pub struct Retries(Arc<Mutex<HashSet<(String, String)>>>);
+
+impl Retries {
+    /// Retries the pod later, unless a retry is already pending.
+    pub fn schedule(&self, client: &Client, pod: &Pod) {
+        let id = (pod.metadata.namespace.clone().unwrap_or_default(), pod.metadata.name.clone().unwrap_or_default());
+        if self.0.lock().unwrap().insert(id.clone()) {
+            tokio::spawn(retry_later(client.clone(), pod.clone(), id, self.clone()));
+        }
+    }
+
+    pub fn pending(&self, id: &(String, String)) -> bool {
+        self.0.lock().unwrap().contains(id)
+    }
+}
+
+// After a pause the pod's failure count is bumped, and the resulting watch
+// event tries it again.
+async fn retry_later(client: Client, pod: Pod, (namespace, name): (String, String), retries: Retries) {
+    tokio::time::sleep(RETRY_DELAY).await;
+    // Before the patch, so the event it causes can schedule the next retry
+    retries.0.lock().unwrap().remove(&(namespace.clone(), name.clone()));
+
+    let failures = pod.metadata.annotations.as_ref()
+        .and_then(|a| a.get(DOWNLOAD_FAILURES_ANNOTATION))
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0) + 1;
+
+    let patch = json!({
+        "metadata": { "annotations": { DOWNLOAD_FAILURES_ANNOTATION: failures.to_string() } }
+    });
+    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+    if let Err(e) = pods.patch(&name, &PatchParams::default(), &Patch::Merge(patch)).await {
+        // Usually the pod was deleted meanwhile
+        warn!(event = "retry_error", pod_name = %name, error = ?e, "Failed to schedule download retry");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn waiter(name: &str, priority: i32) -> Waiter {
+        let mut pod = Pod::default();
+        pod.metadata.namespace = Some("ml".to_string());
+        pod.metadata.name = Some(name.to_string());
+        Waiter { pod, priority }
+    }
+
+    fn dataset(key: &str) -> (DatasetRef, String) {
+        let dataset = DatasetRef::parse(&format!("s3://bucket/{}", key)).unwrap();
+        let path = dataset.cache_path("/cache");
+        (dataset, path)
+    }
+
+    fn enqueue(queue: &DownloadQueue, key: &str, pod: &str, priority: i32) -> bool {
+        let (dataset, path) = dataset(key);
+        queue.enqueue(dataset, path, waiter(pod, priority))
+    }
+
+    fn entry(key: &str, priority: Option<i32>, enqueued: Instant) -> Entry {
+        let (dataset, path) = dataset(key);
+        Entry {
+            dataset,
+            path,
+            enqueued,
+            waiters: priority.map(|p| vec![waiter(key, p)]).unwrap_or_default(),
+            prewarm: priority.is_none(),
+        }
+    }
+
+    fn popped(queue: &DownloadQueue) -> String {
+        queue.try_pop().unwrap().dataset.key
+    }
+
+    #[test]
+    fn pops_by_priority_then_arrival() {
+        let queue = DownloadQueue::new(MINUTE);
+        enqueue(&queue, "batch", "b", 0);
+        enqueue(&queue, "serving", "s", 1_000_000);
+        enqueue(&queue, "batch-2", "b2", 0);
+        assert_eq!(popped(&queue), "serving");
+        assert_eq!(popped(&queue), "batch");
+        assert_eq!(popped(&queue), "batch-2");
+        assert!(queue.try_pop().is_none());
+    }
+
+    #[test]
+    fn pods_share_a_download() {
+        let queue = DownloadQueue::new(MINUTE);
+        assert!(enqueue(&queue, "w", "a", 0));
+        assert!(enqueue(&queue, "w", "b", 5));
+        assert!(!enqueue(&queue, "w", "a", 0));
+        assert_eq!(queue.depth(), 1);
+
+        let job = queue.try_pop().unwrap();
+        assert!(enqueue(&queue, "w", "c", 0));
+        let done = queue.finish(&job.path, true);
+        assert_eq!(done.waiters.len(), 3);
+        assert!(!queue.contains(&job.path));
+    }
+
+    #[test]
+    fn aging_climbs_one_rank_per_interval() {
+        let start = Instant::now();
+        let later = start + 2 * MINUTE;
+        let pending = vec![
+            entry("low", Some(0), start),
+            entry("mid", Some(1_000), later),
+            entry("high", Some(1_000_000_000), later),
+        ];
+        assert_eq!(pop_order(&pending, start, MINUTE), vec![2, 1, 0]);
+        // One interval ties the lowest class with the middle one; it came first
+        assert_eq!(pop_order(&pending, start + MINUTE, MINUTE), vec![2, 0, 1]);
+        // Two lift it level with the highest
+        assert_eq!(pop_order(&pending, later, MINUTE), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn prewarm_goes_last_until_a_pod_asks() {
+        let queue = DownloadQueue::new(MINUTE);
+        let (warm, path) = dataset("warm");
+        assert!(queue.enqueue_prewarm(warm.clone(), path.clone()));
+        assert!(!queue.enqueue_prewarm(warm, path.clone()));
+        enqueue(&queue, "other", "o", -100);
+        assert_eq!(popped(&queue), "other");
+
+        let start = Instant::now();
+        let pending = vec![entry("warm", None, start), entry("pod", Some(i32::MIN), start)];
+        assert_eq!(pop_order(&pending, start + 60 * MINUTE, MINUTE), vec![1, 0]);
+
+        enqueue(&queue, "warm", "p", 0);
+        assert_eq!(queue.positions(), vec![(("ml".to_string(), "p".to_string()), 1)]);
+        let job = queue.try_pop().unwrap();
+        assert!(!job.prewarm);
+        assert_eq!(job.path, path);
+    }
+
+    #[test]
+    fn forget_drops_pods_but_keeps_prewarm() {
+        let queue = DownloadQueue::new(MINUTE);
+        enqueue(&queue, "a", "a", 0);
+        let (warm, path) = dataset("warm");
+        queue.enqueue_prewarm(warm, path.clone());
+        enqueue(&queue, "warm", "w", 0);
+
+        queue.forget("ml", "a");
+        queue.forget("ml", "w");
+        assert_eq!(queue.depth(), 1);
+        assert!(queue.contains(&path));
+        assert!(queue.positions().is_empty());
+    }
+
+    #[test]
+    fn positions_follow_pop_order() {
+        let queue = DownloadQueue::new(MINUTE);
+        enqueue(&queue, "a", "a", 0);
+        enqueue(&queue, "b", "b", 10);
+        enqueue(&queue, "b", "b2", 0);
+        let mut positions = queue.positions();
+        positions.sort();
+        let id = |name: &str| ("ml".to_string(), name.to_string());
+        assert_eq!(positions, vec![(id("a"), 2), (id("b"), 1), (id("b2"), 1)]);
+    }
+}
@@ -0,0 +1,302 @@
+// Azure Blob Storage through the REST API. `az://account/container/blob`.
+// Credentials, first match wins:
+//
+//   AZURE_STORAGE_KEY           account key, signed per request (SharedKey);
+//                               Azurite's well-known key works here
+//   AZURE_STORAGE_SAS_TOKEN     SAS query string appended to every request
+//   AZURE_FEDERATED_TOKEN_FILE  workload identity: the projected service account
+//     + AZURE_CLIENT_ID,        token is exchanged for an Entra ID access token
+//       AZURE_TENANT_ID         (the env the workload identity webhook injects)
+//   none                        public containers
+//
+// `azure_endpoint` switches to path-style URLs for emulators,
+// `<endpoint>/<account>/<container>/<blob>`. Blobs uploaded with a
+// Content-MD5 are checked against it after download.
+
+use super::http::{backend_error, check_status};
+use super::token::{TokenCache, TokenResponse};
+use super::{ByteStream, Checksum, ObjectInfo, StorageBackend, StorageError};
+use crate::dataset::DatasetRef;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::{StreamExt, TryStreamExt};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH, ETAG, IF_MATCH, RANGE};
+use reqwest::{Method, StatusCode, Url};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::ops::Range;
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+const API_VERSION: &str = "2021-08-06";
+const STORAGE_SCOPE: &str = "https://storage.azure.com/.default";
+const ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+const DEFAULT_AUTHORITY: &str = "https://login.microsoftonline.com/";
+
+// Path segments keep their '/' separators; everything else unusual is escaped
+const BLOB_PATH: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/').remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+enum Credentials {
+    SharedKey(Vec<u8>),
+    Sas(String),
+    WorkloadIdentity { client_id: String, tenant_id: String, token_file: String, authority: String },
+    Anonymous,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResults {
+    blobs: Blobs,
+    next_marker: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Blobs {
+    #[serde(rename = "Blob", default)]
+    blobs: Vec<Blob>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Blob {
+    name: String,
+    properties: Properties,
+}
+
+#[derive(Deserialize)]
+struct Properties {
+    #[serde(rename = "Content-Length")]
+    content_length: u64,
+    #[serde(rename = "Etag")]
+    etag: Option<String>,
+    #[serde(rename = "Content-MD5")]
+    content_md5: Option<String>,
+}
+
+pub struct AzureBackend {
+    client: reqwest::Client,
+    /// Path-style endpoint for emulators
+    endpoint: Option<String>,
+    credentials: Credentials,
+    token: TokenCache,
+}
+
+impl AzureBackend {
+    pub fn new(endpoint: Option<&str>) -> Self {
+        let env = |name| std::env::var(name).ok().filter(|v: &String| !v.is_empty());
+        let credentials = if let Some(key) = env("AZURE_STORAGE_KEY") {
+            match BASE64.decode(key.trim()) {
+                Ok(key) => Credentials::SharedKey(key),
+                Err(e) => {
+                    warn!(event = "azure_credentials_error", error = %e, "AZURE_STORAGE_KEY is not base64, ignoring it");
+                    Credentials::Anonymous
+                }
+            }
+        } else if let Some(sas) = env("AZURE_STORAGE_SAS_TOKEN") {
+            Credentials::Sas(sas.trim_start_matches('?').to_string())
+        } else if let (Some(token_file), Some(client_id), Some(tenant_id)) =
+            (env("AZURE_FEDERATED_TOKEN_FILE"), env("AZURE_CLIENT_ID"), env("AZURE_TENANT_ID"))
+        {
+            let authority = env("AZURE_AUTHORITY_HOST").unwrap_or_else(|| DEFAULT_AUTHORITY.to_string());
+            Credentials::WorkloadIdentity { client_id, tenant_id, token_file, authority }
+        } else {
+            Credentials::Anonymous
+        };
+        let kind = match &credentials {
+            Credentials::SharedKey(_) => "shared_key",
+            Credentials::Sas(_) => "sas",
+            Credentials::WorkloadIdentity { .. } => "workload_identity",
+            Credentials::Anonymous => "anonymous",
+        };
+        let endpoint = endpoint.map(|e| e.trim_end_matches('/').to_string());
+        info!(event = "config_check", endpoint = ?endpoint, credentials = kind, "Configured Azure Blob access");
+
+        Self { client: reqwest::Client::new(), endpoint, credentials, token: TokenCache::new() }
+    }
+
+    // `path` is `container[/blob]`, unescaped
+    fn url(&self, account: &str, path: &str, query: &[(&str, String)]) -> Result<Url, StorageError> {
+        let path = utf8_percent_encode(path, BLOB_PATH);
+        let base = match &self.endpoint {
+            Some(endpoint) => format!("{}/{}/{}", endpoint, account, path),
+            None => format!("https://{}.blob.core.windows.net/{}", account, path),
+        };
+        // Percent-encode by hand: Azure does not read '+' as a space
+        let mut params: Vec<String> = query.iter()
+            .map(|(name, value)| format!("{}={}", name, utf8_percent_encode(value, QUERY_VALUE)))
+            .collect();
+        if let Credentials::Sas(sas) = &self.credentials {
+            params.push(sas.clone());
+        }
+
+        let mut url = Url::parse(&base).map_err(|e| StorageError::Backend(format!("{}: {}", base, e)))?;
+        if !params.is_empty() {
+            url.set_query(Some(&params.join("&")));
+        }
+        Ok(url)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        object: &DatasetRef,
+        path: &str,
+        query: &[(&str, String)],
+        range: Option<&Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<reqwest::Response, StorageError> {
+        let account = &object.bucket;
+        let url = self.url(account, path, query)?;
+        let date = httpdate::fmt_http_date(SystemTime::now());
+        let range = range.map(|r| format!("bytes={}-{}", r.start, r.end.saturating_sub(1)));
+
+        let mut req = self.client.request(method.clone(), url.clone())
+            .header("x-ms-date", &date)
+            .header("x-ms-version", API_VERSION);
+        if let Some(range) = &range {
+            req = req.header(RANGE, range);
+        }
+        if let Some(etag) = if_match {
+            req = req.header(IF_MATCH, etag);
+        }
+
+        match &self.credentials {
+            Credentials::SharedKey(key) => {
+                let string_to_sign = format!(
+                    "{}\n\n\n\n\n\n\n\n{}\n\n\n{}\nx-ms-date:{}\nx-ms-version:{}\n{}",
+                    method,
+                    if_match.unwrap_or(""),
+                    range.as_deref().unwrap_or(""),
+                    date,
+                    API_VERSION,
+                    canonical_resource(account, &url),
+                );
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+                mac.update(string_to_sign.as_bytes());
+                let signature = BASE64.encode(mac.finalize().into_bytes());
+                req = req.header(AUTHORIZATION, format!("SharedKey {}:{}", account, signature));
+            }
+            Credentials::WorkloadIdentity { client_id, tenant_id, token_file, authority } => {
+                let token = self.token.get(|| self.exchange_token(client_id, tenant_id, token_file, authority)).await?;
+                req = req.header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            Credentials::Sas(_) | Credentials::Anonymous => {}
+        }
+
+        let resp = req.send().await.map_err(backend_error)?;
+        check_status(object, resp)
+    }
+
+    // The projected token is rotated by the kubelet, so read it every time
+    async fn exchange_token(
+        &self,
+        client_id: &str,
+        tenant_id: &str,
+        token_file: &str,
+        authority: &str,
+    ) -> Result<TokenResponse, StorageError> {
+        let assertion = tokio::fs::read_to_string(token_file).await
+            .map_err(|e| StorageError::Auth(format!("cannot read {}: {}", token_file, e)))?;
+        let url = format!("{}/{}/oauth2/v2.0/token", authority.trim_end_matches('/'), tenant_id);
+        let resp = self.client.post(url)
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("client_id", client_id),
+                ("scope", STORAGE_SCOPE),
+                ("client_assertion_type", ASSERTION_TYPE),
+                ("client_assertion", assertion.trim()),
+            ])
+            .send()
+            .await
+            .map_err(|e| StorageError::Auth(e.to_string()))?;
+        TokenResponse::read(resp).await
+    }
+}
+
+// `/<account><path>` plus the query parameters sorted by name, one per line
+fn canonical_resource(account: &str, url: &Url) -> String {
+    let mut params: Vec<(String, String)> = url.query_pairs()
+        .map(|(k, v)| (k.to_lowercase(), v.into_owned()))
+        .collect();
+    params.sort();
+
+    let mut resource = format!("/{}{}", account, url.path());
+    for (name, value) in params {
+        resource.push_str(&format!("\n{}:{}", name, value));
+    }
+    resource
+}
+
+fn split_key(object: &DatasetRef) -> (&str, &str) {
+    object.key.split_once('/').unwrap_or((&object.key, ""))
+}
+
+#[async_trait]
+impl StorageBackend for AzureBackend {
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError> {
+        let resp = self.send(Method::HEAD, object, &object.key, &[], None, None).await?;
+        let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok());
+
+        let size = header(CONTENT_LENGTH.as_str())
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| StorageError::Backend(format!("{}: no Content-Length", object.uri)))?;
+        Ok(ObjectInfo {
+            key: object.key.clone(),
+            size,
+            etag: header(ETAG.as_str()).map(str::to_string),
+            checksum: header("content-md5").and_then(Checksum::md5_base64),
+        })
+    }
+
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError> {
+        let (container, blob_prefix) = split_key(prefix);
+        let mut objects = Vec::new();
+        let mut marker: Option<String> = None;
+
+        loop {
+            let mut query = vec![
+                ("restype", "container".to_string()),
+                ("comp", "list".to_string()),
+                ("prefix", blob_prefix.to_string()),
+            ];
+            if let Some(marker) = marker.take() {
+                query.push(("marker", marker));
+            }
+            let resp = self.send(Method::GET, prefix, container, &query, None, None).await?;
+            let body = resp.text().await.map_err(backend_error)?;
+            let page: EnumerationResults = quick_xml::de::from_str(&body)
+                .map_err(|e| StorageError::Backend(format!("{}: bad blob listing: {}", prefix.uri, e)))?;
+
+            objects.extend(page.blobs.blobs.into_iter().map(|blob| ObjectInfo {
+                key: format!("{}/{}", container, blob.name),
+                size: blob.properties.content_length,
+                etag: blob.properties.etag,
+                checksum: blob.properties.content_md5.as_deref().and_then(Checksum::md5_base64),
+            }));
+
+            match page.next_marker {
+                Some(next) if !next.is_empty() => marker = Some(next),
+                _ => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError> {
+        let resp = self.send(Method::GET, object, &object.key, &[], range.as_ref(), if_match).await?;
+        if range.is_some() && resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(StorageError::Unsupported(format!("{}: server ignored the Range header", object.uri)));
+        }
+        Ok(resp.bytes_stream().map_err(backend_error).boxed())
+    }
+}
@@ -0,0 +1,199 @@
+// --- TENANT CREDENTIALS ---
+// A dataset may name a Secret in the pod's namespace (see `dataset`) so the
+// tenant's own identity reads the source instead of the operator's. The keys
+// are the ones the PVC downloader takes:
+//
+//   AWS_ACCESS_KEY_ID, AWS_SECRET_ACCESS_KEY  static keys (+ AWS_SESSION_TOKEN)
+//   AWS_ROLE_ARN                              role to assume with those keys, or
+//                                             with the operator's identity if allowed
+//                                             (+ AWS_EXTERNAL_ID, AWS_ROLE_SESSION_NAME)
+//   AWS_REGION                                optional
+//
+// One S3 client per endpoint (`s3_endpoint`, then `s3_mirror_endpoints`) is
+// built per Secret and rebuilt when the Secret changes. Only
+// s3:// sources take tenant credentials so far.
+//
+// With `allow_operator_credentials: false`, datasets that name no Secret are
+// refused (plain HTTP(S) excepted) rather than read with the operator's identity.
+//
+// Cache entries are shared by every pod wanting the same URI, whichever
+// identity downloaded them. So before a pod gets one, `check_access` proves
+// the identity that pod would read with (its Secret, or the operator's) can
+// read the URI itself.
+
+use super::{file, S3Backend, StorageError};
+use crate::dataset::{DatasetRef, Scheme, SecretRef};
+use aws_config::sts::AssumeRoleProvider;
+use aws_sdk_s3::config::{Credentials, SharedCredentialsProvider};
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::info;
+
+/// How long a successful access check is trusted before it is repeated.
+const ACCESS_CHECK_TTL: Duration = Duration::from_secs(300);
+
+/// One client per endpoint, in endpoint order
+type EndpointClients = Vec<Arc<S3Backend>>;
+
+pub struct TenantClients {
+    client: Client,
+    endpoints: Vec<String>,
+    /// The operator's own client on the primary endpoint
+    operator: Arc<S3Backend>,
+    allow_operator: bool,
+    /// file:// keys resolve under this; unset refuses file:// sources
+    file_seed_root: Option<PathBuf>,
+    /// Clients per Secret (one per endpoint), with the Secret's resourceVersion
+    /// they were built from
+    clients: Mutex<HashMap<SecretRef, (String, EndpointClients)>>,
+    /// Successful access checks per (Secret, or None for the operator, dataset URI)
+    verified: std::sync::Mutex<HashMap<(Option<SecretRef>, String), Instant>>,
+}
+
+impl TenantClients {
+    pub fn new(
+        client: Client,
+        endpoints: Vec<String>,
+        operator: Arc<S3Backend>,
+        allow_operator: bool,
+        file_seed_root: Option<&str>,
+    ) -> Self {
+        Self {
+            client,
+            endpoints,
+            operator,
+            allow_operator,
+            file_seed_root: file_seed_root.map(PathBuf::from),
+            clients: Mutex::new(HashMap::new()),
+            verified: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Refuses datasets that would be read with the operator's identity when
+    /// that is not allowed, that name credentials their scheme cannot use, or
+    /// file:// sources outside the seed root.
+    pub fn permit(&self, dataset: &DatasetRef) -> Result<(), StorageError> {
+        if dataset.scheme == Scheme::File {
+            let Some(root) = &self.file_seed_root else {
+                return Err(StorageError::Unsupported(format!(
+                    "{}: file:// sources are disabled (no file_seed_root is configured)", dataset.uri
+                )));
+            };
+            file::seed_path(root, &dataset.key)?;
+        }
+        match (&dataset.credentials, dataset.scheme) {
+            (Some(_), Scheme::S3) => Ok(()),
+            (Some(secret), scheme) => Err(StorageError::Unsupported(format!(
+                "credentials secret {} for a {}:// source (only s3:// takes tenant credentials)",
+                secret, scheme.as_str()
+            ))),
+            (None, Scheme::Http | Scheme::Https) => Ok(()),
+            (None, _) if self.allow_operator => Ok(()),
+            (None, _) => Err(StorageError::Auth(format!(
+                "{} names no credentials secret and operator credentials are disabled", dataset.uri
+            ))),
+        }
+    }
+
+    /// The S3 clients for a Secret, in endpoint order, built on first use or
+    /// after the Secret changed.
+    pub async fn s3(&self, secret: &SecretRef) -> Result<EndpointClients, StorageError> {
+        let secrets: Api<Secret> = Api::namespaced(self.client.clone(), &secret.namespace);
+        let found = secrets.get_opt(&secret.name).await
+            .map_err(|e| StorageError::Auth(format!("cannot read secret {}: {}", secret, e)))?
+            .ok_or_else(|| StorageError::Auth(format!("secret {} not found", secret)))?;
+        let version = found.metadata.resource_version.clone().unwrap_or_default();
+
+        let mut clients = self.clients.lock().await;
+        if let Some((built_from, backends)) = clients.get(secret) {
+            if *built_from == version {
+                return Ok(backends.clone());
+            }
+        }
+
+        let backends = self.build(secret, &found).await?;
+        info!(event = "tenant_client", secret = %secret, endpoints = backends.len(), "Built S3 clients for tenant credentials");
+        clients.insert(secret.clone(), (version, backends.clone()));
+        self.verified.lock().unwrap().retain(|(s, _), _| s.as_ref() != Some(secret));
+        Ok(backends)
+    }
+
+    /// Checks the identity the dataset would be read with can read it: its
+    /// Secret's, or the operator's for S3 datasets naming none. Otherwise a
+    /// pod could get a cache entry another tenant downloaded with their own
+    /// credentials without having access itself. Other schemes only ever
+    /// use the operator's identity, so their entries need no check. The
+    /// check goes to the primary endpoint only.
+    pub async fn check_access(&self, dataset: &DatasetRef) -> Result<(), StorageError> {
+        self.permit(dataset)?;
+        if dataset.credentials.is_none() && dataset.scheme != Scheme::S3 {
+            return Ok(());
+        }
+
+        let key = (dataset.credentials.clone(), dataset.uri.clone());
+        if let Some(checked) = self.verified.lock().unwrap().get(&key) {
+            if checked.elapsed() < ACCESS_CHECK_TTL {
+                return Ok(());
+            }
+        }
+        let primary = match &dataset.credentials {
+            Some(secret) => self.s3(secret).await?.first().cloned(),
+            None => Some(self.operator.clone()),
+        };
+        if let Some(primary) = primary {
+            primary.check_access(dataset).await?;
+        }
+        self.verified.lock().unwrap().insert(key, Instant::now());
+        Ok(())
+    }
+
+    async fn build(&self, secret: &SecretRef, found: &Secret) -> Result<EndpointClients, StorageError> {
+        let data = found.data.as_ref();
+        let get = |key: &str| {
+            data.and_then(|d| d.get(key))
+                .map(|v| String::from_utf8_lossy(&v.0).trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        let keys = match (get("AWS_ACCESS_KEY_ID"), get("AWS_SECRET_ACCESS_KEY")) {
+            (Some(id), Some(key)) => Some(Credentials::new(id, key, get("AWS_SESSION_TOKEN"), None, "kube-cache-secret")),
+            _ => None,
+        };
+        let region = get("AWS_REGION");
+
+        let provider = match (keys, get("AWS_ROLE_ARN")) {
+            (Some(keys), None) => SharedCredentialsProvider::new(keys),
+            (keys, Some(role)) => {
+                let mut builder = AssumeRoleProvider::builder(role)
+                    .session_name(get("AWS_ROLE_SESSION_NAME").unwrap_or_else(|| format!("kube-cache-{}", secret.namespace)));
+                if let Some(external_id) = get("AWS_EXTERNAL_ID") {
+                    builder = builder.external_id(external_id);
+                }
+                if let Some(region) = &region {
+                    builder = builder.region(aws_sdk_s3::config::Region::new(region.clone()));
+                }
+                match keys {
+                    Some(keys) => SharedCredentialsProvider::new(builder.build_from_provider(keys).await),
+                    None if self.allow_operator => SharedCredentialsProvider::new(builder.build().await),
+                    None => return Err(StorageError::Auth(format!(
+                        "secret {} has a role but no keys, and operator credentials are disabled", secret
+                    ))),
+                }
+            }
+            (None, None) => return Err(StorageError::Auth(format!(
+                "secret {} has neither AWS_ACCESS_KEY_ID/AWS_SECRET_ACCESS_KEY nor AWS_ROLE_ARN", secret
+            ))),
+        };
+
+        let mut backends = Vec::with_capacity(self.endpoints.len());
+        for endpoint in &self.endpoints {
+            backends.push(Arc::new(S3Backend::with_credentials(endpoint, region.clone(), provider.clone()).await));
+        }
+        Ok(backends)
+    }
+}
@@ -0,0 +1,152 @@
+// Local files (`file:///weights.bin`), read from the operator's own
+// filesystem, e.g. a volume with seed data in an air-gapped cluster. The
+// version is derived from mtime and size.
+//
+// Keys resolve under `file_seed_root` and may only hold plain path
+// components: a tenant must not be able to name `..` or any other file the
+// operator can read (its service account token, for one).
+
+use super::{is_plain_relative, ByteStream, ObjectInfo, StorageBackend, StorageError};
+use crate::dataset::DatasetRef;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::StreamExt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+const CHUNK: usize = 1 << 20;
+
+pub struct FileBackend {
+    /// `file_seed_root`; without one every file:// read is refused
+    root: Option<PathBuf>,
+}
+
+impl FileBackend {
+    pub fn new(root: Option<&str>) -> Self {
+        Self { root: root.map(PathBuf::from) }
+    }
+
+    fn path_of(&self, object: &DatasetRef) -> Result<PathBuf, StorageError> {
+        let root = self.root.as_deref().ok_or_else(|| StorageError::Unsupported(
+            "file:// sources are disabled (no file_seed_root is configured)".to_string(),
+        ))?;
+        seed_path(root, &object.key)
+    }
+}
+
+/// Where a file:// key lives under the seed root. Refuses keys that are not
+/// plain relative paths (`..`, `.`, absolute or empty).
+pub fn seed_path(root: &Path, key: &str) -> Result<PathBuf, StorageError> {
+    let key = key.trim_end_matches('/');
+    if key.is_empty() || !is_plain_relative(key) {
+        return Err(StorageError::Auth(format!("file key '{}' is not a plain relative path", key)));
+    }
+    Ok(root.join(key))
+}
+
+fn info(key: String, meta: &std::fs::Metadata) -> ObjectInfo {
+    let mtime = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    ObjectInfo {
+        key,
+        size: meta.len(),
+        etag: Some(format!("{:x}-{:x}", mtime, meta.len())),
+        checksum: None,
+    }
+}
+
+fn not_found(object: &DatasetRef) -> impl Fn(std::io::Error) -> StorageError + '_ {
+    move |e| match e.kind() {
+        std::io::ErrorKind::NotFound => StorageError::NotFound(object.uri.clone()),
+        _ => StorageError::Io(e),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FileBackend {
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError> {
+        let meta = tokio::fs::metadata(self.path_of(object)?).await.map_err(not_found(object))?;
+        if meta.is_dir() {
+            return Err(StorageError::Unsupported(format!("{} is a directory (end the URI with '/')", object.uri)));
+        }
+        Ok(info(object.key.clone(), &meta))
+    }
+
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError> {
+        let seed = self.root.as_deref().unwrap_or(Path::new("/"));
+        let root = self.path_of(prefix)?;
+        tokio::fs::metadata(&root).await.map_err(not_found(prefix))?;
+
+        let mut objects = Vec::new();
+        let mut dirs = vec![root];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let meta = entry.metadata().await?;
+                if meta.is_dir() {
+                    dirs.push(entry.path());
+                } else if meta.is_file() {
+                    let path = entry.path();
+                    let key = path.strip_prefix(seed).unwrap_or(&path).to_string_lossy().into_owned();
+                    objects.push(info(key, &meta));
+                }
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError> {
+        let path = self.path_of(object)?;
+        let mut file = tokio::fs::File::open(&path).await.map_err(not_found(object))?;
+        let meta = file.metadata().await?;
+        if let Some(etag) = if_match {
+            if info(object.key.clone(), &meta).etag.as_deref() != Some(etag) {
+                return Err(StorageError::Changed(object.uri.clone()));
+            }
+        }
+
+        let range = range.unwrap_or(0..meta.len());
+        file.seek(std::io::SeekFrom::Start(range.start)).await?;
+        let remaining = range.end.saturating_sub(range.start);
+
+        let body = futures::stream::unfold((file, remaining), |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+            let mut buf = vec![0u8; CHUNK.min(remaining as usize)];
+            match file.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), (file, remaining - n as u64)))
+                }
+                Err(e) => Some((Err(StorageError::Io(e)), (file, 0))),
+            }
+        });
+        Ok(body.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_stay_under_the_seed_root() {
+        let root = Path::new("/seed");
+        assert_eq!(seed_path(root, "models/a.bin").unwrap(), Path::new("/seed/models/a.bin"));
+        assert_eq!(seed_path(root, "models/").unwrap(), Path::new("/seed/models"));
+        for key in ["../etc/passwd", "models/../../etc", "./a", "", "/var/run/secrets/token"] {
+            assert!(seed_path(root, key).is_err(), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// Google Cloud Storage through the JSON API. Credentials, first match wins:
+//
+//   GOOGLE_APPLICATION_CREDENTIALS  service account key file (signed JWT grant)
+//   GCE metadata server             workload identity / the node's service account,
+//                                   only against the real endpoint
+//   none                            public buckets and fake-gcs-server
+//
+// The object generation is the version: reads pin it with `ifGenerationMatch`.
+// Every object carries a CRC32C, which `fetch` checks after download.
+
+use super::http::{backend_error, check_status};
+use super::token::{TokenCache, TokenResponse};
+use super::{ByteStream, Checksum, ObjectInfo, StorageBackend, StorageError};
+use crate::dataset::DatasetRef;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{AUTHORIZATION, RANGE};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const READ_ONLY_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_only";
+const JWT_GRANT: &str = "urn:ietf:params:oauth:grant-type:jwt-bearer";
+
+// Object names are a single path segment in the JSON API, so '/' is escaped too
+const OBJECT_NAME: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+enum Credentials {
+    ServiceAccount { email: String, key: EncodingKey, token_uri: String },
+    /// Metadata server host (`GCE_METADATA_HOST` or the GKE default)
+    Metadata(String),
+    Anonymous,
+}
+
+#[derive(Deserialize)]
+struct KeyFile {
+    client_email: String,
+    private_key: String,
+    #[serde(default = "default_token_uri")]
+    token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+// Subset of the object resource; numbers are JSON strings in this API
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Object {
+    name: String,
+    size: String,
+    generation: String,
+    crc32c: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectList {
+    #[serde(default)]
+    items: Vec<Object>,
+    next_page_token: Option<String>,
+}
+
+impl Object {
+    fn info(self) -> Result<ObjectInfo, StorageError> {
+        let size = self.size.parse()
+            .map_err(|_| StorageError::Backend(format!("gs://{}: bad size '{}'", self.name, self.size)))?;
+        Ok(ObjectInfo {
+            key: self.name,
+            size,
+            etag: Some(self.generation),
+            checksum: self.crc32c.as_deref().and_then(Checksum::crc32c_base64),
+        })
+    }
+}
+
+pub struct GcsBackend {
+    client: reqwest::Client,
+    endpoint: String,
+    credentials: Credentials,
+    token: TokenCache,
+}
+
+impl GcsBackend {
+    pub fn new(endpoint: &str) -> Self {
+        let endpoint = endpoint.trim_end_matches('/').to_string();
+        let credentials = match std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+            Ok(path) if !path.is_empty() => load_key_file(&path).unwrap_or_else(|e| {
+                warn!(event = "gcs_credentials_error", path = %path, error = %e, "Ignoring unusable GCS key file");
+                Credentials::Anonymous
+            }),
+            _ if endpoint == DEFAULT_ENDPOINT => Credentials::Metadata(
+                std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| "metadata.google.internal".to_string()),
+            ),
+            _ => Credentials::Anonymous,
+        };
+        let kind = match &credentials {
+            Credentials::ServiceAccount { .. } => "service_account",
+            Credentials::Metadata(_) => "metadata_server",
+            Credentials::Anonymous => "anonymous",
+        };
+        info!(event = "config_check", endpoint = %endpoint, credentials = kind, "Configured GCS access");
+
+        Self { client: reqwest::Client::new(), endpoint, credentials, token: TokenCache::new() }
+    }
+
+    fn object_url(&self, object: &DatasetRef) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.endpoint, object.bucket, utf8_percent_encode(&object.key, OBJECT_NAME)
+        )
+    }
+
+    // Adds a bearer token unless running anonymously. Off GCP the metadata
+    // server is unreachable; requests then go out unauthenticated, which
+    // still works for public buckets.
+    async fn authorize(&self, req: RequestBuilder) -> Result<RequestBuilder, StorageError> {
+        let token = match &self.credentials {
+            Credentials::Anonymous => return Ok(req),
+            Credentials::ServiceAccount { email, key, token_uri } => {
+                self.token.get(|| self.service_account_token(email, key, token_uri)).await?
+            }
+            Credentials::Metadata(host) => match self.token.get(|| self.metadata_token(host)).await {
+                Ok(token) => token,
+                Err(StorageError::Backend(e)) => {
+                    debug!(event = "gcs_metadata_unreachable", error = %e, "No GCE metadata server, sending request anonymously");
+                    return Ok(req);
+                }
+                Err(e) => return Err(e),
+            },
+        };
+        Ok(req.header(AUTHORIZATION, format!("Bearer {}", token)))
+    }
+
+    async fn service_account_token(&self, email: &str, key: &EncodingKey, token_uri: &str) -> Result<TokenResponse, StorageError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let claims = Claims { iss: email, scope: READ_ONLY_SCOPE, aud: token_uri, iat: now, exp: now + 3600 };
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, key)
+            .map_err(|e| StorageError::Auth(format!("cannot sign GCS token request: {}", e)))?;
+
+        let resp = self.client.post(token_uri)
+            .form(&[("grant_type", JWT_GRANT), ("assertion", assertion.as_str())])
+            .send()
+            .await
+            .map_err(|e| StorageError::Auth(e.to_string()))?;
+        TokenResponse::read(resp).await
+    }
+
+    // Connection errors come back as `Backend` so `authorize` can fall back
+    async fn metadata_token(&self, host: &str) -> Result<TokenResponse, StorageError> {
+        let url = format!("http://{}/computeMetadata/v1/instance/service-accounts/default/token", host);
+        let resp = self.client.get(url)
+            .header("Metadata-Flavor", "Google")
+            .send()
+            .await
+            .map_err(backend_error)?;
+        TokenResponse::read(resp).await
+    }
+}
+
+fn load_key_file(path: &str) -> Result<Credentials, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let file: KeyFile = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+    let key = EncodingKey::from_rsa_pem(file.private_key.as_bytes()).map_err(|e| e.to_string())?;
+    Ok(Credentials::ServiceAccount { email: file.client_email, key, token_uri: file.token_uri })
+}
+
+#[async_trait]
+impl StorageBackend for GcsBackend {
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError> {
+        let req = self.authorize(self.client.get(self.object_url(object))).await?;
+        let resp = check_status(object, req.send().await.map_err(backend_error)?)?;
+        resp.json::<Object>().await.map_err(backend_error)?.info()
+    }
+
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError> {
+        let url = format!("{}/storage/v1/b/{}/o", self.endpoint, prefix.bucket);
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("prefix", prefix.key.clone())];
+            if let Some(token) = token.take() {
+                query.push(("pageToken", token));
+            }
+            let req = self.authorize(self.client.get(&url).query(&query)).await?;
+            let resp = check_status(prefix, req.send().await.map_err(backend_error)?)?;
+            let page: ObjectList = resp.json().await.map_err(backend_error)?;
+
+            for item in page.items {
+                objects.push(item.info()?);
+            }
+            match page.next_page_token {
+                Some(next) if !next.is_empty() => token = Some(next),
+                _ => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError> {
+        let mut query = vec![("alt", "media")];
+        if let Some(generation) = if_match {
+            query.push(("ifGenerationMatch", generation));
+        }
+        let mut req = self.client.get(self.object_url(object)).query(&query);
+        if let Some(r) = &range {
+            req = req.header(RANGE, format!("bytes={}-{}", r.start, r.end.saturating_sub(1)));
+        }
+
+        let req = self.authorize(req).await?;
+        let resp = check_status(object, req.send().await.map_err(backend_error)?)?;
+        if range.is_some() && resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(StorageError::Unsupported(format!("{}: server ignored the Range header", object.uri)));
+        }
+        Ok(resp.bytes_stream().map_err(backend_error).boxed())
+    }
+}
@@ -0,0 +1,79 @@
+// Plain HTTP(S) downloads. Servers cannot be listed, so only single-object
+// datasets work here. Ranged reads insist on `206 Partial Content` rather
+// than silently accepting the whole body, and `If-Match` pins the ETag.
+
+use super::{ByteStream, ObjectInfo, StorageBackend, StorageError};
+use crate::dataset::DatasetRef;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::{CONTENT_LENGTH, ETAG, IF_MATCH, RANGE};
+use reqwest::StatusCode;
+use std::ops::Range;
+
+pub struct HttpBackend {
+    client: reqwest::Client,
+}
+
+impl HttpBackend {
+    pub fn new() -> Self {
+        Self { client: reqwest::Client::new() }
+    }
+}
+
+pub(super) fn backend_error(e: reqwest::Error) -> StorageError {
+    StorageError::Backend(e.to_string())
+}
+
+// Maps error statuses; passes successful responses through.
+pub(super) fn check_status(object: &DatasetRef, resp: reqwest::Response) -> Result<reqwest::Response, StorageError> {
+    match resp.status() {
+        StatusCode::NOT_FOUND => Err(StorageError::NotFound(object.uri.clone())),
+        StatusCode::PRECONDITION_FAILED => Err(StorageError::Changed(object.uri.clone())),
+        status if !status.is_success() => Err(StorageError::Backend(format!("{}: HTTP {}", object.uri, status))),
+        _ => Ok(resp),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for HttpBackend {
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError> {
+        let resp = self.client.head(&object.uri).send().await.map_err(backend_error)?;
+        let resp = check_status(object, resp)?;
+        let header = |name| resp.headers().get(name).and_then(|v| v.to_str().ok());
+
+        let size = header(CONTENT_LENGTH)
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| StorageError::Backend(format!("{}: no Content-Length", object.uri)))?;
+        Ok(ObjectInfo {
+            key: object.key.clone(),
+            size,
+            etag: header(ETAG).map(str::to_string),
+            checksum: None,
+        })
+    }
+
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError> {
+        Err(StorageError::Unsupported(format!("listing {} (HTTP sources must name a single file)", prefix.uri)))
+    }
+
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError> {
+        let mut req = self.client.get(&object.uri);
+        if let Some(r) = &range {
+            req = req.header(RANGE, format!("bytes={}-{}", r.start, r.end.saturating_sub(1)));
+        }
+        if let Some(etag) = if_match {
+            req = req.header(IF_MATCH, etag);
+        }
+
+        let resp = check_status(object, req.send().await.map_err(backend_error)?)?;
+        if range.is_some() && resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(StorageError::Unsupported(format!("{}: server ignored the Range header", object.uri)));
+        }
+        Ok(resp.bytes_stream().map_err(backend_error).boxed())
+    }
+}
@@ -0,0 +1,610 @@
+// --- LAZY MOUNTS ---
+// With `lazy_mounts` on (a build with the `fuse` feature), a pod annotated
+// `kube-cache.openai.com/lazy: "true"` is released as soon as its dataset is
+// listed instead of after the whole download. Its volume points at
+// `<entry>.lazy` (see `webhook`), where the operator mounts a read-only FUSE
+// filesystem showing the dataset's full tree:
+//
+// - A read fetches the `BLOCK_SIZE` blocks it touches from the dataset's
+//   mirrors, and the `READAHEAD_BLOCKS` after them in the background, so a
+//   loader reading files front to back rarely waits.
+// - Blocks land in sparse files under `<entry>.lazy-part` while a background
+//   fill fetches the rest, one block at a time. Once every block is in, the
+//   copy is renamed into place as a regular cache entry for later pods.
+// - Both count against the bandwidth limits like any download.
+//
+// Only prefix datasets without archives can be mounted lazily. Mounts are made
+// by the node agent on the pod's node, off its watch loop, and last as long as
+// the operator process. A restarted process finds the dead ones (reads fail
+// with ENOTCONN) and detaches them before the kubelet can hand one to a pod.
+// They need /dev/fuse, a privileged container and `mountPropagation:
+// Bidirectional` on the cache volume, so the kubelet and the CSI plugin see them;
+// deploy-lazy-mounts.yaml patches those into the DaemonSet.
+//
+// The filesystem's callbacks run on tokio worker threads, so the local copy
+// is set up, read, written, synced and moved through `block_in_place`.
+
+use super::{is_plain_relative, Backends, Mirror, ObjectCopy, ObjectInfo, StorageError};
+use crate::dataset::DatasetRef;
+use crate::throttle::Throttle;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen, Request,
+    FUSE_ROOT_ID,
+};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+use tokio::sync::OnceCell;
+use tokio::task::block_in_place;
+use tracing::{debug, info, warn};
+
+const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+const READAHEAD_BLOCKS: u64 = 8;
+/// Nothing in a mount ever changes, so the kernel may cache attributes
+const TTL: Duration = Duration::from_secs(3600);
+/// Attempts per block before the background fill gives up
+const FILL_ATTEMPTS: u32 = 5;
+const FILL_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Lazy mounts made by this process, by mount point. Dropping a session
+/// unmounts it.
+pub struct LazyMounts {
+    sessions: Mutex<HashMap<PathBuf, fuser::BackgroundSession>>,
+    /// One mount at a time per mount point; pods sharing a dataset wait for it
+    mounting: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl LazyMounts {
+    /// Detaches the mounts a previous process left under `cache_root` first.
+    pub fn new(cache_root: &str) -> Self {
+        clear_stale(Path::new(cache_root));
+        Self { sessions: Mutex::new(HashMap::new()), mounting: Mutex::new(HashMap::new()) }
+    }
+
+    /// Mounts a prefix dataset at its lazy path, unless it already is, and
+    /// starts filling its cache entry behind the mount.
+    pub async fn mount(
+        &self,
+        backends: Arc<Backends>,
+        throttle: Arc<Throttle>,
+        dataset: &DatasetRef,
+        cache_root: &str,
+    ) -> Result<(), StorageError> {
+        let mountpoint = PathBuf::from(dataset.lazy_path(cache_root));
+        let lock = self.mounting.lock().unwrap().entry(mountpoint.clone()).or_default().clone();
+        let _mounting = lock.lock().await;
+        if self.sessions.lock().unwrap().contains_key(&mountpoint) {
+            return Ok(());
+        }
+        if !dataset.is_prefix() {
+            return Err(StorageError::Unsupported(format!("{}: only prefix datasets can be mounted lazily", dataset.uri)));
+        }
+
+        let target = PathBuf::from(dataset.cache_path(cache_root));
+        let (lazy, tree) = if super::is_complete(&target) && target.is_dir() {
+            Lazy::from_entry(backends, throttle, &target)?
+        } else {
+            Lazy::from_listing(backends, throttle, dataset, &target).await?
+        };
+        let lazy = Arc::new(lazy);
+
+        // Not one of ours, so whatever is mounted there is dead
+        unmount_stale(&mountpoint)?;
+        std::fs::create_dir_all(&mountpoint)?;
+        let fs = LazyFs { lazy: lazy.clone(), tree, runtime: Handle::current(), mounted: SystemTime::now() };
+        let options = [MountOption::RO, MountOption::FSName("kube-cache".to_string()), MountOption::AllowOther];
+        let session = fuser::spawn_mount2(fs, &mountpoint, &options)?;
+        info!(
+            event = "lazy_mount", dataset = %dataset.uri, mountpoint = %mountpoint.display(), files = lazy.files.len(),
+            missing_blocks = lazy.missing.load(Ordering::SeqCst), "Mounted dataset lazily"
+        );
+        self.sessions.lock().unwrap().insert(mountpoint, session);
+
+        if lazy.missing.load(Ordering::SeqCst) > 0 {
+            tokio::spawn(lazy.fill());
+        }
+        Ok(())
+    }
+}
+
+// One file of a mount and its sparse local copy
+struct LazyFile {
+    info: ObjectInfo,
+    /// Path inside the entry
+    path: String,
+    backing: std::fs::File,
+    /// Set once a block is in the local copy
+    blocks: Vec<OnceCell<()>>,
+}
+
+// What a mount reads from, shared by the filesystem and the fill
+struct Lazy {
+    backends: Arc<Backends>,
+    throttle: Arc<Throttle>,
+    mirrors: Vec<Mirror>,
+    /// Index of the mirror the dataset was listed on
+    listed: usize,
+    entry: String,
+    files: Vec<LazyFile>,
+    /// Blocks not yet in the local copy
+    missing: AtomicUsize,
+    part: PathBuf,
+    target: PathBuf,
+}
+
+impl Lazy {
+    // A mount over a complete entry: nothing to fetch
+    fn from_entry(backends: Arc<Backends>, throttle: Arc<Throttle>, target: &Path) -> Result<(Self, Tree), StorageError> {
+        let mut paths = Vec::new();
+        walk(target, "", &mut paths)?;
+        let (tree, accepted) = Tree::build(paths.iter().map(|(path, _)| path.as_str()));
+        let mut files = Vec::with_capacity(accepted.len());
+        for i in accepted {
+            let (path, size) = &paths[i];
+            let info = ObjectInfo { key: path.clone(), size: *size, etag: None, checksum: None };
+            let blocks = (0..info.size.div_ceil(BLOCK_SIZE)).map(|_| OnceCell::new_with(Some(()))).collect();
+            let backing = std::fs::File::open(target.join(path))?;
+            files.push(LazyFile { info, path: path.clone(), backing, blocks });
+        }
+
+        let lazy = Self {
+            backends,
+            throttle,
+            mirrors: Vec::new(),
+            listed: 0,
+            entry: entry_name(target),
+            files,
+            missing: AtomicUsize::new(0),
+            part: target.to_path_buf(),
+            target: target.to_path_buf(),
+        };
+        Ok((lazy, tree))
+    }
+
+    // A mount over a fresh, empty sparse copy of the listed dataset
+    async fn from_listing(
+        backends: Arc<Backends>,
+        throttle: Arc<Throttle>,
+        dataset: &DatasetRef,
+        target: &Path,
+    ) -> Result<(Self, Tree), StorageError> {
+        let mirrors = backends.mirrors(dataset).await?;
+        let (listed, entries) = backends.health.first(&mirrors, |m| async {
+            let _slot = throttle.request_slot().await;
+            m.backend.entries(&m.dataset).await
+        }).await?;
+        if entries.iter().any(|e| e.archive.is_some()) {
+            return Err(StorageError::Unsupported(format!("{}: datasets with archives cannot be mounted lazily", dataset.uri)));
+        }
+        let listed = mirrors.iter().position(|m| std::ptr::eq(m, listed)).unwrap_or(0);
+        let entries: Vec<_> = entries.into_iter().filter(|e| is_plain_relative(&e.path)).collect();
+        let (tree, accepted) = Tree::build(entries.iter().map(|e| e.path.as_str()));
+
+        let part = PathBuf::from(format!("{}.lazy-part", target.display()));
+        let (files, missing) = block_in_place(|| -> std::io::Result<_> {
+            let _ = std::fs::remove_dir_all(&part);
+            std::fs::create_dir_all(&part)?;
+            let mut files = Vec::with_capacity(accepted.len());
+            let mut missing = 0;
+            for i in accepted {
+                let entry = &entries[i];
+                let dest = part.join(&entry.path);
+                if let Some(parent) = dest.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                let backing = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&dest)?;
+                backing.set_len(entry.object.size)?;
+                let count = entry.object.size.div_ceil(BLOCK_SIZE) as usize;
+                missing += count;
+                files.push(LazyFile {
+                    info: entry.object.clone(),
+                    path: entry.path.clone(),
+                    backing,
+                    blocks: (0..count).map(|_| OnceCell::new()).collect(),
+                });
+            }
+            Ok((files, missing))
+        })?;
+
+        let lazy = Self {
+            backends,
+            throttle,
+            mirrors,
+            listed,
+            entry: entry_name(target),
+            files,
+            missing: AtomicUsize::new(missing),
+            part,
+            target: target.to_path_buf(),
+        };
+        if missing == 0 {
+            lazy.complete();
+        }
+        Ok((lazy, tree))
+    }
+
+    // Makes sure one block is in the local copy. Concurrent callers share
+    // a single fetch; a failed fetch is tried again by the next caller.
+    async fn block(&self, file: usize, block: u64) -> Result<(), StorageError> {
+        self.files[file].blocks[block as usize].get_or_try_init(|| self.fetch(file, block)).await?;
+        Ok(())
+    }
+
+    async fn fetch(&self, file: usize, block: u64) -> Result<(), StorageError> {
+        let lazy_file = &self.files[file];
+        let range = block * BLOCK_SIZE..((block + 1) * BLOCK_SIZE).min(lazy_file.info.size);
+        let copy = ObjectCopy {
+            backends: &self.backends,
+            mirrors: &self.mirrors,
+            listed: &self.mirrors[self.listed],
+            peers: &[],
+            entry: &self.entry,
+            path: Some(&lazy_file.path),
+            info: &lazy_file.info,
+        };
+        let bytes = copy.read_range(&copy.origin(), range.clone(), &self.throttle.metered()).await?;
+        block_in_place(|| lazy_file.backing.write_all_at(&bytes, range.start))?;
+
+        if self.missing.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.complete();
+        }
+        Ok(())
+    }
+
+    // Bytes `offset..offset + size` of a file, fetching what is missing and
+    // starting readahead past it.
+    async fn read(self: Arc<Self>, file: usize, offset: u64, size: u32) -> Result<Vec<u8>, StorageError> {
+        let lazy_file = &self.files[file];
+        let end = (offset + u64::from(size)).min(lazy_file.info.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let (first, last) = (offset / BLOCK_SIZE, (end - 1) / BLOCK_SIZE);
+
+        let count = lazy_file.blocks.len() as u64;
+        for block in (last + 1..last + 1 + READAHEAD_BLOCKS).take_while(|b| *b < count) {
+            if lazy_file.blocks[block as usize].initialized() {
+                continue;
+            }
+            let lazy = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = lazy.block(file, block).await {
+                    debug!(event = "lazy_readahead_error", path = %lazy.files[file].path, block, error = %e, "Readahead failed");
+                }
+            });
+        }
+
+        futures::future::try_join_all((first..=last).map(|block| self.block(file, block))).await?;
+        let mut buf = vec![0u8; (end - offset) as usize];
+        block_in_place(|| lazy_file.backing.read_exact_at(&mut buf, offset))?;
+        Ok(buf)
+    }
+
+    // Fetches every block nobody has read yet, in file order.
+    async fn fill(self: Arc<Self>) {
+        for (file, lazy_file) in self.files.iter().enumerate() {
+            for block in 0..lazy_file.blocks.len() as u64 {
+                let mut attempt = 1;
+                while let Err(e) = self.block(file, block).await {
+                    if attempt == FILL_ATTEMPTS {
+                        warn!(
+                            event = "lazy_fill_error", entry = %self.entry, path = %lazy_file.path, error = %e,
+                            "Giving up filling the cache behind a lazy mount; reads still fetch on demand"
+                        );
+                        return;
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(FILL_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    // Every block is in: move the copy into place as a regular cache entry.
+    // Open files keep working, they follow the rename.
+    fn complete(&self) {
+        if self.part != self.target {
+            block_in_place(|| self.move_into_cache());
+        }
+    }
+
+    fn move_into_cache(&self) {
+        for file in &self.files {
+            if let Err(e) = file.backing.sync_all() {
+                warn!(event = "lazy_sync_error", path = %file.path, error = %e, "Cannot sync lazily fetched file");
+                return;
+            }
+        }
+        // Someone downloaded the whole entry meanwhile
+        if super::is_complete(&self.target) {
+            info!(event = "lazy_complete", entry = %self.entry, "Cache entry already present, keeping the lazy copy for this mount");
+            return;
+        }
+        match std::fs::rename(&self.part, &self.target).and_then(|()| super::mark_complete(&self.target)) {
+            Ok(()) => info!(event = "lazy_complete", entry = %self.entry, path = %self.target.display(), "Lazy mount fully cached"),
+            Err(e) => warn!(event = "lazy_complete_error", entry = %self.entry, error = %e, "Cannot move lazy copy into the cache"),
+        }
+    }
+}
+
+// Detaches and removes the `.lazy` mount points under the cache root. Left
+// in place, the kubelet would mount the dead mount (or the empty directory
+// under it) into a new pod as its dataset.
+fn clear_stale(cache_root: &Path) {
+    let Ok(dir) = std::fs::read_dir(cache_root) else { return };
+    for item in dir.flatten() {
+        let path = item.path();
+        if path.extension() != Some(OsStr::new("lazy")) {
+            continue;
+        }
+        match unmount_stale(&path).and_then(|()| std::fs::remove_dir(&path)) {
+            Ok(()) => info!(event = "lazy_stale_cleared", mountpoint = %path.display(), "Cleared lazy mount left by a previous run"),
+            Err(e) => warn!(event = "lazy_stale_error", mountpoint = %path.display(), error = %e, "Cannot clear lazy mount left by a previous run"),
+        }
+    }
+}
+
+// Lazily detaches whatever is mounted at `path`.
+fn unmount_stale(path: &Path) -> std::io::Result<()> {
+    if !is_mountpoint(path) {
+        return Ok(());
+    }
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is a valid NUL-terminated string for the duration of the call
+    if unsafe { libc::umount2(c_path.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    info!(event = "lazy_stale_unmounted", mountpoint = %path.display(), "Detached dead lazy mount");
+    Ok(())
+}
+
+// Looked up in mountinfo: a dead FUSE mount fails every stat with ENOTCONN.
+fn is_mountpoint(path: &Path) -> bool {
+    let Ok(mountinfo) = std::fs::read_to_string("/proc/self/mountinfo") else { return false };
+    mountinfo.lines().filter_map(|line| line.split(' ').nth(4)).any(|point| unescape(point) == path.as_os_str().as_bytes())
+}
+
+// Mountinfo writes space, tab, newline and backslash as octal escapes (`\040`).
+fn unescape(field: &str) -> Vec<u8> {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).and_then(|d| std::str::from_utf8(d).ok()).and_then(|d| u8::from_str_radix(d, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(byte)) => {
+                out.push(byte);
+                i += 4;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+// Every file under `dir` with its size, as paths relative to the entry
+fn walk(dir: &Path, prefix: &str, out: &mut Vec<(String, u64)>) -> std::io::Result<()> {
+    for item in std::fs::read_dir(dir)? {
+        let item = item?;
+        let Some(name) = item.file_name().to_str().map(str::to_string) else { continue };
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let meta = item.metadata()?;
+        if meta.is_dir() {
+            walk(&item.path(), &path, out)?;
+        } else if meta.is_file() {
+            out.push((path, meta.len()));
+        }
+    }
+    Ok(())
+}
+
+fn entry_name(target: &Path) -> String {
+    target.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+// A directory or file in a mount. Inode numbers are index + 1, so the root
+// (index 0) is `FUSE_ROOT_ID`.
+struct Node {
+    parent: u64,
+    name: OsString,
+    /// Index into the mount's files; `None` for directories
+    file: Option<usize>,
+    children: Vec<u64>,
+}
+
+struct Tree {
+    nodes: Vec<Node>,
+    by_name: HashMap<(u64, OsString), u64>,
+}
+
+impl Tree {
+    // The tree of a list of file paths, and which of them made it in. A path
+    // clashing with another (a key that is also a "directory") is left out.
+    fn build<'a>(paths: impl Iterator<Item = &'a str>) -> (Self, Vec<usize>) {
+        let root = Node { parent: FUSE_ROOT_ID, name: OsString::new(), file: None, children: Vec::new() };
+        let mut tree = Self { nodes: vec![root], by_name: HashMap::new() };
+        let mut accepted = Vec::new();
+
+        'paths: for (i, path) in paths.enumerate() {
+            let parts: Vec<&str> = path.split('/').collect();
+            let mut parent = FUSE_ROOT_ID;
+            for (depth, part) in parts.iter().enumerate() {
+                let is_file = depth == parts.len() - 1;
+                let key = (parent, OsString::from(part));
+                if let Some(&ino) = tree.by_name.get(&key) {
+                    if is_file || tree.nodes[ino as usize - 1].file.is_some() {
+                        warn!(event = "lazy_path_clash", path = %path, "Path clashes with another in the dataset, leaving it out");
+                        continue 'paths;
+                    }
+                    parent = ino;
+                    continue;
+                }
+                let ino = tree.nodes.len() as u64 + 1;
+                let file = is_file.then_some(accepted.len());
+                tree.nodes.push(Node { parent, name: key.1.clone(), file, children: Vec::new() });
+                tree.nodes[parent as usize - 1].children.push(ino);
+                tree.by_name.insert(key, ino);
+                parent = ino;
+            }
+            accepted.push(i);
+        }
+        (tree, accepted)
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get((ino as usize).checked_sub(1)?)
+    }
+}
+
+// The FUSE side: metadata from the tree, data from `Lazy` on the runtime
+struct LazyFs {
+    lazy: Arc<Lazy>,
+    tree: Tree,
+    runtime: Handle,
+    mounted: SystemTime,
+}
+
+impl LazyFs {
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let node = self.tree.node(ino)?;
+        let (kind, perm, nlink, size) = match node.file {
+            Some(file) => (FileType::RegularFile, 0o444, 1, self.lazy.files[file].info.size),
+            None => (FileType::Directory, 0o555, 2, 0),
+        };
+        Some(FileAttr {
+            ino,
+            size,
+            blocks: size.div_ceil(512),
+            atime: self.mounted,
+            mtime: self.mounted,
+            ctime: self.mounted,
+            crtime: self.mounted,
+            kind,
+            perm,
+            nlink,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: BLOCK_SIZE as u32,
+            flags: 0,
+        })
+    }
+}
+
+impl Filesystem for LazyFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.tree.by_name.get(&(parent, name.to_os_string())).and_then(|ino| self.attr(*ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.tree.node(ino) {
+            // Contents never change, so the page cache may outlive the open
+            Some(node) if node.file.is_some() => reply.opened(0, fuser::consts::FOPEN_KEEP_CACHE),
+            Some(_) => reply.error(libc::EISDIR),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(file) = self.tree.node(ino).and_then(|n| n.file) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        let lazy = self.lazy.clone();
+        self.runtime.spawn(async move {
+            let path = lazy.files[file].path.clone();
+            match lazy.read(file, offset.max(0) as u64, size).await {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    warn!(event = "lazy_read_error", path = %path, offset, error = %e, "Lazy read failed");
+                    reply.error(libc::EIO);
+                }
+            }
+        });
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let Some(node) = self.tree.node(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+        if node.file.is_some() {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+        let children = node.children.iter().filter_map(|child| {
+            let child_node = self.tree.node(*child)?;
+            let kind = if child_node.file.is_some() { FileType::RegularFile } else { FileType::Directory };
+            Some((*child, kind, child_node.name.as_os_str()))
+        });
+        let entries = [(ino, FileType::Directory, OsStr::new(".")), (node.parent, FileType::Directory, OsStr::new(".."))]
+            .into_iter()
+            .chain(children);
+        // `offset` is where the previous call stopped
+        for (i, (child, kind, name)) in entries.enumerate().skip(offset.max(0) as usize) {
+            if reply.add(child, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn child(tree: &Tree, parent: u64, name: &str) -> u64 {
+        tree.by_name[&(parent, OsString::from(name))]
+    }
+
+    #[test]
+    fn paths_become_a_tree_and_clashes_are_left_out() {
+        let paths = ["a/b.bin", "a/c/d.bin", "e.bin", "a/b.bin/x", "a/c", "e.bin"];
+        let (tree, accepted) = Tree::build(paths.into_iter());
+        assert_eq!(accepted, vec![0, 1, 2]);
+
+        let a = child(&tree, FUSE_ROOT_ID, "a");
+        let c = child(&tree, a, "c");
+        let e = child(&tree, FUSE_ROOT_ID, "e.bin");
+        assert_eq!(tree.node(FUSE_ROOT_ID).unwrap().children, vec![a, e]);
+        assert_eq!(tree.node(a).unwrap().children, vec![child(&tree, a, "b.bin"), c]);
+        assert_eq!(tree.node(a).unwrap().file, None);
+        assert_eq!(tree.node(child(&tree, a, "b.bin")).unwrap().file, Some(0));
+        assert_eq!(tree.node(child(&tree, c, "d.bin")).unwrap().file, Some(1));
+        assert_eq!(tree.node(e).unwrap().file, Some(2));
+        assert_eq!(tree.node(c).unwrap().parent, a);
+        assert_eq!(tree.nodes.len(), 6);
+        assert!(tree.node(0).is_none());
+        assert!(tree.node(7).is_none());
+    }
+}
@@ -0,0 +1,148 @@
+// --- MIRRORS ---
+// A dataset is read from an ordered list of mirrors: the source itself, then
+// each of its `mirrors` (see `dataset`). An s3:// source is tried on
+// `s3_endpoint` and then on every `s3_mirror_endpoints` entry.
+//
+// Metadata calls (stat, listing) go to the first mirror that answers. An
+// object download that fails part way resumes on the next mirror with a
+// ranged read from the byte it stopped at, once that mirror reports the same
+// size (and checksum, where both publish one).
+//
+// A mirror that fails `FAILURE_THRESHOLD` times in a row is marked down and
+// tried last for `MIN_BACKOFF`, doubling up to `MAX_BACKOFF` while it keeps
+// failing. One success brings it back. Per mirror, `mirror_requests_total`
+// counts outcomes, `mirror_request_duration_seconds` times object reads and
+// `mirror_up` shows the health state.
+
+use super::oci::split_reference;
+use super::{StorageBackend, StorageError};
+use crate::dataset::{DatasetRef, Scheme};
+use crate::metrics::MetricsState;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const FAILURE_THRESHOLD: u32 = 3;
+const MIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// One place a dataset can be read from.
+pub struct Mirror {
+    /// `s3://<bucket>@<endpoint host>`, `<scheme>://<bucket>` or
+    /// `peer:<pod>`; names the mirror in metrics and logs
+    pub label: String,
+    pub backend: Arc<dyn StorageBackend>,
+    pub dataset: DatasetRef,
+    /// Another node's cache rather than the object store; its bytes do not
+    /// count against the bandwidth limits
+    pub peer: bool,
+}
+
+impl Mirror {
+    /// The object at `key` on `from` (another mirror of the same dataset), as
+    /// found on this mirror: same path under the prefix, or same OCI digest.
+    pub fn translate(&self, from: &Mirror, key: &str) -> DatasetRef {
+        if self.dataset.scheme == Scheme::Oci {
+            let (repo, _) = split_reference(&self.dataset.key);
+            let (_, digest) = split_reference(key);
+            return self.dataset.with_key(&format!("{}@{}", repo, digest));
+        }
+        let relative = key.strip_prefix(from.dataset.key.as_str()).unwrap_or(key);
+        self.dataset.with_key(&format!("{}{}", self.dataset.key, relative))
+    }
+}
+
+/// True if another mirror might succeed where this error came from. Local I/O
+/// errors and objects changing mid-read are not the mirror's fault.
+pub fn can_fail_over(error: &StorageError) -> bool {
+    !matches!(error, StorageError::Io(_) | StorageError::Changed(_))
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    down_until: Option<Instant>,
+    backoff: Duration,
+}
+
+/// Consecutive failures per mirror label, shared by all downloads.
+pub struct MirrorHealth {
+    states: Mutex<HashMap<String, State>>,
+    metrics: MetricsState,
+}
+
+impl MirrorHealth {
+    pub fn new(metrics: MetricsState) -> Self {
+        Self { states: Mutex::new(HashMap::new()), metrics }
+    }
+
+    /// Healthy mirrors first, otherwise keeping the configured order.
+    pub fn order(&self, mut mirrors: Vec<Mirror>) -> Vec<Mirror> {
+        let now = Instant::now();
+        let states = self.states.lock().unwrap();
+        mirrors.sort_by_key(|m| {
+            states.get(&m.label).and_then(|s| s.down_until).map(|until| until > now).unwrap_or(false)
+        });
+        mirrors
+    }
+
+    /// Records a successful request, with how long an object read took.
+    pub fn succeeded(&self, mirror: &Mirror, elapsed: Option<Duration>) {
+        self.metrics.count_mirror(&mirror.label, "success");
+        if let Some(elapsed) = elapsed {
+            self.metrics.observe_mirror(&mirror.label, elapsed.as_secs_f64());
+        }
+        self.metrics.set_mirror_up(&mirror.label, true);
+        self.states.lock().unwrap().remove(&mirror.label);
+    }
+
+    /// Records a failed request. Missing objects (a mirror lagging behind)
+    /// are counted but do not count against the mirror's health.
+    pub fn failed(&self, mirror: &Mirror, error: &StorageError) {
+        if let StorageError::NotFound(_) = error {
+            self.metrics.count_mirror(&mirror.label, "not_found");
+            return;
+        }
+        self.metrics.count_mirror(&mirror.label, "failure");
+
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(mirror.label.clone()).or_default();
+        state.failures += 1;
+        if state.failures < FAILURE_THRESHOLD {
+            return;
+        }
+        state.backoff = (state.backoff * 2).clamp(MIN_BACKOFF, MAX_BACKOFF);
+        state.down_until = Some(Instant::now() + state.backoff);
+        self.metrics.set_mirror_up(&mirror.label, false);
+        warn!(
+            event = "mirror_down", mirror = %mirror.label, failures = state.failures,
+            retry_secs = state.backoff.as_secs(), error = %error, "Mirror keeps failing, trying it last for a while"
+        );
+    }
+
+    /// Runs a metadata call against each mirror in turn until one answers.
+    pub async fn first<'a, T, F, Fut>(&self, mirrors: &'a [Mirror], mut call: F) -> Result<(&'a Mirror, T), StorageError>
+    where
+        F: FnMut(&'a Mirror) -> Fut,
+        Fut: Future<Output = Result<T, StorageError>>,
+    {
+        let mut last_error = None;
+        for mirror in mirrors {
+            match call(mirror).await {
+                Ok(value) => {
+                    self.succeeded(mirror, None);
+                    return Ok((mirror, value));
+                }
+                Err(e) if can_fail_over(&e) => {
+                    self.failed(mirror, &e);
+                    warn!(event = "mirror_failover", mirror = %mirror.label, error = %e, "Mirror failed, trying the next one");
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| StorageError::Backend("dataset has no usable mirror".to_string())))
+    }
+}
@@ -0,0 +1,815 @@
+// --- STORAGE BACKENDS ---
+// Every dataset source sits behind `StorageBackend`, picked by URI scheme, so
+// queueing, throttling, atomic cache writes, prefix datasets and size checks
+// behave the same whatever the data comes from:
+//
+//   s3://     S3 / MinIO (one shared client per endpoint)
+//   gs://     Google Cloud Storage JSON API (or fake-gcs-server)
+//   az://     Azure Blob Storage REST API (or Azurite)
+//   oci://    OCI registry artifacts (or a local registry:2); layers are
+//             verified by digest and tarball layers unpacked
+//   http(s):// plain web servers; ranged reads use `Range`, versions use `ETag`
+//   file://   local paths, for seeding air-gapped clusters from a mounted volume
+//
+// Where the store publishes a content checksum (S3 additional checksums, GCS
+// CRC32C, Azure Content-MD5, OCI digests) it is checked once the object is on
+// disk. Every dataset may have mirrors to fail over to, mid-download included
+// (see `mirrors`), and other nodes' caches are asked first (see `peers`).
+
+mod azure;
+mod credentials;
+mod file;
+mod gcs;
+mod http;
+#[cfg(feature = "fuse")]
+pub mod lazy;
+mod mirrors;
+mod oci;
+mod s3;
+mod swarm;
+mod token;
+
+use crate::config::Config;
+use crate::dataset::{DatasetRef, Scheme};
+use crate::metrics::MetricsState;
+use crate::peers::{self, Peer, Peers, PEER_PATH};
+use crate::throttle::Metered;
+use kube::Client;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::Bytes;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use md5::{Digest, Md5};
+use sha2::Sha256;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::SemaphorePermit;
+use tracing::{info, warn};
+
+pub use azure::AzureBackend;
+pub use credentials::TenantClients;
+pub use file::{seed_path, FileBackend};
+pub use gcs::GcsBackend;
+pub use http::HttpBackend;
+pub use mirrors::{Mirror, MirrorHealth};
+pub use oci::OciBackend;
+pub use s3::S3Backend;
+use swarm::ChunkHasher;
+
+/// Peers a single-peer object copy may read from, at most
+const MAX_PEER_SOURCES: usize = 3;
+
+/// Appended to an entry's path to name its completion marker
+const COMPLETE_SUFFIX: &str = ".complete";
+
+/// Size and version of one object.
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    /// Full key, as in [`DatasetRef::key`]
+    pub key: String,
+    pub size: u64,
+    /// Changes whenever the content does (ETag, generation for GCS, or
+    /// mtime+size for files)
+    pub etag: Option<String>,
+    /// Published content checksum, if the store keeps one
+    pub checksum: Option<Checksum>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checksum {
+    Md5([u8; 16]),
+    Crc32c(u32),
+    Sha256([u8; 32]),
+}
+
+impl Checksum {
+    /// Decodes a base64 MD5 digest (Azure `Content-MD5`, GCS `md5Hash`).
+    pub fn md5_base64(value: &str) -> Option<Self> {
+        let bytes = BASE64.decode(value).ok()?;
+        Some(Checksum::Md5(bytes.try_into().ok()?))
+    }
+
+    /// Decodes a base64 big-endian CRC32C (GCS `crc32c`).
+    pub fn crc32c_base64(value: &str) -> Option<Self> {
+        let bytes: [u8; 4] = BASE64.decode(value).ok()?.try_into().ok()?;
+        Some(Checksum::Crc32c(u32::from_be_bytes(bytes)))
+    }
+
+    /// Decodes a base64 SHA-256 digest (S3 `x-amz-checksum-sha256`).
+    pub fn sha256_base64(value: &str) -> Option<Self> {
+        let bytes = BASE64.decode(value).ok()?;
+        Some(Checksum::Sha256(bytes.try_into().ok()?))
+    }
+
+    /// Parses an OCI digest, `sha256:<hex>`.
+    pub fn oci_digest(digest: &str) -> Option<Self> {
+        let hex = digest.strip_prefix("sha256:")?;
+        Some(Checksum::Sha256(hex::decode(hex).ok()?.try_into().ok()?))
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Checksum::Md5(digest) => write!(f, "md5:{}", BASE64.encode(digest)),
+            Checksum::Crc32c(crc) => write!(f, "crc32c:{:08x}", crc),
+            Checksum::Sha256(digest) => write!(f, "sha256:{}", hex::encode(digest)),
+        }
+    }
+}
+
+// Running checksum of the bytes written so far, of the same kind as `expected`.
+enum Hasher {
+    Md5(Md5),
+    Crc32c(u32),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    fn for_checksum(expected: &Checksum) -> Self {
+        match expected {
+            Checksum::Md5(_) => Hasher::Md5(Md5::new()),
+            Checksum::Crc32c(_) => Hasher::Crc32c(0),
+            Checksum::Sha256(_) => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(md5) => md5.update(data),
+            Hasher::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+            Hasher::Sha256(sha) => sha.update(data),
+        }
+    }
+
+    fn finish(self) -> Checksum {
+        match self {
+            Hasher::Md5(md5) => Checksum::Md5(md5.finalize().into()),
+            Hasher::Crc32c(crc) => Checksum::Crc32c(crc),
+            Hasher::Sha256(sha) => Checksum::Sha256(sha.finalize().into()),
+        }
+    }
+}
+
+pub type ByteStream = BoxStream<'static, Result<Bytes, StorageError>>;
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// The object does not exist
+    NotFound(String),
+    /// The backend cannot do this (e.g. listing a plain HTTP server)
+    Unsupported(String),
+    /// The object changed while it was being read
+    Changed(String),
+    /// The downloaded bytes do not match the published checksum
+    Corrupt(String),
+    /// No usable credentials for the store
+    Auth(String),
+    Io(std::io::Error),
+    /// Any other error reported by the backend
+    Backend(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound(what) => write!(f, "{} not found", what),
+            StorageError::Unsupported(what) => write!(f, "not supported: {}", what),
+            StorageError::Changed(what) => write!(f, "{} changed during download", what),
+            StorageError::Corrupt(what) => write!(f, "checksum mismatch: {}", what),
+            StorageError::Auth(msg) => write!(f, "authentication failed: {}", msg),
+            StorageError::Io(e) => write!(f, "I/O error: {}", e),
+            StorageError::Backend(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+/// One object of a directory dataset and where it goes in the cache entry.
+pub struct Entry {
+    /// Relative path inside the entry (for archives, where the archive is
+    /// staged before unpacking)
+    pub path: String,
+    pub object: ObjectInfo,
+    /// Unpack into the entry instead of keeping the object as a file
+    pub archive: Option<Archive>,
+}
+
+/// Which page of a listing to fetch, S3 ListObjectsV2 style.
+pub struct PageRequest<'a> {
+    /// Keys are folded into common prefixes up to the first one after the prefix
+    pub delimiter: Option<&'a str>,
+    /// Only keys sorting after this one
+    pub start_after: Option<&'a str>,
+    /// Objects plus common prefixes
+    pub max_keys: usize,
+}
+
+/// One page of a listing, in key order.
+pub struct Page {
+    pub objects: Vec<ObjectInfo>,
+    /// Each ending in the delimiter
+    pub common_prefixes: Vec<String>,
+    /// More keys follow
+    pub truncated: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Archive {
+    Tar,
+    TarGzip,
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Size and version of a single object.
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError>;
+
+    /// Every object under a prefix dataset (`key` ending in `/`).
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError>;
+
+    /// One page of the objects whose keys start with `prefix.key` (any
+    /// string, not only a directory), without listing the rest.
+    async fn list_page(&self, prefix: &DatasetRef, _page: &PageRequest<'_>) -> Result<Page, StorageError> {
+        Err(StorageError::Unsupported(format!("paged listing of {}", prefix.uri)))
+    }
+
+    /// What makes up a directory dataset. By default every listed object,
+    /// at its key relative to the prefix; directory markers are skipped.
+    async fn entries(&self, dataset: &DatasetRef) -> Result<Vec<Entry>, StorageError> {
+        let objects = self.list(dataset).await?;
+        Ok(objects.into_iter()
+            .filter_map(|object| {
+                let path = object.key.strip_prefix(dataset.key.as_str())?.to_string();
+                if path.is_empty() || path.ends_with('/') {
+                    return None;
+                }
+                Some(Entry { path, object, archive: None })
+            })
+            .collect())
+    }
+
+    /// Streams `range` of an object (all of it if `None`). With `if_match`,
+    /// fails with [`StorageError::Changed`] unless the version still matches.
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError>;
+}
+
+/// One backend per scheme (per endpoint for S3), plus per-tenant S3 clients,
+/// mirror health and peers.
+pub struct Backends {
+    /// `s3_endpoint` then `s3_mirror_endpoints`, each with its host
+    s3: Vec<(String, Arc<dyn StorageBackend>)>,
+    gcs: Arc<dyn StorageBackend>,
+    azure: Arc<dyn StorageBackend>,
+    oci: Arc<dyn StorageBackend>,
+    http: Arc<dyn StorageBackend>,
+    file: Arc<dyn StorageBackend>,
+    pub tenants: TenantClients,
+    pub health: MirrorHealth,
+    pub peers: Arc<Peers>,
+    metrics: MetricsState,
+}
+
+impl Backends {
+    pub async fn from_config(config: &Config, client: Client, metrics: MetricsState, peers: Arc<Peers>) -> Self {
+        let endpoints: Vec<String> = std::iter::once(&config.s3_endpoint)
+            .chain(&config.s3_mirror_endpoints)
+            .cloned()
+            .collect();
+        let mut s3: Vec<(String, Arc<dyn StorageBackend>)> = Vec::with_capacity(endpoints.len());
+        let mut operator = None;
+        for endpoint in &endpoints {
+            let backend = Arc::new(S3Backend::new(endpoint).await);
+            operator.get_or_insert_with(|| backend.clone());
+            s3.push((endpoint_host(endpoint), backend));
+        }
+        let operator = operator.expect("s3_endpoint is always configured");
+
+        Self {
+            s3,
+            gcs: Arc::new(GcsBackend::new(&config.gcs_endpoint)),
+            azure: Arc::new(AzureBackend::new(config.azure_endpoint.as_deref())),
+            oci: Arc::new(OciBackend::new(&config.insecure_registries)),
+            http: Arc::new(HttpBackend::new()),
+            file: Arc::new(FileBackend::new(config.file_seed_root.as_deref())),
+            tenants: TenantClients::new(
+                client, endpoints, operator, config.allow_operator_credentials, config.file_seed_root.as_deref(),
+            ),
+            health: MirrorHealth::new(metrics.clone()),
+            peers,
+            metrics,
+        }
+    }
+
+    /// Every place to read a dataset from, healthy mirrors first. Each uses
+    /// its tenant's clients if it names a credentials Secret, else the shared
+    /// backend for its scheme.
+    pub async fn mirrors(&self, dataset: &DatasetRef) -> Result<Vec<Mirror>, StorageError> {
+        let mut mirrors = Vec::new();
+        for source in std::iter::once(dataset).chain(&dataset.mirrors) {
+            self.tenants.permit(source)?;
+            if source.scheme != Scheme::S3 {
+                mirrors.push(Mirror {
+                    label: format!("{}://{}", source.scheme.as_str(), source.bucket),
+                    backend: self.get(source.scheme),
+                    dataset: source.clone(),
+                    peer: false,
+                });
+                continue;
+            }
+
+            let clients: Vec<Arc<dyn StorageBackend>> = match &source.credentials {
+                Some(secret) => self.tenants.s3(secret).await?.into_iter().map(|c| c as Arc<dyn StorageBackend>).collect(),
+                None => self.s3.iter().map(|(_, backend)| backend.clone()).collect(),
+            };
+            for ((host, _), backend) in self.s3.iter().zip(clients) {
+                mirrors.push(Mirror {
+                    label: format!("s3://{}@{}", source.bucket, host),
+                    backend,
+                    dataset: source.clone(),
+                    peer: false,
+                });
+            }
+        }
+        Ok(self.health.order(mirrors))
+    }
+
+    /// Up to `MAX_PEER_SOURCES` peers holding the cache entry, each read as
+    /// an HTTP mirror rooted at the entry, healthiest first.
+    async fn peer_mirrors(&self, entry: &str) -> Vec<Mirror> {
+        let mirrors = self.peers.holders(entry, MAX_PEER_SOURCES).await.iter()
+            .filter_map(|peer| self.peer_mirror(peer, entry, None))
+            .collect();
+        self.health.order(mirrors)
+    }
+
+    fn peer_mirror(&self, peer: &Peer, entry: &str, path: Option<&str>) -> Option<Mirror> {
+        Some(Mirror {
+            label: format!("peer:{}", peer.name),
+            backend: self.http.clone(),
+            dataset: DatasetRef::parse(&peer.url(PEER_PATH, entry, path)).ok()?,
+            peer: true,
+        })
+    }
+
+    /// The shared backend for a scheme (the primary endpoint for S3).
+    pub fn get(&self, scheme: Scheme) -> Arc<dyn StorageBackend> {
+        match scheme {
+            Scheme::S3 => self.s3[0].1.clone(),
+            Scheme::Gs => self.gcs.clone(),
+            Scheme::Az => self.azure.clone(),
+            Scheme::Oci => self.oci.clone(),
+            Scheme::Http | Scheme::Https => self.http.clone(),
+            Scheme::File => self.file.clone(),
+        }
+    }
+}
+
+// `http://minio:9000/` -> `minio:9000`
+fn endpoint_host(endpoint: &str) -> String {
+    let rest = endpoint.split_once("://").map(|(_, rest)| rest).unwrap_or(endpoint);
+    rest.split('/').next().unwrap_or(rest).to_string()
+}
+
+/// Downloads a dataset into the cache at `target_path`, from peers that have
+/// it or else whichever of its mirrors answers. Prefix datasets and OCI
+/// artifacts become a directory. Time held back by request slots and
+/// bandwidth adds up in `throttle`, whether or not the download succeeds.
+#[tracing::instrument(skip(backends, dataset, throttle), fields(uri = %dataset.uri))]
+pub async fn fetch(
+    backends: &Backends,
+    dataset: &DatasetRef,
+    target_path: &str,
+    throttle: &Metered<'_>,
+) -> Result<(), StorageError> {
+    let mirrors = backends.mirrors(dataset).await?;
+    let health = &backends.health;
+    let name = Path::new(target_path).file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let peers = backends.peer_mirrors(name).await;
+    if !peers.is_empty() {
+        info!(event = "peer_sources", peers = peers.len(), "Found peers holding the dataset");
+    }
+
+    // Write to a side path, rename once complete and then mark it, so a crash
+    // never leaves a partial entry that looks like a cache hit (see `is_complete`)
+    let part_path = format!("{}.part", target_path);
+
+    if dataset.is_directory() {
+        let (listed, entries) = health.first(&mirrors, |m| async {
+            let _slot = throttle.request_slot().await;
+            m.backend.entries(&m.dataset).await
+        }).await?;
+        info!(event = "download_list", objects = entries.len(), mirror = %listed.label, "Listed directory dataset");
+
+        let _ = tokio::fs::remove_dir_all(&part_path).await;
+        tokio::fs::create_dir_all(&part_path).await?;
+        for entry in entries {
+            // Skip anything that would escape the entry
+            if !is_plain_relative(&entry.path) {
+                continue;
+            }
+            let dest = Path::new(&part_path).join(&entry.path);
+            if let Some(parent) = dest.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            // Archives are unpacked and removed, so peers never have them
+            let peers: &[Mirror] = if entry.archive.is_none() { &peers } else { &[] };
+            let copy = ObjectCopy {
+                backends, mirrors: &mirrors, listed, peers, entry: name, path: Some(&entry.path), info: &entry.object,
+            };
+            copy.run(&dest, throttle).await?;
+
+            if let Some(format) = entry.archive {
+                unpack(format, dest, Path::new(&part_path).to_path_buf()).await?;
+            }
+        }
+    } else {
+        let (listed, object) = health.first(&mirrors, |m| async {
+            let _slot = throttle.request_slot().await;
+            m.backend.stat(&m.dataset).await
+        }).await?;
+        let copy = ObjectCopy { backends, mirrors: &mirrors, listed, peers: &peers, entry: name, path: None, info: &object };
+        copy.run(Path::new(&part_path), throttle).await?;
+    }
+
+    // Anything already at the target without a marker is stale: an
+    // interrupted move, or a directory the kubelet made for a hostPath volume
+    let target = target_path.to_string();
+    blocking(move || {
+        remove_entry(Path::new(&target));
+        std::fs::rename(&part_path, &target)?;
+        mark_complete(&target)
+    }).await?;
+    info!(event = "download_complete", path = %target_path, throttled_secs = throttle.held().as_secs_f64(), "Download finished successfully");
+    Ok(())
+}
+
+// One object to download: listed or stat'ed on `listed`, at `path` inside the
+// cache entry `entry` (`None` for single-object datasets).
+struct ObjectCopy<'a> {
+    backends: &'a Backends,
+    mirrors: &'a [Mirror],
+    listed: &'a Mirror,
+    peers: &'a [Mirror],
+    entry: &'a str,
+    path: Option<&'a str>,
+    info: &'a ObjectInfo,
+}
+
+impl ObjectCopy<'_> {
+    // Peers first, if there is a source checksum to check their bytes against:
+    // chunk by chunk from the whole swarm for objects over one chunk, else
+    // streamed from the peers holding the entry. If they turn out wrong,
+    // everything is read again from the mirrors.
+    async fn run(&self, dest: &Path, throttle: &Metered<'_>) -> Result<(), StorageError> {
+        let mirrors = self.origin();
+        let expected = self.info.checksum.or_else(|| self.etag_md5());
+
+        let swarm = &self.backends.peers;
+        if let Some(expected) = expected.filter(|_| swarm.known() > 0 && swarm.chunk_count(self.info.size) > 1) {
+            return match swarm::download(self, expected, dest, throttle).await {
+                Err(StorageError::Corrupt(e)) => {
+                    warn!(event = "peer_corrupt", error = %e, "Object from the swarm failed verification, reading it from the source");
+                    self.copy(&mirrors, self.info.checksum, dest, throttle).await
+                }
+                result => result,
+            };
+        }
+        if self.peers.is_empty() || expected.is_none() {
+            return self.copy(&mirrors, self.info.checksum, dest, throttle).await;
+        }
+        let mut sources: Vec<(&Mirror, DatasetRef)> = self.peers.iter()
+            .map(|peer| {
+                let object = match self.path {
+                    Some(path) => {
+                        peer.dataset.with_key(&format!("{}/{}", peer.dataset.key, peers::escape(path)))
+                    }
+                    None => peer.dataset.clone(),
+                };
+                (peer, object)
+            })
+            .collect();
+        sources.extend(mirrors.iter().cloned());
+
+        match self.copy(&sources, expected, dest, throttle).await {
+            Err(StorageError::Corrupt(e)) => {
+                warn!(event = "peer_corrupt", error = %e, "Object from peers failed verification, reading it from the source");
+                self.copy(&mirrors, self.info.checksum, dest, throttle).await
+            }
+            result => result,
+        }
+    }
+
+    // The object on each mirror, the one it was listed on first.
+    fn origin(&self) -> Vec<(&Mirror, DatasetRef)> {
+        std::iter::once(self.listed)
+            .chain(self.mirrors.iter().filter(|m| !std::ptr::eq(*m, self.listed)))
+            .map(|m| (m, m.translate(self.listed, &self.info.key)))
+            .collect()
+    }
+
+    // Reads `range` of the object from the first of `sources` that has it,
+    // charging the throttle.
+    async fn read_range(
+        &self,
+        sources: &[(&Mirror, DatasetRef)],
+        range: Range<u64>,
+        throttle: &Metered<'_>,
+    ) -> Result<Vec<u8>, StorageError> {
+        let len = (range.end - range.start) as usize;
+        let mut last_error = None;
+        for (mirror, object) in sources {
+            let started = Instant::now();
+            let read = async {
+                let etag = if std::ptr::eq(*mirror, self.listed) {
+                    self.info.etag.clone()
+                } else {
+                    same_object(mirror, object, self.info, throttle).await?
+                };
+                let _slot = request_slot(mirror, throttle).await;
+                let mut body = mirror.backend.read(object, Some(range.clone()), etag.as_deref()).await?;
+                let mut bytes = Vec::with_capacity(len);
+                while let Some(data) = body.try_next().await? {
+                    throttle.consume(data.len() as u64).await;
+                    bytes.extend_from_slice(&data);
+                }
+                if bytes.len() != len {
+                    return Err(StorageError::Backend(format!(
+                        "{}: range {}-{} returned {} bytes", object.uri, range.start, range.end, bytes.len()
+                    )));
+                }
+                Ok(bytes)
+            }
+            .await;
+
+            match read {
+                Ok(bytes) => {
+                    self.backends.health.succeeded(mirror, Some(started.elapsed()));
+                    return Ok(bytes);
+                }
+                Err(e) if mirrors::can_fail_over(&e) => {
+                    self.backends.health.failed(mirror, &e);
+                    warn!(
+                        event = "mirror_failover", mirror = %mirror.label, uri = %object.uri, offset = range.start,
+                        error = %e, "Range read failed, trying the next mirror"
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| StorageError::Backend("dataset has no usable mirror".to_string())))
+    }
+
+    // Single-part S3 uploads have the content MD5 as their ETag. That is not
+    // true of every bucket (SSE-KMS), so this only vets peers, never the source.
+    fn etag_md5(&self) -> Option<Checksum> {
+        if self.listed.dataset.scheme != Scheme::S3 {
+            return None;
+        }
+        let etag = self.info.etag.as_deref()?.trim_matches('"');
+        if etag.len() != 32 {
+            return None;
+        }
+        Some(Checksum::Md5(hex::decode(etag).ok()?.try_into().ok()?))
+    }
+
+    // Streams the object to `dest`, charging the throttle per chunk for bytes
+    // from the object store. If a source fails part way, the rest comes from
+    // the next one that has the same object.
+    async fn copy(
+        &self,
+        sources: &[(&Mirror, DatasetRef)],
+        expected: Option<Checksum>,
+        dest: &Path,
+        throttle: &Metered<'_>,
+    ) -> Result<(), StorageError> {
+        let info = self.info;
+        let health = &self.backends.health;
+        let writing = self.backends.peers.writing(dest, info.size);
+        let mut chunks = ChunkHasher::new(self.backends.peers.chunk_size());
+        let mut file = tokio::fs::File::create(dest).await?;
+        let mut written = 0u64;
+        let mut hasher = expected.as_ref().map(Hasher::for_checksum);
+        let mut last_error = None;
+
+        for (mirror, object) in sources {
+            let started = Instant::now();
+            let copied = async {
+                let etag = if std::ptr::eq(*mirror, self.listed) {
+                    info.etag.clone()
+                } else {
+                    same_object(mirror, object, info, throttle).await?
+                };
+                let range = (written > 0).then_some(written..info.size);
+                let _slot = request_slot(mirror, throttle).await;
+                let mut body = mirror.backend.read(object, range, etag.as_deref()).await?;
+
+                while let Some(bytes) = body.try_next().await? {
+                    if !mirror.peer {
+                        throttle.consume(bytes.len() as u64).await;
+                    }
+                    file.write_all(&bytes).await?;
+                    if let Some(hasher) = &mut hasher {
+                        hasher.update(&bytes);
+                    }
+                    chunks.update(&bytes, &writing);
+                    written += bytes.len() as u64;
+                }
+                if written < info.size {
+                    return Err(StorageError::Backend(format!(
+                        "{}: connection closed after {} of {} bytes", object.uri, written, info.size
+                    )));
+                }
+                Ok(())
+            }
+            .await;
+
+            match copied {
+                Ok(()) => {
+                    health.succeeded(mirror, Some(started.elapsed()));
+                    last_error = None;
+                    break;
+                }
+                Err(e) if mirrors::can_fail_over(&e) => {
+                    health.failed(mirror, &e);
+                    warn!(
+                        event = "mirror_failover", mirror = %mirror.label, uri = %object.uri, resume_at = written,
+                        error = %e, "Object download failed, resuming from the next mirror"
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(e) = last_error {
+            return Err(e);
+        }
+        file.sync_all().await?;
+
+        if written != info.size {
+            return Err(StorageError::Backend(format!(
+                "{}: expected {} bytes, got {}", info.key, info.size, written
+            )));
+        }
+        if let (Some(expected), Some(hasher)) = (expected, hasher) {
+            let actual = hasher.finish();
+            if actual != expected {
+                return Err(StorageError::Corrupt(format!("{}: expected {}, got {}", info.key, expected, actual)));
+            }
+        }
+        chunks.finish(&writing);
+        Ok(())
+    }
+}
+
+// A request slot for one call to `mirror`, held while it lasts. Peers are
+// not object stores and take none.
+async fn request_slot<'a>(mirror: &Mirror, throttle: &Metered<'a>) -> Option<SemaphorePermit<'a>> {
+    match mirror.peer {
+        true => None,
+        false => Some(throttle.request_slot().await),
+    }
+}
+
+// Checks a mirror holds the same object as `info` describes before resuming
+// from it, and returns the mirror's version to pin. Content-addressed stores
+// (OCI) cannot stat a blob and are trusted as is.
+async fn same_object(
+    mirror: &Mirror,
+    object: &DatasetRef,
+    info: &ObjectInfo,
+    throttle: &Metered<'_>,
+) -> Result<Option<String>, StorageError> {
+    let stat = async {
+        let _slot = request_slot(mirror, throttle).await;
+        mirror.backend.stat(object).await
+    };
+    let found = match stat.await {
+        Ok(found) => found,
+        Err(StorageError::Unsupported(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    // Stores may publish different kinds of checksum; only like can be compared
+    let checksums_differ = matches!(
+        (found.checksum, info.checksum),
+        (Some(a), Some(b)) if std::mem::discriminant(&a) == std::mem::discriminant(&b) && a != b
+    );
+    if found.size != info.size || checksums_differ {
+        return Err(StorageError::NotFound(format!("{} with the same content", object.uri)));
+    }
+    Ok(found.etag)
+}
+
+// Runs file work that blocks (large writes, syncs, renames, removing trees)
+// on the blocking pool, so downloads never stall the runtime's workers.
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> std::io::Result<T> + Send + 'static) -> Result<T, StorageError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| StorageError::Backend(format!("file task failed: {}", e)))?
+        .map_err(StorageError::from)
+}
+
+// Extracts a (verified) archive into `into`, then removes it. The tar crate
+// refuses entries that would land outside `into`.
+async fn unpack(format: Archive, archive: PathBuf, into: PathBuf) -> Result<(), StorageError> {
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&archive)?;
+        match format {
+            Archive::Tar => tar::Archive::new(file).unpack(&into)?,
+            Archive::TarGzip => tar::Archive::new(flate2::read::GzDecoder::new(file)).unpack(&into)?,
+        }
+        std::fs::remove_file(&archive)
+    })
+    .await
+    .map_err(|e| StorageError::Backend(format!("unpack task failed: {}", e)))??;
+    Ok(())
+}
+
+/// True if `path` only names things below the directory it is joined to.
+pub fn is_plain_relative(path: &str) -> bool {
+    Path::new(path).components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+/// True if the cache entry at `path` was written in full. The path existing
+/// is not enough: the kubelet creates missing hostPath volumes, and a crash
+/// can leave an entry moved into place but never marked. Must match
+/// `is_complete` in the CSI plugin.
+pub fn is_complete(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+    marker(path).exists() && path.exists()
+}
+
+/// Marks the entry at `path` complete, once it is in place.
+pub fn mark_complete(path: impl AsRef<Path>) -> std::io::Result<()> {
+    std::fs::File::create(marker(path.as_ref())).map(drop)
+}
+
+/// Stops the entry at `path` counting as cached, ahead of removing it.
+pub fn unmark(path: impl AsRef<Path>) -> std::io::Result<()> {
+    match std::fs::remove_file(marker(path.as_ref())) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn marker(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(COMPLETE_SUFFIX);
+    PathBuf::from(marker)
+}
+
+// Removes whatever is at `path`, file or directory, if anything.
+fn remove_entry(path: &Path) {
+    let _ = match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(_) => return,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_marked_entries_are_complete() {
+        let root = std::env::temp_dir().join(format!("kube-cache-marker-{}", std::process::id()));
+        let entry = root.join("models-weights");
+        std::fs::create_dir_all(&entry).unwrap();
+        // What the kubelet leaves behind for a missing hostPath volume
+        assert!(!is_complete(&entry));
+
+        mark_complete(&entry).unwrap();
+        assert!(is_complete(&entry));
+
+        unmark(&entry).unwrap();
+        unmark(&entry).unwrap();
+        assert!(!is_complete(&entry));
+
+        // A marker alone is no entry either
+        mark_complete(&entry).unwrap();
+        remove_entry(&entry);
+        assert!(!entry.exists());
+        assert!(!is_complete(&entry));
+        let _ = std::fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,369 @@
+// OCI registry artifacts (ORAS-style), `oci://registry/repo:tag` or
+// `oci://registry/repo@sha256:<digest>`; the tag defaults to `latest`. The
+// manifest is resolved (an image index picks its linux/amd64 manifest, or the
+// first one) and every layer is pulled by digest and verified against it:
+//
+//   titled layers (`org.opencontainers.image.title`) are stored under their
+//   title, or unpacked if ORAS packed a directory (`io.deis.oras.content.unpack`)
+//   untitled tar / tar+gzip layers (container images) are unpacked in order
+//
+// Registries that answer 401 get Basic auth or a bearer token from their token
+// service, with credentials from a Docker config file (`REGISTRY_AUTH_FILE`,
+// else `$DOCKER_CONFIG/config.json`) when it has the registry, anonymous pull
+// tokens otherwise. `insecure_registries` (e.g. a local registry:2) use plain HTTP.
+
+use super::http::{backend_error, check_status};
+use super::token::{TokenCache, TokenResponse};
+use super::{Archive, ByteStream, Checksum, Entry, ObjectInfo, StorageBackend, StorageError};
+use crate::dataset::DatasetRef;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::header::{HeaderMap, ACCEPT, AUTHORIZATION, RANGE, WWW_AUTHENTICATE};
+use reqwest::{RequestBuilder, StatusCode};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+const MANIFEST_TYPES: &str = "application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json";
+const TITLE_ANNOTATION: &str = "org.opencontainers.image.title";
+const UNPACK_ANNOTATION: &str = "io.deis.oras.content.unpack";
+
+#[derive(Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    layers: Vec<Descriptor>,
+    /// Only set on image indexes
+    #[serde(default)]
+    manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: String,
+    digest: String,
+    size: u64,
+    #[serde(default)]
+    annotations: HashMap<String, String>,
+    platform: Option<Platform>,
+}
+
+#[derive(Deserialize)]
+struct Platform {
+    os: String,
+    architecture: String,
+}
+
+// Docker config file; `auth` is base64 `user:password`
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, DockerAuth>,
+}
+
+#[derive(Deserialize)]
+struct DockerAuth {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+// Token service responses use either field name, expiry is optional
+#[derive(Deserialize)]
+struct RegistryToken {
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+enum Challenge {
+    Basic,
+    Bearer { realm: String, service: Option<String>, scope: String },
+}
+
+// What a repository asked for on its first 401
+struct RepoAuth {
+    challenge: Challenge,
+    token: TokenCache,
+}
+
+pub struct OciBackend {
+    client: reqwest::Client,
+    insecure: Vec<String>,
+    /// base64 `user:password` per registry host
+    logins: HashMap<String, String>,
+    /// Keyed by `registry/repo`
+    auth: Mutex<HashMap<String, Arc<RepoAuth>>>,
+}
+
+impl OciBackend {
+    pub fn new(insecure_registries: &[String]) -> Self {
+        let logins = load_logins();
+        info!(event = "config_check", logins = logins.len(), insecure = ?insecure_registries, "Configured OCI registry access");
+        Self {
+            client: reqwest::Client::new(),
+            insecure: insecure_registries.to_vec(),
+            logins,
+            auth: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // GET `/v2/<repo>/<path>`, answering an auth challenge once if needed
+    async fn get(&self, dataset: &DatasetRef, path: &str, headers: HeaderMap) -> Result<reqwest::Response, StorageError> {
+        let registry = &dataset.bucket;
+        let (repo, _) = split_reference(&dataset.key);
+        let protocol = if self.insecure.iter().any(|r| r == registry) { "http" } else { "https" };
+        let url = format!("{}://{}/v2/{}/{}", protocol, registry, repo, path);
+        let auth_key = format!("{}/{}", registry, repo);
+
+        let known = self.auth.lock().await.get(&auth_key).cloned();
+        let mut req = self.client.get(&url).headers(headers.clone());
+        if let Some(auth) = &known {
+            req = self.authorize(req, auth, registry).await?;
+        }
+        let mut resp = req.send().await.map_err(backend_error)?;
+
+        if resp.status() == StatusCode::UNAUTHORIZED && known.is_none() {
+            let challenge = resp.headers().get(WWW_AUTHENTICATE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| parse_challenge(v, repo))
+                .ok_or_else(|| StorageError::Auth(format!("{}: unsupported registry auth challenge", registry)))?;
+            let auth = Arc::new(RepoAuth { challenge, token: TokenCache::new() });
+            self.auth.lock().await.insert(auth_key, auth.clone());
+
+            let req = self.authorize(self.client.get(&url).headers(headers), &auth, registry).await?;
+            resp = req.send().await.map_err(backend_error)?;
+        }
+        if resp.status() == StatusCode::UNAUTHORIZED || resp.status() == StatusCode::FORBIDDEN {
+            return Err(StorageError::Auth(format!("{}: registry returned {}", dataset.uri, resp.status())));
+        }
+        check_status(dataset, resp)
+    }
+
+    async fn authorize(&self, req: RequestBuilder, auth: &RepoAuth, registry: &str) -> Result<RequestBuilder, StorageError> {
+        let login = self.logins.get(registry);
+        match &auth.challenge {
+            Challenge::Basic => {
+                let login = login.ok_or_else(|| StorageError::Auth(format!("{} requires credentials", registry)))?;
+                Ok(req.header(AUTHORIZATION, format!("Basic {}", login)))
+            }
+            Challenge::Bearer { realm, service, scope } => {
+                let token = auth.token.get(|| self.fetch_token(realm, service.as_deref(), scope, login)).await?;
+                Ok(req.header(AUTHORIZATION, format!("Bearer {}", token)))
+            }
+        }
+    }
+
+    async fn fetch_token(
+        &self,
+        realm: &str,
+        service: Option<&str>,
+        scope: &str,
+        login: Option<&String>,
+    ) -> Result<TokenResponse, StorageError> {
+        let mut query = vec![("scope", scope)];
+        if let Some(service) = service {
+            query.push(("service", service));
+        }
+        let mut req = self.client.get(realm).query(&query);
+        if let Some(login) = login {
+            req = req.header(AUTHORIZATION, format!("Basic {}", login));
+        }
+
+        let resp = req.send().await.map_err(|e| StorageError::Auth(e.to_string()))?;
+        if !resp.status().is_success() {
+            return Err(StorageError::Auth(format!("token service {} returned {}", realm, resp.status())));
+        }
+        let token: RegistryToken = resp.json().await
+            .map_err(|e| StorageError::Auth(format!("bad token response: {}", e)))?;
+        Ok(TokenResponse {
+            access_token: token.token.or(token.access_token)
+                .ok_or_else(|| StorageError::Auth(format!("token service {} returned no token", realm)))?,
+            // The spec's default lifetime
+            expires_in: token.expires_in.unwrap_or(60),
+        })
+    }
+
+    // Fetches a manifest and, when pinned by digest, checks it
+    async fn manifest(&self, dataset: &DatasetRef, reference: &str) -> Result<Manifest, StorageError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, MANIFEST_TYPES.parse().expect("static header value"));
+        let body = self.get(dataset, &format!("manifests/{}", reference), headers).await?
+            .bytes().await.map_err(backend_error)?;
+
+        if let Some(expected) = Checksum::oci_digest(reference) {
+            let actual = Checksum::Sha256(Sha256::digest(&body).into());
+            if actual != expected {
+                return Err(StorageError::Corrupt(format!("{}: manifest is {}", dataset.uri, actual)));
+            }
+        }
+        serde_json::from_slice(&body)
+            .map_err(|e| StorageError::Backend(format!("{}: bad manifest: {}", dataset.uri, e)))
+    }
+}
+
+/// Splits `repo:tag` / `repo@digest` into the repository and reference.
+pub(super) fn split_reference(key: &str) -> (&str, &str) {
+    if let Some((repo, digest)) = key.split_once('@') {
+        return (repo, digest);
+    }
+    match key.rsplit_once(':') {
+        Some((repo, tag)) if !tag.contains('/') => (repo, tag),
+        _ => (key, "latest"),
+    }
+}
+
+// `Bearer realm="...",service="...",scope="..."` or `Basic realm="..."`
+fn parse_challenge(header: &str, repo: &str) -> Option<Challenge> {
+    let (scheme, params) = header.split_once(' ').unwrap_or((header, ""));
+    if scheme.eq_ignore_ascii_case("basic") {
+        return Some(Challenge::Basic);
+    }
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    // Values are quoted and may contain commas (`scope="repository:a:pull,push"`)
+    let mut values = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((name, after)) = rest.split_once('=') {
+        let after = after.trim_start();
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        values.insert(name.trim().to_ascii_lowercase(), value.to_string());
+        rest = next.trim_start_matches([',', ' ']);
+    }
+
+    Some(Challenge::Bearer {
+        realm: values.remove("realm")?,
+        service: values.remove("service"),
+        scope: values.remove("scope").unwrap_or_else(|| format!("repository:{}:pull", repo)),
+    })
+}
+
+fn load_logins() -> HashMap<String, String> {
+    let path = match std::env::var("REGISTRY_AUTH_FILE") {
+        Ok(path) if !path.is_empty() => path,
+        _ => match std::env::var("DOCKER_CONFIG") {
+            Ok(dir) if !dir.is_empty() => format!("{}/config.json", dir),
+            _ => return HashMap::new(),
+        },
+    };
+    let config: DockerConfig = match std::fs::read_to_string(&path).map_err(|e| e.to_string())
+        .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            warn!(event = "oci_credentials_error", path = %path, error = %e, "Ignoring unusable registry auth file");
+            return HashMap::new();
+        }
+    };
+
+    config.auths.into_iter()
+        .filter_map(|(server, entry)| {
+            let login = match (entry.auth, entry.username, entry.password) {
+                (Some(auth), _, _) if !auth.is_empty() => auth,
+                (_, Some(user), Some(password)) => BASE64.encode(format!("{}:{}", user, password)),
+                _ => return None,
+            };
+            // Keys may be bare hosts or URLs like https://index.docker.io/v1/
+            let host = server.trim_start_matches("https://").trim_start_matches("http://");
+            Some((host.split('/').next().unwrap_or(host).to_string(), login))
+        })
+        .collect()
+}
+
+fn is_tarball(media_type: &str) -> Option<Archive> {
+    if media_type.ends_with("tar+gzip") || media_type.ends_with("tar.gzip") {
+        Some(Archive::TarGzip)
+    } else if media_type.ends_with(".tar") || media_type.ends_with("+tar") {
+        Some(Archive::Tar)
+    } else {
+        None
+    }
+}
+
+#[async_trait]
+impl StorageBackend for OciBackend {
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError> {
+        Err(StorageError::Unsupported(format!("{} is an artifact, not a single object", object.uri)))
+    }
+
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError> {
+        Err(StorageError::Unsupported(format!("listing {} (use the artifact's tag or digest)", prefix.uri)))
+    }
+
+    async fn entries(&self, dataset: &DatasetRef) -> Result<Vec<Entry>, StorageError> {
+        let (repo, reference) = split_reference(&dataset.key);
+        let mut manifest = self.manifest(dataset, reference).await?;
+
+        if !manifest.manifests.is_empty() {
+            let chosen = manifest.manifests.iter()
+                .find(|m| m.platform.as_ref().map(|p| p.os == "linux" && p.architecture == "amd64").unwrap_or(false))
+                .unwrap_or(&manifest.manifests[0]);
+            let digest = chosen.digest.clone();
+            manifest = self.manifest(dataset, &digest).await?;
+        }
+
+        manifest.layers.into_iter()
+            .map(|layer| {
+                let checksum = Checksum::oci_digest(&layer.digest).ok_or_else(|| {
+                    StorageError::Unsupported(format!("{}: cannot verify layer digest {}", dataset.uri, layer.digest))
+                })?;
+                let staged = format!(".layer-{}", layer.digest.trim_start_matches("sha256:"));
+                let (path, archive) = match layer.annotations.get(TITLE_ANNOTATION) {
+                    Some(_) if layer.annotations.get(UNPACK_ANNOTATION).map(String::as_str) == Some("true") => {
+                        (staged, Some(is_tarball(&layer.media_type).unwrap_or(Archive::TarGzip)))
+                    }
+                    Some(title) => (title.clone(), None),
+                    None => match is_tarball(&layer.media_type) {
+                        Some(archive) => (staged, Some(archive)),
+                        None => (staged.trim_start_matches('.').to_string(), None),
+                    },
+                };
+                Ok(Entry {
+                    path,
+                    object: ObjectInfo {
+                        key: format!("{}@{}", repo, layer.digest),
+                        size: layer.size,
+                        etag: None,
+                        checksum: Some(checksum),
+                    },
+                    archive,
+                })
+            })
+            .collect()
+    }
+
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        _if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError> {
+        // Blobs are content-addressed, so there is no version to pin
+        let (_, digest) = split_reference(&object.key);
+        let mut headers = HeaderMap::new();
+        if let Some(r) = &range {
+            let value = format!("bytes={}-{}", r.start, r.end.saturating_sub(1));
+            headers.insert(RANGE, value.parse().map_err(|_| StorageError::Backend("bad range".to_string()))?);
+        }
+
+        let resp = self.get(object, &format!("blobs/{}", digest), headers).await?;
+        if range.is_some() && resp.status() != StatusCode::PARTIAL_CONTENT {
+            return Err(StorageError::Unsupported(format!("{}: registry ignored the Range header", object.uri)));
+        }
+        Ok(resp.bytes_stream().map_err(backend_error).boxed())
+    }
+}
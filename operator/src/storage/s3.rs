@@ -0,0 +1,180 @@
+// S3 / MinIO. Path-style addressing so MinIO works without wildcard DNS.
+// HEAD asks for the additional checksum objects may have been uploaded with
+// (CRC32C or SHA-256); composite checksums of multipart uploads are not used.
+
+use super::{ByteStream, Checksum, ObjectInfo, Page, PageRequest, StorageBackend, StorageError};
+use crate::dataset::DatasetRef;
+use async_trait::async_trait;
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::config::{Region, SharedCredentialsProvider};
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::types::{ChecksumMode, Object};
+use aws_sdk_s3::Client as S3Client;
+use futures::StreamExt;
+use std::ops::Range;
+use tracing::info;
+
+pub struct S3Backend {
+    client: S3Client,
+}
+
+impl S3Backend {
+    /// Client with the operator's own identity (env, IRSA, instance profile).
+    pub async fn new(endpoint: &str) -> Self {
+        info!(event = "config_check", endpoint = %endpoint, "Connecting to S3 Storage");
+        Self::build(endpoint, None, None).await
+    }
+
+    /// Client with a tenant's credentials (see `credentials`).
+    pub async fn with_credentials(
+        endpoint: &str,
+        region: Option<String>,
+        credentials: SharedCredentialsProvider,
+    ) -> Self {
+        Self::build(endpoint, region, Some(credentials)).await
+    }
+
+    async fn build(endpoint: &str, region: Option<String>, credentials: Option<SharedCredentialsProvider>) -> Self {
+        let region_provider = RegionProviderChain::first_try(region.map(Region::new))
+            .or_default_provider()
+            .or_else(Region::new("us-east-1"));
+
+        let mut loader = aws_config::defaults(aws_config::BehaviorVersion::latest())
+            .region(region_provider)
+            .endpoint_url(endpoint);
+        if let Some(credentials) = credentials {
+            loader = loader.credentials_provider(credentials);
+        }
+        let config = loader.load().await;
+
+        let s3_config = aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(true)
+            .build();
+
+        Self { client: S3Client::from_conf(s3_config) }
+    }
+
+    /// Checks that this client may read the dataset: HEAD for an object,
+    /// a one-key listing for a prefix.
+    pub async fn check_access(&self, dataset: &DatasetRef) -> Result<(), StorageError> {
+        if dataset.is_prefix() {
+            self.client.list_objects_v2()
+                .bucket(&dataset.bucket)
+                .prefix(&dataset.key)
+                .max_keys(1)
+                .send()
+                .await
+                .map_err(backend_error)?;
+            return Ok(());
+        }
+        self.stat(dataset).await.map(|_| ())
+    }
+}
+
+fn object_info(object: &Object) -> Option<ObjectInfo> {
+    Some(ObjectInfo {
+        key: object.key()?.to_string(),
+        size: object.size().unwrap_or(0).max(0) as u64,
+        etag: object.e_tag().map(str::to_string),
+        checksum: None,
+    })
+}
+
+fn backend_error<E: std::error::Error>(e: E) -> StorageError {
+    StorageError::Backend(DisplayErrorContext(e).to_string())
+}
+
+#[async_trait]
+impl StorageBackend for S3Backend {
+    async fn stat(&self, object: &DatasetRef) -> Result<ObjectInfo, StorageError> {
+        let head = self.client.head_object()
+            .bucket(&object.bucket)
+            .key(&object.key)
+            .checksum_mode(ChecksumMode::Enabled)
+            .send()
+            .await
+            .map_err(|e| match e.as_service_error() {
+                Some(se) if se.is_not_found() => StorageError::NotFound(object.uri.clone()),
+                _ => backend_error(e),
+            })?;
+
+        Ok(ObjectInfo {
+            key: object.key.clone(),
+            size: head.content_length().unwrap_or(0).max(0) as u64,
+            etag: head.e_tag().map(str::to_string),
+            checksum: head.checksum_crc32_c().and_then(Checksum::crc32c_base64)
+                .or_else(|| head.checksum_sha256().and_then(Checksum::sha256_base64)),
+        })
+    }
+
+    async fn list(&self, prefix: &DatasetRef) -> Result<Vec<ObjectInfo>, StorageError> {
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let page = self.client.list_objects_v2()
+                .bucket(&prefix.bucket)
+                .prefix(&prefix.key)
+                .set_continuation_token(token.take())
+                .send()
+                .await
+                .map_err(backend_error)?;
+
+            objects.extend(page.contents().iter().filter_map(object_info));
+
+            match page.next_continuation_token() {
+                Some(next) if page.is_truncated().unwrap_or(false) => token = Some(next.to_string()),
+                _ => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn list_page(&self, prefix: &DatasetRef, page: &PageRequest<'_>) -> Result<Page, StorageError> {
+        let resp = self.client.list_objects_v2()
+            .bucket(&prefix.bucket)
+            .prefix(&prefix.key)
+            .set_delimiter(page.delimiter.map(str::to_string))
+            .set_start_after(page.start_after.map(str::to_string))
+            .max_keys(i32::try_from(page.max_keys).unwrap_or(i32::MAX))
+            .send()
+            .await
+            .map_err(backend_error)?;
+        Ok(Page {
+            objects: resp.contents().iter().filter_map(object_info).collect(),
+            common_prefixes: resp.common_prefixes().iter().filter_map(|p| p.prefix().map(str::to_string)).collect(),
+            truncated: resp.is_truncated().unwrap_or(false),
+        })
+    }
+
+    async fn read(
+        &self,
+        object: &DatasetRef,
+        range: Option<Range<u64>>,
+        if_match: Option<&str>,
+    ) -> Result<ByteStream, StorageError> {
+        let resp = self.client.get_object()
+            .bucket(&object.bucket)
+            .key(&object.key)
+            .set_range(range.map(|r| format!("bytes={}-{}", r.start, r.end.saturating_sub(1))))
+            .set_if_match(if_match.map(str::to_string))
+            .send()
+            .await
+            .map_err(|e| {
+                let status = e.raw_response().map(|r| r.status().as_u16());
+                match e.as_service_error() {
+                    Some(se) if se.is_no_such_key() => StorageError::NotFound(object.uri.clone()),
+                    _ if status == Some(412) => StorageError::Changed(object.uri.clone()),
+                    _ => backend_error(e),
+                }
+            })?;
+
+        let body = futures::stream::unfold(resp.body, |mut body| async move {
+            body.try_next().await
+                .map_err(backend_error)
+                .transpose()
+                .map(|chunk| (chunk, body))
+        });
+        Ok(body.boxed())
+    }
+}
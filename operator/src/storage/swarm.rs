@@ -0,0 +1,435 @@
+// --- SWARM TRANSFERS ---
+// Objects larger than one chunk are fetched chunk by chunk from every peer
+// working on the same object, BitTorrent style, instead of all of it from one
+// source:
+//
+// - Peers advertise the chunks they hold and the ones they are fetching
+//   (see `peers`); adverts are polled every `ADVERT_INTERVAL` and the set of
+//   peers re-probed every `PROBE_INTERVAL`.
+// - Chunks some peer holds are fetched rarest first, from the least busy
+//   holder, and must match the SHA-256 that peer sends along.
+// - Chunks no one holds are split between the peers on the object: the
+//   i-th member (by pod name) fetches every chunk `c` with
+//   `c % members == i` from the mirrors, unless another member already is.
+//   If the swarm makes no progress for `STALL_TIMEOUT`, anything missing
+//   comes from the mirrors.
+//
+// So a cluster-wide rollout reads close to one copy from the object store.
+// The whole object is still checked against the source checksum at the end.
+
+use super::{blocking, Hasher, Mirror, ObjectCopy, StorageError};
+use crate::dataset::DatasetRef;
+use crate::peers::{decode_bits, Advert, Peer, Writing, CHUNK_HASH_HEADER, CHUNK_PATH};
+use crate::throttle::Metered;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Chunks fetched at once
+const PARALLEL: usize = 4;
+/// Peers a swarm download follows, at most
+const SWARM_PEERS: usize = 16;
+const ADVERT_INTERVAL: Duration = Duration::from_secs(2);
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// Failed chunk requests after which a peer is left out until re-probed
+const MAX_PEER_FAILURES: u32 = 3;
+const STALL_TIMEOUT: Duration = Duration::from_secs(15);
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Hashes chunks of an object written front to back and marks them done.
+pub(super) struct ChunkHasher {
+    chunk_size: u64,
+    index: usize,
+    filled: u64,
+    sha: Sha256,
+}
+
+impl ChunkHasher {
+    pub(super) fn new(chunk_size: u64) -> Self {
+        Self { chunk_size, index: 0, filled: 0, sha: Sha256::new() }
+    }
+
+    pub(super) fn update(&mut self, mut data: &[u8], writing: &Writing) {
+        if self.chunk_size == 0 {
+            return;
+        }
+        while !data.is_empty() {
+            let take = (self.chunk_size - self.filled).min(data.len() as u64) as usize;
+            self.sha.update(&data[..take]);
+            self.filled += take as u64;
+            data = &data[take..];
+            if self.filled == self.chunk_size {
+                writing.done(self.index, self.sha.finalize_reset().into());
+                self.index += 1;
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Marks the last, short chunk.
+    pub(super) fn finish(self, writing: &Writing) {
+        if self.chunk_size > 0 && self.filled > 0 {
+            writing.done(self.index, self.sha.finalize().into());
+        }
+    }
+}
+
+// A peer with the entry, with its last advert for the object
+struct Member {
+    peer: Peer,
+    mirror: Arc<Mirror>,
+    /// Its advert is for this very object
+    on_object: bool,
+    have: Vec<bool>,
+    fetching: Vec<bool>,
+    busy: usize,
+    failures: u32,
+}
+
+enum Source {
+    Peer(usize),
+    Origin,
+}
+
+struct Chunk {
+    index: usize,
+    bytes: Vec<u8>,
+    hash: [u8; 32],
+}
+
+/// Fetches one object chunk by chunk from its swarm and the mirrors into
+/// `dest`, checking it against `expected` once complete.
+pub(super) async fn download(
+    copy: &ObjectCopy<'_>,
+    expected: super::Checksum,
+    dest: &Path,
+    throttle: &Metered<'_>,
+) -> Result<(), StorageError> {
+    let backends = copy.backends;
+    let info = copy.info;
+    let chunk_size = backends.peers.chunk_size();
+    let count = backends.peers.chunk_count(info.size);
+    let writing = backends.peers.writing_chunks(dest, info.size);
+    let (path, size) = (dest.to_path_buf(), info.size);
+    let file = Arc::new(blocking(move || {
+        let file = std::fs::File::create(path)?;
+        file.set_len(size)?;
+        Ok(file)
+    }).await?);
+
+    let client = reqwest::Client::builder().timeout(CHUNK_TIMEOUT).build().unwrap_or_default();
+    let origin = copy.origin();
+    let own_name = backends.peers.own_name().unwrap_or_default().to_string();
+    let mut members: Vec<Member> = Vec::new();
+    let mut have = vec![false; count];
+    let mut in_flight = vec![false; count];
+    let (mut from_peers, mut from_origin) = (0u64, 0u64);
+    let mut last_probe: Option<Instant> = None;
+    let mut advert_due = Instant::now();
+    let mut last_progress = Instant::now();
+    let mut tasks = FuturesUnordered::new();
+
+    while have.iter().any(|h| !h) {
+        if last_probe.map(|t| t.elapsed() >= PROBE_INTERVAL).unwrap_or(true) {
+            let peers = backends.peers.holders(copy.entry, SWARM_PEERS).await;
+            members.retain(|m| m.busy > 0 || peers.contains(&m.peer));
+            for member in &mut members {
+                member.failures = 0;
+            }
+            for peer in peers {
+                if members.iter().any(|m| m.peer == peer) {
+                    continue;
+                }
+                if let Some(mirror) = backends.peer_mirror(&peer, copy.entry, copy.path) {
+                    members.push(Member {
+                        peer,
+                        mirror: Arc::new(mirror),
+                        on_object: false,
+                        have: vec![false; count],
+                        fetching: vec![false; count],
+                        busy: 0,
+                        failures: 0,
+                    });
+                }
+            }
+            members.sort_by(|a, b| a.peer.name.cmp(&b.peer.name));
+            last_probe = Some(Instant::now());
+            advert_due = Instant::now();
+        }
+        if Instant::now() >= advert_due {
+            if refresh_adverts(&client, copy, &mut members, info.size, chunk_size).await {
+                last_progress = Instant::now();
+            }
+            advert_due = Instant::now() + ADVERT_INTERVAL;
+        }
+
+        let stalled = last_progress.elapsed() >= STALL_TIMEOUT;
+        while tasks.len() < PARALLEL {
+            let Some((index, source)) = pick(&members, &have, &in_flight, &own_name, stalled) else { break };
+            in_flight[index] = true;
+            let range = index as u64 * chunk_size..((index as u64 + 1) * chunk_size).min(info.size);
+            let peer = match source {
+                Source::Peer(m) => {
+                    members[m].busy += 1;
+                    Some((members[m].peer.clone(), members[m].mirror.clone()))
+                }
+                Source::Origin => {
+                    writing.fetching(index, true);
+                    None
+                }
+            };
+            let name = peer.as_ref().map(|(p, _)| p.name.clone());
+            let fetch = fetch_chunk(&client, copy, peer, &origin, index, range, throttle);
+            tasks.push(async move { (index, name, fetch.await) });
+        }
+
+        if tasks.is_empty() {
+            tokio::time::sleep(ADVERT_INTERVAL).await;
+            continue;
+        }
+        let wait = advert_due.saturating_duration_since(Instant::now());
+        let Ok(Some((index, peer, result))) = tokio::time::timeout(wait, tasks.next()).await else { continue };
+        in_flight[index] = false;
+        let from_peer = peer.is_some();
+        let member = peer.and_then(|name| members.iter().position(|m| m.peer.name == name));
+        if let Some(m) = member {
+            members[m].busy -= 1;
+        }
+
+        match result {
+            Ok(Chunk { index, bytes, hash }) => {
+                let (len, file) = (bytes.len() as u64, file.clone());
+                blocking(move || file.write_all_at(&bytes, index as u64 * chunk_size)).await?;
+                writing.done(index, hash);
+                have[index] = true;
+                if from_peer {
+                    from_peers += len;
+                } else {
+                    from_origin += len;
+                }
+                last_progress = Instant::now();
+            }
+            Err(e) if from_peer => {
+                // Not from that peer again until its next advert
+                if let Some(m) = member {
+                    let member = &mut members[m];
+                    backends.health.failed(&member.mirror, &e);
+                    member.have[index] = false;
+                    member.failures += 1;
+                    debug!(event = "swarm_chunk_error", peer = %member.peer.name, chunk = index, error = %e, "Peer failed to serve chunk");
+                }
+            }
+            Err(e) => {
+                writing.fetching(index, false);
+                return Err(e);
+            }
+        }
+    }
+    blocking(move || file.sync_all()).await?;
+    backends.metrics.count_swarm_bytes("peer", from_peers);
+    backends.metrics.count_swarm_bytes("origin", from_origin);
+    info!(
+        event = "swarm_complete", uri = %info.key, chunks = count, peers = members.len(),
+        peer_bytes = from_peers, origin_bytes = from_origin, "Fetched object from the swarm"
+    );
+
+    verify(dest, expected).await?;
+    Ok(())
+}
+
+// Polls every member's advert for this object. True if any of them holds
+// more chunks than before.
+async fn refresh_adverts(
+    client: &reqwest::Client,
+    copy: &ObjectCopy<'_>,
+    members: &mut [Member],
+    size: u64,
+    chunk_size: u64,
+) -> bool {
+    let count = copy.backends.peers.chunk_count(size);
+    let requests = members.iter().map(|m| {
+        let url = m.peer.url(CHUNK_PATH, copy.entry, copy.path);
+        async move {
+            let resp = client.get(url).send().await.ok()?.error_for_status().ok()?;
+            resp.json::<Advert>().await.ok()
+        }
+    });
+    let adverts = futures::future::join_all(requests).await;
+
+    let mut progressed = false;
+    for (member, advert) in members.iter_mut().zip(adverts) {
+        // A peer not (yet) on this object, or with another version of it
+        let Some(advert) = advert.filter(|a| a.size == size && a.chunk_size == chunk_size) else {
+            member.on_object = false;
+            member.have = vec![false; count];
+            member.fetching = vec![false; count];
+            continue;
+        };
+        member.on_object = true;
+        let have = decode_bits(&advert.have, count);
+        progressed |= have.iter().zip(&member.have).any(|(new, old)| *new && !old);
+        member.have = have;
+        member.fetching = decode_bits(&advert.fetching, count);
+    }
+    progressed
+}
+
+// The next chunk to fetch and where from, if any is worth starting now.
+fn pick(members: &[Member], have: &[bool], in_flight: &[bool], own_name: &str, stalled: bool) -> Option<(usize, Source)> {
+    let wanted = |c: &usize| !have[*c] && !in_flight[*c];
+
+    // Rarest chunk some peer holds, from its least busy holder
+    let usable = |m: &usize| members[*m].failures < MAX_PEER_FAILURES;
+    let from_peer = (0..have.len()).filter(wanted)
+        .filter_map(|c| {
+            let holders: Vec<usize> = (0..members.len()).filter(usable).filter(|m| members[*m].have[c]).collect();
+            let idlest = holders.iter().min_by_key(|m| members[**m].busy).copied()?;
+            Some((holders.len(), c, idlest))
+        })
+        .min_by_key(|(rarity, c, _)| (*rarity, *c));
+    if let Some((_, c, m)) = from_peer {
+        return Some((c, Source::Peer(m)));
+    }
+
+    // This pod's share of the chunks nobody has yet
+    let on_object: Vec<&Member> = members.iter().filter(|m| m.on_object).collect();
+    let total = on_object.len() + 1;
+    let rank = on_object.iter().filter(|m| m.peer.name.as_str() < own_name).count();
+    (0..have.len()).filter(wanted)
+        .find(|c| {
+            let someone_fetching = on_object.iter().any(|m| m.fetching[*c]);
+            stalled || (c % total == rank && !someone_fetching)
+        })
+        .map(|c| (c, Source::Origin))
+}
+
+// Fetches one chunk from a peer (checking the hash it sends) or from the
+// mirrors in turn.
+async fn fetch_chunk(
+    client: &reqwest::Client,
+    copy: &ObjectCopy<'_>,
+    peer: Option<(Peer, Arc<Mirror>)>,
+    origin: &[(&Mirror, DatasetRef)],
+    index: usize,
+    range: std::ops::Range<u64>,
+    throttle: &Metered<'_>,
+) -> Result<Chunk, StorageError> {
+    if let Some((peer, mirror)) = peer {
+        let started = Instant::now();
+        let url = format!("{}?index={}", peer.url(CHUNK_PATH, copy.entry, copy.path), index);
+        let bad = |what: &str| StorageError::Backend(format!("{} chunk {} from {}: {}", copy.info.key, index, peer.name, what));
+        let resp = client.get(url).send().await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| bad(&e.to_string()))?;
+        let claimed = resp.headers().get(CHUNK_HASH_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| hex::decode(v).ok())
+            .ok_or_else(|| bad("no chunk hash"))?;
+        let bytes = resp.bytes().await.map_err(|e| bad(&e.to_string()))?.to_vec();
+        let hash: [u8; 32] = Sha256::digest(&bytes).into();
+        if bytes.len() as u64 != range.end - range.start || hash[..] != claimed[..] {
+            return Err(StorageError::Corrupt(format!("{} chunk {} from {}", copy.info.key, index, peer.name)));
+        }
+        copy.backends.health.succeeded(&mirror, Some(started.elapsed()));
+        return Ok(Chunk { index, bytes, hash });
+    }
+
+    let bytes = copy.read_range(origin, range, throttle).await?;
+    let hash = Sha256::digest(&bytes).into();
+    Ok(Chunk { index, bytes, hash })
+}
+
+// Re-reads the assembled object and checks it against the source checksum.
+async fn verify(dest: &Path, expected: super::Checksum) -> Result<(), StorageError> {
+    let path = dest.to_path_buf();
+    let actual = tokio::task::spawn_blocking(move || {
+        let mut file = std::fs::File::open(&path)?;
+        let mut hasher = Hasher::for_checksum(&expected);
+        let mut buf = vec![0u8; 1024 * 1024];
+        loop {
+            match std::io::Read::read(&mut file, &mut buf)? {
+                0 => return Ok::<_, std::io::Error>(hasher.finish()),
+                n => hasher.update(&buf[..n]),
+            }
+        }
+    })
+    .await
+    .map_err(|e| StorageError::Backend(format!("verify task failed: {}", e)))??;
+
+    if actual != expected {
+        return Err(StorageError::Corrupt(format!("{}: expected {}, got {}", dest.display(), expected, actual)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileBackend;
+
+    // "1" for every chunk held, e.g. "110000"
+    fn bits(chunks: &str) -> Vec<bool> {
+        chunks.chars().map(|c| c == '1').collect()
+    }
+
+    fn member(name: &str, have: &str) -> Member {
+        let dataset = DatasetRef::parse("s3://b/models/weights.bin").unwrap();
+        Member {
+            peer: Peer { name: name.to_string(), addr: "10.0.0.1:8081".to_string() },
+            mirror: Arc::new(Mirror { label: format!("peer:{}", name), backend: Arc::new(FileBackend::new(None)), dataset, peer: true }),
+            on_object: true,
+            have: bits(have),
+            fetching: vec![false; have.len()],
+            busy: 0,
+            failures: 0,
+        }
+    }
+
+    fn picked(pick: Option<(usize, Source)>) -> Option<(usize, Option<usize>)> {
+        pick.map(|(c, source)| match source {
+            Source::Peer(m) => (c, Some(m)),
+            Source::Origin => (c, None),
+        })
+    }
+
+    #[test]
+    fn rarest_chunk_comes_from_the_idlest_holder() {
+        let mut members = vec![member("a", "110000"), member("c", "100000")];
+        let none = bits("000000");
+        assert_eq!(picked(pick(&members, &none, &none, "b", false)), Some((1, Some(0))));
+        // Already in flight, chunk 0 from whoever is less busy
+        let in_flight = bits("010000");
+        members[0].busy = 2;
+        assert_eq!(picked(pick(&members, &none, &in_flight, "b", false)), Some((0, Some(1))));
+        // A peer that keeps failing is not asked again
+        members[1].failures = MAX_PEER_FAILURES;
+        assert_eq!(picked(pick(&members, &none, &in_flight, "b", false)), Some((0, Some(0))));
+    }
+
+    #[test]
+    fn unheld_chunks_are_split_by_pod_name() {
+        let mut members = vec![member("a", "000000"), member("c", "000000")];
+        let none = bits("000000");
+        // Second of three by name: chunks 1 and 4
+        assert_eq!(picked(pick(&members, &none, &none, "b", false)), Some((1, None)));
+        assert_eq!(picked(pick(&members, &bits("010000"), &none, "b", false)), Some((4, None)));
+        // Another member fetching it, or members not on the object, change the share
+        members[0].fetching = bits("010000");
+        assert_eq!(picked(pick(&members, &none, &none, "b", false)), Some((4, None)));
+        members[1].on_object = false;
+        assert_eq!(picked(pick(&members, &none, &none, "b", false)), Some((3, None)));
+        assert_eq!(picked(pick(&members, &none, &none, "0", false)), Some((0, None)));
+    }
+
+    #[test]
+    fn a_stalled_swarm_fetches_anything_missing() {
+        let members = vec![member("a", "000000"), member("c", "000000")];
+        assert_eq!(picked(pick(&members, &bits("100000"), &bits("000000"), "b", true)), Some((1, None)));
+        assert_eq!(picked(pick(&members, &bits("111100"), &bits("000011"), "b", true)), None);
+    }
+}
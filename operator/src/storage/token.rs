@@ -0,0 +1,62 @@
+// OAuth access tokens for the GCS and Azure backends. One token per backend,
+// fetched on first use and refreshed shortly before it expires; concurrent
+// downloads wait on a single refresh instead of each requesting their own.
+
+use super::StorageError;
+use serde::Deserialize;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// Refresh this long before the token actually expires
+const REFRESH_MARGIN: Duration = Duration::from_secs(120);
+
+/// Body of an OAuth token endpoint response (GCE metadata server, Google and
+/// Entra ID token endpoints all use this shape).
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: u64,
+}
+
+impl TokenResponse {
+    /// Reads a token endpoint response, surfacing error bodies as
+    /// [`StorageError::Auth`].
+    pub async fn read(resp: reqwest::Response) -> Result<Self, StorageError> {
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(StorageError::Auth(format!("token endpoint returned {}: {}", status, body.trim())));
+        }
+        resp.json().await.map_err(|e| StorageError::Auth(format!("bad token response: {}", e)))
+    }
+}
+
+pub struct TokenCache {
+    current: Mutex<Option<(String, Instant)>>,
+}
+
+impl TokenCache {
+    pub fn new() -> Self {
+        Self { current: Mutex::new(None) }
+    }
+
+    /// The cached token, or a new one from `fetch` if it is about to expire.
+    pub async fn get<F, Fut>(&self, fetch: F) -> Result<String, StorageError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<TokenResponse, StorageError>>,
+    {
+        let mut current = self.current.lock().await;
+        if let Some((token, expires)) = current.as_ref() {
+            if *expires > Instant::now() + REFRESH_MARGIN {
+                return Ok(token.clone());
+            }
+        }
+
+        let fresh = fetch().await?;
+        let expires = Instant::now() + Duration::from_secs(fresh.expires_in);
+        *current = Some((fresh.access_token.clone(), expires));
+        Ok(fresh.access_token)
+    }
+}
@@ -0,0 +1,336 @@
+// --- BANDWIDTH THROTTLING ---
+// Keeps a rollout of hundreds of gated pods from turning into a thundering
+// herd on MinIO/S3. Three independent limits apply to every transfer:
+//
+// 1. A per-node token bucket (bytes/sec) for this operator instance
+// 2. A cluster-wide token bucket, split evenly between running instances
+// 3. A cap on concurrent S3 requests: one slot per call to an object store
+//    (a stat, a listing, or one read or ranged read for as long as its body
+//    streams), not per transfer, so many-object and chunked downloads share
+//    the cap fairly and lazy or proxied reads count too
+//
+// A rate of 0 disables that bucket. All three can be changed at runtime.
+
+use crate::config::Config;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::ListParams, Api, Client};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore, SemaphorePermit};
+use tracing::{info, warn};
+
+pub struct TokenBucket {
+    rate: AtomicU64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            rate: AtomicU64::new(bytes_per_sec),
+            state: Mutex::new(BucketState { tokens: bytes_per_sec as f64, last: Instant::now() }),
+        }
+    }
+
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        self.rate.store(bytes_per_sec, Ordering::Relaxed);
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Takes `bytes` tokens, sleeping off any debt. Burst is capped at one
+    /// second of traffic. Holding the lock while sleeping keeps waiters FIFO.
+    pub async fn acquire(&self, bytes: u64) -> Duration {
+        let rate = self.rate();
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let refill = now.duration_since(state.last).as_secs_f64() * rate as f64;
+        state.tokens = (state.tokens + refill).min(rate as f64);
+        state.last = now;
+        state.tokens -= bytes as f64;
+
+        if state.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+
+        let wait = Duration::from_secs_f64(-state.tokens / rate as f64);
+        tokio::time::sleep(wait).await;
+        wait
+    }
+}
+
+/// Request slots standing in for "unlimited". Finite so the limit can
+/// later be lowered by acquiring the difference.
+const UNLIMITED_REQUESTS: usize = 1 << 20;
+
+pub struct Throttle {
+    pub node: TokenBucket,
+    pub cluster: TokenBucket,
+    cluster_total: AtomicU64,
+    instances: AtomicU64,
+    requests: Arc<Semaphore>,
+    request_limit: AtomicUsize,
+    /// Slots still to retire after the cap was lowered below what was in use.
+    /// A later raise cancels this debt before adding slots.
+    retiring: Arc<AtomicUsize>,
+}
+
+impl Throttle {
+    pub fn new(node_bps: u64, cluster_bps: u64, max_requests: usize) -> Self {
+        // 0 means unlimited
+        let limit = if max_requests == 0 { UNLIMITED_REQUESTS } else { max_requests };
+        Self {
+            node: TokenBucket::new(node_bps),
+            cluster: TokenBucket::new(cluster_bps),
+            cluster_total: AtomicU64::new(cluster_bps),
+            instances: AtomicU64::new(1),
+            requests: Arc::new(Semaphore::new(limit)),
+            request_limit: AtomicUsize::new(limit),
+            retiring: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.node_bandwidth_bytes_per_sec,
+            config.cluster_bandwidth_bytes_per_sec,
+            config.max_concurrent_s3_requests,
+        )
+    }
+
+    /// Waits for a free S3 request slot. Returns the permit and how long it took.
+    pub async fn request_slot(&self) -> (SemaphorePermit<'_>, Duration) {
+        let start = Instant::now();
+        let permit = self.requests.acquire().await.expect("request semaphore closed");
+        (permit, start.elapsed())
+    }
+
+    /// Like `request_slot`, for a request that outlives the borrow (a
+    /// response body streamed from the object store).
+    pub async fn owned_request_slot(&self) -> OwnedSemaphorePermit {
+        self.requests.clone().acquire_owned().await.expect("request semaphore closed")
+    }
+
+    /// Charges `bytes` against both buckets. Returns time spent throttled.
+    pub async fn consume(&self, bytes: u64) -> Duration {
+        self.node.acquire(bytes).await + self.cluster.acquire(bytes).await
+    }
+
+    /// A view of the throttle for one transfer, adding up its waits.
+    pub fn metered(&self) -> Metered<'_> {
+        Metered { throttle: self, held_nanos: AtomicU64::new(0) }
+    }
+
+    /// Splits the cluster-wide budget evenly across `instances` operators.
+    pub fn set_instances(&self, instances: u64) {
+        self.instances.store(instances.max(1), Ordering::Relaxed);
+        let total = self.cluster_total.load(Ordering::Relaxed);
+        if total == 0 {
+            self.cluster.set_rate(0);
+            return;
+        }
+        self.cluster.set_rate((total / instances.max(1)).max(1));
+    }
+
+    /// Applies new limits without interrupting transfers. A lower request
+    /// cap takes effect as in-flight requests finish.
+    pub fn set_limits(&self, node_bps: u64, cluster_bps: u64, max_requests: usize) {
+        self.node.set_rate(node_bps);
+        self.cluster_total.store(cluster_bps, Ordering::Relaxed);
+        self.set_instances(self.instances.load(Ordering::Relaxed));
+
+        let limit = if max_requests == 0 { UNLIMITED_REQUESTS } else { max_requests };
+        let previous = self.request_limit.swap(limit, Ordering::Relaxed);
+        if limit > previous {
+            // Slots not yet retired count towards the raise first
+            let raise = limit - previous;
+            let owed = self.retiring.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| Some(d.saturating_sub(raise)))
+                .unwrap_or(0);
+            self.requests.add_permits(raise - owed.min(raise));
+        } else if limit < previous {
+            let excess = previous - limit;
+            let forgotten = self.requests.forget_permits(excess);
+            if forgotten < excess {
+                // The rest are in use; retire them as they come back
+                self.retiring.fetch_add(excess - forgotten, Ordering::SeqCst);
+                tokio::spawn(retire(self.requests.clone(), self.retiring.clone()));
+            }
+        }
+    }
+}
+
+// Takes returned slots one at a time, forgetting each while any are still
+// owed. A slot taken after a raise cancelled the debt is given back.
+async fn retire(requests: Arc<Semaphore>, retiring: Arc<AtomicUsize>) {
+    while retiring.load(Ordering::SeqCst) > 0 {
+        let Ok(permit) = requests.clone().acquire_owned().await else { return };
+        if retiring.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| d.checked_sub(1)).is_ok() {
+            permit.forget();
+        }
+    }
+}
+
+/// One transfer's view of the throttle. Adds up the time it is held back
+/// across every request and chunk, so that time can be reported whether or
+/// not the transfer succeeds.
+pub struct Metered<'a> {
+    throttle: &'a Throttle,
+    held_nanos: AtomicU64,
+}
+
+impl<'a> Metered<'a> {
+    /// Waits for a free S3 request slot.
+    pub async fn request_slot(&self) -> SemaphorePermit<'a> {
+        let (permit, waited) = self.throttle.request_slot().await;
+        self.add(waited);
+        permit
+    }
+
+    /// Charges `bytes` against both buckets.
+    pub async fn consume(&self, bytes: u64) {
+        self.add(self.throttle.consume(bytes).await);
+    }
+
+    /// Time held back so far.
+    pub fn held(&self) -> Duration {
+        Duration::from_nanos(self.held_nanos.load(Ordering::Relaxed))
+    }
+
+    fn add(&self, waited: Duration) {
+        self.held_nanos.fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Periodically counts running kube-cache instances so the cluster-wide
+/// budget is shared fairly between them.
+pub async fn track_cluster_share(client: Client, namespace: String, throttle: Arc<Throttle>) {
+    let pods: Api<Pod> = Api::namespaced(client, &namespace);
+    let lp = ListParams::default().labels("app=kube-cache");
+    let mut tick = tokio::time::interval(Duration::from_secs(30));
+    let mut last = 0;
+
+    loop {
+        tick.tick().await;
+        // Nothing to share unless a cluster budget is set (it can be set live)
+        if throttle.cluster_total.load(Ordering::Relaxed) == 0 {
+            continue;
+        }
+        let running = match pods.list(&lp).await {
+            Ok(list) => list.items.iter()
+                .filter(|p| p.status.as_ref().and_then(|s| s.phase.as_deref()) == Some("Running"))
+                .count() as u64,
+            Err(e) => {
+                warn!(event = "throttle_share_error", error = ?e, "Failed to count kube-cache instances");
+                continue;
+            }
+        };
+
+        if running != last {
+            throttle.set_instances(running);
+            info!(event = "throttle_share", instances = running, bytes_per_sec = throttle.cluster.rate(), "Rebalanced cluster bandwidth share");
+            last = running;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unlimited_bucket_never_waits() {
+        let bucket = TokenBucket::new(0);
+        assert_eq!(bucket.acquire(u64::MAX).await, Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn bucket_allows_one_second_burst_then_waits() {
+        let bucket = TokenBucket::new(10_000);
+        assert_eq!(bucket.acquire(10_000).await, Duration::ZERO);
+
+        let waited = bucket.acquire(1_000).await;
+        assert!(waited >= Duration::from_millis(90) && waited < Duration::from_millis(500), "{:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn bucket_follows_rate_changes() {
+        let bucket = TokenBucket::new(1_000);
+        bucket.acquire(1_000).await;
+        bucket.set_rate(0);
+        assert_eq!(bucket.acquire(1_000_000).await, Duration::ZERO);
+        assert_eq!(bucket.rate(), 0);
+    }
+
+    #[test]
+    fn cluster_budget_is_split_between_instances() {
+        let throttle = Throttle::new(0, 1_000, 0);
+        throttle.set_instances(4);
+        assert_eq!(throttle.cluster.rate(), 250);
+        throttle.set_instances(0);
+        assert_eq!(throttle.cluster.rate(), 1_000);
+
+        throttle.set_limits(0, 0, 0);
+        assert_eq!(throttle.cluster.rate(), 0);
+    }
+
+    #[tokio::test]
+    async fn request_cap_can_be_lowered_and_raised() {
+        let throttle = Throttle::new(0, 0, 2);
+        throttle.set_limits(0, 0, 1);
+        let held = throttle.request_slot().await;
+        assert!(throttle.requests.try_acquire().is_err());
+        drop(held);
+
+        throttle.set_limits(0, 0, 3);
+        let slots: Vec<_> = (0..3).map(|_| throttle.requests.try_acquire().unwrap()).collect();
+        assert_eq!(slots.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn raising_cancels_retirement_of_held_slots() {
+        let throttle = Throttle::new(0, 0, 2);
+        let first = throttle.request_slot().await;
+        let second = throttle.request_slot().await;
+
+        // Both slots are in use, so lowering leaves one to retire on return
+        throttle.set_limits(0, 0, 1);
+        assert_eq!(throttle.retiring.load(Ordering::SeqCst), 1);
+        // Raising cancels that debt and adds only the rest
+        throttle.set_limits(0, 0, 3);
+        assert_eq!(throttle.retiring.load(Ordering::SeqCst), 0);
+        tokio::task::yield_now().await;
+
+        let third = throttle.requests.try_acquire().unwrap();
+        assert!(throttle.requests.try_acquire().is_err());
+        drop(first);
+        tokio::task::yield_now().await;
+        let fourth = throttle.requests.try_acquire().unwrap();
+        assert!(throttle.requests.try_acquire().is_err());
+
+        drop((second, third, fourth));
+        tokio::task::yield_now().await;
+        assert_eq!(throttle.requests.available_permits(), 3);
+    }
+
+    #[tokio::test]
+    async fn metered_adds_up_waits() {
+        let throttle = Throttle::new(10_000, 0, 0);
+        let metered = throttle.metered();
+        metered.consume(10_000).await;
+        metered.consume(500).await;
+        metered.consume(500).await;
+        assert!(metered.held() >= Duration::from_millis(90), "{:?}", metered.held());
+    }
+}
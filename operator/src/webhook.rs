@@ -0,0 +1,673 @@
+// --- ADMISSION WEBHOOK ---
+// Users used to hand-write the scheduling gate into every pod spec, and a
+// forgotten gate meant the cache was silently bypassed. The operator now
+// serves a mutating webhook that adds the gate to any pod asking for a
+// dataset (inline annotation or Dataset reference).
+//
+// It also mounts the dataset's cache entry read-only into every container
+// and points `KUBE_CACHE_DATASET_PATH` at it, so workloads stop hardcoding
+// cache paths. Pod volumes are immutable after creation, which is why this
+// happens at admission rather than at release.
+//
+// A second, validating webhook rejects pods whose dataset reference can never
+// resolve (malformed URI, unknown scheme, missing Dataset object) or points at
+// a source the namespace may not use, so the mistake shows up at
+// `kubectl apply` instead of as a hung gate. Admission can be skipped, so
+// the source policy is checked again before anything is downloaded.
+//
+// The webhook manages its own serving certificate: a CA and leaf cert are
+// generated once and kept in a Secret, and the CA is written into the
+// MutatingWebhookConfiguration's caBundle. The configuration uses
+// `failurePolicy: Ignore`, so pods are still admitted (ungated) when the
+// operator is down.
+
+use crate::config::{CacheMode, Config};
+use crate::dataset::{self, DatasetError, DatasetRef};
+use crate::pvc;
+use axum::{extract::State, routing::post, Json, Router};
+use axum_server::tls_rustls::RustlsConfig;
+use k8s_openapi::api::admissionregistration::v1::{MutatingWebhookConfiguration, ValidatingWebhookConfiguration};
+use k8s_openapi::api::core::v1::{Namespace, Pod, Secret};
+use k8s_openapi::ByteString;
+use kube::api::{ObjectMeta, Patch, PatchParams, PostParams};
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview};
+use kube::core::DynamicObject;
+use kube::{Api, Client};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::SocketAddr;
+use tracing::{error, info, warn};
+
+/// Where the dataset appears inside the pod. Defaults to [`DEFAULT_MOUNT_PATH`].
+pub const MOUNT_PATH_ANNOTATION: &str = "kube-cache.openai.com/mount-path";
+pub const DEFAULT_MOUNT_PATH: &str = "/kube-cache/dataset";
+pub const DATASET_PATH_ENV: &str = "KUBE_CACHE_DATASET_PATH";
+/// Set to `"true"` to release the pod before a directory dataset is
+/// downloaded and read it through a lazy mount (needs `lazy_mounts`).
+pub const LAZY_ANNOTATION: &str = "kube-cache.openai.com/lazy";
+const VOLUME_NAME: &str = "kube-cache-dataset";
+
+/// Namespace annotation listing the source prefixes its pods may use,
+/// comma separated (e.g. `s3://team-a/,s3://shared/models/`). Buckets match
+/// exactly and key prefixes whole segments at a time. Absent means any.
+pub const ALLOWED_SOURCES_ANNOTATION: &str = "kube-cache.openai.com/allowed-sources";
+
+const WEBHOOK_CONFIG_NAME: &str = "kube-cache";
+const TLS_SECRET_NAME: &str = "kube-cache-webhook-tls";
+
+/// How the dataset is mounted into pods.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MountMode {
+    /// hostPath of the cache entry; needs a privileged Pod Security exception
+    #[value(name = "hostpath")]
+    HostPath,
+    /// Ephemeral inline volume served by the kube-cache CSI node plugin
+    Csi,
+    /// Shared per-dataset PersistentVolumeClaim (`cache_mode: pvc`)
+    #[value(skip)]
+    Pvc,
+}
+
+/// Must match the CSI plugin's DRIVER_NAME.
+const CSI_DRIVER: &str = "cache.kube-cache.openai.com";
+
+pub struct WebhookSettings {
+    pub port: u16,
+    pub gate_name: String,
+    pub dataset_annotation: String,
+    pub service: String,
+    pub namespace: String,
+    /// Cache root on the node (the operator mounts the same hostPath)
+    pub cache_root: String,
+    pub mount_mode: MountMode,
+    pub lazy_mounts: bool,
+}
+
+impl WebhookSettings {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            port: config.webhook_port,
+            gate_name: config.gate_name.clone(),
+            dataset_annotation: config.dataset_annotation.clone(),
+            service: config.webhook_service.clone(),
+            namespace: config.pod_namespace.clone(),
+            cache_root: config.cache_root.clone(),
+            mount_mode: match config.cache_mode {
+                CacheMode::Pvc => MountMode::Pvc,
+                CacheMode::Node => config.mount_mode,
+            },
+            lazy_mounts: config.lazy_mounts,
+        }
+    }
+}
+
+struct TlsMaterial {
+    ca_pem: String,
+    cert_pem: String,
+    key_pem: String,
+}
+
+/// Sets up certificates and the webhook registration, then serves admission
+/// requests. Any setup failure is logged and the webhook stays off; pods can
+/// still carry the gate by hand.
+pub async fn run(client: Client, settings: WebhookSettings) {
+    let tls = match ensure_certificate(&client, &settings).await {
+        Ok(tls) => tls,
+        Err(e) => {
+            error!(event = "webhook_cert_error", error = ?e, "Failed to set up webhook certificate, webhook disabled");
+            return;
+        }
+    };
+
+    if let Err(e) = register(&client, &settings, &tls.ca_pem).await {
+        error!(event = "webhook_register_error", error = ?e, "Failed to register webhook, webhook disabled");
+        return;
+    }
+
+    let config = match RustlsConfig::from_pem(tls.cert_pem.into_bytes(), tls.key_pem.into_bytes()).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!(event = "webhook_tls_error", error = ?e, "Invalid webhook TLS material");
+            return;
+        }
+    };
+
+    let state = WebhookState {
+        client,
+        gate_name: settings.gate_name.clone(),
+        dataset_annotation: settings.dataset_annotation.clone(),
+        cache_root: settings.cache_root.clone(),
+        mount_mode: settings.mount_mode,
+        lazy_mounts: settings.lazy_mounts,
+    };
+    let app = Router::new()
+        .route("/mutate", post(mutate_handler))
+        .route("/validate", post(validate_handler))
+        .with_state(state);
+    let addr = SocketAddr::from(([0, 0, 0, 0], settings.port));
+    info!(event = "server_start", port = settings.port, "Admission Webhook listening");
+
+    if let Err(e) = axum_server::bind_rustls(addr, config).serve(app.into_make_service()).await {
+        error!(event = "webhook_server_error", error = ?e, "Admission webhook stopped");
+    }
+}
+
+#[derive(Clone)]
+struct WebhookState {
+    client: Client,
+    gate_name: String,
+    dataset_annotation: String,
+    cache_root: String,
+    mount_mode: MountMode,
+    lazy_mounts: bool,
+}
+
+/// True if the pod asked for a lazy mount of a dataset that can have one.
+pub fn wants_lazy(pod: &Pod, dataset: &DatasetRef) -> bool {
+    dataset.is_prefix()
+        && pod.metadata.annotations.as_ref().and_then(|a| a.get(LAZY_ANNOTATION)).map(String::as_str) == Some("true")
+}
+
+async fn mutate_handler(
+    State(state): State<WebhookState>,
+    Json(review): Json<AdmissionReview<Pod>>,
+) -> Json<AdmissionReview<DynamicObject>> {
+    let req: AdmissionRequest<Pod> = match review.try_into() {
+        Ok(req) => req,
+        Err(e) => {
+            warn!(event = "webhook_bad_review", error = %e, "Malformed AdmissionReview");
+            return Json(AdmissionResponse::invalid(e.to_string()).into_review());
+        }
+    };
+
+    Json(mutate(&state, &req).await.into_review())
+}
+
+async fn mutate(state: &WebhookState, req: &AdmissionRequest<Pod>) -> AdmissionResponse {
+    let response = AdmissionResponse::from(req);
+    let Some(pod) = req.object.as_ref() else { return response };
+
+    if !dataset::wants_dataset(pod, &state.dataset_annotation) {
+        return response;
+    }
+
+    let name = pod.metadata.name.as_deref().or(pod.metadata.generate_name.as_deref()).unwrap_or_default();
+    let mut ops = Vec::new();
+
+    // 1. The scheduling gate
+    let gates = pod.spec.as_ref().and_then(|s| s.scheduling_gates.as_ref());
+    if !gates.map(|g| g.iter().any(|g| g.name == state.gate_name)).unwrap_or(false) {
+        ops.push(match gates {
+            Some(_) => json!({ "op": "add", "path": "/spec/schedulingGates/-", "value": { "name": state.gate_name } }),
+            None => json!({ "op": "add", "path": "/spec/schedulingGates", "value": [{ "name": state.gate_name }] }),
+        });
+        info!(event = "gate_injected", pod_name = %name, namespace = ?req.namespace, "Added scheduling gate at admission");
+    }
+
+    // 2. The dataset mount. Job controllers set the completion index at
+    // creation, so templated references already render here.
+    let mut pod = pod.clone();
+    if pod.metadata.namespace.is_none() {
+        pod.metadata.namespace = req.namespace.clone();
+    }
+    match dataset::lookup(&state.client, &pod, &state.dataset_annotation).await {
+        Some(Ok(dataset)) => ops.extend(mount_ops(state.mount_mode, &state.cache_root, state.lazy_mounts, &pod, &dataset)),
+        Some(Err(e)) => warn!(event = "mount_skipped", pod_name = %name, error = %e, "Cannot resolve dataset, not mounting it"),
+        None => {}
+    }
+
+    if ops.is_empty() {
+        return response;
+    }
+
+    let patch: json_patch::Patch = serde_json::from_value(json!(ops)).expect("patch operations are valid");
+    match response.with_patch(patch) {
+        Ok(response) => response,
+        Err(e) => AdmissionResponse::invalid(e.to_string()),
+    }
+}
+
+async fn validate_handler(
+    State(state): State<WebhookState>,
+    Json(review): Json<AdmissionReview<Pod>>,
+) -> Json<AdmissionReview<DynamicObject>> {
+    let req: AdmissionRequest<Pod> = match review.try_into() {
+        Ok(req) => req,
+        Err(e) => {
+            warn!(event = "webhook_bad_review", error = %e, "Malformed AdmissionReview");
+            return Json(AdmissionResponse::invalid(e.to_string()).into_review());
+        }
+    };
+
+    Json(validate(&state, &req).await.into_review())
+}
+
+async fn validate(state: &WebhookState, req: &AdmissionRequest<Pod>) -> AdmissionResponse {
+    let response = AdmissionResponse::from(req);
+    let Some(pod) = req.object.as_ref() else { return response };
+
+    let mut pod = pod.clone();
+    if pod.metadata.namespace.is_none() {
+        pod.metadata.namespace = req.namespace.clone();
+    }
+    let name = pod.metadata.name.as_deref().or(pod.metadata.generate_name.as_deref()).unwrap_or_default().to_string();
+
+    let dataset = match dataset::lookup(&state.client, &pod, &state.dataset_annotation).await {
+        None => return response,
+        Some(Ok(dataset)) => dataset,
+        // Fail open, as for the source policy below; the operator resolves it again
+        Some(Err(e @ DatasetError::Lookup(..))) => {
+            warn!(event = "dataset_lookup_error", pod_name = %name, error = %e, "Could not read the pod's Dataset");
+            return response;
+        }
+        Some(Err(e)) => {
+            info!(event = "pod_rejected", pod_name = %name, error = %e, "Rejected invalid dataset reference");
+            return response.deny(format!("kube-cache: invalid dataset reference: {}", e));
+        }
+    };
+
+    let namespace = pod.metadata.namespace.clone().unwrap_or_else(|| "default".to_string());
+    match check_source(&state.client, &namespace, &dataset).await {
+        Ok(()) => response,
+        // Fail open, like the webhook itself; the download path checks again
+        Err(e @ SourceError::Lookup(..)) => {
+            warn!(event = "namespace_lookup_error", namespace = %namespace, error = %e, "Could not read namespace source policy");
+            response
+        }
+        Err(e) => {
+            info!(event = "pod_rejected", pod_name = %name, dataset = %dataset.uri, error = %e, "Rejected disallowed dataset source");
+            response.deny(format!("kube-cache: {}", e))
+        }
+    }
+}
+
+/// Why a namespace may not read a dataset.
+#[derive(Debug)]
+pub enum SourceError {
+    /// The namespace's allowed-sources annotation does not cover this URI
+    NotAllowed { namespace: String, uri: String, allowed: Vec<String> },
+    /// The namespace, and so its policy, could not be read
+    Lookup(String, kube::Error),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::NotAllowed { namespace, uri, allowed } => write!(
+                f, "namespace '{}' may not use '{}' (allowed: {})", namespace, uri, allowed.join(", ")
+            ),
+            SourceError::Lookup(namespace, e) => write!(f, "cannot read the source policy of namespace '{}': {}", namespace, e),
+        }
+    }
+}
+
+/// Checks the dataset and each of its mirrors against the namespace's
+/// allowed sources. Fails closed: admission can be skipped (the webhook
+/// ignores failures), so every download checks this again first.
+pub async fn check_source(client: &Client, namespace: &str, dataset: &DatasetRef) -> Result<(), SourceError> {
+    let Some(allowed) = allowed_sources(client, namespace).await
+        .map_err(|e| SourceError::Lookup(namespace.to_string(), e))? else { return Ok(()) };
+    for source in std::iter::once(dataset).chain(&dataset.mirrors) {
+        if !allowed.iter().any(|prefix| allows(prefix, source)) {
+            return Err(SourceError::NotAllowed { namespace: namespace.to_string(), uri: source.uri.clone(), allowed });
+        }
+    }
+    Ok(())
+}
+
+// Whether one allowed-sources entry covers a source. Scheme and bucket (or
+// account, registry, host) must match exactly and the key prefix on `/`
+// boundaries, so `s3://team-a` does not cover `s3://team-a-secrets/...` nor
+// `s3://b/models` cover `s3://b/models-old/...`. Keys with `.` or `..`
+// segments (also percent-encoded) are never covered: `file:///seed/../etc`
+// must not pass as being under `file:///seed/`.
+fn allows(prefix: &str, source: &DatasetRef) -> bool {
+    let Some((scheme, rest)) = prefix.split_once("://") else { return false };
+    let (bucket, key_prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if scheme != source.scheme.as_str() || bucket != source.bucket {
+        return false;
+    }
+
+    let segments: Vec<&str> = source.key.split('/').collect();
+    let dotted = |segment: &&str| {
+        let decoded = percent_encoding::percent_decode_str(segment).decode_utf8_lossy();
+        decoded == "." || decoded == ".."
+    };
+    if segments.iter().any(dotted) {
+        return false;
+    }
+    let wanted: Vec<&str> = key_prefix.trim_end_matches('/').split('/').filter(|s| !s.is_empty()).collect();
+    segments.len() >= wanted.len() && segments.iter().zip(&wanted).all(|(have, want)| have == want)
+}
+
+// Source prefixes a namespace is limited to, or None if unrestricted.
+async fn allowed_sources(client: &Client, namespace: &str) -> Result<Option<Vec<String>>, kube::Error> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+    let ns = namespaces.get(namespace).await?;
+    Ok(ns.metadata.annotations.as_ref()
+        .and_then(|a| a.get(ALLOWED_SOURCES_ANNOTATION))
+        .map(|v| v.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect()))
+}
+
+// JSON patch operations that add a read-only volume for the cache entry and
+// mount it (plus the env var) into every container.
+fn mount_ops(
+    mount_mode: MountMode,
+    cache_root: &str,
+    lazy_mounts: bool,
+    pod: &Pod,
+    dataset: &DatasetRef,
+) -> Vec<serde_json::Value> {
+    let Some(spec) = pod.spec.as_ref() else { return Vec::new() };
+
+    if spec.volumes.as_ref().map(|v| v.iter().any(|v| v.name == VOLUME_NAME)).unwrap_or(false) {
+        return Vec::new();
+    }
+
+    let mount_path = pod.metadata.annotations.as_ref()
+        .and_then(|a| a.get(MOUNT_PATH_ANNOTATION))
+        .map(String::as_str)
+        .unwrap_or(DEFAULT_MOUNT_PATH);
+
+    // hostPath mounts the entry itself; CSI and PVC volumes are directories
+    // holding the entry under its last key segment. Lazy pods get the lazy
+    // mount instead of the entry.
+    let lazy = lazy_mounts && wants_lazy(pod, dataset);
+    let (volume, dataset_path) = match mount_mode {
+        MountMode::HostPath => {
+            let path = match lazy {
+                true => dataset.lazy_path(cache_root),
+                false => dataset.cache_path(cache_root),
+            };
+            // Typed, so the kubelet refuses a missing entry instead of creating it
+            let kind = if lazy || dataset.is_directory() { "Directory" } else { "File" };
+            (json!({ "name": VOLUME_NAME, "hostPath": { "path": path, "type": kind } }), mount_path.to_string())
+        }
+        MountMode::Csi => (
+            json!({ "name": VOLUME_NAME, "csi": {
+                "driver": CSI_DRIVER,
+                "readOnly": true,
+                "volumeAttributes": match lazy {
+                    true => json!({ "dataset": dataset.uri, "lazy": "true" }),
+                    false => json!({ "dataset": dataset.uri }),
+                },
+            } }),
+            format!("{}/{}", mount_path.trim_end_matches('/'), dataset.entry_name()),
+        ),
+        MountMode::Pvc => (
+            json!({ "name": VOLUME_NAME, "persistentVolumeClaim": {
+                "claimName": pvc::claim_name(dataset),
+                "readOnly": true,
+            } }),
+            format!("{}/{}", mount_path.trim_end_matches('/'), dataset.entry_name()),
+        ),
+    };
+    let mount = json!({ "name": VOLUME_NAME, "mountPath": mount_path, "readOnly": true });
+    let env = json!({ "name": DATASET_PATH_ENV, "value": dataset_path });
+
+    let mut ops = vec![match spec.volumes {
+        Some(_) => json!({ "op": "add", "path": "/spec/volumes/-", "value": volume }),
+        None => json!({ "op": "add", "path": "/spec/volumes", "value": [volume] }),
+    }];
+
+    for (i, container) in spec.containers.iter().enumerate() {
+        ops.push(match container.volume_mounts {
+            Some(_) => json!({ "op": "add", "path": format!("/spec/containers/{}/volumeMounts/-", i), "value": mount }),
+            None => json!({ "op": "add", "path": format!("/spec/containers/{}/volumeMounts", i), "value": [mount] }),
+        });
+        ops.push(match container.env {
+            Some(_) => json!({ "op": "add", "path": format!("/spec/containers/{}/env/-", i), "value": env }),
+            None => json!({ "op": "add", "path": format!("/spec/containers/{}/env", i), "value": [env] }),
+        });
+    }
+
+    ops
+}
+
+// Loads the serving certificate from its Secret, creating it on first start.
+// Replicas racing to create it all end up with whichever write won.
+async fn ensure_certificate(client: &Client, settings: &WebhookSettings) -> Result<TlsMaterial, Box<dyn std::error::Error>> {
+    let secrets: Api<Secret> = Api::namespaced(client.clone(), &settings.namespace);
+
+    if let Some(secret) = secrets.get_opt(TLS_SECRET_NAME).await? {
+        if let Some(tls) = read_tls(&secret) {
+            return Ok(tls);
+        }
+        warn!(event = "webhook_cert_invalid", "Webhook TLS secret is incomplete, regenerating");
+        secrets.delete(TLS_SECRET_NAME, &Default::default()).await?;
+    }
+
+    let tls = generate_certificate(settings)?;
+    let data = BTreeMap::from([
+        ("ca.crt".to_string(), ByteString(tls.ca_pem.clone().into_bytes())),
+        ("tls.crt".to_string(), ByteString(tls.cert_pem.clone().into_bytes())),
+        ("tls.key".to_string(), ByteString(tls.key_pem.clone().into_bytes())),
+    ]);
+    let secret = Secret {
+        metadata: ObjectMeta { name: Some(TLS_SECRET_NAME.to_string()), ..Default::default() },
+        type_: Some("kubernetes.io/tls".to_string()),
+        data: Some(data),
+        ..Default::default()
+    };
+
+    match secrets.create(&PostParams::default(), &secret).await {
+        Ok(_) => {
+            info!(event = "webhook_cert_created", secret = TLS_SECRET_NAME, "Generated webhook serving certificate");
+            Ok(tls)
+        }
+        // Another replica got there first
+        Err(kube::Error::Api(e)) if e.code == 409 => {
+            let secret = secrets.get(TLS_SECRET_NAME).await?;
+            read_tls(&secret).ok_or_else(|| "webhook TLS secret is incomplete".into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_tls(secret: &Secret) -> Option<TlsMaterial> {
+    let data = secret.data.as_ref()?;
+    let field = |k: &str| data.get(k).and_then(|v| String::from_utf8(v.0.clone()).ok());
+    Some(TlsMaterial {
+        ca_pem: field("ca.crt")?,
+        cert_pem: field("tls.crt")?,
+        key_pem: field("tls.key")?,
+    })
+}
+
+fn generate_certificate(settings: &WebhookSettings) -> Result<TlsMaterial, rcgen::Error> {
+    // 1. Self-signed CA
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.distinguished_name.push(DnType::CommonName, "kube-cache-webhook-ca");
+    let ca_key = KeyPair::generate()?;
+    let ca_cert = ca_params.self_signed(&ca_key)?;
+
+    // 2. Serving cert for the in-cluster Service DNS names
+    let service = &settings.service;
+    let namespace = &settings.namespace;
+    let mut params = CertificateParams::new(vec![
+        service.clone(),
+        format!("{}.{}", service, namespace),
+        format!("{}.{}.svc", service, namespace),
+        format!("{}.{}.svc.cluster.local", service, namespace),
+    ])?;
+    params.distinguished_name.push(DnType::CommonName, format!("{}.{}.svc", service, namespace));
+    let key = KeyPair::generate()?;
+    let cert = params.signed_by(&key, &ca_cert, &ca_key)?;
+
+    Ok(TlsMaterial {
+        ca_pem: ca_cert.pem(),
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+    })
+}
+
+// Server-side applies both webhook registrations with the current CA bundle.
+async fn register(client: &Client, settings: &WebhookSettings, ca_pem: &str) -> Result<(), kube::Error> {
+    let pp = PatchParams::apply("kube-cache").force();
+    let client_config = |path: &str| json!({
+        "service": { "name": settings.service, "namespace": settings.namespace, "path": path, "port": 443 },
+        "caBundle": ByteString(ca_pem.as_bytes().to_vec()),
+    });
+    let rules = json!([{
+        "apiGroups": [""],
+        "apiVersions": ["v1"],
+        "operations": ["CREATE"],
+        "resources": ["pods"],
+    }]);
+    let namespace_selector = json!({
+        "matchExpressions": [{ "key": "kubernetes.io/metadata.name", "operator": "NotIn", "values": ["kube-system"] }]
+    });
+
+    let mutating: MutatingWebhookConfiguration = serde_json::from_value(json!({
+        "apiVersion": "admissionregistration.k8s.io/v1",
+        "kind": "MutatingWebhookConfiguration",
+        "metadata": { "name": WEBHOOK_CONFIG_NAME },
+        "webhooks": [{
+            "name": "gate.kube-cache.openai.com",
+            "admissionReviewVersions": ["v1"],
+            "sideEffects": "None",
+            // Fail open: never block pod creation on the operator
+            "failurePolicy": "Ignore",
+            "timeoutSeconds": 5,
+            "clientConfig": client_config("/mutate"),
+            "rules": rules,
+            "namespaceSelector": namespace_selector,
+        }],
+    })).expect("static webhook configuration is valid");
+
+    let validating: ValidatingWebhookConfiguration = serde_json::from_value(json!({
+        "apiVersion": "admissionregistration.k8s.io/v1",
+        "kind": "ValidatingWebhookConfiguration",
+        "metadata": { "name": WEBHOOK_CONFIG_NAME },
+        "webhooks": [{
+            "name": "dataset.kube-cache.openai.com",
+            "admissionReviewVersions": ["v1"],
+            "sideEffects": "None",
+            "failurePolicy": "Ignore",
+            "timeoutSeconds": 5,
+            "clientConfig": client_config("/validate"),
+            "rules": rules,
+            "namespaceSelector": namespace_selector,
+        }],
+    })).expect("static webhook configuration is valid");
+
+    let mutating_api: Api<MutatingWebhookConfiguration> = Api::all(client.clone());
+    mutating_api.patch(WEBHOOK_CONFIG_NAME, &pp, &Patch::Apply(&mutating)).await?;
+    let validating_api: Api<ValidatingWebhookConfiguration> = Api::all(client.clone());
+    validating_api.patch(WEBHOOK_CONFIG_NAME, &pp, &Patch::Apply(&validating)).await?;
+
+    info!(event = "webhook_registered", name = WEBHOOK_CONFIG_NAME, "Registered admission webhooks");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(uri: &str) -> DatasetRef {
+        DatasetRef::parse(uri).unwrap()
+    }
+
+    fn pod(annotations: serde_json::Value) -> Pod {
+        serde_json::from_value(json!({
+            "metadata": {"name": "trainer", "annotations": annotations},
+            "spec": {"containers": [
+                {"name": "main"},
+                {"name": "sidecar", "volumeMounts": [{"name": "scratch", "mountPath": "/scratch"}], "env": []},
+            ]},
+        })).unwrap()
+    }
+
+    fn volume(ops: &[serde_json::Value]) -> &serde_json::Value {
+        assert_eq!(ops[0]["path"], "/spec/volumes");
+        &ops[0]["value"][0]
+    }
+
+    #[test]
+    fn host_path_mounts_the_entry_itself() {
+        let file = source("s3://b/models/weights.bin");
+        let ops = mount_ops(MountMode::HostPath, "/cache", false, &pod(json!({})), &file);
+        assert_eq!(volume(&ops)["hostPath"], json!({"path": file.cache_path("/cache"), "type": "File"}));
+
+        assert_eq!(ops[1]["path"], "/spec/containers/0/volumeMounts");
+        assert_eq!(ops[1]["value"][0]["mountPath"], DEFAULT_MOUNT_PATH);
+        assert_eq!(ops[2]["path"], "/spec/containers/0/env");
+        assert_eq!(ops[2]["value"][0], json!({"name": DATASET_PATH_ENV, "value": DEFAULT_MOUNT_PATH}));
+        assert_eq!(ops[3]["path"], "/spec/containers/1/volumeMounts/-");
+        assert_eq!(ops[4]["path"], "/spec/containers/1/env/-");
+        assert_eq!(ops.len(), 5);
+
+        let prefix = source("s3://b/models/");
+        let ops = mount_ops(MountMode::HostPath, "/cache", false, &pod(json!({})), &prefix);
+        assert_eq!(volume(&ops)["hostPath"]["type"], "Directory");
+    }
+
+    #[test]
+    fn csi_and_pvc_volumes_hold_the_entry_by_name() {
+        let dataset = source("s3://b/models/weights.bin");
+        let annotations = json!({MOUNT_PATH_ANNOTATION: "/data/"});
+
+        let ops = mount_ops(MountMode::Csi, "/cache", false, &pod(annotations.clone()), &dataset);
+        assert_eq!(volume(&ops)["csi"]["driver"], CSI_DRIVER);
+        assert_eq!(volume(&ops)["csi"]["volumeAttributes"], json!({"dataset": "s3://b/models/weights.bin"}));
+        assert_eq!(ops[1]["value"][0]["mountPath"], "/data/");
+        assert_eq!(ops[2]["value"][0]["value"], "/data/weights.bin");
+
+        let ops = mount_ops(MountMode::Pvc, "/cache", false, &pod(annotations), &dataset);
+        assert_eq!(volume(&ops)["persistentVolumeClaim"], json!({"claimName": pvc::claim_name(&dataset), "readOnly": true}));
+        assert_eq!(ops[2]["value"][0]["value"], "/data/weights.bin");
+    }
+
+    #[test]
+    fn lazy_pods_get_the_lazy_mount_when_enabled() {
+        let prefix = source("s3://b/models/");
+        let lazy = pod(json!({LAZY_ANNOTATION: "true"}));
+
+        let ops = mount_ops(MountMode::HostPath, "/cache", true, &lazy, &prefix);
+        assert_eq!(volume(&ops)["hostPath"], json!({"path": prefix.lazy_path("/cache"), "type": "Directory"}));
+        let ops = mount_ops(MountMode::Csi, "/cache", true, &lazy, &prefix);
+        assert_eq!(volume(&ops)["csi"]["volumeAttributes"], json!({"dataset": "s3://b/models/", "lazy": "true"}));
+
+        // Off, or for a single object, the pod waits for the whole entry
+        let ops = mount_ops(MountMode::HostPath, "/cache", false, &lazy, &prefix);
+        assert_eq!(volume(&ops)["hostPath"]["path"], prefix.cache_path("/cache"));
+        let file = source("s3://b/models/weights.bin");
+        let ops = mount_ops(MountMode::HostPath, "/cache", true, &lazy, &file);
+        assert_eq!(volume(&ops)["hostPath"]["path"], file.cache_path("/cache"));
+    }
+
+    #[test]
+    fn pods_that_already_have_the_volume_are_left_alone() {
+        let mut pod = pod(json!({}));
+        pod.spec.as_mut().unwrap().volumes = Some(vec![serde_json::from_value(json!({"name": VOLUME_NAME})).unwrap()]);
+        assert!(mount_ops(MountMode::HostPath, "/cache", false, &pod, &source("s3://b/x.bin")).is_empty());
+    }
+
+    #[test]
+    fn buckets_match_exactly() {
+        assert!(allows("s3://team-a", &source("s3://team-a/x.bin")));
+        assert!(allows("s3://team-a/", &source("s3://team-a/x.bin")));
+        assert!(!allows("s3://team-a", &source("s3://team-a-secrets/x.bin")));
+        assert!(!allows("s3://team-a/", &source("gs://team-a/x.bin")));
+    }
+
+    #[test]
+    fn key_prefixes_match_on_segment_boundaries() {
+        assert!(allows("s3://b/models", &source("s3://b/models/a.bin")));
+        assert!(allows("s3://b/models/", &source("s3://b/models/sub/")));
+        assert!(!allows("s3://b/models", &source("s3://b/models-old/a.bin")));
+        assert!(!allows("s3://b/models/sub/", &source("s3://b/models/")));
+    }
+
+    #[test]
+    fn dot_segments_are_never_allowed() {
+        assert!(allows("file:///seed/", &source("file:///seed/weights.bin")));
+        assert!(!allows("file:///seed/", &source("file:///seed/../../etc/passwd")));
+        assert!(!allows("https://host/allowed/", &source("https://host/allowed/%2e%2e/secret")));
+        assert!(!allows("s3://b/", &source("s3://b/./a.bin")));
+    }
+}